@@ -0,0 +1,78 @@
+//! Benchmarks for the storage operations behind the client endpoints, run
+//! against the database in `DATABASE_URL` (migrations must already be run).
+//!
+//! ```sh
+//! DATABASE_URL=postgres://localhost/vss cargo bench
+//! ```
+//!
+//! Postgres is the only backend today, new backends should get the same
+//! set of benchmarks so they can be compared.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use vss_rs::models::VssItem;
+
+const STORE_ID: &str = "bench_store_id";
+const VALUE: [u8; 256] = [7; 256];
+
+fn connection() -> PooledConnection<ConnectionManager<PgConnection>> {
+    dotenv::dotenv().ok();
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let manager = ConnectionManager::<PgConnection>::new(url);
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(manager)
+        .expect("Could not build connection pool");
+    pool.get().expect("Could not get a connection")
+}
+
+fn clear_store(conn: &mut PgConnection) {
+    diesel::sql_query("DELETE FROM vss_db WHERE store_id = $1")
+        .bind::<diesel::sql_types::Text, _>(STORE_ID)
+        .execute(conn)
+        .unwrap();
+}
+
+fn backend(c: &mut Criterion) {
+    let mut conn = connection();
+    clear_store(&mut conn);
+
+    // every iteration writes a newer version so none are ignored as stale
+    let mut version = 0;
+    c.bench_function("put single", |b| {
+        b.iter(|| {
+            version += 1;
+            VssItem::put_item(&mut conn, STORE_ID, "single", &VALUE, version).unwrap();
+        })
+    });
+
+    let keys: Vec<String> = (0..100).map(|i| format!("tx/{i:03}")).collect();
+    let mut version = 0;
+    c.bench_function("put 100 item transaction", |b| {
+        b.iter(|| {
+            version += 1;
+            conn.transaction::<_, anyhow::Error, _>(|conn| {
+                for key in keys.iter() {
+                    VssItem::put_item(conn, STORE_ID, key, &VALUE, version)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        })
+    });
+
+    c.bench_function("get", |b| {
+        b.iter(|| black_box(VssItem::get_item(&mut conn, None, STORE_ID, "single").unwrap()))
+    });
+
+    // the transaction benchmark left 100 keys under tx/ to list
+    c.bench_function("list by prefix", |b| {
+        b.iter(|| black_box(VssItem::list_key_versions(&mut conn, STORE_ID, Some("tx/")).unwrap()))
+    });
+
+    clear_store(&mut conn);
+}
+
+criterion_group!(benches, backend);
+criterion_main!(benches);
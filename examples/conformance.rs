@@ -0,0 +1,249 @@
+//! Checks a running VSS server against the behavior defined by the reference
+//! vss-server protocol (vss.proto), reporting every deviation.
+//!
+//! ```sh
+//! VSS_URL=http://localhost:8080 VSS_TOKEN=<jwt> cargo run --example conformance
+//! ```
+//!
+//! `VSS_TOKEN` is only needed when the server has an `AUTH_KEY`, its `sub`
+//! claim must be the store id given by `VSS_STORE_ID` (default: a fresh id).
+
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct Client {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+    store_id: String,
+}
+
+type Check = fn(&Client) -> Result<(), String>;
+
+/// Status and JSON body of a response, error statuses included.
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+impl Client {
+    fn call(&self, method: &str, path: &str, body: Value, auth: bool) -> Result<Reply, String> {
+        let mut req = self
+            .agent
+            .request(method, &format!("{}{path}", self.url))
+            .set("Content-Type", "application/json");
+        if auth {
+            if let Some(ref token) = self.token {
+                req = req.set("Authorization", &format!("Bearer {token}"));
+            }
+        }
+
+        let resp = match req.send_string(&body.to_string()) {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(_, resp)) => resp,
+            Err(e) => return Err(format!("{method} {path} failed: {e}")),
+        };
+
+        let status = resp.status();
+        let text = resp.into_string().map_err(|e| e.to_string())?;
+        let body = serde_json::from_str(&text).unwrap_or(Value::String(text));
+
+        Ok(Reply { status, body })
+    }
+
+    fn put(&self, items: Value, global_version: Option<i64>) -> Result<Reply, String> {
+        let mut body = json!({ "store_id": self.store_id, "transaction_items": items });
+        if let Some(v) = global_version {
+            body["global_version"] = json!(v);
+        }
+        self.call("PUT", "/v2/putObjects", body, true)
+    }
+
+    fn get(&self, key: &str) -> Result<Reply, String> {
+        let body = json!({ "store_id": self.store_id, "key": key });
+        self.call("POST", "/v2/getObject", body, true)
+    }
+
+    fn list(&self, prefix: Option<&str>, page_size: Option<i32>) -> Result<Reply, String> {
+        let body = json!({
+            "store_id": self.store_id,
+            "key_prefix": prefix,
+            "page_size": page_size,
+        });
+        self.call("POST", "/v2/listKeyVersions", body, true)
+    }
+}
+
+fn item(key: &str, value: &[u8], version: i64) -> Value {
+    json!({ "key": key, "value": value, "version": version })
+}
+
+fn expect_status(reply: &Reply, expected: u16) -> Result<(), String> {
+    if reply.status == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected status {expected}, got {}: {}",
+            reply.status, reply.body
+        ))
+    }
+}
+
+fn expect_eq(what: &str, actual: &Value, expected: &Value) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {what} {expected}, got {actual}"))
+    }
+}
+
+/// Writing a new key with version 0 succeeds.
+fn put_new_key(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("new", &[1, 2, 3], 0)]), None)?, 200)
+}
+
+/// The server increments a key's version on every write.
+fn version_increments(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("incr", &[1], 0)]), None)?, 200)?;
+    let reply = c.get("incr")?;
+    expect_status(&reply, 200)?;
+    expect_eq("version", &reply.body["version"], &json!(1))
+}
+
+/// Values read back exactly as written.
+fn get_round_trips(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("round", &[9, 8, 7], 0)]), None)?, 200)?;
+    let reply = c.get("round")?;
+    expect_status(&reply, 200)?;
+    expect_eq("value", &reply.body["value"], &json!([9, 8, 7]))
+}
+
+/// Reading a key that was never written is a NoSuchKeyException (404).
+fn get_missing_key(c: &Client) -> Result<(), String> {
+    expect_status(&c.get("missing")?, 404)
+}
+
+/// Writing with a stale version is a ConflictException (409) and leaves
+/// the stored value alone.
+fn stale_version_conflicts(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("stale", &[1], 0)]), None)?, 200)?;
+    expect_status(&c.put(json!([item("stale", &[1], 1)]), None)?, 200)?;
+    expect_status(&c.put(json!([item("stale", &[2], 0)]), None)?, 409)?;
+
+    let reply = c.get("stale")?;
+    expect_eq("value", &reply.body["value"], &json!([1]))
+}
+
+/// A transaction with one conflicting item writes nothing.
+fn transactions_are_atomic(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("atomic_a", &[1], 0)]), None)?, 200)?;
+    expect_status(&c.put(json!([item("atomic_a", &[1], 1)]), None)?, 200)?;
+
+    let items = json!([item("atomic_b", &[2], 0), item("atomic_a", &[2], 0)]);
+    expect_status(&c.put(items, None)?, 409)?;
+
+    expect_status(&c.get("atomic_b")?, 404)
+}
+
+/// A mismatched global_version is a ConflictException (409).
+fn global_version_conflicts(c: &Client) -> Result<(), String> {
+    expect_status(&c.put(json!([item("global", &[1], 0)]), Some(0))?, 200)?;
+    expect_status(&c.put(json!([item("global2", &[1], 0)]), Some(0))?, 409)
+}
+
+/// listKeyVersions returns `key_versions`, filtered by prefix.
+fn list_filters_by_prefix(c: &Client) -> Result<(), String> {
+    let items = json!([item("prefix/a", &[1], 0), item("prefix/b", &[1], 0)]);
+    expect_status(&c.put(items, None)?, 200)?;
+
+    let reply = c.list(Some("prefix/"), None)?;
+    expect_status(&reply, 200)?;
+    let Some(versions) = reply.body["key_versions"].as_array() else {
+        return Err(format!("expected a key_versions array, got {}", reply.body));
+    };
+    expect_eq("key count", &json!(versions.len()), &json!(2))
+}
+
+/// listKeyVersions pages with page_size and next_page_token.
+fn list_paginates(c: &Client) -> Result<(), String> {
+    let items = json!([item("page/a", &[1], 0), item("page/b", &[1], 0)]);
+    expect_status(&c.put(items, None)?, 200)?;
+
+    let reply = c.list(Some("page/"), Some(1))?;
+    expect_status(&reply, 200)?;
+    expect_eq(
+        "page length",
+        &json!(reply.body["key_versions"].as_array().map(|v| v.len())),
+        &json!(1),
+    )?;
+    if reply.body["next_page_token"].as_str().is_none() {
+        return Err(format!("expected a next_page_token, got {}", reply.body));
+    }
+    Ok(())
+}
+
+/// The first page of listKeyVersions carries the store's global_version.
+fn list_returns_global_version(c: &Client) -> Result<(), String> {
+    let reply = c.list(None, None)?;
+    expect_status(&reply, 200)?;
+    if reply.body["global_version"].as_i64().is_none() {
+        return Err(format!("expected a global_version, got {}", reply.body));
+    }
+    Ok(())
+}
+
+/// Requests without a token are rejected (401) by servers requiring auth.
+fn requires_auth(c: &Client) -> Result<(), String> {
+    if c.token.is_none() {
+        return Ok(());
+    }
+    let body = json!({ "store_id": c.store_id, "key": "new" });
+    expect_status(&c.call("POST", "/v2/getObject", body, false)?, 401)
+}
+
+fn main() {
+    let url = std::env::var("VSS_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+    let store_id = std::env::var("VSS_STORE_ID").unwrap_or_else(|_| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        format!("conformance_{}", now.as_millis())
+    });
+
+    let client = Client {
+        agent: ureq::agent(),
+        url: url.trim_end_matches('/').to_string(),
+        token: std::env::var("VSS_TOKEN").ok(),
+        store_id,
+    };
+
+    let checks: [(&str, Check); 11] = [
+        ("put new key", put_new_key),
+        ("version increments", version_increments),
+        ("get round trips", get_round_trips),
+        ("get missing key", get_missing_key),
+        ("stale version conflicts", stale_version_conflicts),
+        ("transactions are atomic", transactions_are_atomic),
+        ("global version conflicts", global_version_conflicts),
+        ("list filters by prefix", list_filters_by_prefix),
+        ("list paginates", list_paginates),
+        ("list returns global version", list_returns_global_version),
+        ("requires auth", requires_auth),
+    ];
+
+    println!("Checking {} with store {}", client.url, client.store_id);
+
+    let mut deviations = 0;
+    for (name, check) in checks {
+        match check(&client) {
+            Ok(()) => println!("PASS {name}"),
+            Err(e) => {
+                deviations += 1;
+                println!("FAIL {name}: {e}");
+            }
+        }
+    }
+
+    println!("{deviations} deviation(s) from the reference protocol");
+    if deviations > 0 {
+        std::process::exit(1);
+    }
+}
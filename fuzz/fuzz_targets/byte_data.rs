@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/kv.rs"]
+mod kv;
+
+use kv::ByteData;
+
+fuzz_target!(|data: &[u8]| {
+    // anything that parses must survive a round trip through both formats
+    if let Ok(parsed) = serde_json::from_slice::<ByteData>(data) {
+        let json = serde_json::to_vec(&parsed).unwrap();
+        let again: ByteData = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed.0, again.0);
+    }
+
+    if let Ok(parsed) = serde_cbor::from_slice::<ByteData>(data) {
+        let cbor = serde_cbor::to_vec(&parsed).unwrap();
+        let again: ByteData = serde_cbor::from_slice(&cbor).unwrap();
+        assert_eq!(parsed.0, again.0);
+    }
+
+    // base64 strings and byte arrays must decode to the same value
+    if let Ok(s) = std::str::from_utf8(data) {
+        let quoted = serde_json::to_string(s).unwrap();
+        if let Ok(parsed) = serde_json::from_str::<ByteData>(&quoted) {
+            let array = serde_json::to_string(&parsed.0).unwrap();
+            let again: ByteData = serde_json::from_str(&array).unwrap();
+            assert_eq!(parsed.0, again.0);
+        }
+    }
+});
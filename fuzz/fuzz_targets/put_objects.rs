@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+
+#[allow(dead_code)]
+#[path = "../../src/kv.rs"]
+mod kv;
+
+use kv::KeyValue;
+
+/// Mirrors `routes::PutObjectsRequest`, which can't be imported from the
+/// binary crate.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct PutObjectsRequest {
+    store_id: Option<String>,
+    global_version: Option<u64>,
+    transaction_items: Vec<KeyValue>,
+}
+
+fuzz_target!(|data: &[u8]| {
+    // malformed requests must be rejected with an error, never a panic
+    if let Ok(req) = serde_json::from_slice::<PutObjectsRequest>(data) {
+        for item in req.transaction_items {
+            let json = serde_json::to_vec(&item).unwrap();
+            let again: KeyValue = serde_json::from_slice(&json).unwrap();
+            assert_eq!(item.value.0, again.value.0);
+            assert_eq!(item.version, again.version);
+        }
+    }
+
+    let _ = serde_cbor::from_slice::<PutObjectsRequest>(data);
+});
@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS tr_touch_vss_store ON vss_db;
+DROP FUNCTION IF EXISTS touch_vss_store();
+
+DROP TABLE IF EXISTS vss_stores;
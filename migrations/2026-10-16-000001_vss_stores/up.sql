@@ -0,0 +1,34 @@
+CREATE TABLE vss_stores
+(
+    store_id      TEXT PRIMARY KEY CHECK (store_id != ''),
+    created_at    TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_write_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    label         TEXT,
+    flags         BIGINT    DEFAULT 0                 NOT NULL
+);
+
+-- backfill stores that already have data
+INSERT INTO vss_stores (store_id, created_at, last_write_at)
+SELECT store_id, MIN(created_date), MAX(updated_date)
+FROM vss_db
+GROUP BY store_id;
+
+-- Function to create or touch the store row whenever one of its items is written
+CREATE OR REPLACE FUNCTION touch_vss_store()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    INSERT INTO vss_stores (store_id)
+    VALUES (NEW.store_id)
+    ON CONFLICT (store_id)
+        DO UPDATE SET last_write_at = CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Trigger for INSERT and UPDATE operations on vss_db
+CREATE TRIGGER tr_touch_vss_store
+    AFTER INSERT OR UPDATE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION touch_vss_store();
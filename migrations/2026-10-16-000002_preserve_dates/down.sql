@@ -0,0 +1,20 @@
+DROP FUNCTION IF EXISTS upsert_vss_db_with_dates(TEXT, TEXT, bytea, BIGINT, TIMESTAMP, TIMESTAMP);
+
+CREATE OR REPLACE FUNCTION set_created_date()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    NEW.created_date := CURRENT_TIMESTAMP;
+    NEW.updated_date := CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION set_updated_date()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    NEW.updated_date := CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
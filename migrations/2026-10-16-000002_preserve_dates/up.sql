@@ -0,0 +1,70 @@
+-- Let imports keep their original dates by setting vss.preserve_dates
+-- for the duration of the write
+
+CREATE OR REPLACE FUNCTION set_created_date()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF current_setting('vss.preserve_dates', true) = 'on' THEN
+        RETURN NEW;
+    END IF;
+    NEW.created_date := CURRENT_TIMESTAMP;
+    NEW.updated_date := CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION set_updated_date()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF current_setting('vss.preserve_dates', true) = 'on' THEN
+        RETURN NEW;
+    END IF;
+    NEW.updated_date := CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db_with_dates(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_created_date TIMESTAMP,
+    p_updated_date TIMESTAMP
+) RETURNS VOID AS
+$$
+BEGIN
+
+    PERFORM set_config('vss.preserve_dates', 'on', true);
+
+    WITH new_values (store_id, key, value, version, created_date, updated_date)
+             AS (VALUES (p_store_id, p_key, p_value, p_version, p_created_date, p_updated_date))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, version, created_date, updated_date)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.version,
+           new_values.created_date,
+           new_values.updated_date
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE CASE
+              WHEN new_values.version >= 4294967295 THEN new_values.version >= COALESCE(existing.version, -1)
+              ELSE new_values.version > COALESCE(existing.version, -1)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+
+    PERFORM set_config('vss.preserve_dates', 'off', true);
+
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,9 @@
+-- Idempotency keys of recently applied putObjects requests, so retried
+-- requests aren't applied twice
+CREATE TABLE vss_idempotency_keys
+(
+    store_id        TEXT                                NOT NULL,
+    idempotency_key TEXT                                NOT NULL,
+    created_at      TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (store_id, idempotency_key)
+);
@@ -0,0 +1,150 @@
+-- Values larger than the vss.chunk_size setting are split across rows of
+-- vss_chunks, leaving an empty value in vss_db as the manifest row. Chunks
+-- are reassembled in order of idx when the value is read.
+CREATE TABLE vss_chunks
+(
+    store_id TEXT    NOT NULL,
+    key      TEXT    NOT NULL,
+    idx      INTEGER NOT NULL,
+    data     bytea   NOT NULL,
+    PRIMARY KEY (store_id, key, idx),
+    FOREIGN KEY (store_id, key) REFERENCES vss_db (store_id, key) ON DELETE CASCADE
+);
+
+-- Whether a value should be split, according to the connection's vss.chunk_size
+CREATE OR REPLACE FUNCTION vss_chunk_size(p_value bytea) RETURNS INTEGER AS
+$$
+DECLARE
+    chunk_size INTEGER := COALESCE(NULLIF(current_setting('vss.chunk_size', true), ''), '0')::INTEGER;
+BEGIN
+    IF chunk_size > 0 AND p_value IS NOT NULL AND length(p_value) > chunk_size THEN
+        RETURN chunk_size;
+    END IF;
+    RETURN 0;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Replaces the chunks of a key after its row has been written
+CREATE OR REPLACE FUNCTION write_vss_chunks(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_chunk_size INTEGER
+) RETURNS VOID AS
+$$
+BEGIN
+    DELETE FROM vss_chunks WHERE store_id = p_store_id AND key = p_key;
+
+    IF p_chunk_size > 0 THEN
+        INSERT INTO vss_chunks (store_id, key, idx, data)
+        SELECT p_store_id, p_key, i, substring(p_value FROM i * p_chunk_size + 1 FOR p_chunk_size)
+        FROM generate_series(0, (length(p_value) - 1) / p_chunk_size) AS i;
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    WITH new_values (store_id, key, value, version)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END, p_version))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, version)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.version
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE CASE
+              WHEN new_values.version >= 4294967295 THEN new_values.version >= COALESCE(existing.version, -1)
+              ELSE new_values.version > COALESCE(existing.version, -1)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value   = excluded.value,
+                      version = excluded.version;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db_with_dates(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_created_date TIMESTAMP,
+    p_updated_date TIMESTAMP
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    PERFORM set_config('vss.preserve_dates', 'on', true);
+
+    WITH new_values (store_id, key, value, version, created_date, updated_date)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END, p_version,
+                         p_created_date, p_updated_date))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, version, created_date, updated_date)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.version,
+           new_values.created_date,
+           new_values.updated_date
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE CASE
+              WHEN new_values.version >= 4294967295 THEN new_values.version >= COALESCE(existing.version, -1)
+              ELSE new_values.version > COALESCE(existing.version, -1)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+    PERFORM set_config('vss.preserve_dates', 'off', true);
+
+END;
+$$ LANGUAGE plpgsql;
+
+-- Tombstoning a value drops its chunks
+CREATE OR REPLACE FUNCTION drop_vss_chunks()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    DELETE FROM vss_chunks WHERE store_id = OLD.store_id AND key = OLD.key;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_drop_vss_chunks
+    BEFORE UPDATE OF value
+    ON vss_db
+    FOR EACH ROW
+    WHEN (NEW.value IS NULL)
+EXECUTE FUNCTION drop_vss_chunks();
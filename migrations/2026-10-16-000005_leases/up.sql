@@ -0,0 +1,10 @@
+-- Expiring named leases, so devices sharing a store can agree on which one
+-- is currently allowed to write
+CREATE TABLE vss_leases
+(
+    store_id   TEXT      NOT NULL,
+    name       TEXT      NOT NULL,
+    holder     TEXT      NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (store_id, name)
+);
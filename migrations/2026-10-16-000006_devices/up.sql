@@ -0,0 +1,12 @@
+-- Devices that have registered themselves as using a store
+CREATE TABLE vss_devices
+(
+    store_id          TEXT                                NOT NULL,
+    device_id         TEXT                                NOT NULL,
+    platform          TEXT,
+    token_fingerprint TEXT,
+    created_at        TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen_at      TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    revoked_at        TIMESTAMP,
+    PRIMARY KEY (store_id, device_id)
+);
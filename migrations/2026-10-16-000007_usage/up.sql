@@ -0,0 +1,10 @@
+-- Daily request and traffic totals per store
+CREATE TABLE vss_usage
+(
+    store_id      TEXT   NOT NULL,
+    day           DATE   NOT NULL,
+    requests      BIGINT NOT NULL DEFAULT 0,
+    bytes_read    BIGINT NOT NULL DEFAULT 0,
+    bytes_written BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (store_id, day)
+);
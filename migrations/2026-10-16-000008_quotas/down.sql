@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS vss_quota_invoices;
+DROP TABLE IF EXISTS vss_quotas;
@@ -0,0 +1,21 @@
+-- Storage bought for each store on top of the default allowance
+CREATE TABLE vss_quotas
+(
+    store_id        TEXT PRIMARY KEY                    NOT NULL,
+    purchased_bytes BIGINT                              NOT NULL DEFAULT 0,
+    updated_at      TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Lightning invoices issued for quota purchases, credited once paid
+CREATE TABLE vss_quota_invoices
+(
+    payment_hash TEXT PRIMARY KEY                    NOT NULL,
+    store_id     TEXT                                NOT NULL,
+    bytes        BIGINT                              NOT NULL,
+    amount_msat  BIGINT                              NOT NULL,
+    bolt11       TEXT                                NOT NULL,
+    created_at   TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    paid_at      TIMESTAMP
+);
+
+CREATE INDEX vss_quota_invoices_store_id_idx ON vss_quota_invoices (store_id);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS vss_nostr_subscriptions;
@@ -0,0 +1,7 @@
+-- Nostr pubkeys notified when a store's backup is updated
+CREATE TABLE vss_nostr_subscriptions
+(
+    store_id   TEXT PRIMARY KEY                    NOT NULL,
+    pubkey     TEXT                                NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
@@ -0,0 +1,16 @@
+-- Writes that arrived with an older version than the key already had, which
+-- usually means a client is trying to restore stale state
+CREATE TABLE vss_version_regressions
+(
+    id                BIGSERIAL PRIMARY KEY,
+    store_id          TEXT                                NOT NULL,
+    key               TEXT                                NOT NULL,
+    client_id         TEXT,
+    attempted_version BIGINT                              NOT NULL,
+    current_version   BIGINT                              NOT NULL,
+    delta             BIGINT                              NOT NULL,
+    created_at        TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX vss_version_regressions_store_id_idx ON vss_version_regressions (store_id, created_at);
+CREATE INDEX vss_version_regressions_created_at_idx ON vss_version_regressions (created_at);
@@ -0,0 +1,8 @@
+-- Which instance currently runs each singleton background job. A leader
+-- keeps renewing its row, and any instance may take over once it expires
+CREATE TABLE vss_job_leaders
+(
+    job        TEXT      PRIMARY KEY,
+    holder     TEXT      NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS tr_capture_vss_change ON vss_db;
+DROP FUNCTION IF EXISTS capture_vss_change();
+
+DROP TABLE IF EXISTS vss_outbox;
@@ -0,0 +1,39 @@
+-- Committed changes to vss_db waiting to be published to the change stream.
+-- Rows are written in the same transaction as the change, and deleted once
+-- the stream has accepted them
+CREATE TABLE vss_outbox
+(
+    id         BIGSERIAL PRIMARY KEY,
+    store_id   TEXT                                NOT NULL,
+    key        TEXT                                NOT NULL,
+    version    BIGINT                              NOT NULL,
+    op         TEXT                                NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Records a change to vss_db in the outbox, only on connections that set
+-- vss.capture_changes so nothing piles up when no stream is configured
+CREATE OR REPLACE FUNCTION capture_vss_change()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF COALESCE(current_setting('vss.capture_changes', true), '') != 'on' THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO vss_outbox (store_id, key, version, op)
+        VALUES (OLD.store_id, OLD.key, OLD.version, 'delete');
+    ELSE
+        INSERT INTO vss_outbox (store_id, key, version, op)
+        VALUES (NEW.store_id, NEW.key, NEW.version, CASE WHEN NEW.value IS NULL THEN 'delete' ELSE 'put' END);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_capture_vss_change
+    AFTER INSERT OR UPDATE OR DELETE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION capture_vss_change();
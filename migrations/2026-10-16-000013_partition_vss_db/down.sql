@@ -0,0 +1 @@
+DROP FUNCTION IF EXISTS mirror_vss_db();
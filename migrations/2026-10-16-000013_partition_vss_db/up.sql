@@ -0,0 +1,23 @@
+-- Copies every change to vss_db into vss_db_partitioned while an existing
+-- install is being converted to a hash-partitioned vss_db, so writes made
+-- during the conversion aren't lost. Only attached to vss_db while the
+-- conversion runs
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
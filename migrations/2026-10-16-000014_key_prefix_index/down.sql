@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS vss_db_key_prefix_idx;
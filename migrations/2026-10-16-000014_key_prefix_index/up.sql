@@ -0,0 +1,4 @@
+-- Lets prefix listings and deletes range scan a store's keys byte-wise
+-- instead of filtering every key with LIKE. Built concurrently so writes
+-- aren't blocked on large installs
+CREATE INDEX CONCURRENTLY IF NOT EXISTS vss_db_key_prefix_idx ON vss_db (store_id, key text_pattern_ops);
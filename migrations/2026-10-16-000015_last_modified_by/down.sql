@@ -0,0 +1,25 @@
+DROP TRIGGER IF EXISTS tr_set_last_modified_by ON vss_db;
+DROP FUNCTION IF EXISTS set_last_modified_by;
+
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE vss_db
+    DROP COLUMN last_modified_by;
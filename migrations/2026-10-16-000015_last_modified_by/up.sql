@@ -0,0 +1,45 @@
+-- Who last wrote each item, taken from the vss.writer setting of the
+-- writing transaction: `device:<id>` for a registered device, otherwise
+-- `token:<fingerprint>`. NULL for writes made without a token. Imports and
+-- clones (vss.preserve_dates) keep the writer they carry
+ALTER TABLE vss_db
+    ADD COLUMN last_modified_by TEXT;
+
+CREATE OR REPLACE FUNCTION set_last_modified_by()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF current_setting('vss.preserve_dates', true) = 'on' THEN
+        RETURN NEW;
+    END IF;
+    NEW.last_modified_by := NULLIF(current_setting('vss.writer', true), '');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_set_last_modified_by
+    BEFORE INSERT OR UPDATE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION set_last_modified_by();
+
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date, last_modified_by)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date, NEW.last_modified_by)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value            = excluded.value,
+                      version          = excluded.version,
+                      created_date     = excluded.created_date,
+                      updated_date     = excluded.updated_date,
+                      last_modified_by = excluded.last_modified_by;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- Lets listChangedKeys find what a store changed since a watermark without
+-- reading every key. Built concurrently so writes aren't blocked on large
+-- installs
+CREATE INDEX CONCURRENTLY IF NOT EXISTS vss_db_updated_date_idx ON vss_db (store_id, updated_date);
@@ -0,0 +1,106 @@
+-- Versions are unsigned 64-bit like the proto's, stored in BIGINT columns by
+-- their bits so versions past 9223372036854775807 are negative. Flipping the
+-- sign bit orders them as unsigned, and leaves every existing version as is
+CREATE OR REPLACE FUNCTION vss_version_order(version BIGINT) RETURNS BIGINT AS
+$$
+SELECT version # (-9223372036854775807 - 1)
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    WITH new_values (store_id, key, value, value_hash, version)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         sha256(p_value), p_version))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value      = excluded.value,
+                      value_hash = excluded.value_hash,
+                      version    = excluded.version;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db_with_dates(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_created_date TIMESTAMP,
+    p_updated_date TIMESTAMP
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    PERFORM set_config('vss.preserve_dates', 'on', true);
+
+    WITH new_values (store_id, key, value, value_hash, version, created_date, updated_date)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         sha256(p_value), p_version,
+                         p_created_date, p_updated_date))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version, created_date, updated_date)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version,
+           new_values.created_date,
+           new_values.updated_date
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      value_hash   = excluded.value_hash,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+    PERFORM set_config('vss.preserve_dates', 'off', true);
+
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+ALTER TABLE vss_stores
+    DROP COLUMN v1_base64_encoding,
+    DROP COLUMN lazy_versions,
+    DROP COLUMN null_for_missing;
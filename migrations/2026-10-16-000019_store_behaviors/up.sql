@@ -0,0 +1,5 @@
+-- Protocol behaviors a store is pinned to, NULL follows the server's default
+ALTER TABLE vss_stores
+    ADD COLUMN v1_base64_encoding TEXT,
+    ADD COLUMN lazy_versions      BOOLEAN,
+    ADD COLUMN null_for_missing   BOOLEAN;
@@ -0,0 +1,136 @@
+-- Values offloaded to S3-compatible object storage leave an empty value in
+-- vss_db, like chunked values, and a pointer to their object here. The
+-- writer uploads the object and adds the pointer after its upsert, which
+-- takes the hash of the whole value from the vss.value_hash setting since
+-- it only sees the empty value
+CREATE TABLE vss_blobs
+(
+    store_id   TEXT   NOT NULL,
+    key        TEXT   NOT NULL,
+    object_key TEXT   NOT NULL,
+    size       BIGINT NOT NULL,
+    value_hash bytea  NOT NULL,
+    PRIMARY KEY (store_id, key),
+    FOREIGN KEY (store_id, key) REFERENCES vss_db (store_id, key) ON DELETE CASCADE
+);
+
+CREATE OR REPLACE FUNCTION vss_value_hash(p_value bytea) RETURNS bytea AS
+$$
+SELECT COALESCE(decode(NULLIF(current_setting('vss.value_hash', true), ''), 'hex'), sha256(p_value))
+$$ LANGUAGE sql STABLE;
+
+-- Rewriting or tombstoning a value drops its pointer, the object itself is
+-- left in the bucket
+CREATE OR REPLACE FUNCTION drop_vss_blob()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    DELETE FROM vss_blobs WHERE store_id = OLD.store_id AND key = OLD.key;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_drop_vss_blob
+    BEFORE UPDATE OF value, version
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION drop_vss_blob();
+
+CREATE OR REPLACE FUNCTION upsert_vss_db(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    WITH new_values (store_id, key, value, value_hash, version)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         vss_value_hash(p_value), p_version))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value      = excluded.value,
+                      value_hash = excluded.value_hash,
+                      version    = excluded.version;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db_with_dates(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_created_date TIMESTAMP,
+    p_updated_date TIMESTAMP
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    PERFORM set_config('vss.preserve_dates', 'on', true);
+
+    WITH new_values (store_id, key, value, value_hash, version, created_date, updated_date)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         vss_value_hash(p_value), p_version,
+                         p_created_date, p_updated_date))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version, created_date, updated_date)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version,
+           new_values.created_date,
+           new_values.updated_date
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      value_hash   = excluded.value_hash,
+                      version      = excluded.version,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+    PERFORM set_config('vss.preserve_dates', 'off', true);
+
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,147 @@
+-- Optional JSON metadata clients attach to an item when writing it, like
+-- their app version or a content hint. Every write replaces it and
+-- tombstoning an item clears it
+ALTER TABLE vss_db
+    ADD COLUMN metadata jsonb;
+
+ALTER TABLE IF EXISTS vss_db_partitioned
+    ADD COLUMN IF NOT EXISTS metadata jsonb;
+
+DROP FUNCTION upsert_vss_db(TEXT, TEXT, bytea, BIGINT);
+
+CREATE FUNCTION upsert_vss_db(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_metadata jsonb
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    WITH new_values (store_id, key, value, value_hash, version, metadata)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         vss_value_hash(p_value), p_version, p_metadata))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version, metadata)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version,
+           new_values.metadata
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value      = excluded.value,
+                      value_hash = excluded.value_hash,
+                      version    = excluded.version,
+                      metadata   = excluded.metadata;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION upsert_vss_db_with_dates(
+    p_store_id TEXT,
+    p_key TEXT,
+    p_value bytea,
+    p_version BIGINT,
+    p_created_date TIMESTAMP,
+    p_updated_date TIMESTAMP
+) RETURNS VOID AS
+$$
+DECLARE
+    chunk_size INTEGER := vss_chunk_size(p_value);
+BEGIN
+
+    PERFORM set_config('vss.preserve_dates', 'on', true);
+
+    WITH new_values (store_id, key, value, value_hash, version, created_date, updated_date)
+             AS (VALUES (p_store_id, p_key, CASE WHEN chunk_size > 0 THEN ''::bytea ELSE p_value END,
+                         vss_value_hash(p_value), p_version,
+                         p_created_date, p_updated_date))
+    INSERT
+    INTO vss_db
+        (store_id, key, value, value_hash, version, created_date, updated_date)
+    SELECT new_values.store_id,
+           new_values.key,
+           new_values.value,
+           new_values.value_hash,
+           new_values.version,
+           new_values.created_date,
+           new_values.updated_date
+    FROM new_values
+             LEFT JOIN vss_db AS existing
+                       ON new_values.store_id = existing.store_id
+                           AND new_values.key = existing.key
+    WHERE existing.version IS NULL
+       OR CASE
+              WHEN vss_version_order(new_values.version) >= vss_version_order(4294967295)
+                  THEN vss_version_order(new_values.version) >= vss_version_order(existing.version)
+              ELSE vss_version_order(new_values.version) > vss_version_order(existing.version)
+              END
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value        = excluded.value,
+                      value_hash   = excluded.value_hash,
+                      version      = excluded.version,
+                      metadata     = NULL,
+                      created_date = excluded.created_date,
+                      updated_date = excluded.updated_date;
+
+    IF FOUND THEN
+        PERFORM write_vss_chunks(p_store_id, p_key, p_value, chunk_size);
+    END IF;
+
+    PERFORM set_config('vss.preserve_dates', 'off', true);
+
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION drop_vss_chunks()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    DELETE FROM vss_chunks WHERE store_id = OLD.store_id AND key = OLD.key;
+    NEW.value_hash := NULL;
+    NEW.metadata := NULL;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date, last_modified_by, value_hash, metadata)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date, NEW.last_modified_by, NEW.value_hash, NEW.metadata)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value            = excluded.value,
+                      version          = excluded.version,
+                      created_date     = excluded.created_date,
+                      updated_date     = excluded.updated_date,
+                      last_modified_by = excluded.last_modified_by,
+                      value_hash       = excluded.value_hash,
+                      metadata         = excluded.metadata;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS vss_org_stores;
+DROP TABLE IF EXISTS vss_orgs;
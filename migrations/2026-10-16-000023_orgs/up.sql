@@ -0,0 +1,20 @@
+-- Organizations managing many stores, e.g. an LSP's or wallet provider's
+-- users, with limits across all of them
+CREATE TABLE vss_orgs
+(
+    org_id     TEXT PRIMARY KEY                    NOT NULL,
+    name       TEXT,
+    max_stores BIGINT,
+    max_bytes  BIGINT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Which org each store belongs to, a store belongs to at most one
+CREATE TABLE vss_org_stores
+(
+    store_id TEXT PRIMARY KEY                    NOT NULL,
+    org_id   TEXT                                NOT NULL REFERENCES vss_orgs (org_id) ON DELETE CASCADE,
+    added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX vss_org_stores_org_id_idx ON vss_org_stores (org_id);
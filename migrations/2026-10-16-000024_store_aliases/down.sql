@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS vss_stores_alias_idx;
+
+ALTER TABLE vss_stores
+    DROP COLUMN alias;
@@ -0,0 +1,5 @@
+-- Human-readable name a store can be referred to by instead of its id
+ALTER TABLE vss_stores
+    ADD COLUMN alias TEXT;
+
+CREATE UNIQUE INDEX vss_stores_alias_idx ON vss_stores (alias);
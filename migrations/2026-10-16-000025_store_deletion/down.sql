@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS vss_stores_purge_at_idx;
+
+ALTER TABLE vss_stores
+    DROP COLUMN delete_requested_at,
+    DROP COLUMN purge_at,
+    DROP COLUMN deletion_token_hash;
@@ -0,0 +1,8 @@
+-- Stores scheduled for deletion are read-only until purged at purge_at,
+-- unless cancelled with the token whose hash is kept here
+ALTER TABLE vss_stores
+    ADD COLUMN delete_requested_at TIMESTAMP,
+    ADD COLUMN purge_at            TIMESTAMP,
+    ADD COLUMN deletion_token_hash TEXT;
+
+CREATE INDEX vss_stores_purge_at_idx ON vss_stores (purge_at) WHERE purge_at IS NOT NULL;
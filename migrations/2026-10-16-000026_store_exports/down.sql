@@ -0,0 +1 @@
+DROP TABLE IF EXISTS vss_store_exports;
@@ -0,0 +1,18 @@
+-- Downloadable bundles of everything kept about a store, built in the
+-- background and fetched with the token whose hash is kept here
+CREATE TABLE vss_store_exports
+(
+    export_id    TEXT PRIMARY KEY                    NOT NULL,
+    store_id     TEXT                                NOT NULL,
+    token_hash   TEXT                                NOT NULL,
+    status       TEXT      DEFAULT 'pending'         NOT NULL,
+    error        TEXT,
+    bundle       BYTEA,
+    size         BIGINT,
+    created_at   TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    completed_at TIMESTAMP,
+    expires_at   TIMESTAMP                           NOT NULL
+);
+
+CREATE INDEX vss_store_exports_store_id_idx ON vss_store_exports (store_id);
+CREATE INDEX vss_store_exports_expires_at_idx ON vss_store_exports (expires_at);
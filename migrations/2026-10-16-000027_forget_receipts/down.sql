@@ -0,0 +1 @@
+DROP TABLE IF EXISTS vss_forget_receipts;
@@ -0,0 +1,13 @@
+-- All that is kept of a store once it is forgotten: a hash of its id, who
+-- asked and how much was erased
+CREATE TABLE vss_forget_receipts
+(
+    receipt_id   TEXT PRIMARY KEY                    NOT NULL,
+    store_hash   TEXT                                NOT NULL,
+    requested_by TEXT                                NOT NULL,
+    items        BIGINT                              NOT NULL,
+    objects      BIGINT                              NOT NULL,
+    forgotten_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX vss_forget_receipts_store_hash_idx ON vss_forget_receipts (store_hash);
@@ -0,0 +1,10 @@
+-- Keys under a store's key_prefix that haven't been updated for
+-- max_age_days are deleted by the retention job
+CREATE TABLE vss_retention_rules
+(
+    store_id     TEXT                                NOT NULL,
+    key_prefix   TEXT                                NOT NULL,
+    max_age_days INTEGER                             NOT NULL,
+    created_at   TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (store_id, key_prefix)
+);
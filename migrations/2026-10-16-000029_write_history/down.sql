@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS tr_archive_vss_version ON vss_db;
+DROP FUNCTION IF EXISTS archive_vss_version();
+
+DROP TABLE IF EXISTS vss_history;
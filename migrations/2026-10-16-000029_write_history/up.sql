@@ -0,0 +1,71 @@
+-- Versions of items that have since been overwritten, tombstoned or
+-- deleted, kept only on connections that set vss.keep_history so nothing
+-- piles up unless write history is enabled. A row was the current version
+-- of its key from updated_date until superseded_at. Chunked values are
+-- reassembled, values in object storage keep the key of their object,
+-- which is never deleted from the bucket, and tombstones have no value.
+-- The history job prunes rows past the configured policy
+CREATE TABLE vss_history
+(
+    id            BIGSERIAL PRIMARY KEY,
+    store_id      TEXT                                NOT NULL,
+    key           TEXT                                NOT NULL,
+    value         bytea,
+    object_key    TEXT,
+    value_hash    bytea,
+    version       BIGINT                              NOT NULL,
+    metadata      JSONB,
+    content_type  TEXT,
+    created_date  TIMESTAMP                           NOT NULL,
+    updated_date  TIMESTAMP                           NOT NULL,
+    superseded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX vss_history_key_idx ON vss_history (store_id, key, id);
+CREATE INDEX vss_history_superseded_at_idx ON vss_history (superseded_at);
+
+-- Runs before the triggers dropping the old value's chunks and pointer, by
+-- the order of their names, so the whole old value can still be found
+CREATE OR REPLACE FUNCTION archive_vss_version()
+    RETURNS TRIGGER AS
+$$
+DECLARE
+    old_value  bytea := OLD.value;
+    old_object TEXT;
+BEGIN
+    IF COALESCE(current_setting('vss.keep_history', true), '') != 'on' THEN
+        RETURN COALESCE(NEW, OLD);
+    END IF;
+    -- removing a tombstone loses nothing
+    IF TG_OP = 'DELETE' AND OLD.value IS NULL THEN
+        RETURN OLD;
+    END IF;
+
+    IF old_value = ''::bytea THEN
+        SELECT b.object_key INTO old_object
+        FROM vss_blobs b
+        WHERE b.store_id = OLD.store_id AND b.key = OLD.key;
+
+        IF old_object IS NOT NULL THEN
+            old_value := NULL;
+        ELSE
+            SELECT COALESCE(string_agg(c.data, ''::bytea ORDER BY c.idx), ''::bytea) INTO old_value
+            FROM vss_chunks c
+            WHERE c.store_id = OLD.store_id AND c.key = OLD.key;
+        END IF;
+    END IF;
+
+    INSERT INTO vss_history (store_id, key, value, object_key, value_hash, version, metadata, content_type,
+                             created_date, updated_date)
+    VALUES (OLD.store_id, OLD.key, old_value, old_object, OLD.value_hash, OLD.version, OLD.metadata,
+            OLD.content_type, OLD.created_date, OLD.updated_date);
+
+    RETURN COALESCE(NEW, OLD);
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_archive_vss_version
+    BEFORE UPDATE OF value, version OR DELETE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION archive_vss_version();
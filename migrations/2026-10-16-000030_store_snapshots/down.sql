@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS vss_snapshot_items;
+DROP TABLE IF EXISTS vss_snapshots;
@@ -0,0 +1,32 @@
+-- Labeled copies of a store's live items, taken in a single statement so
+-- they are consistent, for clients to checkpoint known-good state and
+-- restore it later
+CREATE TABLE vss_snapshots
+(
+    snapshot_id TEXT PRIMARY KEY,
+    store_id    TEXT                                NOT NULL,
+    label       TEXT                                NOT NULL,
+    items       BIGINT    DEFAULT 0                 NOT NULL,
+    size        BIGINT    DEFAULT 0                 NOT NULL,
+    created_at  TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    UNIQUE (store_id, label)
+);
+
+-- Chunked values are copied whole, values in object storage only keep the
+-- key of their object, which is never deleted from the bucket
+CREATE TABLE vss_snapshot_items
+(
+    snapshot_id      TEXT      NOT NULL REFERENCES vss_snapshots (snapshot_id) ON DELETE CASCADE,
+    key              TEXT      NOT NULL,
+    value            bytea,
+    object_key       TEXT,
+    size             BIGINT    NOT NULL,
+    value_hash       bytea,
+    version          BIGINT    NOT NULL,
+    last_modified_by TEXT,
+    metadata         JSONB,
+    content_type     TEXT,
+    created_date     TIMESTAMP NOT NULL,
+    updated_date     TIMESTAMP NOT NULL,
+    PRIMARY KEY (snapshot_id, key)
+);
@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS tr_log_vss_mutation ON vss_db;
+DROP FUNCTION IF EXISTS log_vss_mutation();
+
+DROP TABLE IF EXISTS vss_mutation_log;
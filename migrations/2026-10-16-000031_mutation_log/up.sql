@@ -0,0 +1,49 @@
+-- Every committed change to vss_db, kept in order for replication tools and
+-- audits, only on connections that set vss.log_mutations so nothing piles up
+-- unless the mutation log is enabled. Rows are ordered by the transaction
+-- that made them and then by id, and are read only once every transaction
+-- before theirs has finished, so a reader never skips a row that commits
+-- after it has moved past. Values aren't kept, just their hash
+CREATE TABLE vss_mutation_log
+(
+    id         BIGSERIAL PRIMARY KEY,
+    txid       BIGINT    DEFAULT pg_current_xact_id()::TEXT::BIGINT NOT NULL,
+    store_id   TEXT                                                NOT NULL,
+    key        TEXT                                                NOT NULL,
+    version    BIGINT                                              NOT NULL,
+    op         TEXT                                                NOT NULL,
+    value_hash bytea,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP                 NOT NULL
+);
+
+CREATE INDEX vss_mutation_log_order_idx ON vss_mutation_log (txid, id);
+CREATE INDEX vss_mutation_log_created_at_idx ON vss_mutation_log (created_at);
+
+CREATE OR REPLACE FUNCTION log_vss_mutation()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF COALESCE(current_setting('vss.log_mutations', true), '') != 'on' THEN
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO vss_mutation_log (store_id, key, version, op)
+        VALUES (OLD.store_id, OLD.key, OLD.version, 'delete');
+    ELSIF NEW.value IS NULL THEN
+        INSERT INTO vss_mutation_log (store_id, key, version, op)
+        VALUES (NEW.store_id, NEW.key, NEW.version, 'delete');
+    ELSE
+        INSERT INTO vss_mutation_log (store_id, key, version, op, value_hash)
+        VALUES (NEW.store_id, NEW.key, NEW.version, 'put',
+                COALESCE(NEW.value_hash, CASE WHEN NEW.value != ''::bytea THEN sha256(NEW.value) END));
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_log_vss_mutation
+    AFTER INSERT OR UPDATE OF value, version OR DELETE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION log_vss_mutation();
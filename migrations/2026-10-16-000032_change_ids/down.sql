@@ -0,0 +1,32 @@
+DROP TRIGGER IF EXISTS tr_set_change_id ON vss_db;
+DROP FUNCTION IF EXISTS set_change_id();
+
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date, last_modified_by, value_hash, metadata, content_type)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date, NEW.last_modified_by, NEW.value_hash, NEW.metadata, NEW.content_type)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value            = excluded.value,
+                      version          = excluded.version,
+                      created_date     = excluded.created_date,
+                      updated_date     = excluded.updated_date,
+                      last_modified_by = excluded.last_modified_by,
+                      value_hash       = excluded.value_hash,
+                      metadata         = excluded.metadata,
+                      content_type     = excluded.content_type;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE IF EXISTS vss_db_partitioned
+    DROP COLUMN IF EXISTS change_id;
+
+ALTER TABLE vss_db
+    DROP COLUMN change_id;
@@ -0,0 +1,51 @@
+-- Transaction that last changed each item, so listChangedKeys can order
+-- changes by when they were made rather than by the dates they carry, which
+-- imports and clones keep from their source. Transaction ids only grow, and
+-- once every transaction before an id has finished no lower id can still
+-- commit, which is what the listChangedKeys watermark relies on. Items
+-- written before this migration have 0
+ALTER TABLE vss_db
+    ADD COLUMN change_id BIGINT DEFAULT 0 NOT NULL;
+
+ALTER TABLE IF EXISTS vss_db_partitioned
+    ADD COLUMN IF NOT EXISTS change_id BIGINT DEFAULT 0 NOT NULL;
+
+CREATE OR REPLACE FUNCTION set_change_id()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    NEW.change_id := pg_current_xact_id()::TEXT::BIGINT;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_set_change_id
+    BEFORE INSERT OR UPDATE
+    ON vss_db
+    FOR EACH ROW
+EXECUTE FUNCTION set_change_id();
+
+CREATE OR REPLACE FUNCTION mirror_vss_db()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM vss_db_partitioned WHERE store_id = OLD.store_id AND key = OLD.key;
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO vss_db_partitioned (store_id, key, value, version, created_date, updated_date, last_modified_by, value_hash, metadata, content_type, change_id)
+    VALUES (NEW.store_id, NEW.key, NEW.value, NEW.version, NEW.created_date, NEW.updated_date, NEW.last_modified_by, NEW.value_hash, NEW.metadata, NEW.content_type, NEW.change_id)
+    ON CONFLICT (store_id, key)
+        DO UPDATE SET value            = excluded.value,
+                      version          = excluded.version,
+                      created_date     = excluded.created_date,
+                      updated_date     = excluded.updated_date,
+                      last_modified_by = excluded.last_modified_by,
+                      value_hash       = excluded.value_hash,
+                      metadata         = excluded.metadata,
+                      content_type     = excluded.content_type,
+                      change_id        = excluded.change_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS vss_db_change_id_idx;
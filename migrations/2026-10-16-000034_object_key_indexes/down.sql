@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS vss_snapshot_items_object_key_idx;
+DROP INDEX IF EXISTS vss_history_object_key_idx;
+DROP INDEX IF EXISTS vss_blobs_object_key_idx;
@@ -0,0 +1,5 @@
+-- Lets the object storage sweep check whether anything still points to an
+-- object without reading every pointer
+CREATE INDEX IF NOT EXISTS vss_blobs_object_key_idx ON vss_blobs (object_key);
+CREATE INDEX IF NOT EXISTS vss_history_object_key_idx ON vss_history (object_key) WHERE object_key IS NOT NULL;
+CREATE INDEX IF NOT EXISTS vss_snapshot_items_object_key_idx ON vss_snapshot_items (object_key) WHERE object_key IS NOT NULL;
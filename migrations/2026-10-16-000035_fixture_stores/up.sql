@@ -0,0 +1,7 @@
+-- Stores whose contents were loaded from a fixtures file, which later loads
+-- may replace without the operator saying so
+CREATE TABLE vss_fixture_stores
+(
+    store_id  TEXT PRIMARY KEY                    NOT NULL,
+    loaded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
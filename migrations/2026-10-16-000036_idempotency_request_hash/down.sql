@@ -0,0 +1,2 @@
+ALTER TABLE vss_idempotency_keys
+    DROP COLUMN request_hash;
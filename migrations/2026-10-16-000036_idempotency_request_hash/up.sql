@@ -0,0 +1,5 @@
+-- SHA-256 of the request an idempotency key was claimed by, so the key
+-- can't be reused for a different request. Keys claimed before this column
+-- existed have none and match any request.
+ALTER TABLE vss_idempotency_keys
+    ADD COLUMN request_hash BYTEA;
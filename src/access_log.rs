@@ -0,0 +1,87 @@
+use crate::proxy::{client_ip, ClientIp};
+use axum::extract::MatchedPath;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Per-request slot that handlers fill in once they know which store a
+/// request is for, so the access log can include it.
+#[derive(Debug, Clone, Default)]
+pub struct AccessLog(Arc<Mutex<Option<String>>>);
+
+impl AccessLog {
+    pub fn set_store_id(&self, store_id: Option<&str>) {
+        if let Some(store_id) = store_id {
+            *self.0.lock().expect("access log lock poisoned") = Some(store_id.to_string());
+        }
+    }
+
+    pub fn store_id(&self) -> Option<String> {
+        self.0.lock().expect("access log lock poisoned").clone()
+    }
+
+    /// A short hash of the store id, so logs can be correlated per store
+    /// without recording the id itself.
+    fn store_hash(&self) -> String {
+        match self.0.lock().expect("access log lock poisoned").as_deref() {
+            Some(store_id) => hex::encode(&Sha256::digest(store_id.as_bytes())[..8]),
+            None => "-".to_string(),
+        }
+    }
+}
+
+/// Logs one line per request with its route, outcome, size and latency.
+pub async fn access_log<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+
+    let method = req.method().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let ip = client_ip(&req);
+    if let Some(ip) = ip {
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+
+    let log = AccessLog::default();
+    req.extensions_mut().insert(log.clone());
+
+    // give each request its own sentry scope so reported errors carry its route
+    #[cfg(feature = "sentry")]
+    let res = {
+        use sentry::SentryFutureExt;
+
+        let hub = Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+        hub.configure_scope(|scope| {
+            scope.set_tag("method", method.as_str());
+            scope.set_tag("route", &route);
+        });
+        next.run(req).bind_hub(hub).await
+    };
+    #[cfg(not(feature = "sentry"))]
+    let res = next.run(req).await;
+
+    info!(
+        target: "vss_rs::access",
+        "{method} {route} status={} store={} ip={} bytes={size} latency_ms={}",
+        res.status().as_u16(),
+        log.store_hash(),
+        ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string()),
+        start.elapsed().as_millis()
+    );
+
+    res
+}
@@ -0,0 +1,471 @@
+use crate::auth::verify_admin_token;
+use crate::kv::Base64Encoding;
+use crate::models::{
+    with_db_retry, Device, JobLeader, RegressionStats, StoreBehaviors, UsageDay, VersionRegression,
+    VssItem, VssStore,
+};
+use crate::routes::{
+    get_usage_impl, handle_anyhow_error, resolve_store_id, ErrorResponse, GetUsageRequest,
+};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::{Path, Query};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::StatusCode;
+use axum::{Extension, Json, TypedHeader};
+use diesel::Connection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneStoreRequest {
+    pub from_store_id: String,
+    pub to_store_id: String,
+    pub batch_size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneStoreResponse {
+    pub count: usize,
+}
+
+pub async fn clone_store_impl(
+    req: CloneStoreRequest,
+    state: &State,
+) -> anyhow::Result<CloneStoreResponse> {
+    if req.from_store_id == req.to_store_id {
+        return Err(InvalidRequest::field(
+            "to_store_id",
+            FieldErrorCode::NotAllowed,
+            "Source and destination stores must differ",
+        )
+        .into());
+    }
+
+    let batch_size = req.batch_size.unwrap_or(100).max(1);
+
+    let shard = state.shards.for_store(&req.from_store_id);
+    if state.shards.for_store(&req.to_store_id).name != shard.name {
+        return Err(anyhow!(
+            "Stores {} and {} are on different database shards",
+            req.from_store_id,
+            req.to_store_id
+        ));
+    }
+    let mut conn = shard.pool.get()?;
+
+    if VssItem::store_exists(&mut conn, &req.to_store_id)? {
+        return Err(anyhow!("Store {} already exists", req.to_store_id));
+    }
+
+    info!(
+        "Cloning store {} into {}",
+        req.from_store_id, req.to_store_id
+    );
+
+    let mut count = 0;
+    let mut last_key: Option<String> = None;
+    loop {
+        let batch = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::clone_store_batch(
+                conn,
+                &req.from_store_id,
+                &req.to_store_id,
+                last_key.as_deref(),
+                batch_size,
+            )
+        })?;
+
+        match batch {
+            Some((key, copied)) => {
+                count += copied;
+                last_key = Some(key);
+            }
+            None => break,
+        }
+    }
+
+    info!("Cloned {count} items into {}", req.to_store_id);
+
+    Ok(CloneStoreResponse { count })
+}
+
+pub async fn clone_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Json(payload): Json<CloneStoreRequest>,
+) -> Result<Json<CloneStoreResponse>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match clone_store_impl(payload, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("clone_store", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListStoresQuery {
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+pub async fn list_stores_impl(
+    query: ListStoresQuery,
+    state: &State,
+) -> anyhow::Result<Vec<VssStore>> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1_000);
+
+    state.shards.list_stores(query.after.as_deref(), limit)
+}
+
+pub async fn list_stores(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Query(query): Query<ListStoresQuery>,
+) -> Result<Json<Vec<VssStore>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_stores_impl(query, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_stores", e)),
+    }
+}
+
+pub async fn get_store_impl(store_id: &str, state: &State) -> anyhow::Result<Option<VssStore>> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+
+    VssStore::get_store(&mut conn, store_id)
+}
+
+pub async fn get_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<VssStore>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match get_store_impl(&store_id, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Store {store_id} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("get_store", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateStoreRequest {
+    pub label: Option<String>,
+    pub flags: Option<i64>,
+}
+
+pub async fn update_store_impl(
+    store_id: &str,
+    req: UpdateStoreRequest,
+    state: &State,
+) -> anyhow::Result<Option<VssStore>> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+
+    VssStore::update_store(&mut conn, store_id, req.label.as_deref(), req.flags)
+}
+
+pub async fn update_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+    Json(payload): Json<UpdateStoreRequest>,
+) -> Result<Json<VssStore>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match update_store_impl(&store_id, payload, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Store {store_id} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("update_store", e)),
+    }
+}
+
+pub async fn set_store_behaviors_impl(
+    store_id: &str,
+    req: StoreBehaviors,
+    state: &State,
+) -> anyhow::Result<Option<VssStore>> {
+    if let Some(Err(e)) = req
+        .v1_base64_encoding
+        .as_deref()
+        .map(|e| e.parse::<Base64Encoding>())
+    {
+        return Err(
+            InvalidRequest::field("v1_base64_encoding", FieldErrorCode::InvalidFormat, e).into(),
+        );
+    }
+
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+    VssStore::set_behaviors(&mut conn, store_id, &req)
+}
+
+/// Pins a store to protocol behaviors, replacing its previous pins. Null
+/// fields follow the server's defaults.
+pub async fn set_store_behaviors(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+    Json(payload): Json<StoreBehaviors>,
+) -> Result<Json<VssStore>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match set_store_behaviors_impl(&store_id, payload, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Store {store_id} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("set_store_behaviors", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinBehaviorsResponse {
+    /// Stores that had behaviors left to pin
+    pub stores: usize,
+    /// The server defaults they were pinned to
+    pub behaviors: StoreBehaviors,
+}
+
+pub async fn pin_behaviors_impl(state: &State) -> anyhow::Result<PinBehaviorsResponse> {
+    let behaviors = state.default_behaviors();
+
+    let mut stores = 0;
+    for shard in state.shards.all() {
+        stores += with_db_retry("pin_behaviors", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            VssStore::pin_behaviors(&mut conn, &behaviors)
+        })
+        .await?;
+    }
+    info!("Pinned {stores} stores to the current default behaviors");
+
+    Ok(PinBehaviorsResponse { stores, behaviors })
+}
+
+/// Pins every existing store to the server's current defaults for any
+/// behavior it hasn't pinned, before those defaults are changed.
+pub async fn pin_behaviors(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<PinBehaviorsResponse>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match pin_behaviors_impl(&state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("pin_behaviors", e)),
+    }
+}
+
+pub async fn list_store_devices_impl(store_id: &str, state: &State) -> anyhow::Result<Vec<Device>> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+    Device::list_devices(&mut conn, store_id)
+}
+
+pub async fn list_store_devices(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<Vec<Device>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_store_devices_impl(&store_id, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_store_devices", e)),
+    }
+}
+
+pub async fn revoke_store_device_impl(
+    store_id: &str,
+    device_id: &str,
+    state: &State,
+) -> anyhow::Result<Option<Device>> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+    Device::revoke(&mut conn, store_id, device_id)
+}
+
+pub async fn revoke_store_device(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path((store_id, device_id)): Path<(String, String)>,
+) -> Result<Json<Device>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match revoke_store_device_impl(&store_id, &device_id, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Device {device_id} not found"),
+        )
+            .into()),
+        Err(e) => Err(handle_anyhow_error("revoke_store_device", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageQuery {
+    pub days: Option<i32>,
+}
+
+pub async fn get_store_usage_impl(
+    store_id: String,
+    query: UsageQuery,
+    state: &State,
+) -> anyhow::Result<Vec<UsageDay>> {
+    let req = GetUsageRequest {
+        store_id: Some(resolve_store_id(&store_id, state).await?),
+        days: query.days,
+    };
+    get_usage_impl(req, state).await
+}
+
+pub async fn get_store_usage(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<Vec<UsageDay>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match get_store_usage_impl(store_id, query, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("get_store_usage", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegressionsQuery {
+    /// Only count regressions from the last this many hours, defaults to 24
+    pub hours: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_regression_stats_impl(
+    query: RegressionsQuery,
+    state: &State,
+) -> anyhow::Result<RegressionStats> {
+    let hours = query.hours.unwrap_or(24).clamp(1, 24 * 365);
+    let limit = query.limit.unwrap_or(100).clamp(1, 1_000);
+    let since = chrono::Utc::now().naive_utc() - chrono::Duration::hours(hours);
+
+    let mut stats = RegressionStats {
+        since,
+        total: 0,
+        stores: 0,
+        recent: vec![],
+    };
+    for shard in state.shards.all() {
+        let mut conn = shard.pool.get()?;
+        let shard_stats = VersionRegression::stats(&mut conn, since, limit)?;
+        stats.total += shard_stats.total;
+        stats.stores += shard_stats.stores;
+        stats.recent.extend(shard_stats.recent);
+    }
+    stats
+        .recent
+        .sort_unstable_by_key(|r| std::cmp::Reverse(r.created_at));
+    stats.recent.truncate(limit as usize);
+
+    Ok(stats)
+}
+
+/// Writes that tried to replace a key with an older version, a sign of a
+/// client restoring stale state.
+pub async fn get_regression_stats(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Query(query): Query<RegressionsQuery>,
+) -> Result<Json<RegressionStats>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match get_regression_stats_impl(query, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("get_regression_stats", e)),
+    }
+}
+
+pub async fn list_store_regressions_impl(
+    store_id: &str,
+    query: RegressionsQuery,
+    state: &State,
+) -> anyhow::Result<Vec<VersionRegression>> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1_000);
+
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let mut conn = state.db(store_id).get()?;
+    VersionRegression::list_for_store(&mut conn, store_id, limit)
+}
+
+pub async fn list_store_regressions(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+    Query(query): Query<RegressionsQuery>,
+) -> Result<Json<Vec<VersionRegression>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_store_regressions_impl(&store_id, query, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_store_regressions", e)),
+    }
+}
+
+pub async fn list_leaders_impl(state: &State) -> anyhow::Result<Vec<JobLeader>> {
+    let mut conn = state.db_pool.get()?;
+    JobLeader::list(&mut conn)
+}
+
+/// Which instance runs each singleton background job, and until when.
+pub async fn list_leaders(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<JobLeader>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_leaders_impl(&state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_leaders", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceStatus {
+    pub read_only: bool,
+}
+
+pub async fn get_maintenance(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<MaintenanceStatus>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    Ok(Json(MaintenanceStatus {
+        read_only: state.read_only.load(Ordering::SeqCst),
+    }))
+}
+
+pub async fn set_maintenance(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Json(payload): Json<MaintenanceStatus>,
+) -> Result<Json<MaintenanceStatus>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    let was_read_only = state.read_only.swap(payload.read_only, Ordering::SeqCst);
+    if was_read_only != payload.read_only {
+        if payload.read_only {
+            info!("Entering read-only maintenance mode");
+        } else {
+            info!("Leaving read-only maintenance mode");
+        }
+    }
+
+    Ok(Json(payload))
+}
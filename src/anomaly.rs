@@ -0,0 +1,265 @@
+use crate::client::{self, with_retry};
+use crate::kv::KeyValue;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+/// Activity is evaluated over windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+/// A store isn't alerted on again for this long after an alert
+const ALERT_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+/// Versions at or above this are LDK's "always overwrite" marker, not counters
+const MAX_COUNTED_VERSION: u64 = 4_294_967_295;
+
+const DEFAULT_WRITES_PER_MIN: u64 = 300;
+const DEFAULT_DELETES_PER_MIN: u64 = 500;
+const DEFAULT_VERSION_JUMP: u64 = 1_000;
+
+/// Header carrying the hex HMAC-SHA256 of the alert body
+pub const SIGNATURE_HEADER: &str = "X-VSS-Signature";
+
+/// Writes to a store within the current window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteActivity {
+    pub writes: u64,
+    pub deletes: u64,
+    /// Largest increase of a single key's version
+    pub max_version_jump: u64,
+}
+
+impl WriteActivity {
+    fn merge(&mut self, other: WriteActivity) {
+        self.writes += other.writes;
+        self.deletes += other.deletes;
+        self.max_version_jump = self.max_version_jump.max(other.max_version_jump);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub writes_per_min: u64,
+    pub deletes_per_min: u64,
+    pub version_jump: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            writes_per_min: DEFAULT_WRITES_PER_MIN,
+            deletes_per_min: DEFAULT_DELETES_PER_MIN,
+            version_jump: DEFAULT_VERSION_JUMP,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Describes each threshold the activity crossed.
+    pub fn check(&self, activity: &WriteActivity) -> Vec<String> {
+        let mut reasons = vec![];
+        if activity.writes > self.writes_per_min {
+            reasons.push(format!("{} writes in a minute", activity.writes));
+        }
+        if activity.deletes > self.deletes_per_min {
+            reasons.push(format!("{} deletes in a minute", activity.deletes));
+        }
+        if activity.max_version_jump > self.version_jump {
+            reasons.push(format!(
+                "a key's version jumped by {}",
+                activity.max_version_jump
+            ));
+        }
+        reasons
+    }
+}
+
+/// Body of the alert webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub store_id: String,
+    pub reasons: Vec<String>,
+    pub writes: u64,
+    pub deletes: u64,
+    pub max_version_jump: u64,
+    pub window_secs: u64,
+    pub detected_at: chrono::NaiveDateTime,
+}
+
+#[derive(Clone)]
+struct Webhook {
+    client: Agent,
+    url: String,
+    secret: Option<String>,
+}
+
+/// Watches per-store write activity for bursts that suggest a leaked token
+/// or a runaway client, logging an alert and optionally posting it to
+/// `ALERT_WEBHOOK_URL`.
+#[derive(Clone)]
+pub struct AnomalyDetector {
+    thresholds: Thresholds,
+    webhook: Option<Webhook>,
+    pending: Arc<Mutex<HashMap<String, WriteActivity>>>,
+    alerted: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(thresholds: Thresholds) -> Self {
+        AnomalyDetector {
+            thresholds,
+            webhook: None,
+            pending: Default::default(),
+            alerted: Default::default(),
+        }
+    }
+
+    /// Enabled by `ANOMALY_DETECTION`, with thresholds from
+    /// `ANOMALY_WRITES_PER_MIN`, `ANOMALY_DELETES_PER_MIN` and
+    /// `ANOMALY_VERSION_JUMP`.
+    pub fn from_env() -> anyhow::Result<Option<AnomalyDetector>> {
+        let enabled = std::env::var("ANOMALY_DETECTION")
+            .ok()
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let defaults = Thresholds::default();
+        let thresholds = Thresholds {
+            writes_per_min: env_or("ANOMALY_WRITES_PER_MIN", defaults.writes_per_min)?,
+            deletes_per_min: env_or("ANOMALY_DELETES_PER_MIN", defaults.deletes_per_min)?,
+            version_jump: env_or("ANOMALY_VERSION_JUMP", defaults.version_jump)?,
+        };
+
+        let mut detector = AnomalyDetector::new(thresholds);
+        if let Ok(url) = std::env::var("ALERT_WEBHOOK_URL") {
+            info!("Sending anomaly alerts to {url}");
+            detector.webhook = Some(Webhook {
+                client: client::agent()?,
+                url,
+                secret: std::env::var("ALERT_WEBHOOK_SECRET").ok(),
+            });
+        }
+
+        Ok(Some(detector))
+    }
+
+    pub fn record(&self, store_id: &str, activity: WriteActivity) {
+        let mut pending = self.pending.lock().expect("anomaly lock poisoned");
+        match pending.get_mut(store_id) {
+            Some(existing) => existing.merge(activity),
+            None => {
+                pending.insert(store_id.to_string(), activity);
+            }
+        }
+    }
+
+    /// Ends the current window, returning alerts for the stores that crossed
+    /// a threshold and haven't been alerted on recently.
+    pub fn evaluate(&self) -> Vec<Alert> {
+        let window = std::mem::take(&mut *self.pending.lock().expect("anomaly lock poisoned"));
+        let mut alerted = self.alerted.lock().expect("anomaly lock poisoned");
+        alerted.retain(|_, at| at.elapsed() < ALERT_COOLDOWN);
+
+        let now = chrono::Utc::now().naive_utc();
+        let mut alerts = vec![];
+        for (store_id, activity) in window {
+            let reasons = self.thresholds.check(&activity);
+            if reasons.is_empty() || alerted.contains_key(&store_id) {
+                continue;
+            }
+            alerted.insert(store_id.clone(), Instant::now());
+            alerts.push(Alert {
+                store_id,
+                reasons,
+                writes: activity.writes,
+                deletes: activity.deletes,
+                max_version_jump: activity.max_version_jump,
+                window_secs: WINDOW.as_secs(),
+                detected_at: now,
+            });
+        }
+        alerts
+    }
+
+    async fn send(&self, alert: &Alert) -> anyhow::Result<()> {
+        warn!(
+            target: "vss_rs::anomaly",
+            "Anomalous writes to store {}: {}",
+            alert.store_id,
+            alert.reasons.join(", ")
+        );
+
+        let Some(webhook) = self.webhook.clone() else {
+            return Ok(());
+        };
+        let body = serde_json::to_string(alert)?;
+        let signature = webhook.secret.as_deref().map(|s| sign(s, &body));
+
+        with_retry("Send anomaly alert", move || {
+            let mut req = webhook
+                .client
+                .post(&webhook.url)
+                .set("Content-Type", "application/json");
+            if let Some(signature) = signature.as_deref() {
+                req = req.set(SIGNATURE_HEADER, signature);
+            }
+            req.send_string(&body)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(std::env::var(name)
+        .ok()
+        .map(|s| s.parse::<T>())
+        .transpose()?
+        .unwrap_or(default))
+}
+
+/// Hex HMAC-SHA256 of `body` keyed with `secret`, so receivers can check an
+/// alert came from this server.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key size");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Largest amount a write of `items` would raise an existing key's
+/// `current` version by.
+pub fn max_version_jump(current: &HashMap<String, u64>, items: &[KeyValue]) -> u64 {
+    items
+        .iter()
+        .filter(|kv| kv.version < MAX_COUNTED_VERSION)
+        .filter_map(|kv| current.get(&kv.key).map(|v| kv.version.saturating_sub(*v)))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Checks the activity of the last window every minute until the server
+/// shuts down.
+pub async fn run_detector(detector: AnomalyDetector) {
+    let mut ticker = tokio::time::interval(WINDOW);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for alert in detector.evaluate() {
+            if let Err(e) = detector.send(&alert).await {
+                error!("Failed to send anomaly alert: {e}");
+            }
+        }
+    }
+}
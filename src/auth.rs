@@ -1,16 +1,14 @@
+use crate::routes::{reject_if_store_read_only, ErrorResponse};
 use crate::State;
 use axum::http::StatusCode;
 use jwt_compact::alg::Es256k;
-use jwt_compact::{AlgorithmExt, TimeOptions, Token, UntrustedToken};
+use jwt_compact::{AlgorithmExt, Claims, Header, TimeOptions, Token, UntrustedToken};
 use log::error;
-use secp256k1::PublicKey;
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
-pub(crate) fn verify_token(
-    token: &str,
-    state: &State,
-) -> Result<Option<String>, (StatusCode, String)> {
+pub(crate) fn verify_token(token: &str, state: &State) -> Result<Option<String>, ErrorResponse> {
     let Some(auth_key) = state.auth_key else {
         return Ok(None);
     };
@@ -21,13 +19,132 @@ pub(crate) fn verify_token(
         .map(Some)
         .map_err(|e| {
             error!("Unauthorized: {e}");
-            (StatusCode::UNAUTHORIZED, format!("Unauthorized: {e}"))
+            (StatusCode::UNAUTHORIZED, format!("Unauthorized: {e}")).into()
         })
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// The store the client's token is for, failing without a token or when the
+/// server has no auth key to verify one with. Used by endpoints that must not
+/// trust a store id from the request body.
+pub(crate) fn verify_store_token(
+    token: Option<&str>,
+    state: &State,
+) -> Result<String, ErrorResponse> {
+    let unauthorized = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized: a token for the store is required".to_string(),
+        )
+    };
+    let token = token.ok_or_else(unauthorized)?;
+    let store_id = verify_token(token, state)?.ok_or_else(unauthorized)?;
+    reject_if_store_read_only(&store_id, state)?;
+    Ok(store_id)
+}
+
+/// Checks the bearer token is a valid JWT with the admin claim set, signed by
+/// the admin auth key (or the regular auth key if no admin key is configured).
+pub(crate) fn verify_admin_token(token: &str, state: &State) -> Result<(), ErrorResponse> {
+    let Some(admin_key) = state.admin_auth_key.or(state.auth_key) else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ADMIN_AUTH_KEY not set".to_string(),
+        )
+            .into());
+    };
+
+    let es256k1 = Es256k::<Sha256>::new(state.secp.clone());
+
+    let claims = validate_jwt_claims(token, admin_key, &es256k1).map_err(|e| {
+        error!("Unauthorized admin request: {e}");
+        (StatusCode::UNAUTHORIZED, format!("Unauthorized: {e}"))
+    })?;
+
+    if !claims.admin {
+        error!("Unauthorized admin request from {}", claims.sub);
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()).into());
+    }
+
+    Ok(())
+}
+
+/// Checks the bearer token is signed by the admin key and either has the
+/// admin claim set or is scoped to `org_id` by its org claim.
+pub(crate) fn verify_org_token(
+    token: &str,
+    org_id: &str,
+    state: &State,
+) -> Result<(), ErrorResponse> {
+    let Some(admin_key) = state.admin_auth_key.or(state.auth_key) else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "ADMIN_AUTH_KEY not set".to_string(),
+        )
+            .into());
+    };
+
+    let es256k1 = Es256k::<Sha256>::new(state.secp.clone());
+
+    let claims = validate_jwt_claims(token, admin_key, &es256k1).map_err(|e| {
+        error!("Unauthorized org request: {e}");
+        (StatusCode::UNAUTHORIZED, format!("Unauthorized: {e}"))
+    })?;
+
+    if !claims.admin && claims.org.as_deref() != Some(org_id) {
+        error!("Unauthorized request for org {org_id} from {}", claims.sub);
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()).into());
+    }
+
+    Ok(())
+}
+
+/// Signs an admin JWT valid for `valid_for`, accepted by
+/// [`verify_admin_token`] when `secret_key` is the admin key.
+pub fn sign_admin_token(
+    secret_key: &SecretKey,
+    secp: &Secp256k1<All>,
+    valid_for: chrono::Duration,
+) -> anyhow::Result<String> {
+    sign_token(secret_key, secp, "admin", true, valid_for)
+}
+
+/// Signs a client JWT for `store_id` valid for `valid_for`, accepted by
+/// [`verify_token`] when `secret_key` is the auth key.
+pub fn sign_store_token(
+    secret_key: &SecretKey,
+    secp: &Secp256k1<All>,
+    store_id: &str,
+    valid_for: chrono::Duration,
+) -> anyhow::Result<String> {
+    sign_token(secret_key, secp, store_id, false, valid_for)
+}
+
+fn sign_token(
+    secret_key: &SecretKey,
+    secp: &Secp256k1<All>,
+    sub: &str,
+    admin: bool,
+    valid_for: chrono::Duration,
+) -> anyhow::Result<String> {
+    let es256k1 = Es256k::<Sha256>::new(secp.clone());
+    let claims = Claims::new(CustomClaims {
+        sub: sub.to_string(),
+        admin,
+        org: None,
+    })
+    .set_duration_and_issuance(&TimeOptions::default(), valid_for);
+
+    Ok(es256k1.token(&Header::empty(), &claims, secret_key)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct CustomClaims {
     pub sub: String,
+    #[serde(default)]
+    pub admin: bool,
+    /// Org the token manages, for org-scoped admin tokens
+    #[serde(default)]
+    pub org: Option<String>,
 }
 
 fn validate_jwt_from_user(
@@ -35,6 +152,14 @@ fn validate_jwt_from_user(
     auth_key: PublicKey,
     es256k1: &Es256k<Sha256>,
 ) -> anyhow::Result<String> {
+    validate_jwt_claims(token_str, auth_key, es256k1).map(|claims| claims.sub)
+}
+
+fn validate_jwt_claims(
+    token_str: &str,
+    auth_key: PublicKey,
+    es256k1: &Es256k<Sha256>,
+) -> anyhow::Result<CustomClaims> {
     let untrusted_token = UntrustedToken::new(token_str)?;
 
     let token: Token<CustomClaims> = es256k1.validator(&auth_key).validate(&untrusted_token)?;
@@ -45,5 +170,5 @@ fn validate_jwt_from_user(
 
     let claims = token.claims();
 
-    Ok(claims.custom.sub.clone())
+    Ok(claims.custom.clone())
 }
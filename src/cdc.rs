@@ -0,0 +1,296 @@
+use crate::client::{self, with_retry};
+use crate::models::{with_db_retry, ChangeEvent};
+use crate::shard::DbPool;
+use crate::State;
+use anyhow::anyhow;
+use log::{debug, error, info};
+use rustls::{
+    ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName, StreamOwned,
+};
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use ureq::Agent;
+
+const DEFAULT_SUBJECT: &str = "vss.changes";
+const DEFAULT_BATCH_SIZE: i64 = 500;
+const DEFAULT_INTERVAL_SECS: u64 = 1;
+const NATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where change events are published.
+#[derive(Clone)]
+pub enum Sink {
+    /// A NATS server, reached over TCP and upgraded to TLS if `tls` is set
+    Nats {
+        addr: String,
+        tls: Option<(ServerName, Arc<ClientConfig>)>,
+        subject: String,
+        token: Option<String>,
+    },
+    /// A Kafka topic, through a Kafka REST proxy
+    Kafka {
+        client: Agent,
+        url: String,
+        topic: String,
+    },
+}
+
+/// Publishes every committed change to `vss_db` to NATS or Kafka. Changes are
+/// captured in the `vss_outbox` table by the transaction that made them and
+/// only removed once the sink has accepted them, so each is delivered at
+/// least once, in order.
+#[derive(Clone)]
+pub struct ChangePublisher {
+    sink: Sink,
+    batch_size: i64,
+    pub interval: Duration,
+}
+
+impl ChangePublisher {
+    /// Enabled by `CDC_NATS_URL` or `CDC_KAFKA_REST_URL`, publishing to
+    /// `CDC_SUBJECT` in batches of `CDC_BATCH_SIZE` every
+    /// `CDC_PUBLISH_INTERVAL_SECS`.
+    pub fn from_env() -> anyhow::Result<Option<ChangePublisher>> {
+        let nats_url = std::env::var("CDC_NATS_URL").ok();
+        let kafka_url = std::env::var("CDC_KAFKA_REST_URL").ok();
+        let subject = std::env::var("CDC_SUBJECT").unwrap_or_else(|_| DEFAULT_SUBJECT.to_string());
+
+        let sink = match (nats_url, kafka_url) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Only one of CDC_NATS_URL and CDC_KAFKA_REST_URL can be set"
+                ))
+            }
+            (Some(url), None) => {
+                let (addr, tls) = match url.strip_prefix("tls://") {
+                    Some(addr) => {
+                        let addr = addr.trim_end_matches('/');
+                        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+                        let name = ServerName::try_from(host)
+                            .map_err(|_| anyhow!("Invalid NATS host {host}"))?;
+                        (addr, Some((name, tls_config())))
+                    }
+                    None => {
+                        let addr = url.trim_start_matches("nats://").trim_end_matches('/');
+                        (addr, None)
+                    }
+                };
+                info!("Publishing changes to NATS subject {subject} on {addr}");
+                Sink::Nats {
+                    addr: addr.to_string(),
+                    tls,
+                    subject,
+                    token: std::env::var("CDC_NATS_TOKEN").ok(),
+                }
+            }
+            (None, Some(url)) => {
+                info!("Publishing changes to Kafka topic {subject} through {url}");
+                Sink::Kafka {
+                    client: client::agent()?,
+                    url: url.trim_end_matches('/').to_string(),
+                    topic: subject,
+                }
+            }
+        };
+
+        let batch_size = std::env::var("CDC_BATCH_SIZE")
+            .ok()
+            .map(|s| s.parse::<i64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+        let interval = std::env::var("CDC_PUBLISH_INTERVAL_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_INTERVAL_SECS);
+
+        Ok(Some(ChangePublisher {
+            sink,
+            batch_size: batch_size.max(1),
+            interval: Duration::from_secs(interval.max(1)),
+        }))
+    }
+
+    /// Publishes everything in the outbox, a batch at a time. Stops at the
+    /// first batch that fails, leaving it to be retried on the next run.
+    pub async fn publish_pending(&self, state: &State) {
+        for shard in state.shards.all() {
+            loop {
+                match self.publish_batch(state, &shard.pool).await {
+                    Ok(published) if published < self.batch_size as usize => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Failed to publish changes from {}: {e}", shard.name);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn publish_batch(&self, state: &State, db_pool: &DbPool) -> anyhow::Result<usize> {
+        let events = with_db_retry("pending_changes", &state.breaker, || {
+            let mut conn = db_pool.get()?;
+            ChangeEvent::pending(&mut conn, self.batch_size)
+        })
+        .await?;
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        let sink = self.sink.clone();
+        let batch = events.clone();
+        with_retry("Publish changes", move || publish(&sink, &batch)).await?;
+
+        let ids: Vec<i64> = events.iter().map(|e| e.id).collect();
+        with_db_retry("acknowledge_changes", &state.breaker, || {
+            let mut conn = db_pool.get()?;
+            ChangeEvent::acknowledge(&mut conn, &ids)
+        })
+        .await?;
+
+        debug!("Published {} changes", events.len());
+        Ok(events.len())
+    }
+}
+
+fn publish(sink: &Sink, events: &[ChangeEvent]) -> anyhow::Result<()> {
+    match sink {
+        Sink::Nats {
+            addr,
+            tls,
+            subject,
+            token,
+        } => publish_nats(addr, tls.as_ref(), subject, token.as_deref(), events),
+        Sink::Kafka { client, url, topic } => publish_kafka(client, url, topic, events),
+    }
+}
+
+/// Verifies servers against the Mozilla root store.
+fn tls_config() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Publishes over the NATS text protocol, waiting for the PONG to a final
+/// PING so every message is known to have been processed by the server.
+/// NATS sends its INFO in the clear, so a TLS connection is only started
+/// after it.
+fn publish_nats(
+    addr: &str,
+    tls: Option<&(ServerName, Arc<ClientConfig>)>,
+    subject: &str,
+    token: Option<&str>,
+    events: &[ChangeEvent],
+) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(NATS_TIMEOUT))?;
+    stream.set_write_timeout(Some(NATS_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("INFO") {
+        return Err(anyhow!("Unexpected greeting from NATS: {}", line.trim()));
+    }
+
+    match tls {
+        Some((name, config)) => {
+            if !reader.buffer().is_empty() {
+                return Err(anyhow!("NATS sent data before the TLS handshake"));
+            }
+            let conn = ClientConnection::new(config.clone(), name.clone())?;
+            let stream = StreamOwned::new(conn, reader.into_inner());
+            nats_publish(BufReader::new(stream), true, subject, token, events)
+        }
+        None => nats_publish(reader, false, subject, token, events),
+    }
+}
+
+fn nats_publish<S: Read + Write>(
+    mut reader: BufReader<S>,
+    tls: bool,
+    subject: &str,
+    token: Option<&str>,
+    events: &[ChangeEvent],
+) -> anyhow::Result<()> {
+    let mut connect = json!({
+        "verbose": false,
+        "pedantic": false,
+        "tls_required": tls,
+        "name": "vss-rs",
+    });
+    if let Some(token) = token {
+        connect["auth_token"] = json!(token);
+    }
+    let mut buf = format!("CONNECT {connect}\r\n").into_bytes();
+    for event in events {
+        let payload = serde_json::to_vec(event)?;
+        buf.extend_from_slice(format!("PUB {subject} {}\r\n", payload.len()).as_bytes());
+        buf.extend_from_slice(&payload);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"PING\r\n");
+    reader.get_mut().write_all(&buf)?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("NATS closed the connection"));
+        }
+        match line.trim_end() {
+            "PONG" => return Ok(()),
+            "PING" => reader.get_mut().write_all(b"PONG\r\n")?,
+            l if l.starts_with("-ERR") => return Err(anyhow!("NATS error: {l}")),
+            _ => {}
+        }
+    }
+}
+
+/// Publishes through the Kafka REST proxy's v2 API, keyed by store id so a
+/// store's changes stay in order on one partition.
+fn publish_kafka(
+    client: &Agent,
+    url: &str,
+    topic: &str,
+    events: &[ChangeEvent],
+) -> anyhow::Result<()> {
+    let records: Vec<_> = events
+        .iter()
+        .map(|e| json!({ "key": e.store_id, "value": e }))
+        .collect();
+
+    let res: serde_json::Value = client
+        .post(&format!("{url}/topics/{topic}"))
+        .set("Content-Type", "application/vnd.kafka.json.v2+json")
+        .send_json(json!({ "records": records }))?
+        .into_json()?;
+
+    let failed = res["offsets"]
+        .as_array()
+        .map(|offsets| offsets.iter().filter(|o| !o["error"].is_null()).count())
+        .unwrap_or(0);
+    if failed > 0 {
+        return Err(anyhow!(
+            "Kafka rejected {failed} of {} changes",
+            events.len()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use anyhow::anyhow;
+use log::warn;
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder};
+
+const MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Builds an agent for talking to other VSS servers, every request is bounded
+/// by `HTTP_TIMEOUT_SECS` (default 30s).
+pub(crate) fn agent() -> anyhow::Result<Agent> {
+    let timeout = std::env::var("HTTP_TIMEOUT_SECS")
+        .ok()
+        .map(|s| s.parse::<u64>())
+        .transpose()?
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+
+    Ok(AgentBuilder::new()
+        .timeout(Duration::from_secs(timeout))
+        .build())
+}
+
+/// Runs a blocking request on the blocking thread pool so it doesn't stall
+/// the runtime, retrying failures with exponential backoff. Error responses
+/// other than 429 and 5xx are returned straight away, since repeating the
+/// request won't change them.
+pub(crate) async fn with_retry<T, F>(name: &str, f: F) -> anyhow::Result<T>
+where
+    F: Fn() -> anyhow::Result<T> + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let res = tokio::task::spawn_blocking(f.clone()).await?;
+
+        match res {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt < MAX_ATTEMPTS && retryable(&e) => {
+                let backoff = Duration::from_millis(250 * 2u64.pow(attempt));
+                warn!("{name} failed (attempt {attempt}), retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) if attempt < MAX_ATTEMPTS => return Err(anyhow!("{name} failed: {e}")),
+            Err(e) => return Err(anyhow!("{name} failed after {attempt} attempts: {e}")),
+        }
+    }
+}
+
+/// Whether a failed request may succeed if tried again. Anything other
+/// than an error response, like a dropped connection, is retried.
+fn retryable(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(code, _)) => *code == 429 || *code >= 500,
+        Some(ureq::Error::Transport(_)) | None => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retryable() {
+        let status = |code| {
+            let res = ureq::Response::new(code, "", "").unwrap();
+            anyhow::Error::from(ureq::Error::Status(code, res))
+        };
+        assert!(retryable(&status(500)));
+        assert!(retryable(&status(503)));
+        assert!(retryable(&status(429)));
+        assert!(!retryable(&status(401)));
+        assert!(!retryable(&status(404)));
+        assert!(retryable(&anyhow!("NATS closed the connection")));
+    }
+}
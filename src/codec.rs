@@ -0,0 +1,271 @@
+use crate::kv::{with_hex_values, VALUE_ENCODING};
+use crate::routes::ErrorResponse;
+use crate::validation::{FieldError, FieldErrorCode, InvalidRequest};
+use axum::async_trait;
+use axum::body::{Bytes, HttpBody};
+use axum::extract::FromRequest;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const CBOR: &str = "application/cbor";
+const JSON: &str = "application/json";
+#[cfg(feature = "msgpack")]
+const MSGPACK: &str = "application/msgpack";
+
+/// Wire format of a request or response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    /// JSON with values written as hex strings, see [`crate::kv::VALUE_ENCODING`]
+    JsonHex,
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+impl Format {
+    fn from_header(headers: &HeaderMap, name: header::HeaderName) -> Option<Format> {
+        let value = headers.get(name)?.to_str().ok()?;
+        if value.contains(CBOR) {
+            return Some(Format::Cbor);
+        }
+        // also matches the older application/x-msgpack
+        #[cfg(feature = "msgpack")]
+        if value.contains("msgpack") {
+            return Some(Format::MsgPack);
+        }
+
+        if value.contains(JSON) {
+            Some(Format::Json)
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json | Format::JsonHex => JSON,
+            Format::Cbor => CBOR,
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MSGPACK,
+        }
+    }
+}
+
+/// Request body decoded according to its `Content-Type`, remembering which
+/// format the client wants back. Responses use the `Accept` header if it
+/// names a supported format, otherwise the format of the request, and JSON
+/// responses carry hex values when asked for with `Value-Encoding`. With
+/// `STRICT_JSON` set, bodies with fields the request doesn't have are
+/// rejected instead of those fields being ignored.
+pub struct Negotiated<T> {
+    pub body: T,
+    pub accept: Format,
+}
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let format =
+            Format::from_header(req.headers(), header::CONTENT_TYPE).unwrap_or(Format::Json);
+        let accept = match Format::from_header(req.headers(), header::ACCEPT).unwrap_or(format) {
+            Format::Json if hex_values(req.headers())? => Format::JsonHex,
+            accept => accept,
+        };
+        let strict = req
+            .extensions()
+            .get::<crate::State>()
+            .map(|state| state.strict_json)
+            .unwrap_or(false);
+
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+        let mut unknown = vec![];
+        let mut ignored = |path: serde_ignored::Path| unknown.push(field_path(&path));
+        let body = match format {
+            Format::Json | Format::JsonHex => {
+                let mut de = serde_json::Deserializer::from_slice(&bytes);
+                serde_ignored::deserialize(&mut de, &mut ignored)
+                    .and_then(|body| de.end().map(|_| body))
+                    .map_err(|e| e.to_string())
+            }
+            Format::Cbor => {
+                let mut de = serde_cbor::Deserializer::from_slice(&bytes);
+                serde_ignored::deserialize(&mut de, &mut ignored)
+                    .and_then(|body| de.end().map(|_| body))
+                    .map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => {
+                let mut de = rmp_serde::Deserializer::from_read_ref(&bytes);
+                serde_ignored::deserialize(&mut de, &mut ignored).map_err(|e| e.to_string())
+            }
+        }
+        .map_err(|e| {
+            InvalidRequest::field(
+                "body",
+                FieldErrorCode::InvalidFormat,
+                format!("Failed to deserialize the body: {e}"),
+            )
+            .to_response()
+            .with_status(StatusCode::UNPROCESSABLE_ENTITY)
+        })?;
+
+        if strict && !unknown.is_empty() {
+            let field_errors = unknown.into_iter().map(unknown_field).collect();
+            return Err(InvalidRequest::fields(field_errors)
+                .to_response()
+                .with_status(StatusCode::UNPROCESSABLE_ENTITY));
+        }
+
+        Ok(Negotiated { body, accept })
+    }
+}
+
+/// Whether the client asked for hex values, rejecting encodings other than
+/// `hex`.
+fn hex_values(headers: &HeaderMap) -> Result<bool, ErrorResponse> {
+    match headers.get(VALUE_ENCODING).map(|v| v.to_str()) {
+        None => Ok(false),
+        Some(Ok("hex")) => Ok(true),
+        Some(_) => Err(InvalidRequest::field(
+            VALUE_ENCODING,
+            FieldErrorCode::InvalidFormat,
+            format!("{VALUE_ENCODING} must be hex"),
+        )
+        .to_response()),
+    }
+}
+
+/// Writes a path like the `field` of a [`FieldError`], e.g.
+/// `transaction_items[1].key`.
+fn field_path(path: &serde_ignored::Path) -> String {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{index}]", field_path(parent)),
+        Path::Map { parent, key } => match field_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{parent}.{key}"),
+        },
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => field_path(parent),
+    }
+}
+
+/// Rejects a field the request doesn't have, suggesting the snake_case
+/// name when it looks like a camelCase spelling of one.
+fn unknown_field(field: String) -> FieldError {
+    let name = field.rsplit('.').next().unwrap_or(&field);
+    let snake_case = name.chars().fold(String::new(), |mut s, c| {
+        if c.is_ascii_uppercase() {
+            s.push('_');
+            s.push(c.to_ascii_lowercase());
+        } else {
+            s.push(c);
+        }
+        s
+    });
+    let message = match snake_case != name {
+        true => format!("Unknown field {field}, did you mean {snake_case}?"),
+        false => format!("Unknown field {field}"),
+    };
+    FieldError {
+        field,
+        code: FieldErrorCode::NotAllowed,
+        message,
+    }
+}
+
+/// Response body serialized in the negotiated format.
+pub struct Encoded<T>(pub Format, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(format, value) = self;
+
+        let bytes = match format {
+            Format::Json => serde_json::to_vec(&value).map_err(|e| e.to_string()),
+            Format::JsonHex => {
+                with_hex_values(|| serde_json::to_vec(&value)).map_err(|e| e.to_string())
+            }
+            Format::Cbor => serde_cbor::to_vec(&value).map_err(|e| e.to_string()),
+            // structs are written as maps so responses mirror the JSON shape
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => rmp_serde::to_vec_named(&value).map_err(|e| e.to_string()),
+        };
+
+        match bytes {
+            Ok(bytes) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kv::KeyValue;
+
+    #[test]
+    fn test_byte_data_errors() {
+        let parse = |json: &str| serde_json::from_str::<KeyValue>(json).unwrap_err();
+
+        let bad_base64 = parse(r#"{"key":"k","value":"not base64!","version":0}"#);
+        assert!(bad_base64.to_string().starts_with("invalid byte data:"));
+
+        let out_of_range = parse(r#"{"key":"k","value":[1,256],"version":0}"#);
+        assert!(out_of_range.to_string().starts_with("invalid byte data:"));
+
+        let base64 = r#"{"key":"k","value":"AQID","version":0}"#;
+        let array = r#"{"key":"k","value":[1,2,3],"version":0}"#;
+        let from_base64: KeyValue = serde_json::from_str(base64).unwrap();
+        let from_array: KeyValue = serde_json::from_str(array).unwrap();
+        assert_eq!(from_base64.value.0, from_array.value.0);
+    }
+
+    #[test]
+    fn test_key_value_cbor() {
+        let kv = KeyValue::new("key".to_string(), vec![1, 2, 3], 7);
+
+        // values are a native byte string in CBOR, not an array of numbers
+        let bytes = serde_cbor::to_vec(&kv).unwrap();
+        let value: serde_cbor::Value = serde_cbor::from_slice(&bytes).unwrap();
+        let serde_cbor::Value::Map(map) = value else {
+            panic!("expected a map");
+        };
+        assert_eq!(
+            map.get(&serde_cbor::Value::Text("value".to_string())),
+            Some(&serde_cbor::Value::Bytes(vec![1, 2, 3]))
+        );
+
+        let decoded: KeyValue = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.value.0, vec![1, 2, 3]);
+        assert_eq!(decoded.version, 7);
+
+        // JSON is unchanged
+        let json = serde_json::to_string(&kv).unwrap();
+        assert_eq!(json, r#"{"key":"key","value":[1,2,3],"version":7}"#);
+    }
+}
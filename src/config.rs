@@ -0,0 +1,116 @@
+use anyhow::anyhow;
+use diesel::{Connection, PgConnection};
+use log::warn;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Collects every problem with the configuration so they can be reported
+/// together at startup, rather than failing on the first one.
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl ConfigReport {
+    /// The value of `res`, or None after recording its error under `name`.
+    pub fn check<T>(&mut self, name: &str, res: anyhow::Result<T>) -> Option<T> {
+        match res {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error(name, e);
+                None
+            }
+        }
+    }
+
+    /// Parses the environment variable `name`, None if it is unset or
+    /// invalid.
+    pub fn var<T>(&mut self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let value = std::env::var(name).ok()?;
+        let parsed = value
+            .parse::<T>()
+            .map_err(|e| anyhow!("{e}, got {value:?}"));
+        self.check(name, parsed)
+    }
+
+    pub fn error(&mut self, name: &str, message: impl fmt::Display) {
+        self.errors.push(format!("{name}: {message}"));
+    }
+
+    pub fn warn(&mut self, message: impl fmt::Display) {
+        self.warnings.push(message.to_string());
+    }
+
+    /// Logs the warnings, failing with every error if there were any.
+    pub fn finish(self) -> anyhow::Result<()> {
+        for warning in &self.warnings {
+            warn!("{warning}");
+        }
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!("{self}")),
+        }
+    }
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.errors {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects once to make sure the database at `url` is reachable.
+pub fn check_database(url: &str) -> anyhow::Result<()> {
+    PgConnection::establish(url).map_err(|e| anyhow!("can't connect: {e}"))?;
+    Ok(())
+}
+
+/// Makes sure nothing else is listening on `addr`.
+pub fn check_bind(addr: SocketAddr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind(addr).map_err(|e| anyhow!("can't listen on {addr}: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_report() {
+        std::env::set_var("VSS_TEST_CONFIG_PORT", "80800");
+        std::env::set_var("VSS_TEST_CONFIG_SECS", "30");
+
+        let mut config = ConfigReport::default();
+        assert_eq!(config.var::<u16>("VSS_TEST_CONFIG_PORT"), None);
+        assert_eq!(config.var::<u64>("VSS_TEST_CONFIG_SECS"), Some(30));
+        assert_eq!(config.var::<u64>("VSS_TEST_CONFIG_UNSET"), None);
+        config.check::<()>(
+            "mirroring",
+            Err(anyhow::anyhow!("MIRROR_QUEUE_SIZE is invalid")),
+        );
+        config.warn("SELF_HOST without AUTH_KEY");
+
+        // every error is reported, warnings are only logged
+        let report = config.finish().unwrap_err().to_string();
+        assert_eq!(
+            report,
+            "Invalid configuration:\n  \
+             - VSS_TEST_CONFIG_PORT: number too large to fit in target type, got \"80800\"\n  \
+             - mirroring: MIRROR_QUEUE_SIZE is invalid"
+        );
+        assert!(ConfigReport::default().finish().is_ok());
+
+        std::env::remove_var("VSS_TEST_CONFIG_PORT");
+        std::env::remove_var("VSS_TEST_CONFIG_SECS");
+    }
+}
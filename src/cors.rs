@@ -0,0 +1,135 @@
+use crate::{ALLOWED_LAN, ALLOWED_LOCALHOST, ALLOWED_ORIGINS};
+use anyhow::anyhow;
+use std::str::FromStr;
+
+/// Subdomains allowed when `CORS_SUBDOMAINS` isn't set
+pub const DEFAULT_CORS_SUBDOMAINS: &str = "*.mutiny-web.pages.dev";
+
+/// Allows origins on any subdomain of a domain, like
+/// `https://*.vercel.app`. Without a scheme any scheme is allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubdomainPattern {
+    scheme: Option<String>,
+    /// The domain with a leading dot, e.g. `.vercel.app`
+    suffix: String,
+}
+
+impl SubdomainPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        let Some((scheme, host)) = origin.split_once("://") else {
+            return false;
+        };
+        if self
+            .scheme
+            .as_deref()
+            .is_some_and(|s| !s.eq_ignore_ascii_case(scheme))
+        {
+            return false;
+        }
+        let host = host.to_ascii_lowercase();
+        match host.strip_suffix(&self.suffix) {
+            Some(subdomain) => {
+                !subdomain.is_empty()
+                    && !subdomain.starts_with('.')
+                    && !subdomain.contains(['/', ':', '@'])
+            }
+            None => false,
+        }
+    }
+}
+
+impl FromStr for SubdomainPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, host) = match s.split_once("://") {
+            Some(("*", host)) => (None, host),
+            Some((scheme, host)) if !scheme.is_empty() => (Some(scheme.to_string()), host),
+            Some(_) => return Err(anyhow!("{s} has an empty scheme")),
+            None => (None, s),
+        };
+        let domain = host
+            .strip_prefix("*.")
+            .ok_or_else(|| anyhow!("{s} must look like *.example.com"))?;
+        if domain.is_empty() || domain.contains(['*', '/', ':', '@']) {
+            return Err(anyhow!("{s} must look like *.example.com"));
+        }
+        Ok(SubdomainPattern {
+            scheme,
+            suffix: format!(".{}", domain.to_ascii_lowercase()),
+        })
+    }
+}
+
+/// Which browser origins may call the API when not self hosting.
+#[derive(Debug, Clone)]
+pub struct CorsRules {
+    subdomains: Vec<SubdomainPattern>,
+}
+
+impl Default for CorsRules {
+    fn default() -> Self {
+        CorsRules::parse(DEFAULT_CORS_SUBDOMAINS).expect("default is valid")
+    }
+}
+
+impl CorsRules {
+    /// Comma-separated subdomain patterns.
+    pub fn parse(patterns: &str) -> anyhow::Result<CorsRules> {
+        let subdomains = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(SubdomainPattern::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(CorsRules { subdomains })
+    }
+
+    /// Subdomain patterns in `CORS_SUBDOMAINS`, replacing the default
+    /// Mutiny preview deploys when set.
+    pub fn from_env() -> anyhow::Result<CorsRules> {
+        match std::env::var("CORS_SUBDOMAINS") {
+            Ok(patterns) => CorsRules::parse(&patterns),
+            Err(_) => Ok(CorsRules::default()),
+        }
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        ALLOWED_ORIGINS.contains(&origin)
+            || self.subdomains.iter().any(|p| p.matches(origin))
+            || origin.starts_with(ALLOWED_LOCALHOST)
+            || origin.starts_with(ALLOWED_LAN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cors_rules() {
+        let default = CorsRules::default();
+        assert!(default.allows("https://app.mutinywallet.com"));
+        assert!(default.allows("https://abc123.mutiny-web.pages.dev"));
+        assert!(!default.allows("https://mutiny-web.pages.dev"));
+        assert!(!default.allows("https://evil.com"));
+        assert!(!default.allows("https://abc.vercel.app"));
+
+        let rules = CorsRules::parse("https://*.vercel.app, *.Netlify.app").unwrap();
+        assert!(rules.allows("https://my-app-git-branch.vercel.app"));
+        assert!(rules.allows("https://a.b.vercel.app"));
+        assert!(!rules.allows("http://my-app.vercel.app"));
+        assert!(!rules.allows("https://evilvercel.app"));
+        assert!(!rules.allows("https://my-app.vercel.app:8080"));
+        assert!(rules.allows("http://preview.netlify.app"));
+        assert!(rules.allows("https://PREVIEW.netlify.app"));
+        // replaces the default subdomains, but not the fixed origins
+        assert!(!rules.allows("https://abc123.mutiny-web.pages.dev"));
+        assert!(rules.allows("capacitor://localhost"));
+
+        assert!(CorsRules::parse("vercel.app").is_err());
+        assert!(CorsRules::parse("https://*.*.vercel.app").is_err());
+        assert!(CorsRules::parse("://*.vercel.app").is_err());
+        assert!(CorsRules::parse("").is_ok());
+    }
+}
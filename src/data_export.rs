@@ -0,0 +1,210 @@
+use crate::models::{
+    with_db_retry, Device, NostrSubscription, Quota, StoreExport, UsageDay, VersionRegression,
+    VssItem, VssStore, EXPORT_PENDING,
+};
+use crate::token::{self, random_hex};
+use crate::State;
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// Layout version of the bundle, bumped whenever it changes
+pub const BUNDLE_VERSION: u32 = 1;
+const ITEM_BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreExportRequest {
+    pub store_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreExportTicket {
+    pub export_id: String,
+    pub store_id: String,
+    pub status: String,
+    pub expires_at: NaiveDateTime,
+    /// Needed to download the bundle, only returned here
+    pub retrieval_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedItem {
+    pub key: String,
+    /// Base64 of the value, null for deleted keys
+    pub value: Option<String>,
+    pub version: u64,
+    pub deleted: bool,
+    pub created_date: NaiveDateTime,
+    pub updated_date: NaiveDateTime,
+    pub last_modified_by: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    pub content_type: Option<String>,
+}
+
+impl From<VssItem> for ExportedItem {
+    fn from(item: VssItem) -> Self {
+        ExportedItem {
+            created_date: item.created_date(),
+            updated_date: item.updated_date(),
+            deleted: item.value.is_none(),
+            value: item.value.map(base64::encode),
+            key: item.key,
+            version: item.version,
+            last_modified_by: item.last_modified_by,
+            metadata: item.metadata,
+            content_type: item.content_type,
+        }
+    }
+}
+
+/// Everything the server keeps about a store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportBundle {
+    pub version: u32,
+    pub generated_at: NaiveDateTime,
+    pub store: VssStore,
+    pub items: Vec<ExportedItem>,
+    pub devices: Vec<Device>,
+    /// Writes rejected for going back in version, as an audit trail
+    pub version_regressions: Vec<VersionRegression>,
+    pub usage: Vec<UsageDay>,
+    pub quota: Option<Quota>,
+    pub nostr_subscription: Option<NostrSubscription>,
+}
+
+/// Collects the store's rows into a JSON bundle.
+pub async fn build_bundle(store_id: &str, state: &State) -> anyhow::Result<Vec<u8>> {
+    let parts = with_db_retry("export_store_rows", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        let store = VssStore::get_store(&mut conn, store_id)?
+            .ok_or_else(|| anyhow!("Store {store_id} doesn't exist"))?;
+        Ok((
+            store,
+            Device::list_devices(&mut conn, store_id)?,
+            VersionRegression::list_for_store(&mut conn, store_id, i64::MAX)?,
+            UsageDay::list_all(&mut conn, store_id)?,
+            Quota::get_quota(&mut conn, store_id)?,
+            NostrSubscription::get_subscription(&mut conn, store_id)?,
+        ))
+    })
+    .await?;
+    let (store, devices, version_regressions, usage, quota, nostr_subscription) = parts;
+
+    let mut items = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let batch = with_db_retry("export_store_items", &state.breaker, || {
+            let mut conn = state.db(store_id).get()?;
+            VssItem::list_items_with_tombstones(
+                &mut conn,
+                state.blobs.as_deref(),
+                store_id,
+                after.as_deref(),
+                ITEM_BATCH_SIZE,
+            )
+        })
+        .await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = Some(last.key.clone());
+        items.extend(batch.into_iter().map(ExportedItem::from));
+    }
+
+    let bundle = ExportBundle {
+        version: BUNDLE_VERSION,
+        generated_at: chrono::Utc::now().naive_utc(),
+        store,
+        items,
+        devices,
+        version_regressions,
+        usage,
+        quota,
+        nostr_subscription,
+    };
+    Ok(serde_json::to_vec_pretty(&bundle)?)
+}
+
+/// Builds the bundle and records the outcome on the export.
+async fn build_export(state: State, export_id: String, store_id: String) {
+    let outcome = build_bundle(&store_id, &state).await;
+    let res = with_db_retry("finish_store_export", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        match outcome {
+            Ok(ref bundle) => {
+                StoreExport::complete(&mut conn, &export_id, bundle, state.export_retention)
+            }
+            Err(ref e) => StoreExport::fail(&mut conn, &export_id, &e.to_string()),
+        }
+    })
+    .await;
+
+    match (outcome, res) {
+        (Ok(bundle), Ok(())) => info!(
+            "Export {export_id} of store {store_id} is ready, {} bytes",
+            bundle.len()
+        ),
+        (Err(e), Ok(())) => error!("Export {export_id} of store {store_id} failed: {e}"),
+        (_, Err(e)) => error!("Failed to save export {export_id} of store {store_id}: {e}"),
+    }
+}
+
+/// Starts building an export of the store in the background, returning the
+/// token to download it with once it is ready.
+pub async fn start_export_impl(store_id: &str, state: &State) -> anyhow::Result<StoreExportTicket> {
+    let export_id = random_hex(16)?;
+    let token = random_hex(32)?;
+    let hash = token::hash(&token);
+
+    let export = with_db_retry("create_store_export", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        if VssStore::get_store(&mut conn, store_id)?.is_none() {
+            return Err(anyhow!("Store {store_id} doesn't exist"));
+        }
+        StoreExport::create(
+            &mut conn,
+            &export_id,
+            store_id,
+            &hash,
+            state.export_retention,
+        )
+    })
+    .await?
+    .ok_or_else(|| anyhow!("An export of store {store_id} is already being generated"))?;
+
+    tokio::spawn(build_export(
+        state.clone(),
+        export.export_id.clone(),
+        store_id.to_string(),
+    ));
+
+    Ok(StoreExportTicket {
+        export_id: export.export_id,
+        store_id: export.store_id,
+        status: EXPORT_PENDING.to_string(),
+        expires_at: export.expires_at,
+        retrieval_token: token,
+    })
+}
+
+/// Finds an unexpired export by its id and retrieval token. Exports are kept
+/// with their store, so every shard is searched.
+pub async fn get_export_impl(
+    export_id: &str,
+    token: &str,
+    state: &State,
+) -> anyhow::Result<Option<StoreExport>> {
+    let hash = token::hash(token);
+    for shard in state.shards.all() {
+        let found = with_db_retry("get_store_export", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            StoreExport::get_export(&mut conn, export_id, &hash)
+        })
+        .await?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
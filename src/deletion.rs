@@ -0,0 +1,289 @@
+use crate::auth::verify_admin_token;
+use crate::models::{with_db_retry, ForgetReceipt, Org, VssItem, VssStore};
+use crate::routes::{handle_anyhow_error, resolve_store_id, ErrorResponse};
+use crate::token;
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::Path;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteStoreRequest {
+    pub store_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelStoreDeletionRequest {
+    pub store_id: Option<String>,
+    /// Token returned when the deletion was requested
+    pub confirmation_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreDeletion {
+    pub store_id: String,
+    /// When the store will be purged, None once the deletion is cancelled
+    pub purge_at: Option<NaiveDateTime>,
+    /// Cancels the deletion, only returned when it is requested
+    pub confirmation_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForgetStoreRequest {
+    pub store_id: Option<String>,
+    /// Token returned when the deletion was requested
+    pub confirmation_token: String,
+}
+
+/// Makes the store read-only and schedules it to be purged once the
+/// deletion grace period is over, returning the token that cancels it.
+pub async fn request_deletion_impl(store_id: &str, state: &State) -> anyhow::Result<StoreDeletion> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let token = token::random_hex(32)?;
+    let hash = token::hash(&token);
+
+    let store = with_db_retry("request_store_deletion", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            // waits for in-flight writes so none land after the store is read-only
+            VssItem::lock_store(conn, store_id)?;
+            VssStore::schedule_deletion(conn, store_id, &hash, state.deletion_grace)
+        })
+    })
+    .await?;
+
+    let Some(store) = store else {
+        return Err(anyhow!(
+            "Store {store_id} doesn't exist or is already scheduled for deletion"
+        ));
+    };
+    info!("Store {store_id} will be purged at {:?}", store.purge_at);
+
+    Ok(StoreDeletion {
+        store_id: store.store_id,
+        purge_at: store.purge_at,
+        confirmation_token: Some(token),
+    })
+}
+
+/// Cancels a pending deletion, which needs its confirmation token unless
+/// `token` is None, as it is for admins.
+pub async fn cancel_deletion_impl(
+    store_id: &str,
+    token: Option<&str>,
+    state: &State,
+) -> anyhow::Result<StoreDeletion> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let hash = token.map(token::hash);
+
+    let store = with_db_retry("cancel_store_deletion", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        VssStore::cancel_deletion(&mut conn, store_id, hash.as_deref())
+    })
+    .await?;
+
+    let Some(store) = store else {
+        return Err(anyhow!(
+            "Store {store_id} isn't scheduled for deletion with that token"
+        ));
+    };
+    info!("Cancelled deletion of store {store_id}");
+
+    Ok(StoreDeletion {
+        store_id: store.store_id,
+        purge_at: None,
+        confirmation_token: None,
+    })
+}
+
+/// Erases what is kept about a store outside its database once its rows are
+/// gone: its values in object storage and its org membership. Then records
+/// the receipt that is all that is left of it.
+async fn erase_elsewhere(
+    store_id: &str,
+    items: usize,
+    requested_by: &str,
+    state: &State,
+) -> anyhow::Result<ForgetReceipt> {
+    let objects = match state.blobs.clone() {
+        Some(blobs) => {
+            let store_id = store_id.to_string();
+            tokio::task::spawn_blocking(move || blobs.delete_store(&store_id)).await??
+        }
+        None => 0,
+    };
+
+    let mut receipt_id = [0u8; 16];
+    getrandom::getrandom(&mut receipt_id).map_err(|e| anyhow!("Failed to generate id: {e}"))?;
+    let receipt = ForgetReceipt {
+        receipt_id: hex::encode(receipt_id),
+        store_hash: ForgetReceipt::store_hash(store_id),
+        requested_by: requested_by.to_string(),
+        items: items as i64,
+        objects: objects as i64,
+        forgotten_at: chrono::Utc::now().naive_utc(),
+    };
+
+    with_db_retry("record_forget_receipt", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            if let Some(org) = Org::for_store(conn, store_id)? {
+                Org::remove_store(conn, &org.org_id, store_id)?;
+            }
+            ForgetReceipt::record(conn, &receipt)
+        })
+    })
+    .await?;
+    info!(
+        "Forgot store {} at the request of {requested_by}, {items} items and {objects} objects",
+        receipt.store_hash
+    );
+
+    Ok(receipt)
+}
+
+/// Irreversibly erases everything kept about a store right away, leaving
+/// only a receipt. Users confirm with the token of a pending deletion,
+/// admins pass None.
+pub async fn forget_store_impl(
+    store_id: &str,
+    token: Option<&str>,
+    state: &State,
+) -> anyhow::Result<ForgetReceipt> {
+    let store_id = &resolve_store_id(store_id, state).await?;
+    let hash = token.map(token::hash);
+
+    let items = with_db_retry("forget_store", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::lock_store(conn, store_id)?;
+            match hash {
+                Some(ref hash) => VssStore::forget(conn, store_id, hash),
+                None => VssStore::erase(conn, store_id).map(Some),
+            }
+        })
+    })
+    .await?;
+
+    let Some(items) = items else {
+        return Err(anyhow!(
+            "Store {store_id} isn't scheduled for deletion with that token"
+        ));
+    };
+    let requested_by = if token.is_some() { "user" } else { "admin" };
+    erase_elsewhere(store_id, items, requested_by, state).await
+}
+
+/// Schedules a store for deletion like the client endpoint does.
+pub async fn delete_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<StoreDeletion>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match request_deletion_impl(&store_id, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("delete_store", e)),
+    }
+}
+
+/// Cancels a store's pending deletion without its confirmation token.
+pub async fn cancel_store_deletion(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<StoreDeletion>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match cancel_deletion_impl(&store_id, None, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("cancel_store_deletion", e)),
+    }
+}
+
+/// Forgets a store right away, without waiting for a pending deletion.
+pub async fn forget_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<ForgetReceipt>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match forget_store_impl(&store_id, None, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("forget_store", e)),
+    }
+}
+
+pub async fn list_forget_receipts_impl(
+    store_id: &str,
+    state: &State,
+) -> anyhow::Result<Vec<ForgetReceipt>> {
+    with_db_retry("list_forget_receipts", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        ForgetReceipt::list_for_store(&mut conn, store_id)
+    })
+    .await
+}
+
+/// Receipts of a forgotten store, looked up by its id.
+pub async fn list_forget_receipts(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+) -> Result<Json<Vec<ForgetReceipt>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_forget_receipts_impl(&store_id, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_forget_receipts", e)),
+    }
+}
+
+/// Permanently deletes every store whose deletion grace period is over.
+pub async fn purge_deleted_stores(state: State) {
+    for shard in state.shards.all() {
+        let due = with_db_retry("list_stores_due_for_purge", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            VssStore::due_for_purge(&mut conn)
+        })
+        .await;
+        let due = match due {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to list stores to purge on {}: {e}", shard.name);
+                continue;
+            }
+        };
+
+        for store_id in due {
+            let res = with_db_retry("purge_store", &state.breaker, || {
+                let mut conn = shard.pool.get()?;
+                conn.transaction::<_, anyhow::Error, _>(|conn| {
+                    VssItem::lock_store(conn, &store_id)?;
+                    VssStore::purge(conn, &store_id)
+                })
+            })
+            .await;
+
+            let res = match res {
+                Ok(Some(items)) => erase_elsewhere(&store_id, items, "schedule", &state)
+                    .await
+                    .map(Some),
+                res => res.map(|_| None),
+            };
+            match res {
+                Ok(Some(_)) => info!("Purged store {store_id}"),
+                Ok(None) => info!("Store {store_id} was no longer due to be purged"),
+                Err(e) => error!("Failed to purge store {store_id}: {e}"),
+            }
+        }
+    }
+}
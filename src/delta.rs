@@ -0,0 +1,58 @@
+use crate::kv::ByteData;
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use serde::{Deserialize, Serialize};
+
+/// Patched values can't be larger than a whole value sent in a request body.
+pub const MAX_PATCHED_LEN: usize = 100_000_000;
+
+/// One instruction of a delta, in the style of xdelta's COPY and ADD: the
+/// new value is built by concatenating the output of every op in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeltaOp {
+    /// Copy `len` bytes of the current value starting at `offset`
+    Copy { offset: u64, len: u64 },
+    /// Append new bytes
+    Insert(ByteData),
+}
+
+/// Builds a new value by applying `ops` to `base`, failing if an op reads
+/// past the end of `base` or the result grows beyond [`MAX_PATCHED_LEN`].
+pub fn apply_delta(base: &[u8], ops: &[DeltaOp]) -> anyhow::Result<Vec<u8>> {
+    let mut value = Vec::with_capacity(base.len());
+
+    for op in ops {
+        let bytes = match op {
+            DeltaOp::Copy { offset, len } => {
+                let start = usize::try_from(*offset)?;
+                let end = start
+                    .checked_add(usize::try_from(*len)?)
+                    .filter(|end| *end <= base.len())
+                    .ok_or_else(|| {
+                        InvalidRequest::field(
+                            "ops",
+                            FieldErrorCode::OutOfRange,
+                            format!(
+                                "Copy of {len} bytes at {offset} is out of bounds of {} bytes",
+                                base.len()
+                            ),
+                        )
+                    })?;
+                &base[start..end]
+            }
+            DeltaOp::Insert(data) => data.0.as_slice(),
+        };
+
+        if value.len() + bytes.len() > MAX_PATCHED_LEN {
+            return Err(InvalidRequest::field(
+                "ops",
+                FieldErrorCode::OutOfRange,
+                format!("Patched value would exceed {MAX_PATCHED_LEN} bytes"),
+            )
+            .into());
+        }
+        value.extend_from_slice(bytes);
+    }
+
+    Ok(value)
+}
@@ -0,0 +1,172 @@
+use crate::auth::verify_admin_token;
+use crate::client::{self, with_retry};
+use crate::kv::KeyValue;
+use crate::models::VssItem;
+use crate::routes::{ErrorResponse, PutObjectsRequest};
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::Query;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use ureq::Agent;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportParams {
+    /// Only export this store, otherwise every store is exported
+    pub store_id: Option<String>,
+}
+
+/// Summary of an export run, logged once it finishes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportReport {
+    pub stores: usize,
+    pub items: usize,
+    pub failed_stores: Vec<String>,
+}
+
+/// PUTs a batch to the remote putObjects endpoint, retrying on failure.
+async fn push_batch(
+    client: &Agent,
+    url: &str,
+    auth_token: Option<&str>,
+    req: PutObjectsRequest,
+) -> anyhow::Result<()> {
+    let client = client.clone();
+    let url = url.to_string();
+    let auth_token = auth_token.map(|t| format!("Bearer {t}"));
+
+    with_retry("Export batch", move || {
+        let mut request = client.put(&url);
+        if let Some(ref token) = auth_token {
+            request = request.set("Authorization", token);
+        }
+        request.send_json(&req)?;
+        Ok(())
+    })
+    .await
+}
+
+async fn export_store(
+    client: &Agent,
+    url: &str,
+    auth_token: Option<&str>,
+    store_id: &str,
+    batch_size: i64,
+    state: &State,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    let mut last_key: Option<String> = None;
+    loop {
+        let items = {
+            let mut conn = state.db(store_id).get()?;
+            VssItem::list_items(
+                &mut conn,
+                state.blobs.as_deref(),
+                store_id,
+                last_key.as_deref(),
+                batch_size,
+            )?
+        };
+
+        let Some(last) = items.last() else {
+            break;
+        };
+        last_key = Some(last.key.clone());
+
+        let transaction_items: Vec<KeyValue> =
+            items.into_iter().filter_map(|i| i.into_kv()).collect();
+        count += transaction_items.len();
+
+        let req = PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items,
+        };
+        push_batch(client, url, auth_token, req).await?;
+    }
+
+    Ok(count)
+}
+
+pub async fn export_impl(params: ExportParams, state: &State) -> anyhow::Result<ExportReport> {
+    let client = client::agent()?;
+    let Ok(base_url) = std::env::var("EXPORT_URL") else {
+        return Err(anyhow!("EXPORT_URL not set"));
+    };
+    let url = format!("{}/v2/putObjects", base_url.trim_end_matches('/'));
+    let auth_token = std::env::var("EXPORT_AUTH_TOKEN").ok();
+
+    let batch_size = std::env::var("EXPORT_BATCH_SIZE")
+        .ok()
+        .map(|s| s.parse::<i64>())
+        .transpose()?
+        .unwrap_or(100);
+
+    let mut report = ExportReport::default();
+
+    info!("Starting export to {base_url}");
+
+    let mut after: Option<String> = None;
+    loop {
+        let store_ids: Vec<String> = match params.store_id {
+            Some(ref store_id) if after.is_none() => vec![store_id.clone()],
+            Some(_) => vec![],
+            None => state
+                .shards
+                .list_stores(after.as_deref(), batch_size)?
+                .into_iter()
+                .map(|s| s.store_id)
+                .collect(),
+        };
+
+        let Some(last) = store_ids.last() else {
+            break;
+        };
+        after = Some(last.clone());
+
+        for store_id in store_ids {
+            match export_store(
+                &client,
+                &url,
+                auth_token.as_deref(),
+                &store_id,
+                batch_size,
+                state,
+            )
+            .await
+            {
+                Ok(count) => {
+                    report.stores += 1;
+                    report.items += count;
+                }
+                Err(e) => {
+                    error!("Failed to export store {store_id}: {e:?}");
+                    report.failed_stores.push(store_id);
+                }
+            }
+        }
+    }
+
+    info!("Export complete! {report:?}");
+
+    Ok(report)
+}
+
+pub async fn export(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Query(params): Query<ExportParams>,
+) -> Result<Json<()>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    tokio::spawn(async move {
+        if let Err(e) = export_impl(params, &state).await {
+            error!("Export failed: {e:?}")
+        }
+    });
+
+    Ok(Json(()))
+}
@@ -0,0 +1,313 @@
+use crate::validation::VersionConflict;
+use crate::State;
+use anyhow::anyhow;
+use axum::body::{boxed, Body, HttpBody};
+use axum::extract::OriginalUri;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+use std::fmt;
+use std::time::Duration;
+
+const DEFAULT_DELAY_MS: u64 = 2_000;
+/// Added to every injected response, naming the fault, so clients can tell
+/// them from real failures
+pub const FAULT_HEADER: &str = "x-vss-fault";
+
+/// A way a request can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A 500 without running the request
+    Error,
+    /// A 409 version conflict without running the request
+    Conflict,
+    /// Waits before running the request
+    Delay,
+    /// Runs the request, then cuts the response body in half
+    Truncate,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FaultKind::Error => "error",
+            FaultKind::Conflict => "conflict",
+            FaultKind::Delay => "delay",
+            FaultKind::Truncate => "truncate",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Injects a fault into `percent` of the requests to `route`, or to every
+/// client route when it is None.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub kind: FaultKind,
+    pub percent: f64,
+    pub route: Option<String>,
+}
+
+impl std::str::FromStr for FaultRule {
+    type Err = anyhow::Error;
+
+    /// Parses `kind:percent`, or `kind:percent@route` for a single route.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, route) = match s.split_once('@') {
+            Some((rule, route)) => (rule, Some(route.to_string())),
+            None => (s, None),
+        };
+        let Some((kind, percent)) = rule.split_once(':') else {
+            return Err(anyhow!("Fault {s:?} must look like kind:percent[@route]"));
+        };
+        let kind = match kind {
+            "error" => FaultKind::Error,
+            "conflict" => FaultKind::Conflict,
+            "delay" => FaultKind::Delay,
+            "truncate" => FaultKind::Truncate,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown fault {kind}, expected error, conflict, delay or truncate"
+                ))
+            }
+        };
+        let percent = percent.parse::<f64>()?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(anyhow!("Fault percentage {percent} must be 0 to 100"));
+        }
+
+        Ok(FaultRule {
+            kind,
+            percent,
+            route,
+        })
+    }
+}
+
+/// Makes a share of client requests fail on purpose, so wallets can test how
+/// they recover from a misbehaving server. Never enable it in production.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjection {
+    /// Tried in order, the first that fires is injected
+    pub rules: Vec<FaultRule>,
+    /// How long `delay` faults wait
+    pub delay: Duration,
+}
+
+impl FaultInjection {
+    /// Configured by the comma separated rules in `FAULT_INJECTION`, with
+    /// `delay` faults waiting `FAULT_DELAY_MS`.
+    pub fn from_env() -> anyhow::Result<Option<FaultInjection>> {
+        let Ok(rules) = std::env::var("FAULT_INJECTION") else {
+            return Ok(None);
+        };
+        let rules = rules
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<FaultRule>>>()?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let delay = std::env::var("FAULT_DELAY_MS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_DELAY_MS);
+
+        warn!("Fault injection is enabled, client requests will fail on purpose: {rules:?}");
+
+        Ok(Some(FaultInjection {
+            rules,
+            delay: Duration::from_millis(delay),
+        }))
+    }
+
+    /// The fault to inject into a request to `path`, if any, rolling each
+    /// matching rule's chance in turn.
+    pub fn pick(&self, path: &str) -> Option<FaultKind> {
+        self.rules
+            .iter()
+            .filter(|r| r.route.as_deref().is_none_or(|route| route == path))
+            .find(|r| roll(r.percent))
+            .map(|r| r.kind)
+    }
+}
+
+/// A random number from 0 to 1, or None if randomness isn't available.
+fn random_fraction() -> Option<f64> {
+    let mut bytes = [0u8; 4];
+    getrandom::getrandom(&mut bytes).ok()?;
+    Some(u32::from_le_bytes(bytes) as f64 / u32::MAX as f64)
+}
+
+/// True `percent` of the time.
+fn roll(percent: f64) -> bool {
+    random_fraction().is_some_and(|f| f * 100.0 < percent)
+}
+
+/// Holds every client response back, so frontends can be tried against a
+/// slow server. For development and staging only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulatedLatency {
+    /// Added to every response
+    pub fixed: Duration,
+    /// Up to this much more is added at random
+    pub jitter: Duration,
+}
+
+impl SimulatedLatency {
+    /// Configured by `SIMULATED_LATENCY_MS` and `SIMULATED_LATENCY_JITTER_MS`,
+    /// None when both are unset or zero.
+    pub fn from_env() -> anyhow::Result<Option<SimulatedLatency>> {
+        let millis = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|s| s.parse::<u64>())
+                .transpose()
+                .map(|ms| Duration::from_millis(ms.unwrap_or(0)))
+        };
+        let latency = SimulatedLatency {
+            fixed: millis("SIMULATED_LATENCY_MS")?,
+            jitter: millis("SIMULATED_LATENCY_JITTER_MS")?,
+        };
+        if latency == SimulatedLatency::default() {
+            return Ok(None);
+        }
+
+        warn!(
+            "Simulating latency, client responses are held back {:?} plus up to {:?}",
+            latency.fixed, latency.jitter
+        );
+
+        Ok(Some(latency))
+    }
+
+    /// How long to hold the next response back.
+    pub fn sample(&self) -> Duration {
+        self.fixed + self.jitter.mul_f64(random_fraction().unwrap_or(0.0))
+    }
+}
+
+/// Delays client responses by the simulated latency.
+pub async fn simulate_latency<B>(req: Request<B>, next: Next<B>) -> Response {
+    let latency = req.extensions().get::<State>().and_then(|s| s.latency);
+    let res = next.run(req).await;
+    if let Some(latency) = latency {
+        tokio::time::sleep(latency.sample()).await;
+    }
+    res
+}
+
+fn tagged(mut res: Response, kind: FaultKind) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&kind.to_string()) {
+        res.headers_mut().insert(FAULT_HEADER, value);
+    }
+    res
+}
+
+/// Injects the configured faults into client requests.
+pub async fn inject_faults<B>(req: Request<B>, next: Next<B>) -> Response {
+    let Some(faults) = req
+        .extensions()
+        .get::<State>()
+        .and_then(|state| state.faults.clone())
+    else {
+        return next.run(req).await;
+    };
+    // routes nested under LDK_BASE_PATH only see the rest of the path
+    let path = match req.extensions().get::<OriginalUri>() {
+        Some(uri) => uri.path().to_string(),
+        None => req.uri().path().to_string(),
+    };
+    let Some(kind) = faults.pick(&path) else {
+        return next.run(req).await;
+    };
+
+    let res = match kind {
+        FaultKind::Error => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Injected fault".to_string(),
+        )
+            .into_response(),
+        FaultKind::Conflict => VersionConflict {
+            error: "CONFLICT".to_string(),
+            message: "Injected fault".to_string(),
+            key: None,
+            items: vec![],
+        }
+        .to_response()
+        .into_response(),
+        FaultKind::Delay => {
+            tokio::time::sleep(faults.delay).await;
+            next.run(req).await
+        }
+        FaultKind::Truncate => {
+            let (mut parts, mut body) = next.run(req).await.into_parts();
+            let mut bytes = vec![];
+            while let Some(Ok(chunk)) = body.data().await {
+                bytes.extend_from_slice(&chunk);
+            }
+            bytes.truncate(bytes.len() / 2);
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, boxed(Body::from(bytes)))
+        }
+    };
+
+    tagged(res, kind)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_fault_rules() {
+        let rule = FaultRule::from_str("conflict:12.5@/v2/putObjects").unwrap();
+        assert_eq!(
+            rule,
+            FaultRule {
+                kind: FaultKind::Conflict,
+                percent: 12.5,
+                route: Some("/v2/putObjects".to_string()),
+            }
+        );
+        assert!(FaultRule::from_str("error").is_err());
+        assert!(FaultRule::from_str("explode:5").is_err());
+        assert!(FaultRule::from_str("delay:101").is_err());
+
+        let faults = FaultInjection {
+            rules: vec![
+                FaultRule::from_str("error:0").unwrap(),
+                FaultRule::from_str("truncate:100@/v2/getObject").unwrap(),
+                FaultRule::from_str("delay:100@/v2/listKeyVersions").unwrap(),
+            ],
+            delay: Duration::from_millis(10),
+        };
+        assert_eq!(faults.pick("/v2/getObject"), Some(FaultKind::Truncate));
+        assert_eq!(faults.pick("/v2/listKeyVersions"), Some(FaultKind::Delay));
+        assert_eq!(faults.pick("/v2/putObjects"), None);
+    }
+
+    #[test]
+    fn test_simulated_latency() {
+        let latency = SimulatedLatency {
+            fixed: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+        };
+        for _ in 0..20 {
+            let delay = latency.sample();
+            assert!(delay >= latency.fixed, "{delay:?}");
+            assert!(delay <= latency.fixed + latency.jitter, "{delay:?}");
+        }
+
+        let fixed = SimulatedLatency {
+            jitter: Duration::ZERO,
+            ..latency
+        };
+        assert_eq!(fixed.sample(), fixed.fixed);
+    }
+}
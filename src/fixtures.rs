@@ -0,0 +1,269 @@
+use crate::models::{with_db_retry, ItemAttributes, VssItem, VssStore};
+use crate::State;
+use anyhow::{anyhow, Context};
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// What clients may do to the stores loaded from a fixtures file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FixtureMode {
+    /// Clients may only read the loaded stores
+    #[default]
+    ReadOnly,
+    /// Clients write to the loaded copy, which the next start replaces
+    CopyOnWrite,
+}
+
+impl std::str::FromStr for FixtureMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(FixtureMode::ReadOnly),
+            "copy-on-write" => Ok(FixtureMode::CopyOnWrite),
+            _ => Err(anyhow!(
+                "Unknown fixtures mode {s:?}, expected read-only or copy-on-write"
+            )),
+        }
+    }
+}
+
+/// An item of a fixture store. Items of a store export are read as they
+/// are, but only `key` is required.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureItem {
+    pub key: String,
+    /// Base64 of the value, null for deleted keys
+    pub value: Option<String>,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub deleted: bool,
+    /// Defaults to `updated_date`, or the Unix epoch if neither is set
+    pub created_date: Option<NaiveDateTime>,
+    /// Defaults to `created_date`
+    pub updated_date: Option<NaiveDateTime>,
+    pub metadata: Option<serde_json::Value>,
+    pub content_type: Option<String>,
+}
+
+impl FixtureItem {
+    fn decoded_value(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        match (&self.value, self.deleted) {
+            (_, true) | (None, _) => Ok(None),
+            (Some(value), false) => {
+                Ok(Some(base64::decode(value).with_context(|| {
+                    format!("Value of {} isn't base64", self.key)
+                })?))
+            }
+        }
+    }
+
+    fn dates(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let created = self.created_date.or(self.updated_date).unwrap_or_default();
+        (created, self.updated_date.unwrap_or(created))
+    }
+}
+
+/// A store and everything in it, as loaded from a fixtures file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureStore {
+    pub store_id: String,
+    #[serde(default)]
+    pub items: Vec<FixtureItem>,
+}
+
+#[derive(Deserialize)]
+struct ExportedStore {
+    store_id: String,
+}
+
+/// Fixture stores are written either like [`FixtureStore`] or as a store
+/// export bundle.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureEntry {
+    Store(FixtureStore),
+    Export {
+        store: ExportedStore,
+        items: Vec<FixtureItem>,
+    },
+}
+
+impl From<FixtureEntry> for FixtureStore {
+    fn from(entry: FixtureEntry) -> Self {
+        match entry {
+            FixtureEntry::Store(store) => store,
+            FixtureEntry::Export { store, items } => FixtureStore {
+                store_id: store.store_id,
+                items,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FixtureFile {
+    Many(Vec<FixtureEntry>),
+    One(FixtureEntry),
+}
+
+/// Canned stores written over whatever the database holds for them on
+/// every start, so demos and tests always begin from the same data.
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    pub path: PathBuf,
+    pub mode: FixtureMode,
+    pub stores: Vec<FixtureStore>,
+    /// Replace stores that exist but weren't loaded from fixtures, which is
+    /// refused otherwise so a fixture can't wipe out a real store
+    pub overwrite: bool,
+}
+
+/// The stores in a fixtures file: a JSON array of stores, a single store,
+/// or one store per line when the file ends in `.ndjson` or `.jsonl`.
+pub fn parse_fixtures(path: &Path, contents: &str) -> anyhow::Result<Vec<FixtureStore>> {
+    let ndjson = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("ndjson" | "jsonl")
+    );
+    let stores: Vec<FixtureStore> = if ndjson {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(n, line)| {
+                serde_json::from_str::<FixtureEntry>(line)
+                    .map(FixtureStore::from)
+                    .with_context(|| format!("Invalid store on line {}", n + 1))
+            })
+            .collect::<anyhow::Result<_>>()?
+    } else {
+        match serde_json::from_str::<FixtureFile>(contents)
+            .context("Expected a store or an array of stores")?
+        {
+            FixtureFile::Many(entries) => entries.into_iter().map(FixtureStore::from).collect(),
+            FixtureFile::One(entry) => vec![entry.into()],
+        }
+    };
+
+    let mut seen = HashSet::new();
+    for store in &stores {
+        if !seen.insert(store.store_id.as_str()) {
+            return Err(anyhow!("Store {} is in the file twice", store.store_id));
+        }
+    }
+    Ok(stores)
+}
+
+impl Fixtures {
+    pub fn from_env() -> anyhow::Result<Option<Fixtures>> {
+        let Ok(path) = std::env::var("FIXTURES_FILE") else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let mode = std::env::var("FIXTURES_MODE")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or_default();
+        let overwrite = std::env::var("FIXTURES_OVERWRITE")
+            .ok()
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let stores = parse_fixtures(&path, &contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Some(Fixtures {
+            path,
+            mode,
+            stores,
+            overwrite,
+        }))
+    }
+
+    /// The stores clients may only read.
+    pub fn read_only_stores(&self) -> HashSet<String> {
+        match self.mode {
+            FixtureMode::ReadOnly => self.stores.iter().map(|s| s.store_id.clone()).collect(),
+            FixtureMode::CopyOnWrite => HashSet::new(),
+        }
+    }
+}
+
+/// Replaces each fixture store with its contents from the file, in one
+/// transaction per store, returning how many items were written. Fails on
+/// stores that exist but weren't loaded from fixtures before, unless
+/// [`Fixtures::overwrite`] is set.
+pub async fn load_fixtures(fixtures: &Fixtures, state: &State) -> anyhow::Result<usize> {
+    let mut written = 0;
+    for store in &fixtures.stores {
+        let store_id = &store.store_id;
+        state.store_id_policy.validate(store_id)?;
+        let items = store
+            .items
+            .iter()
+            .map(|item| {
+                state.key_policy.validate(&item.key)?;
+                Ok((item, item.decoded_value()?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("Invalid fixture store {store_id}"))?;
+
+        with_db_retry("load_fixtures", &state.breaker, || {
+            let mut conn = state.db(store_id).get()?;
+            conn.transaction::<_, anyhow::Error, _>(|conn| {
+                VssItem::lock_store(conn, store_id)?;
+                if !fixtures.overwrite
+                    && VssStore::get_store(conn, store_id)?.is_some()
+                    && !VssStore::is_fixture(conn, store_id)?
+                {
+                    return Err(anyhow!(
+                        "Store {store_id} already exists and wasn't loaded from fixtures, \
+                         set FIXTURES_OVERWRITE=true to replace it"
+                    ));
+                }
+                VssStore::erase(conn, store_id)?;
+                for (item, value) in &items {
+                    let attributes = ItemAttributes {
+                        metadata: item.metadata.clone(),
+                        content_type: item.content_type.clone(),
+                    };
+                    VssItem::put_item_verbatim(
+                        conn,
+                        store_id,
+                        &item.key,
+                        value.as_deref(),
+                        item.version,
+                        &attributes,
+                        item.dates(),
+                    )?;
+                }
+                VssStore::mark_fixture(conn, store_id)
+            })
+        })
+        .await?;
+
+        // values clients offloaded since the last start
+        if let Some(blobs) = state.blobs.clone() {
+            let store_id = store_id.clone();
+            tokio::task::spawn_blocking(move || blobs.delete_store(&store_id)).await??;
+        }
+        written += items.len();
+    }
+
+    info!(
+        "Loaded {} fixture stores with {written} items from {}",
+        fixtures.stores.len(),
+        fixtures.path.display()
+    );
+    Ok(written)
+}
@@ -0,0 +1,203 @@
+use crate::metrics::PoolStatus;
+use crate::models::pending_migrations;
+use crate::shard::DbPool;
+use crate::{State, API_VERSION};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use chrono::Utc;
+use diesel::{sql_query, RunQueryDsl};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How long the health check waits for a database connection
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Mirror lag beyond which the replica is reported as degraded
+const MIRROR_LAG_WARN_MILLIS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// A single entry in the `checks` object of the health response.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheck {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component_id: Option<String>,
+    pub component_type: &'static str,
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_value: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_unit: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    pub time: String,
+}
+
+impl HealthCheck {
+    fn new(component_type: &'static str, status: HealthStatus) -> Self {
+        Self {
+            component_id: None,
+            component_type,
+            status,
+            observed_value: None,
+            observed_unit: None,
+            output: None,
+            time: Utc::now().to_rfc3339(),
+        }
+    }
+
+    fn observed(mut self, value: u64, unit: &'static str) -> Self {
+        self.observed_value = Some(value);
+        self.observed_unit = Some(unit);
+        self
+    }
+
+    fn component(mut self, id: impl Into<String>) -> Self {
+        self.component_id = Some(id.into());
+        self
+    }
+
+    fn output(mut self, output: impl Into<String>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolStatus>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, Vec<HealthCheck>>,
+}
+
+impl HealthResponse {
+    /// Fabricate a status: pass response without checking database connectivity
+    pub fn new_ok() -> Self {
+        Self {
+            status: HealthStatus::Pass,
+            version: String::from(API_VERSION),
+            pool: None,
+            checks: BTreeMap::new(),
+        }
+    }
+
+    fn add_check(&mut self, name: &'static str, check: HealthCheck) {
+        self.status = self.status.max(check.status);
+        self.checks.entry(name).or_default().push(check);
+    }
+}
+
+fn check_database(state: &State, db_pool: &DbPool) -> HealthCheck {
+    if state.breaker.is_open() {
+        return HealthCheck::new("datastore", HealthStatus::Fail).output("Circuit breaker open");
+    }
+
+    let start = Instant::now();
+    let res = db_pool
+        .get_timeout(DB_CHECK_TIMEOUT)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut conn| Ok(sql_query("SELECT 1").execute(&mut conn)?));
+
+    match res {
+        Ok(_) => HealthCheck::new("datastore", HealthStatus::Pass)
+            .observed(start.elapsed().as_millis() as u64, "ms"),
+        Err(e) => HealthCheck::new("datastore", HealthStatus::Fail).output(e.to_string()),
+    }
+}
+
+fn check_connections(pool: &PoolStatus) -> HealthCheck {
+    // every connection busy means new requests are queueing for one
+    let status = if pool.in_use >= pool.max_size {
+        HealthStatus::Warn
+    } else {
+        HealthStatus::Pass
+    };
+
+    HealthCheck::new("datastore", status).observed(pool.in_use as u64, "connections")
+}
+
+fn check_migrations(db_pool: &DbPool) -> HealthCheck {
+    let res = db_pool
+        .get_timeout(DB_CHECK_TIMEOUT)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut conn| pending_migrations(&mut conn));
+
+    match res {
+        Ok(pending) if pending.is_empty() => HealthCheck::new("datastore", HealthStatus::Pass),
+        Ok(pending) => HealthCheck::new("datastore", HealthStatus::Warn)
+            .observed(pending.len() as u64, "migrations")
+            .output(format!("Pending migrations: {}", pending.join(", "))),
+        Err(e) => HealthCheck::new("datastore", HealthStatus::Fail).output(e.to_string()),
+    }
+}
+
+/// IETF draft RFC for HTTP API Health Checks:
+/// https://datatracker.ietf.org/doc/html/draft-inadarei-api-health-check
+pub async fn health_check(
+    Extension(state): Extension<State>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let pool = state.pool_metrics.status(&state);
+
+    let mut res = HealthResponse::new_ok();
+    let sharded = state.shards.all().len() > 1;
+    for shard in state.shards.all() {
+        let mut response_time = check_database(&state, &shard.pool);
+        let mut migrations = check_migrations(&shard.pool);
+        if sharded {
+            response_time = response_time.component(&shard.name);
+            migrations = migrations.component(&shard.name);
+        }
+        res.add_check("postgres:responseTime", response_time);
+        res.add_check("postgres:migrations", migrations);
+    }
+    res.add_check("postgres:connections", check_connections(&pool));
+
+    if let Some(ref mirror) = state.mirror {
+        let mirror = mirror.status();
+
+        let lag_status = if mirror.lag_millis > MIRROR_LAG_WARN_MILLIS {
+            HealthStatus::Warn
+        } else {
+            HealthStatus::Pass
+        };
+        res.add_check(
+            "mirror:lag",
+            HealthCheck::new("component", lag_status).observed(mirror.lag_millis, "ms"),
+        );
+
+        // writes that never reached the secondary leave it out of sync
+        let queue_status = if mirror.failed > 0 || mirror.dropped > 0 {
+            HealthStatus::Warn
+        } else {
+            HealthStatus::Pass
+        };
+        res.add_check(
+            "mirror:queue",
+            HealthCheck::new("component", queue_status)
+                .observed(mirror.pending, "writes")
+                .output(format!(
+                    "{} failed, {} dropped",
+                    mirror.failed, mirror.dropped
+                )),
+        );
+    }
+
+    res.pool = Some(pool);
+
+    let code = match res.status {
+        HealthStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+
+    (code, Json(res))
+}
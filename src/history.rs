@@ -0,0 +1,154 @@
+use crate::models::{with_db_retry, HistoryEntry};
+use crate::State;
+use anyhow::anyhow;
+use log::{error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Entries deleted by one statement, so pruning a large backlog doesn't hold
+/// locks for long
+const PRUNE_BATCH_SIZE: i64 = 1_000;
+
+/// Keeps the versions items are overwritten or deleted from in vss_history,
+/// pruned by the history job so keys written often don't grow it forever.
+#[derive(Debug, Clone, Default)]
+pub struct WriteHistory {
+    /// Most past versions kept per key
+    pub keep_versions: Option<i64>,
+    /// Past versions are kept for this many days after they're superseded
+    pub keep_days: Option<i32>,
+    metrics: Arc<HistoryCounters>,
+}
+
+#[derive(Debug, Default)]
+struct HistoryCounters {
+    prune_runs: AtomicU64,
+    pruned: AtomicU64,
+    prune_failures: AtomicU64,
+    last_prune_micros: AtomicU64,
+}
+
+/// A limit of at least 1 read from `name`, None if it isn't set.
+pub(crate) fn env_limit<T: std::str::FromStr + PartialOrd + Default>(
+    name: &str,
+) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Err(_) => Ok(None),
+        Ok(s) => {
+            let limit = s.parse::<T>()?;
+            if limit <= T::default() {
+                return Err(anyhow!("{name} must be at least 1"));
+            }
+            Ok(Some(limit))
+        }
+    }
+}
+
+impl WriteHistory {
+    pub fn from_env() -> anyhow::Result<Option<WriteHistory>> {
+        let enabled = std::env::var("WRITE_HISTORY")
+            .ok()
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let history = WriteHistory {
+            keep_versions: env_limit("HISTORY_KEEP_VERSIONS")?,
+            keep_days: env_limit("HISTORY_KEEP_DAYS")?,
+            ..Default::default()
+        };
+        match (history.keep_versions, history.keep_days) {
+            (None, None) => info!("Keeping write history forever"),
+            (versions, days) => info!(
+                "Keeping write history, up to {} versions per key for {} days",
+                versions.map_or("any".to_string(), |v| v.to_string()),
+                days.map_or("any".to_string(), |d| d.to_string())
+            ),
+        }
+
+        Ok(Some(history))
+    }
+
+    /// Times the history job has run.
+    pub fn prune_runs(&self) -> u64 {
+        self.metrics.prune_runs.load(Ordering::Relaxed)
+    }
+
+    /// Entries deleted by the history job since startup.
+    pub fn pruned(&self) -> u64 {
+        self.metrics.pruned.load(Ordering::Relaxed)
+    }
+
+    /// Shards the history job failed to prune since startup.
+    pub fn prune_failures(&self) -> u64 {
+        self.metrics.prune_failures.load(Ordering::Relaxed)
+    }
+
+    /// How long the last run of the history job took.
+    pub fn last_prune_micros(&self) -> u64 {
+        self.metrics.last_prune_micros.load(Ordering::Relaxed)
+    }
+}
+
+/// Deletes the history past the policy on every shard, in batches.
+pub async fn prune_history(state: State) {
+    let Some(history) = state.history.clone() else {
+        return;
+    };
+    if history.keep_versions.is_none() && history.keep_days.is_none() {
+        return;
+    }
+
+    let started = Instant::now();
+    for shard in state.shards.all() {
+        let mut pruned = 0;
+        loop {
+            let res = with_db_retry("prune_history", &state.breaker, || {
+                let mut conn = shard.pool.get()?;
+                HistoryEntry::prune(
+                    &mut conn,
+                    history.keep_versions,
+                    history.keep_days,
+                    PRUNE_BATCH_SIZE,
+                )
+            })
+            .await;
+
+            match res {
+                Ok(count) => {
+                    pruned += count;
+                    history
+                        .metrics
+                        .pruned
+                        .fetch_add(count as u64, Ordering::Relaxed);
+                    if (count as i64) < PRUNE_BATCH_SIZE {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    history
+                        .metrics
+                        .prune_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    error!("Failed to prune write history on {}: {e}", shard.name);
+                    break;
+                }
+            }
+        }
+        if pruned > 0 {
+            info!("Pruned {pruned} history entries on {}", shard.name);
+        }
+    }
+
+    history.metrics.prune_runs.fetch_add(1, Ordering::Relaxed);
+    history
+        .metrics
+        .last_prune_micros
+        .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+}
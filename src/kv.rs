@@ -1,22 +1,80 @@
 use core::fmt;
 use serde::de::Visitor;
 use serde::*;
+use std::cell::Cell;
+use std::str::FromStr;
+
+/// Version of a put that writes the key whatever version it is at, giving it
+/// the next version after the stored one. Clients with signed versions send
+/// it as `-1`.
+pub const NO_VERSION_CHECK: u64 = u64::MAX;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyValue {
     pub key: String,
     pub value: ByteData,
-    pub version: i64,
+    #[serde(deserialize_with = "deserialize_version")]
+    pub version: u64,
+    /// JSON object the client attached to the item, replaced on every write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Media type of the value, e.g. `application/json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
 }
 
 impl KeyValue {
-    pub fn new(key: String, value: Vec<u8>, version: i64) -> KeyValue {
+    pub fn new(key: String, value: Vec<u8>, version: u64) -> KeyValue {
         KeyValue {
             key,
             value: ByteData(value),
             version,
+            metadata: None,
+            content_type: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyVersion {
+    pub key: String,
+    pub version: u64,
+}
+
+/// Versions are unsigned like the proto's, but `-1` is taken as
+/// [`NO_VERSION_CHECK`] for clients that use signed versions.
+pub fn deserialize_version<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct VersionVisitor;
+
+    impl<'de> Visitor<'de> for VersionVisitor {
+        type Value = u64;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an unsigned 64-bit version or -1")
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<u64, E>
+        where
+            E: de::Error,
+        {
+            Ok(v)
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<u64, E>
+        where
+            E: de::Error,
+        {
+            match v {
+                -1 => Ok(NO_VERSION_CHECK),
+                v => u64::try_from(v).map_err(|_| E::custom(format!("invalid version {v}"))),
+            }
         }
     }
+
+    deserializer.deserialize_any(VersionVisitor)
 }
 
 #[derive(Debug, Clone)]
@@ -27,7 +85,15 @@ impl Serialize for ByteData {
     where
         S: Serializer,
     {
-        self.0.serialize(serializer)
+        // binary formats get a native byte string instead of an array of numbers
+        if serializer.is_human_readable() {
+            match HEX_VALUES.with(Cell::get) {
+                true => serializer.serialize_str(&format!("{HEX_PREFIX}{}", hex::encode(&self.0))),
+                false => self.0.serialize(serializer),
+            }
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
     }
 }
 
@@ -42,23 +108,67 @@ impl<'de> Deserialize<'de> for ByteData {
             type Value = ByteData;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a Vec<u8> or a base64 encoded string")
+                formatter.write_str(
+                    "a Vec<u8>, a byte string, a base64 or hex: encoded string or an encoding and data",
+                )
             }
 
             fn visit_str<E>(self, v: &str) -> Result<ByteData, E>
             where
                 E: de::Error,
             {
-                let decoded =
-                    base64::decode(v).map_err(|err| de::Error::custom(err.to_string()))?;
+                let decoded = match v.strip_prefix(HEX_PREFIX) {
+                    Some(v) => hex::decode(v).map_err(invalid_byte_data)?,
+                    None => decode_base64(v).map_err(invalid_byte_data)?,
+                };
+                Ok(ByteData(decoded))
+            }
+
+            fn visit_map<M>(self, mut map: M) -> Result<ByteData, M::Error>
+            where
+                M: de::MapAccess<'de>,
+            {
+                let mut encoding: Option<String> = None;
+                let mut data: Option<String> = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "encoding" => encoding = Some(map.next_value()?),
+                        "data" => data = Some(map.next_value()?),
+                        _ => return Err(de::Error::unknown_field(&key, &["encoding", "data"])),
+                    }
+                }
+                let data = data.ok_or_else(|| de::Error::missing_field("data"))?;
+
+                let decoded = match encoding.as_deref().unwrap_or("base64") {
+                    "hex" => hex::decode(data).map_err(invalid_byte_data)?,
+                    "base64" => decode_base64(&data).map_err(invalid_byte_data)?,
+                    other => return Err(de::Error::unknown_variant(other, &["hex", "base64"])),
+                };
                 Ok(ByteData(decoded))
             }
 
-            fn visit_seq<S>(self, seq: S) -> Result<ByteData, S::Error>
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<ByteData, E>
+            where
+                E: de::Error,
+            {
+                Ok(ByteData(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<ByteData, E>
+            where
+                E: de::Error,
+            {
+                Ok(ByteData(v))
+            }
+
+            fn visit_seq<S>(self, mut seq: S) -> Result<ByteData, S::Error>
             where
                 S: de::SeqAccess<'de>,
             {
-                let vec = Vec::<u8>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+                let mut vec = Vec::new();
+                while let Some(byte) = seq.next_element::<u8>().map_err(invalid_byte_data)? {
+                    vec.push(byte);
+                }
                 Ok(ByteData(vec))
             }
         }
@@ -67,21 +177,166 @@ impl<'de> Deserialize<'de> for ByteData {
     }
 }
 
+/// Decodes standard or URL-safe base64, padded or not. A string can't mix
+/// the two alphabets.
+pub fn decode_base64(s: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let unpadded = s.trim_end_matches('=');
+    let config = match unpadded.contains(['-', '_']) {
+        true => base64::URL_SAFE_NO_PAD,
+        false => base64::STANDARD_NO_PAD,
+    };
+    base64::decode_config(unpadded, config)
+}
+
+/// Marks a value string as hex rather than base64. Colons aren't in either
+/// base64 alphabet, so the two can't be confused.
+pub const HEX_PREFIX: &str = "hex:";
+
+/// Header clients send with a value of `hex` to get values in JSON responses
+/// as [`HEX_PREFIX`]ed strings instead of arrays of numbers.
+pub const VALUE_ENCODING: &str = "value-encoding";
+
+thread_local! {
+    static HEX_VALUES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with values serialized as hex strings by human-readable formats.
+pub fn with_hex_values<T>(f: impl FnOnce() -> T) -> T {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            HEX_VALUES.with(|h| h.set(false));
+        }
+    }
+
+    HEX_VALUES.with(|h| h.set(true));
+    let _reset = Reset;
+    f()
+}
+
+/// Header v1 clients send to choose how values in responses are base64
+/// encoded, see [`Base64Encoding`].
+pub const BASE64_ENCODING: &str = "base64-encoding";
+
+/// Base64 variant of values in v1 responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Base64Encoding {
+    #[default]
+    Standard,
+    StandardNoPad,
+    UrlSafe,
+    UrlSafeNoPad,
+}
+
+impl Base64Encoding {
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        let config = match self {
+            Base64Encoding::Standard => base64::STANDARD,
+            Base64Encoding::StandardNoPad => base64::STANDARD_NO_PAD,
+            Base64Encoding::UrlSafe => base64::URL_SAFE,
+            Base64Encoding::UrlSafeNoPad => base64::URL_SAFE_NO_PAD,
+        };
+        base64::encode_config(bytes, config)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Base64Encoding::Standard => "standard",
+            Base64Encoding::StandardNoPad => "standard-nopad",
+            Base64Encoding::UrlSafe => "url",
+            Base64Encoding::UrlSafeNoPad => "url-nopad",
+        }
+    }
+}
+
+impl FromStr for Base64Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Base64Encoding::Standard),
+            "standard-nopad" => Ok(Base64Encoding::StandardNoPad),
+            "url" => Ok(Base64Encoding::UrlSafe),
+            "url-nopad" => Ok(Base64Encoding::UrlSafeNoPad),
+            _ => Err(format!(
+                "{BASE64_ENCODING} must be one of standard, standard-nopad, url or url-nopad"
+            )),
+        }
+    }
+}
+
+/// Malformed base64 and out of range array elements are reported the same
+/// way, whichever encoding the client used.
+fn invalid_byte_data<E: de::Error>(err: impl fmt::Display) -> E {
+    E::custom(format!("invalid byte data: {err}"))
+}
+
 // need this for backwards compat for now
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyValueOld {
     pub key: String,
     pub value: String,
-    pub version: i64,
+    #[serde(deserialize_with = "deserialize_version")]
+    pub version: u64,
 }
 
-impl From<KeyValue> for KeyValueOld {
-    fn from(kv: KeyValue) -> Self {
+impl KeyValueOld {
+    pub fn encoded(kv: KeyValue, encoding: Base64Encoding) -> Self {
         KeyValueOld {
             key: kv.key,
-            value: base64::encode(kv.value.0),
+            value: encoding.encode(&kv.value.0),
             version: kv.version,
         }
     }
 }
+
+impl From<KeyValue> for KeyValueOld {
+    fn from(kv: KeyValue) -> Self {
+        KeyValueOld::encoded(kv, Base64Encoding::Standard)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_byte_data_base64_variants() {
+        let bytes = vec![251, 255, 1, 2];
+        for encoded in ["+/8BAg==", "+/8BAg", "-_8BAg==", "-_8BAg"] {
+            assert_eq!(decode_base64(encoded).unwrap(), bytes, "{encoded}");
+        }
+        assert!(decode_base64("+_8BAg").is_err());
+
+        let encodings = ["standard", "standard-nopad", "url", "url-nopad"]
+            .map(|s| s.parse::<Base64Encoding>().unwrap().encode(&bytes));
+        assert_eq!(encodings, ["+/8BAg==", "+/8BAg", "-_8BAg==", "-_8BAg"]);
+        assert!("base64url".parse::<Base64Encoding>().is_err());
+    }
+
+    #[test]
+    fn test_byte_data_hex() {
+        let bytes = vec![251, 255, 1, 2];
+        for json in [
+            r#""hex:fbff0102""#,
+            r#"{"encoding":"hex","data":"fbff0102"}"#,
+            r#"{"encoding":"base64","data":"+/8BAg=="}"#,
+            r#"{"data":"+/8BAg"}"#,
+        ] {
+            let value: ByteData = serde_json::from_str(json).unwrap();
+            assert_eq!(value.0, bytes, "{json}");
+        }
+        assert!(serde_json::from_str::<ByteData>(r#""hex:fbf""#).is_err());
+        assert!(serde_json::from_str::<ByteData>(r#"{"encoding":"hex"}"#).is_err());
+        assert!(serde_json::from_str::<ByteData>(r#"{"encoding":"z85","data":""}"#).is_err());
+
+        let value = ByteData(bytes);
+        let hex = with_hex_values(|| serde_json::to_string(&value).unwrap());
+        assert_eq!(hex, r#""hex:fbff0102""#);
+        assert_eq!(serde_json::to_string(&value).unwrap(), "[251,255,1,2]");
+        // binary formats keep native byte strings
+        let cbor = with_hex_values(|| serde_cbor::to_vec(&value).unwrap());
+        assert_eq!(cbor, serde_cbor::to_vec(&value).unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::models::{with_db_retry, IdempotencyKey, JobLeader, Lease, StoreExport};
+use crate::State;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TERM_SECS: u64 = 30;
+
+/// Elects one instance to run each singleton background job, using rows in
+/// `vss_job_leaders`. The leader renews its term while it is alive, and if it
+/// dies another instance takes over once the term expires.
+#[derive(Debug, Clone)]
+pub struct LeaderElection {
+    holder: String,
+    term: Duration,
+    leading: Arc<Mutex<HashSet<String>>>,
+}
+
+impl LeaderElection {
+    pub fn new(holder: String, term: Duration) -> Self {
+        LeaderElection {
+            holder,
+            term,
+            leading: Default::default(),
+        }
+    }
+
+    /// Identified by `INSTANCE_ID`, or the host name and a random suffix, with
+    /// terms of `LEADER_TERM_SECS`.
+    pub fn from_env() -> anyhow::Result<LeaderElection> {
+        let holder = match std::env::var("INSTANCE_ID") {
+            Ok(id) => id,
+            Err(_) => {
+                let mut suffix = [0u8; 4];
+                getrandom::getrandom(&mut suffix)?;
+                let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "vss".to_string());
+                format!("{host}-{}", hex::encode(suffix))
+            }
+        };
+        let term = std::env::var("LEADER_TERM_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_TERM_SECS);
+
+        Ok(LeaderElection::new(
+            holder,
+            Duration::from_secs(term.max(3)),
+        ))
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Whether this instance currently leads `job`.
+    pub fn is_leading(&self, job: &str) -> bool {
+        self.leading
+            .lock()
+            .expect("leader lock poisoned")
+            .contains(job)
+    }
+
+    /// Takes or renews leadership of `job`, returning whether this instance
+    /// leads it. Steps down if the database can't be reached, as another
+    /// instance may take over once the term runs out.
+    pub async fn campaign(&self, state: &State, job: &str) -> bool {
+        let res = with_db_retry("campaign", &state.breaker, || {
+            let mut conn = state.db_pool.get()?;
+            JobLeader::try_lead(&mut conn, job, &self.holder, self.term)
+        })
+        .await;
+
+        let leader = match res {
+            Ok(leader) => leader.is_some(),
+            Err(e) => {
+                error!("Failed to campaign for {job}: {e}");
+                false
+            }
+        };
+
+        let mut leading = self.leading.lock().expect("leader lock poisoned");
+        if leader && leading.insert(job.to_string()) {
+            info!("{} is now the leader for {job}", self.holder);
+        } else if !leader && leading.remove(job) {
+            warn!("{} is no longer the leader for {job}", self.holder);
+        }
+        leader
+    }
+
+    /// Gives up every job this instance leads, so others can take over
+    /// straight away on shutdown.
+    pub async fn resign_all(&self, state: &State) {
+        let jobs = std::mem::take(&mut *self.leading.lock().expect("leader lock poisoned"));
+        for job in jobs {
+            let res = with_db_retry("resign", &state.breaker, || {
+                let mut conn = state.db_pool.get()?;
+                JobLeader::resign(&mut conn, &job, &self.holder)
+            })
+            .await;
+
+            match res {
+                Ok(_) => info!("{} resigned as leader for {job}", self.holder),
+                Err(e) => error!("Failed to resign as leader for {job}: {e}"),
+            }
+        }
+    }
+}
+
+/// Runs `job` every `interval` on whichever instance leads it. Every
+/// instance campaigns several times per term, or every interval if that is
+/// shorter, so leadership is kept while the leader is alive and is picked up
+/// soon after it dies.
+pub async fn run_singleton<F, Fut>(state: State, job: &'static str, interval: Duration, mut run: F)
+where
+    F: FnMut(State) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let election = state.leader.clone();
+    let mut ticker = tokio::time::interval((election.term / 3).min(interval));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_run: Option<Instant> = None;
+    loop {
+        ticker.tick().await;
+        if !election.campaign(&state, job).await {
+            continue;
+        }
+        if last_run.map(|at| at.elapsed() >= interval).unwrap_or(true) {
+            last_run = Some(Instant::now());
+            run(state.clone()).await;
+        }
+    }
+}
+
+/// Deletes expired leases and idempotency keys left behind by stores that
+/// are no longer written to.
+pub async fn sweep_expired(state: State) {
+    for shard in state.shards.all() {
+        let res = with_db_retry("sweep_expired", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            let leases = Lease::delete_expired(&mut conn)?;
+            let keys = IdempotencyKey::delete_expired(&mut conn, state.idempotency_window)?;
+            let exports = StoreExport::delete_expired(&mut conn)?;
+            Ok((leases, keys, exports))
+        })
+        .await;
+
+        match res {
+            Ok((leases, keys, exports)) => info!(
+                "Swept {leases} expired leases, {keys} expired idempotency keys and {exports} \
+                 expired exports from {}",
+                shard.name
+            ),
+            Err(e) => error!("Failed to sweep expired rows from {}: {e}", shard.name),
+        }
+    }
+}
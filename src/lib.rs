@@ -0,0 +1,165 @@
+use crate::models::CircuitBreaker;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use secp256k1::{All, PublicKey, Secp256k1};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod access_log;
+pub mod admin;
+pub mod anomaly;
+pub mod auth;
+pub mod blob;
+pub mod cdc;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod cors;
+pub mod data_export;
+pub mod deletion;
+pub mod delta;
+pub mod export;
+pub mod fault;
+pub mod fixtures;
+pub mod health;
+pub mod history;
+pub mod kv;
+pub mod leader;
+pub mod limit;
+pub mod manifest;
+pub mod metrics;
+pub mod migration;
+pub mod mirror;
+pub mod models;
+pub mod mutation_log;
+pub mod nostr;
+pub mod openapi;
+pub mod org;
+pub mod partition;
+pub mod profile;
+pub mod proxy;
+pub mod quota;
+pub mod retention;
+pub mod routes;
+pub mod seed;
+pub mod shard;
+pub mod snapshot;
+pub mod standalone;
+pub mod statsd;
+pub mod systemd;
+pub mod token;
+pub mod usage;
+pub mod validation;
+
+pub const ALLOWED_ORIGINS: [&str; 6] = [
+    "https://app.mutinywallet.com",
+    "capacitor://localhost",
+    "https://signet-app.mutinywallet.com",
+    "http://localhost:3420",
+    "http://localhost",
+    "https://localhost",
+];
+
+pub const ALLOWED_LOCALHOST: &str = "http://127.0.0.1:";
+pub const ALLOWED_LAN: &str = "http://192.168.";
+
+pub const API_VERSION: &str = "v2";
+
+#[derive(Clone)]
+pub struct State {
+    /// The main database, which also holds state shared by every instance
+    /// such as job leaders
+    pub db_pool: Pool<ConnectionManager<PgConnection>>,
+    /// Databases stores are spread across, just `db_pool` unless sharded
+    pub shards: shard::Shards,
+    pub auth_key: Option<PublicKey>,
+    pub admin_auth_key: Option<PublicKey>,
+    pub self_hosted: bool,
+    pub secp: Secp256k1<All>,
+    pub mirror: Option<mirror::Mirror>,
+    /// Object storage values over its threshold are offloaded to, when
+    /// configured
+    pub blobs: Option<Arc<blob::BlobStore>>,
+    /// Client requests fail on purpose when set, for testing wallets
+    pub faults: Option<fault::FaultInjection>,
+    /// Client responses are held back this long when set, for development
+    pub latency: Option<fault::SimulatedLatency>,
+    /// Set during maintenance to reject writes while reads keep working
+    pub read_only: Arc<AtomicBool>,
+    /// Stores clients may only read while the rest are writable, the fixture
+    /// stores in read-only fixtures mode
+    pub read_only_stores: Arc<HashSet<String>>,
+    /// Deadlines for handling a single request, by kind of request
+    pub timeouts: routes::RequestTimeouts,
+    /// In-flight request limits, past which requests are shed
+    pub limits: limit::ConcurrencyLimits,
+    pub breaker: Arc<CircuitBreaker>,
+    pub pool_metrics: metrics::PoolMetrics,
+    /// Database operations slower than this are logged as warnings
+    pub slow_op_threshold: Duration,
+    /// How long putObjects idempotency keys are remembered
+    pub idempotency_window: Duration,
+    pub usage: usage::UsageMeter,
+    /// Sells extra storage over lightning when configured
+    pub quota: Option<quota::QuotaProvider>,
+    pub free_tier: quota::FreeTier,
+    /// Sends nostr messages when backups are updated, when configured
+    pub nostr: Option<nostr::Notifier>,
+    /// Alerts on unusual write activity, when enabled
+    pub anomaly: Option<anomaly::AnomalyDetector>,
+    pub key_policy: validation::KeyPolicy,
+    /// Reject client request bodies with unknown fields, e.g. a misspelled
+    /// `storeId`, instead of ignoring them
+    pub strict_json: bool,
+    /// Accept writes that leave a key with an empty value, which are
+    /// otherwise rejected since keys are deleted with deleteByPrefix
+    pub allow_empty_values: bool,
+    /// v2 getObject fails with a 404 for missing keys instead of returning
+    /// null, like the reference server
+    pub get_object_not_found: bool,
+    /// Reject putObjects items that skip the version check, i.e. lazy
+    /// versioning
+    pub reject_lazy_versions: bool,
+    /// Base64 variant of v1 getObject values when the request doesn't name one
+    pub v1_base64_encoding: kv::Base64Encoding,
+    /// Seconds clients may cache getObject and listKeyVersions responses
+    /// for, only when self hosting. Otherwise they are never stored.
+    pub read_cache_secs: Option<u64>,
+    /// Reject putObjects batches with items older than the stored version
+    /// instead of skipping those items
+    pub strict_versions: bool,
+    pub store_id_policy: validation::StoreIdPolicy,
+    /// Which origins browsers may call the API from when not self hosting
+    pub cors: cors::CorsRules,
+    /// Proxies trusted to say which client a request came from
+    pub trusted_proxies: proxy::TrustedProxies,
+    /// How long stores scheduled for deletion stay read-only before they are
+    /// purged, during which the deletion can be cancelled
+    pub deletion_grace: Duration,
+    /// How long store exports are kept for download once they are ready
+    pub export_retention: Duration,
+    /// Past versions of items are kept and pruned when enabled
+    pub history: Option<history::WriteHistory>,
+    /// Every change to an item is logged in order when enabled
+    pub mutation_log: Option<mutation_log::MutationLog>,
+    /// Decides which instance runs each singleton background job
+    pub leader: leader::LeaderElection,
+}
+
+impl State {
+    /// Connection pool of the database holding `store_id`.
+    pub fn db(&self, store_id: &str) -> &shard::DbPool {
+        &self.shards.for_store(store_id).pool
+    }
+
+    /// Behaviors of stores that haven't pinned their own.
+    pub fn default_behaviors(&self) -> models::StoreBehaviors {
+        models::StoreBehaviors {
+            v1_base64_encoding: Some(self.v1_base64_encoding.as_str().to_string()),
+            lazy_versions: Some(!self.reject_lazy_versions),
+            null_for_missing: Some(!self.get_object_not_found),
+        }
+    }
+}
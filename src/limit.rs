@@ -0,0 +1,164 @@
+use crate::routes::RequestKind;
+use crate::State;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// How long shed clients are told to wait before retrying
+const SHED_RETRY_AFTER_SECS: u64 = 1;
+/// Endpoints that are never shed, so the server can still be monitored
+const UNLIMITED_PATHS: [&str; 2] = ["/health-check", "/metrics"];
+
+/// Caps on requests being handled at once, in total and per kind of request.
+/// Requests over a cap are rejected straight away rather than queued.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimits {
+    global: Arc<Semaphore>,
+    write: Arc<Semaphore>,
+    admin: Arc<Semaphore>,
+    pub limits: LimitSizes,
+    shed: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitSizes {
+    pub global: usize,
+    pub write: usize,
+    pub admin: usize,
+}
+
+impl LimitSizes {
+    /// Defaults relative to the database pool, as nearly every request needs
+    /// a connection: 8 requests per connection in total, 4 per connection
+    /// for writes and half the pool for admin operations.
+    pub fn for_pool(pool_size: u32) -> LimitSizes {
+        let pool_size = pool_size.max(1) as usize;
+        LimitSizes {
+            global: pool_size * 8,
+            write: pool_size * 4,
+            admin: (pool_size / 2).max(1),
+        }
+    }
+
+    /// Configured by `MAX_IN_FLIGHT_REQUESTS`, `MAX_IN_FLIGHT_WRITES` and
+    /// `MAX_IN_FLIGHT_ADMIN`, defaulting to [`LimitSizes::for_pool`].
+    pub fn from_env(pool_size: u32) -> anyhow::Result<LimitSizes> {
+        let limit = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|s| s.parse::<usize>())
+                .transpose()
+        };
+        let defaults = LimitSizes::for_pool(pool_size);
+
+        Ok(LimitSizes {
+            global: limit("MAX_IN_FLIGHT_REQUESTS")?.unwrap_or(defaults.global),
+            write: limit("MAX_IN_FLIGHT_WRITES")?.unwrap_or(defaults.write),
+            admin: limit("MAX_IN_FLIGHT_ADMIN")?.unwrap_or(defaults.admin),
+        })
+    }
+}
+
+impl ConcurrencyLimits {
+    pub fn new(limits: LimitSizes) -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            global: Arc::new(Semaphore::new(limits.global)),
+            write: Arc::new(Semaphore::new(limits.write)),
+            admin: Arc::new(Semaphore::new(limits.admin)),
+            limits,
+            shed: Default::default(),
+        }
+    }
+
+    /// Requests rejected since startup.
+    pub fn shed_count(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.limits.global - self.global.available_permits()
+    }
+}
+
+/// Sent as JSON when a request is shed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Overloaded {
+    /// Always `OVERLOADED`
+    pub error: String,
+    pub message: String,
+    pub kind: RequestKind,
+}
+
+fn shed(limits: &ConcurrencyLimits, status: StatusCode, kind: RequestKind) -> Response {
+    limits.shed.fetch_add(1, Ordering::Relaxed);
+    let message = match (status, kind) {
+        (StatusCode::TOO_MANY_REQUESTS, RequestKind::Admin) => "Too many admin requests in flight",
+        (StatusCode::TOO_MANY_REQUESTS, _) => "Too many writes in flight",
+        _ => "Server is overloaded",
+    }
+    .to_string();
+    warn!("Shedding request: {message}");
+
+    let body = Overloaded {
+        error: "OVERLOADED".to_string(),
+        message,
+        kind,
+    };
+    (
+        status,
+        [(header::RETRY_AFTER, SHED_RETRY_AFTER_SECS.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Rejects requests once the server is at its in-flight limits: with a 429
+/// when too many writes or admin operations are running, and a 503 when the
+/// server as a whole is saturated.
+pub async fn shed_load<B>(req: Request<B>, next: Next<B>) -> Response {
+    let Some(limits) = req.extensions().get::<State>().map(|s| s.limits.clone()) else {
+        return next.run(req).await;
+    };
+    if UNLIMITED_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let kind = RequestKind::of(&req);
+    let kind_limit = match kind {
+        RequestKind::Read => None,
+        RequestKind::Write => Some(&limits.write),
+        RequestKind::Admin => Some(&limits.admin),
+    };
+    let _kind_permit = match kind_limit.map(|s| s.clone().try_acquire_owned()) {
+        Some(Err(_)) => return shed(&limits, StatusCode::TOO_MANY_REQUESTS, kind),
+        permit => permit,
+    };
+    let Ok(_permit) = limits.global.clone().try_acquire_owned() else {
+        return shed(&limits, StatusCode::SERVICE_UNAVAILABLE, kind);
+    };
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limit_sizes() {
+        let sizes = LimitSizes::for_pool(10);
+        assert_eq!((sizes.global, sizes.write, sizes.admin), (80, 40, 5));
+        assert_eq!(LimitSizes::for_pool(1).admin, 1);
+
+        let limits = ConcurrencyLimits::new(sizes);
+        assert_eq!(limits.in_flight(), 0);
+        assert_eq!(limits.shed_count(), 0);
+    }
+}
@@ -1,47 +1,31 @@
-use crate::models::MIGRATIONS;
-use crate::routes::*;
 use axum::extract::DefaultBodyLimit;
 use axum::headers::Origin;
 use axum::http::{request::Parts, HeaderValue, Method, StatusCode, Uri};
+use axum::middleware::from_fn;
 use axum::routing::{get, post, put};
 use axum::{http, Extension, Router, TypedHeader};
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::PgConnection;
-use diesel_migrations::MigrationHarness;
-use log::{error, info};
-use secp256k1::{All, PublicKey, Secp256k1};
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::oneshot;
+use log::{error, info, warn};
+use secp256k1::{PublicKey, Secp256k1};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 use tower_http::cors::{AllowOrigin, CorsLayer};
+use vss_rs::models::{run_migrations, validate_schema, CircuitBreaker, ConnectionOptions};
+use vss_rs::routes::*;
+use vss_rs::{
+    access_log, admin, anomaly, blob, cdc, config, cors, deletion, export, fault, fixtures, health,
+    history, kv, leader, limit, metrics, migration, mirror, mutation_log, nostr, openapi, org,
+    partition, profile, proxy, quota, retention, seed, shard, snapshot, standalone, statsd,
+    systemd, usage, validation, State,
+};
 
-mod auth;
-mod kv;
-mod migration;
-mod models;
-mod routes;
-
-const ALLOWED_ORIGINS: [&str; 6] = [
-    "https://app.mutinywallet.com",
-    "capacitor://localhost",
-    "https://signet-app.mutinywallet.com",
-    "http://localhost:3420",
-    "http://localhost",
-    "https://localhost",
-];
-
-const ALLOWED_SUBDOMAIN: &str = ".mutiny-web.pages.dev";
-const ALLOWED_LOCALHOST: &str = "http://127.0.0.1:";
-const ALLOWED_LAN: &str = "http://192.168.";
-
-const API_VERSION: &str = "v2";
-
-#[derive(Clone)]
-pub struct State {
-    db_pool: Pool<ConnectionManager<PgConnection>>,
-    pub auth_key: Option<PublicKey>,
-    pub self_hosted: bool,
-    pub secp: Secp256k1<All>,
-}
+/// Connections each database pool holds, should be a multiple of 100, our
+/// database connection limit
+const DB_POOL_SIZE: u32 = 10;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -49,85 +33,833 @@ async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::try_init()?;
 
+    // serves tasks' poll and wake times to tokio-console, which also needs a
+    // build with RUSTFLAGS="--cfg tokio_unstable"
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+
+    // report panics and request errors to sentry when a DSN is configured
+    #[cfg(feature = "sentry")]
+    let _sentry = std::env::var("SENTRY_DSN").ok().map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            },
+        ))
+    });
+
+    // problems with the configuration are reported together before starting
+    let mut config = config::ConfigReport::default();
+
+    let standalone = config
+        .check("standalone mode", standalone::Standalone::from_env())
+        .flatten();
+    if let Some(standalone) = &standalone {
+        if let Some(store_id) = standalone::Standalone::client_token_arg()? {
+            if std::env::var("AUTH_KEY").is_ok() {
+                return Err(anyhow::anyhow!(
+                    "AUTH_KEY is set, sign client tokens with its key instead"
+                ));
+            }
+            println!("{}", standalone.client_token(&Secp256k1::new(), &store_id)?);
+            return Ok(());
+        }
+    }
+
     // get values key from env
-    let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let port: u16 = std::env::var("VSS_PORT")
+    let pg_url = std::env::var("DATABASE_URL").ok();
+    match (&pg_url, &standalone) {
+        (Some(_), _) => {}
+        (None, Some(_)) => config.error(
+            "DATABASE_URL",
+            "must be set, standalone mode has no embedded storage and stores items in postgres",
+        ),
+        (None, None) => config.error("DATABASE_URL", "must be set"),
+    }
+    let port: u16 = config.var("VSS_PORT").unwrap_or(8080);
+    let admin_addr: Option<SocketAddr> = config.var("ADMIN_BIND_ADDR");
+
+    let auth_key: Option<PublicKey> = config.var("AUTH_KEY");
+    let admin_auth_key: Option<PublicKey> = config.var("ADMIN_AUTH_KEY");
+
+    let statement_timeout: u64 = config.var("DB_STATEMENT_TIMEOUT_SECS").unwrap_or(30);
+    let slow_op_threshold: u64 = config.var("SLOW_OP_THRESHOLD_MS").unwrap_or(1_000);
+    let idempotency_window: u64 = config.var("IDEMPOTENCY_WINDOW_SECS").unwrap_or(86_400);
+    let usage_flush_interval: u64 = config.var("USAGE_FLUSH_SECS").unwrap_or(60);
+    let sweep_interval: u64 = config.var("SWEEP_INTERVAL_SECS").unwrap_or(3_600);
+    let deletion_grace: u64 = config
+        .var("STORE_DELETION_GRACE_SECS")
+        .unwrap_or(7 * 24 * 60 * 60);
+    let export_retention: u64 = config
+        .var("STORE_EXPORT_RETENTION_SECS")
+        .unwrap_or(24 * 60 * 60);
+    let breaker_threshold: u32 = config.var("DB_BREAKER_THRESHOLD").unwrap_or(5);
+    let breaker_cooldown: u64 = config.var("DB_BREAKER_COOLDOWN_SECS").unwrap_or(30);
+    let chunk_size: Option<u32> = config.var("VALUE_CHUNK_SIZE");
+
+    let change_publisher = config
+        .check("change stream", cdc::ChangePublisher::from_env())
+        .flatten();
+    let write_history = config
+        .check("write history", history::WriteHistory::from_env())
+        .flatten();
+    let mutation_log = config
+        .check("mutation log", mutation_log::MutationLog::from_env())
+        .flatten();
+    let faults = config
+        .check("FAULT_INJECTION", fault::FaultInjection::from_env())
+        .flatten();
+    let latency = config
+        .check("simulated latency", fault::SimulatedLatency::from_env())
+        .flatten();
+    let statsd = config
+        .check("StatsD", statsd::StatsdSink::from_env())
+        .flatten();
+    let fixtures = config
+        .check("FIXTURES_FILE", fixtures::Fixtures::from_env())
+        .flatten();
+
+    let transaction_pooling = std::env::var("PGBOUNCER_TRANSACTION_MODE")
         .ok()
-        .map(|p| p.parse::<u16>())
-        .transpose()?
-        .unwrap_or(8080);
-
-    let auth_key = std::env::var("AUTH_KEY").ok();
-    let auth_key = match auth_key {
-        None => None,
-        Some(data) => {
-            let auth_key_bytes = hex::decode(data)?;
-            Some(PublicKey::from_slice(&auth_key_bytes)?)
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let shard_urls = config
+        .check("DATABASE_SHARDS", shard::Shards::urls_from_env())
+        .flatten();
+
+    let secp = Secp256k1::new();
+
+    let self_hosted = standalone.is_some()
+        || std::env::var("SELF_HOST")
+            .ok()
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+    // standalone servers sign their own tokens unless given keys
+    let auth_key = match &standalone {
+        Some(standalone) if auth_key.is_none() => Some(standalone.auth_pubkey(&secp)),
+        _ => auth_key,
+    };
+    let admin_auth_key = match &standalone {
+        Some(standalone) if admin_auth_key.is_none() => Some(standalone.admin_pubkey(&secp)),
+        _ => admin_auth_key,
+    };
+
+    // startup migrations hold a session lock, which PgBouncer can't keep
+    if self_hosted && transaction_pooling {
+        config.warn(
+            "Not running migrations through PgBouncer, run them against the database directly",
+        );
+    }
+    if self_hosted && auth_key.is_none() {
+        config.warn("SELF_HOST without AUTH_KEY lets any website read and write any store");
+    }
+
+    let read_cache_secs: Option<u64> = config.var("READ_CACHE_MAX_AGE");
+    let read_cache_secs = match read_cache_secs {
+        Some(secs) if secs > MAX_READ_CACHE_SECS => {
+            config.error(
+                "READ_CACHE_MAX_AGE",
+                format!("must be at most {MAX_READ_CACHE_SECS} seconds"),
+            );
+            None
         }
+        Some(_) if !self_hosted => {
+            config.warn("READ_CACHE_MAX_AGE is ignored unless SELF_HOST is set");
+            None
+        }
+        secs => secs,
     };
 
-    // DB management
-    let manager = ConnectionManager::<PgConnection>::new(&pg_url);
-    let db_pool = Pool::builder()
-        .max_size(10) // should be a multiple of 100, our database connection limit
-        .test_on_check_out(true)
-        .build(manager)
-        .expect("Could not build connection pool");
+    let swagger_ui = std::env::var("SWAGGER_UI")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
 
-    let secp = Secp256k1::new();
+    // opt-in, since the aliases only speak JSON and stock LDK clients send
+    // protobuf
+    let ldk_base_path = match std::env::var("LDK_BASE_PATH") {
+        Err(_) => None,
+        Ok(path) if path.is_empty() => None,
+        Ok(path) => {
+            let path = format!("/{}", path.trim_matches('/'));
+            if path == "/" {
+                config.error("LDK_BASE_PATH", "can't be the root path");
+            }
+            Some(path)
+        }
+    };
+
+    let read_only = std::env::var("READ_ONLY")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let read_only_stores = fixtures
+        .as_ref()
+        .map(|f| f.read_only_stores())
+        .unwrap_or_default();
 
-    let self_hosted = std::env::var("SELF_HOST")
+    let mirror = config
+        .check("mirroring", mirror::Mirror::from_env())
+        .flatten();
+    let blob_store = config
+        .check("object storage", blob::BlobStore::from_env())
+        .flatten();
+    let quota = config
+        .check("storage quota", quota::QuotaProvider::from_env())
+        .flatten();
+    let free_tier = config
+        .check("free tier", quota::FreeTier::from_env())
+        .unwrap_or_default();
+    let notifier = config
+        .check("nostr notifications", nostr::Notifier::from_env(&secp))
+        .flatten();
+    let anomaly = config
+        .check("anomaly detection", anomaly::AnomalyDetector::from_env())
+        .flatten();
+    let key_policy = config
+        .check("key policy", validation::KeyPolicy::from_env())
+        .unwrap_or_default();
+    let strict_versions = std::env::var("STRICT_VERSIONS")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let get_object_not_found = std::env::var("GET_OBJECT_NOT_FOUND")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let reject_lazy_versions = std::env::var("REJECT_LAZY_VERSIONS")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let v1_base64_encoding = match std::env::var("V1_BASE64_ENCODING") {
+        Ok(encoding) => encoding.parse::<kv::Base64Encoding>().unwrap_or_else(|_| {
+            config.error(
+                "V1_BASE64_ENCODING",
+                "must be one of standard, standard-nopad, url or url-nopad",
+            );
+            Default::default()
+        }),
+        Err(_) => kv::Base64Encoding::default(),
+    };
+    let allow_empty_values = std::env::var("ALLOW_EMPTY_VALUES")
+        .ok()
+        .map(|s| s == "true" || s == "1")
+        .unwrap_or(false);
+    let strict_json = std::env::var("STRICT_JSON")
         .ok()
         .map(|s| s == "true" || s == "1")
         .unwrap_or(false);
+    let limit_sizes = config
+        .check(
+            "concurrency limits",
+            limit::LimitSizes::from_env(DB_POOL_SIZE),
+        )
+        .unwrap_or(limit::LimitSizes::for_pool(DB_POOL_SIZE));
+    let store_id_policy = config
+        .check("store id policy", validation::StoreIdPolicy::from_env())
+        .unwrap_or_default();
+    let timeouts = config
+        .check("request timeouts", RequestTimeouts::from_env())
+        .unwrap_or_default();
+    let cors = config
+        .check("CORS_SUBDOMAINS", cors::CorsRules::from_env())
+        .unwrap_or_default();
+    let trusted_proxies = config
+        .check("TRUSTED_PROXIES", proxy::TrustedProxies::from_env())
+        .unwrap_or_default();
+    let leader = config.check("leader election", leader::LeaderElection::from_env());
 
-    // run migrations if self hosted, otherwise assume they have been run manually
-    if self_hosted {
-        let mut connection = db_pool.get()?;
-        connection
-            .run_pending_migrations(MIGRATIONS)
-            .expect("migrations could not run");
+    // then that what it needs is there
+    if let Some(url) = &pg_url {
+        config.check("DATABASE_URL", config::check_database(url));
+    }
+    for (name, url) in shard_urls.iter().flatten() {
+        if Some(url) != pg_url.as_ref() {
+            let shard = format!("DATABASE_SHARDS {name}");
+            config.check(&shard, config::check_database(url));
+        }
+    }
+    // systemd owns the port with socket activation
+    if std::env::var_os("LISTEN_FDS").is_none() {
+        config.check("VSS_PORT", config::check_bind(([0, 0, 0, 0], port).into()));
     }
+    if let Some(admin_addr) = admin_addr {
+        config.check("ADMIN_BIND_ADDR", config::check_bind(admin_addr));
+    }
+
+    config.finish()?;
+    let pg_url = pg_url.expect("DATABASE_URL was checked");
+    let leader = leader.expect("leader election was checked");
+
+    // DB management
+    let pool_metrics = metrics::PoolMetrics::default();
+    let connection_options = ConnectionOptions {
+        statement_timeout: Duration::from_secs(statement_timeout),
+        idle_in_transaction_timeout: Duration::from_secs(statement_timeout),
+        chunk_size,
+        capture_changes: change_publisher.is_some(),
+        keep_history: write_history.is_some(),
+        log_mutations: mutation_log.is_some(),
+        transaction_pooling,
+    };
+    let build_pool = |url: &str| {
+        let manager = ConnectionManager::<PgConnection>::new(url);
+        Pool::builder()
+            .max_size(DB_POOL_SIZE)
+            .test_on_check_out(true)
+            .connection_customizer(Box::new(connection_options))
+            .event_handler(Box::new(pool_metrics.clone()))
+            .build(manager)
+            .expect("Could not build connection pool")
+    };
+    let db_pool = build_pool(&pg_url);
+    let shards = match shard_urls {
+        None => shard::Shards::single(db_pool.clone()),
+        Some(urls) => shard::Shards::new(
+            urls.into_iter()
+                .map(|(name, url)| {
+                    let pool = if url == pg_url {
+                        db_pool.clone()
+                    } else {
+                        build_pool(&url)
+                    };
+                    shard::Shard { name, pool }
+                })
+                .collect(),
+        )?,
+    };
+
+    // run migrations if self hosted, otherwise make sure they have been run manually
+    let pools = std::iter::once(&db_pool).chain(shards.all().iter().map(|s| &s.pool));
+    for pool in pools {
+        let mut connection = pool.get()?;
+        if transaction_pooling {
+            connection_options.apply_to_role(&mut connection)?;
+        }
+
+        if self_hosted && !transaction_pooling {
+            run_migrations(&mut connection).expect("migrations could not run");
+        } else if let Err(e) = validate_schema(&mut connection) {
+            error!("Database schema is out of date, run migrations before starting: {e}");
+            return Err(e);
+        }
+    }
+
+    let limits = limit::ConcurrencyLimits::new(limit_sizes);
 
     let state = State {
         db_pool,
+        shards,
         auth_key,
+        admin_auth_key,
         self_hosted,
+        read_cache_secs,
         secp,
+        mirror,
+        blobs: blob_store.map(Arc::new),
+        read_only: Arc::new(AtomicBool::new(read_only)),
+        read_only_stores: Arc::new(read_only_stores),
+        timeouts,
+        limits,
+        breaker: Arc::new(CircuitBreaker::new(
+            breaker_threshold,
+            Duration::from_secs(breaker_cooldown),
+        )),
+        pool_metrics,
+        slow_op_threshold: Duration::from_millis(slow_op_threshold),
+        idempotency_window: Duration::from_secs(idempotency_window),
+        deletion_grace: Duration::from_secs(deletion_grace),
+        export_retention: Duration::from_secs(export_retention),
+        history: write_history,
+        mutation_log,
+        faults,
+        latency,
+        usage: Default::default(),
+        quota,
+        free_tier,
+        nostr: notifier.clone(),
+        anomaly: anomaly.clone(),
+        key_policy,
+        strict_versions,
+        get_object_not_found,
+        reject_lazy_versions,
+        v1_base64_encoding,
+        allow_empty_values,
+        strict_json,
+        store_id_policy,
+        cors,
+        trusted_proxies,
+        leader,
     };
 
-    let addr: std::net::SocketAddr = format!("0.0.0.0:{port}")
+    if state.shards.all().len() > 1 {
+        for status in shard::list_shards_impl(&state).await? {
+            info!("Shard {} holds {} stores", status.name, status.stores);
+            if status.misplaced > 0 {
+                warn!(
+                    "Shard {} holds {} stores that belong on another shard, move them before clients look for them",
+                    status.name, status.misplaced
+                );
+            }
+        }
+    }
+
+    if let Some(fixtures) = fixtures {
+        fixtures::load_fixtures(&fixtures, &state).await?;
+    }
+
+    tokio::spawn(usage::run_flusher(
+        state.clone(),
+        Duration::from_secs(usage_flush_interval.max(1)),
+    ));
+    if let Some(notifier) = notifier {
+        tokio::spawn(nostr::run_notifier(state.clone(), notifier));
+    }
+    if let Some(anomaly) = anomaly {
+        tokio::spawn(anomaly::run_detector(anomaly));
+    }
+    if let Some(statsd) = statsd {
+        tokio::spawn(statsd::run_pusher(state.clone(), statsd));
+    }
+    tokio::spawn(leader::run_singleton(
+        state.clone(),
+        "sweep_expired",
+        Duration::from_secs(sweep_interval.max(1)),
+        leader::sweep_expired,
+    ));
+    tokio::spawn(leader::run_singleton(
+        state.clone(),
+        "purge_deleted_stores",
+        Duration::from_secs(sweep_interval.max(1)),
+        deletion::purge_deleted_stores,
+    ));
+    tokio::spawn(leader::run_singleton(
+        state.clone(),
+        "apply_retention",
+        Duration::from_secs(sweep_interval.max(1)),
+        retention::apply_retention,
+    ));
+    if state.history.is_some() {
+        tokio::spawn(leader::run_singleton(
+            state.clone(),
+            "prune_history",
+            Duration::from_secs(sweep_interval.max(1)),
+            history::prune_history,
+        ));
+    }
+    if state.blobs.is_some() {
+        tokio::spawn(leader::run_singleton(
+            state.clone(),
+            "sweep_orphaned_objects",
+            Duration::from_secs(sweep_interval.max(1)),
+            blob::sweep_orphaned_objects,
+        ));
+    }
+    if state.mutation_log.is_some() {
+        tokio::spawn(leader::run_singleton(
+            state.clone(),
+            "prune_mutation_log",
+            Duration::from_secs(sweep_interval.max(1)),
+            mutation_log::prune_mutation_log,
+        ));
+    }
+    if let Some(publisher) = change_publisher {
+        let interval = publisher.interval;
+        tokio::spawn(leader::run_singleton(
+            state.clone(),
+            "publish_changes",
+            interval,
+            move |state| {
+                let publisher = publisher.clone();
+                async move { publisher.publish_pending(&state).await }
+            },
+        ));
+    }
+
+    let addr: SocketAddr = format!("0.0.0.0:{port}")
         .parse()
         .expect("Failed to parse bind/port for webserver");
 
-    // if the server is self hosted, allow all origins
-    // otherwise, only allow the origins in ALLOWED_ORIGINS
-    let cors_function = if self_hosted {
-        |_: &HeaderValue, _request_parts: &Parts| true
-    } else {
-        |origin: &HeaderValue, _request_parts: &Parts| {
-            let Ok(origin) = origin.to_str() else {
-                return false;
-            };
-
-            valid_origin(origin)
+    let mut server_router = Router::new()
+        .route("/health-check", get(health::health_check))
+        .route("/openapi.json", get(openapi::openapi_spec))
+        .route(
+            "/getObject",
+            post(get_object).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v2/getObject",
+            post(get_object_v2).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v2/getObjectVersion",
+            post(get_object_version).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v3/getObject",
+            post(get_object_v3).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v2/object/*key",
+            get(get_object_raw).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/putObjects",
+            put(put_objects).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/putObjects",
+            put(put_objects).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/listKeyVersions",
+            post(list_key_versions).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v2/listKeyVersions",
+            post(list_key_versions).route_layer(from_fn(cache_reads)),
+        )
+        .route("/v2/getKeyVersions", post(get_key_versions))
+        .route("/v2/listChangedKeys", post(list_changed_keys))
+        .route("/v2/diffManifest", post(diff_manifest))
+        .route("/v2/getStoreDigest", post(get_store_digest))
+        .route(
+            "/v3/listKeyVersions",
+            post(list_key_versions_v3).route_layer(from_fn(cache_reads)),
+        )
+        .route(
+            "/v2/deleteByPrefix",
+            post(delete_by_prefix).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/copyObject",
+            post(copy_object).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/patchObject",
+            post(patch_object).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/lease/acquire",
+            post(acquire_lease).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/lease/renew",
+            post(renew_lease).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/lease/release",
+            post(release_lease).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/devices/register",
+            post(register_device).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/v2/devices/list", post(list_devices))
+        .route("/v2/usage", post(get_usage))
+        .route(
+            "/v2/quota/invoice",
+            post(quota_invoice).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/store/alias",
+            post(set_store_alias).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/store/delete",
+            post(request_store_deletion).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/store/delete/cancel",
+            post(cancel_store_deletion_request).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/retention/set",
+            post(set_retention_rule).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/retention/remove",
+            post(remove_retention_rule).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/v2/retention/list", post(list_retention_rules))
+        .route(
+            "/v2/snapshots/create",
+            post(create_snapshot).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/v2/snapshots/list", post(list_snapshots))
+        .route("/v2/snapshots/download", post(download_snapshot))
+        .route(
+            "/v2/snapshots/delete",
+            post(delete_snapshot).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/snapshots/restore",
+            post(restore_snapshot).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/store/forget",
+            post(forget_store).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/store/export",
+            post(start_store_export).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/v2/store/export/:export_id", get(download_store_export))
+        .route(
+            "/v2/nostr/subscribe",
+            post(nostr_subscribe).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/nostr/unsubscribe",
+            post(nostr_unsubscribe).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/quota/paid",
+            post(quota::quota_paid).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/v2/devices/revoke",
+            post(revoke_device).route_layer(from_fn(reject_if_read_only)),
+        );
+
+    // before the admin routes are merged in, so only client requests fail
+    // or are slowed down
+    server_router = server_router
+        .route_layer(from_fn(fault::inject_faults))
+        .route_layer(from_fn(fault::simulate_latency));
+
+    if swagger_ui {
+        server_router = server_router.route("/docs", get(openapi::swagger_ui));
+    }
+
+    let admin_router = Router::new()
+        .route("/metrics", get(metrics::metrics))
+        .route(
+            "/migration",
+            get(migration::migration).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/cloneStore",
+            post(admin::clone_store).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/pinBehaviors",
+            post(admin::pin_behaviors).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/admin/export", post(export::export))
+        .route(
+            "/admin/seed",
+            post(seed::seed).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/admin/mirror", get(mirror::mirror_status))
+        .route("/admin/mutations", get(mutation_log::stream_mutations))
+        .route("/admin/profile", get(profile::cpu_profile))
+        .route(
+            "/admin/partition",
+            get(partition::partition_status).post(partition::partition),
+        )
+        .route(
+            "/admin/maintenance",
+            get(admin::get_maintenance).post(admin::set_maintenance),
+        )
+        .route("/admin/leaders", get(admin::list_leaders))
+        .route("/admin/shards", get(shard::list_shards))
+        .route("/admin/regressions", get(admin::get_regression_stats))
+        .route("/admin/stores", get(admin::list_stores))
+        .route(
+            "/admin/stores/:store_id",
+            get(admin::get_store)
+                .merge(post(admin::update_store).route_layer(from_fn(reject_if_read_only))),
+        )
+        .route(
+            "/admin/stores/:store_id/behaviors",
+            post(admin::set_store_behaviors).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route("/admin/stores/:store_id/usage", get(admin::get_store_usage))
+        .route(
+            "/admin/stores/:store_id/regressions",
+            get(admin::list_store_regressions),
+        )
+        .route(
+            "/admin/stores/:store_id/devices",
+            get(admin::list_store_devices),
+        )
+        .route(
+            "/admin/stores/:store_id/devices/:device_id/revoke",
+            post(admin::revoke_store_device).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/stores/:store_id/delete",
+            post(deletion::delete_store).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/stores/:store_id/delete/cancel",
+            post(deletion::cancel_store_deletion).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/stores/:store_id/forget",
+            post(deletion::forget_store).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/stores/:store_id/forgotten",
+            get(deletion::list_forget_receipts),
+        )
+        .route(
+            "/admin/stores/:store_id/snapshots/restore",
+            post(snapshot::restore_snapshot).route_layer(from_fn(reject_if_read_only)),
+        )
+        .route(
+            "/admin/orgs",
+            get(org::list_orgs)
+                .merge(post(org::save_org).route_layer(from_fn(reject_if_read_only))),
+        )
+        .route("/admin/orgs/:org_id", get(org::get_org))
+        .route("/admin/orgs/:org_id/stats", get(org::get_org_stats))
+        .route(
+            "/admin/orgs/:org_id/stores",
+            get(org::list_org_stores)
+                .merge(post(org::add_org_store).route_layer(from_fn(reject_if_read_only))),
+        )
+        .route(
+            "/admin/orgs/:org_id/stores/:store_id/remove",
+            post(org::remove_org_store).route_layer(from_fn(reject_if_read_only)),
+        );
+    // served on their own listener when one is configured, so they can be
+    // firewalled off
+    let admin_router = match admin_addr {
+        Some(_) => Some(with_layers(admin_router, &state)),
+        None => {
+            server_router = server_router.merge(admin_router);
+            None
         }
     };
 
-    let server_router = Router::new()
-        .route("/health-check", get(health_check))
-        .route("/getObject", post(get_object))
-        .route("/v2/getObject", post(get_object_v2))
-        .route("/putObjects", put(put_objects))
-        .route("/v2/putObjects", put(put_objects))
-        .route("/listKeyVersions", post(list_key_versions))
-        .route("/v2/listKeyVersions", post(list_key_versions))
-        .route("/migration", get(migration::migration))
+    // same endpoints under the reference vss-server's path layout
+    if let Some(ref base_path) = ldk_base_path {
+        let ldk_router = Router::new()
+            .route(
+                "/getObject",
+                post(get_object_v2).route_layer(from_fn(cache_reads)),
+            )
+            .route(
+                "/putObjects",
+                post(put_objects)
+                    .put(put_objects)
+                    .route_layer(from_fn(reject_if_read_only)),
+            )
+            .route(
+                "/listKeyVersions",
+                post(list_key_versions).route_layer(from_fn(cache_reads)),
+            )
+            .route_layer(from_fn(fault::inject_faults))
+            .route_layer(from_fn(fault::simulate_latency));
+        server_router = server_router.nest(base_path, ldk_router);
+    }
+
+    let server_router = with_layers(server_router, &state);
+
+    // Set up a channel to handle shutdown signal
+    let (tx, mut rx) = watch::channel(());
+
+    // Spawn a task to listen for shutdown signals
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        systemd::notify("STOPPING=1");
+        let _ = tx.send(());
+    });
+
+    let admin_server = match (admin_addr, admin_router) {
+        (Some(admin_addr), Some(admin_router)) => {
+            let mut rx = rx.clone();
+            let server = axum::Server::bind(&admin_addr)
+                .serve(admin_router.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async move {
+                    let _ = rx.changed().await;
+                });
+            info!("Admin endpoints running on http://{admin_addr}");
+            Some(tokio::spawn(server))
+        }
+        _ => None,
+    };
+
+    let server = match systemd::listener_from_env()? {
+        Some(listener) => {
+            info!("Listening on the socket passed by systemd");
+            axum::Server::from_tcp(listener)?
+        }
+        None => axum::Server::bind(&addr),
+    }
+    .serve(server_router.into_make_service_with_connect_info::<SocketAddr>());
+
+    info!("Webserver running on http://{}", server.local_addr());
+    systemd::notify("READY=1");
+    if let Some(standalone) = &standalone {
+        let token = match state.admin_auth_key == Some(standalone.admin_pubkey(&state.secp)) {
+            true => Some(standalone.write_admin_token(&state.secp)?),
+            false => None,
+        };
+        println!("{}", standalone.instructions(port, token.as_deref()));
+    }
+
+    let graceful = server.with_graceful_shutdown(async {
+        let _ = rx.changed().await;
+    });
+
+    // Await the server to receive the shutdown signal
+    if let Err(e) = graceful.await {
+        error!("shutdown error: {e}");
+    }
+    if let Some(admin_server) = admin_server {
+        match admin_server.await {
+            Ok(Err(e)) => error!("admin shutdown error: {e}"),
+            Err(e) => error!("admin server failed: {e}"),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    // write out usage recorded since the last flush
+    state.usage.flush(&state).await;
+    // let another instance pick up singleton jobs without waiting
+    state.leader.resign_all(&state).await;
+
+    info!("Graceful shutdown complete");
+
+    Ok(())
+}
+
+/// Adds the middleware every route is served with.
+fn with_layers(router: Router, state: &State) -> Router {
+    // if the server is self hosted, allow all origins
+    // otherwise, only allow the origins in the CORS rules
+    let cors = (!state.self_hosted).then(|| state.cors.clone());
+    let cors_function = move |origin: &HeaderValue, _request_parts: &Parts| {
+        let Some(cors) = &cors else {
+            return true;
+        };
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+
+        cors.allows(origin)
+    };
+
+    router
+        .route_layer(from_fn(usage::meter_usage))
+        .route_layer(from_fn(access_log::access_log))
         .fallback(fallback)
         .layer(
             CorsLayer::new()
                 .allow_origin(AllowOrigin::predicate(cors_function))
-                .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
+                .allow_headers([
+                    http::header::CONTENT_TYPE,
+                    http::header::AUTHORIZATION,
+                    http::header::ACCEPT,
+                    http::HeaderName::from_static(IDEMPOTENCY_KEY),
+                    http::HeaderName::from_static(kv::BASE64_ENCODING),
+                    http::HeaderName::from_static(kv::VALUE_ENCODING),
+                ])
                 .allow_methods([
                     Method::GET,
                     Method::POST,
@@ -137,56 +869,84 @@ async fn main() -> anyhow::Result<()> {
                 ]),
         )
         .layer(DefaultBodyLimit::max(100_000_000)) // max 100mb body size
-        .layer(Extension(state));
+        .layer(from_fn(enforce_request_timeout))
+        .layer(from_fn(limit::shed_load))
+        .layer(Extension(state.clone()))
+}
 
-    // Set up a oneshot channel to handle shutdown signal
-    let (tx, rx) = oneshot::channel();
+/// Resolves on SIGTERM or SIGINT.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    // Spawn a task to listen for shutdown signals
-    tokio::spawn(async move {
-        let mut term_signal = signal(SignalKind::terminate())
-            .map_err(|e| error!("failed to install TERM signal handler: {e}"))
+    let mut term_signal = signal(SignalKind::terminate())
+        .map_err(|e| error!("failed to install TERM signal handler: {e}"))
+        .unwrap();
+    let mut int_signal = signal(SignalKind::interrupt())
+        .map_err(|e| {
+            error!("failed to install INT signal handler: {e}");
+        })
+        .unwrap();
+
+    tokio::select! {
+        _ = term_signal.recv() => {
+            info!("Received SIGTERM");
+        },
+        _ = int_signal.recv() => {
+            info!("Received SIGINT");
+        },
+    }
+}
+
+/// Resolves on Ctrl-C, or when the console window is closed or the service
+/// is stopped on Windows.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::{ctrl_c, ctrl_close, ctrl_shutdown};
+
+        let mut ctrl_c = ctrl_c()
+            .map_err(|e| error!("failed to install Ctrl-C handler: {e}"))
+            .unwrap();
+        let mut ctrl_close = ctrl_close()
+            .map_err(|e| error!("failed to install Ctrl-Close handler: {e}"))
             .unwrap();
-        let mut int_signal = signal(SignalKind::interrupt())
-            .map_err(|e| {
-                error!("failed to install INT signal handler: {e}");
-            })
+        let mut ctrl_shutdown = ctrl_shutdown()
+            .map_err(|e| error!("failed to install Ctrl-Shutdown handler: {e}"))
             .unwrap();
 
         tokio::select! {
-            _ = term_signal.recv() => {
-                info!("Received SIGTERM");
+            _ = ctrl_c.recv() => {
+                info!("Received Ctrl-C");
             },
-            _ = int_signal.recv() => {
-                info!("Received SIGINT");
+            _ = ctrl_close.recv() => {
+                info!("Received Ctrl-Close");
+            },
+            _ = ctrl_shutdown.recv() => {
+                info!("Received Ctrl-Shutdown");
             },
         }
-
-        let _ = tx.send(());
-    });
-
-    let server = axum::Server::bind(&addr).serve(server_router.into_make_service());
-
-    info!("Webserver running on http://{addr}");
-
-    let graceful = server.with_graceful_shutdown(async {
-        let _ = rx.await;
-    });
-
-    // Await the server to receive the shutdown signal
-    if let Err(e) = graceful.await {
-        error!("shutdown error: {e}");
     }
 
-    info!("Graceful shutdown complete");
-
-    Ok(())
+    #[cfg(not(windows))]
+    {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to install Ctrl-C handler: {e}");
+            return std::future::pending().await;
+        }
+        info!("Received Ctrl-C");
+    }
 }
 
-async fn fallback(origin: Option<TypedHeader<Origin>>, uri: Uri) -> (StatusCode, String) {
-    if let Err((status, msg)) = validate_cors(origin) {
-        return (status, msg);
+async fn fallback(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    uri: Uri,
+) -> ErrorResponse {
+    if let Err(e) = validate_cors(origin, &state.cors) {
+        return e;
     };
 
-    (StatusCode::NOT_FOUND, format!("No route for {uri}"))
+    (StatusCode::NOT_FOUND, format!("No route for {uri}")).into()
 }
@@ -0,0 +1,233 @@
+use crate::kv::KeyVersion;
+use crate::models::{log_if_slow, with_db_retry, KeyMetadata, VssItem};
+use crate::routes::KeyVersionStatus;
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Most keys a client can send in one manifest
+const MAX_MANIFEST_KEYS: usize = 100_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffManifestRequest {
+    pub store_id: Option<String>,
+    /// Only compare keys starting with this
+    pub key_prefix: Option<String>,
+    /// The keys and versions the client holds
+    pub manifest: Option<Vec<KeyVersion>>,
+    /// [`manifest_hash`] of the client's manifest, to skip the comparison
+    /// when nothing changed
+    pub manifest_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestDiff {
+    /// The client's manifest matches the server's live keys
+    pub in_sync: bool,
+    /// Live keys the client doesn't have
+    pub missing: Vec<KeyVersion>,
+    /// Keys the client has at an older version, or that have since been
+    /// deleted
+    pub stale: Vec<KeyVersionStatus>,
+    /// [`manifest_hash`] of the server's live keys
+    pub manifest_hash: String,
+}
+
+/// Hex SHA-256 over each `(key, version)` in byte order of key, hashed as
+/// the key's length as a big-endian u32, its bytes and the version as a
+/// big-endian u64.
+pub fn manifest_hash<'a>(manifest: impl IntoIterator<Item = (&'a str, u64)>) -> String {
+    let mut entries: Vec<(&str, u64)> = manifest.into_iter().collect();
+    entries.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for (key, version) in entries {
+        hasher.update((key.len() as u32).to_be_bytes());
+        hasher.update(key.as_bytes());
+        hasher.update(version.to_be_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Compares a client's manifest against the server's keys, tombstones
+/// included.
+pub fn diff(manifest: &[KeyVersion], server: &[KeyMetadata]) -> ManifestDiff {
+    let client: HashMap<&str, u64> = manifest
+        .iter()
+        .map(|kv| (kv.key.as_str(), kv.version))
+        .collect();
+
+    let mut diff = ManifestDiff::default();
+    for item in server {
+        match client.get(item.key.as_str()) {
+            None if !item.deleted => diff.missing.push(KeyVersion {
+                key: item.key.clone(),
+                version: item.version,
+            }),
+            Some(version) if item.deleted || *version < item.version => {
+                diff.stale.push(KeyVersionStatus {
+                    key: item.key.clone(),
+                    version: item.version,
+                    deleted: item.deleted,
+                })
+            }
+            _ => {}
+        }
+    }
+    diff.in_sync = diff.missing.is_empty()
+        && diff.stale.is_empty()
+        && server.iter().filter(|i| !i.deleted).count() == client.len();
+    diff
+}
+
+/// Tells the client which keys it needs to fetch or delete to catch up.
+/// A matching `manifest_hash` answers without comparing keys; when only a
+/// hash is sent and it doesn't match, the client has to send its manifest.
+pub async fn diff_manifest_impl(
+    req: DiffManifestRequest,
+    state: &State,
+) -> anyhow::Result<ManifestDiff> {
+    let manifest_len = req.manifest.as_ref().map(|m| m.len()).unwrap_or(0);
+    if manifest_len > MAX_MANIFEST_KEYS {
+        return Err(InvalidRequest::field(
+            "manifest",
+            FieldErrorCode::TooMany,
+            format!("Manifest can have at most {MAX_MANIFEST_KEYS} keys"),
+        )
+        .into());
+    }
+    if req.manifest.is_none() && req.manifest_hash.is_none() {
+        return Err(InvalidRequest::field(
+            "manifest",
+            FieldErrorCode::Required,
+            "Either manifest or manifest_hash is required",
+        )
+        .into());
+    }
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let server = with_db_retry("diff_manifest", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::list_key_metadata(&mut conn, &store_id, req.key_prefix.as_deref(), true)
+    })
+    .await?;
+    log_if_slow(
+        "diff_manifest",
+        &store_id,
+        server.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    let server_hash = manifest_hash(
+        server
+            .iter()
+            .filter(|i| !i.deleted)
+            .map(|i| (i.key.as_str(), i.version)),
+    );
+    if req.manifest_hash.as_deref() == Some(server_hash.as_str()) {
+        return Ok(ManifestDiff {
+            in_sync: true,
+            manifest_hash: server_hash,
+            ..Default::default()
+        });
+    }
+
+    let mut res = match req.manifest {
+        Some(manifest) => diff(&manifest, &server),
+        None => ManifestDiff::default(),
+    };
+    res.manifest_hash = server_hash;
+    Ok(res)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreDigestRequest {
+    pub store_id: Option<String>,
+    /// Only digest keys starting with this
+    pub key_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreDigest {
+    /// Hex [`merkle_root`] of the store's live items
+    pub root: String,
+    /// Number of live items
+    pub keys: usize,
+}
+
+/// SHA-256 of the key's length as a big-endian u32, its bytes, the version
+/// as a big-endian u64 and the SHA-256 of the value.
+pub fn merkle_leaf(key: &str, version: u64, value_hash: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((key.len() as u32).to_be_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(version.to_be_bytes());
+    hasher.update(value_hash);
+    hasher.finalize().into()
+}
+
+/// Merkle root over `(key, version, value hash)` leaves in byte order of
+/// key. Each level hashes adjacent pairs together, carrying an odd last node
+/// up unchanged, and the root of no leaves is the SHA-256 of nothing.
+pub fn merkle_root(mut items: Vec<(String, u64, Vec<u8>)>) -> [u8; 32] {
+    items.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut level: Vec<[u8; 32]> = items
+        .iter()
+        .map(|(key, version, hash)| merkle_leaf(key, *version, hash))
+        .collect();
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [odd] => *odd,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Digests a store so clients can check their copy matches it exactly
+/// without listing or downloading anything. Values aren't read, only their
+/// recorded hashes.
+pub async fn get_store_digest_impl(
+    req: StoreDigestRequest,
+    state: &State,
+) -> anyhow::Result<StoreDigest> {
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let items = with_db_retry("get_store_digest", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::value_hashes(&mut conn, &store_id, req.key_prefix.as_deref())
+    })
+    .await?;
+    log_if_slow(
+        "get_store_digest",
+        &store_id,
+        items.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    let keys = items.len();
+    Ok(StoreDigest {
+        root: hex::encode(merkle_root(items)),
+        keys,
+    })
+}
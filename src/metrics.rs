@@ -0,0 +1,253 @@
+use crate::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::Extension;
+use diesel::r2d2::event::{CheckoutEvent, TimeoutEvent};
+use diesel::r2d2::HandleEvent;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Connection pool statistics, collected from r2d2's event hooks.
+#[derive(Debug, Clone, Default)]
+pub struct PoolMetrics {
+    inner: Arc<PoolCounters>,
+}
+
+#[derive(Debug, Default)]
+struct PoolCounters {
+    checkouts: AtomicU64,
+    checkout_timeouts: AtomicU64,
+    wait_micros_total: AtomicU64,
+    wait_micros_max: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use: u32,
+    pub checkouts: u64,
+    pub checkout_timeouts: u64,
+    pub wait_micros_total: u64,
+    pub wait_micros_max: u64,
+}
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        let wait = event.duration().as_micros() as u64;
+        self.inner.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .wait_micros_total
+            .fetch_add(wait, Ordering::Relaxed);
+        self.inner
+            .wait_micros_max
+            .fetch_max(wait, Ordering::Relaxed);
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        self.inner.checkout_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl PoolMetrics {
+    pub fn status(&self, state: &State) -> PoolStatus {
+        let pool = state.db_pool.state();
+
+        PoolStatus {
+            max_size: state.db_pool.max_size(),
+            connections: pool.connections,
+            idle_connections: pool.idle_connections,
+            in_use: pool.connections.saturating_sub(pool.idle_connections),
+            checkouts: self.inner.checkouts.load(Ordering::Relaxed),
+            checkout_timeouts: self.inner.checkout_timeouts.load(Ordering::Relaxed),
+            wait_micros_total: self.inner.wait_micros_total.load(Ordering::Relaxed),
+            wait_micros_max: self.inner.wait_micros_max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether a metric can go down, or only ever counts up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+/// The current value of every metric, by its Prometheus name.
+pub fn collect(state: &State) -> Vec<(&'static str, MetricKind, u64)> {
+    let pool = state.pool_metrics.status(state);
+
+    let mut samples = vec![
+        (
+            "vss_db_pool_max_size",
+            MetricKind::Gauge,
+            pool.max_size as u64,
+        ),
+        (
+            "vss_db_pool_connections",
+            MetricKind::Gauge,
+            pool.connections as u64,
+        ),
+        (
+            "vss_db_pool_idle_connections",
+            MetricKind::Gauge,
+            pool.idle_connections as u64,
+        ),
+        ("vss_db_pool_in_use", MetricKind::Gauge, pool.in_use as u64),
+        (
+            "vss_db_pool_wait_micros_max",
+            MetricKind::Gauge,
+            pool.wait_micros_max,
+        ),
+        (
+            "vss_requests_in_flight",
+            MetricKind::Gauge,
+            state.limits.in_flight() as u64,
+        ),
+        (
+            "vss_db_pool_checkouts_total",
+            MetricKind::Counter,
+            pool.checkouts,
+        ),
+        (
+            "vss_db_pool_checkout_timeouts_total",
+            MetricKind::Counter,
+            pool.checkout_timeouts,
+        ),
+        (
+            "vss_db_pool_wait_micros_total",
+            MetricKind::Counter,
+            pool.wait_micros_total,
+        ),
+        (
+            "vss_requests_shed_total",
+            MetricKind::Counter,
+            state.limits.shed_count(),
+        ),
+    ];
+
+    samples.extend(runtime_samples());
+
+    if let Some(history) = &state.history {
+        samples.extend([
+            (
+                "vss_history_prune_micros_last",
+                MetricKind::Gauge,
+                history.last_prune_micros(),
+            ),
+            (
+                "vss_history_prune_runs_total",
+                MetricKind::Counter,
+                history.prune_runs(),
+            ),
+            (
+                "vss_history_pruned_total",
+                MetricKind::Counter,
+                history.pruned(),
+            ),
+            (
+                "vss_history_prune_failures_total",
+                MetricKind::Counter,
+                history.prune_failures(),
+            ),
+        ]);
+    }
+
+    samples
+}
+
+/// Metrics of the tokio runtime the server runs on, to tell when blocking
+/// database calls are starving it. The blocking pool and poll times are only
+/// reported in builds with `--cfg tokio_unstable`.
+fn runtime_samples() -> Vec<(&'static str, MetricKind, u64)> {
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        return vec![];
+    };
+    let runtime = handle.metrics();
+    let workers = runtime.num_workers();
+
+    let busy_micros: u64 = (0..workers)
+        .map(|w| runtime.worker_total_busy_duration(w).as_micros() as u64)
+        .sum();
+    let parks: u64 = (0..workers).map(|w| runtime.worker_park_count(w)).sum();
+
+    #[allow(unused_mut)]
+    let mut samples = vec![
+        ("vss_runtime_workers", MetricKind::Gauge, workers as u64),
+        (
+            "vss_runtime_alive_tasks",
+            MetricKind::Gauge,
+            runtime.num_alive_tasks() as u64,
+        ),
+        (
+            "vss_runtime_global_queue_depth",
+            MetricKind::Gauge,
+            runtime.global_queue_depth() as u64,
+        ),
+        (
+            "vss_runtime_busy_micros_total",
+            MetricKind::Counter,
+            busy_micros,
+        ),
+        ("vss_runtime_parks_total", MetricKind::Counter, parks),
+    ];
+
+    #[cfg(tokio_unstable)]
+    {
+        let mean_poll_micros = (0..workers)
+            .map(|w| runtime.worker_mean_poll_time(w).as_micros() as u64)
+            .sum::<u64>()
+            / workers.max(1) as u64;
+        samples.extend([
+            (
+                "vss_runtime_blocking_threads",
+                MetricKind::Gauge,
+                runtime.num_blocking_threads() as u64,
+            ),
+            (
+                "vss_runtime_idle_blocking_threads",
+                MetricKind::Gauge,
+                runtime.num_idle_blocking_threads() as u64,
+            ),
+            (
+                "vss_runtime_blocking_queue_depth",
+                MetricKind::Gauge,
+                runtime.blocking_queue_depth() as u64,
+            ),
+            (
+                "vss_runtime_mean_poll_micros",
+                MetricKind::Gauge,
+                mean_poll_micros,
+            ),
+            (
+                "vss_runtime_spawned_tasks_total",
+                MetricKind::Counter,
+                runtime.spawned_tasks_count(),
+            ),
+            (
+                "vss_runtime_forced_yields_total",
+                MetricKind::Counter,
+                runtime.budget_forced_yield_count(),
+            ),
+        ]);
+    }
+
+    samples
+}
+
+/// Prometheus text exposition of the server's metrics.
+pub async fn metrics(Extension(state): Extension<State>) -> impl IntoResponse {
+    let mut out = String::new();
+    for (name, kind, value) in collect(&state) {
+        let kind = match kind {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+        };
+        let _ = writeln!(out, "# TYPE {name} {kind}\n{name} {value}");
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
@@ -1,14 +1,18 @@
+use crate::auth::verify_admin_token;
+use crate::client::{self, with_retry};
 use crate::models::VssItem;
+use crate::routes::ErrorResponse;
 use crate::State;
 use anyhow::anyhow;
+use axum::extract::Query;
 use axum::headers::authorization::Bearer;
 use axum::headers::Authorization;
-use axum::http::StatusCode;
 use axum::{Extension, Json, TypedHeader};
 use chrono::{DateTime, NaiveDateTime, Utc};
-use diesel::Connection;
-use log::{error, info};
-use serde::{Deserialize, Deserializer};
+use diesel::{Connection, PgConnection};
+use futures::{stream, StreamExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use ureq::Agent;
 
@@ -18,7 +22,7 @@ pub struct Item {
     pub key: String,
     #[serde(default)]
     pub value: String,
-    pub version: i64,
+    pub version: u64,
 
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_datetime_opt")]
@@ -46,11 +50,156 @@ where
     })
 }
 
-pub async fn migration_impl(admin_key: String, state: &State) -> anyhow::Result<()> {
-    let client = Agent::new();
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MigrationParams {
+    /// Fetch and validate everything without writing to the database
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Summary of a migration run, logged once it finishes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub fetched: usize,
+    pub valid: usize,
+    pub invalid: usize,
+    pub decode_failures: usize,
+}
+
+impl Item {
+    /// Decodes the item's value, checking it can be stored.
+    fn decode(&self) -> Result<Vec<u8>, MigrationError> {
+        if self.store_id.is_empty() || self.key.is_empty() {
+            return Err(MigrationError::Invalid);
+        }
+
+        base64::decode(&self.value).map_err(|_| MigrationError::Decode)
+    }
+
+    /// Writes the item, keeping the source's dates when it provided them.
+    fn put(&self, conn: &mut PgConnection, value: &[u8]) -> anyhow::Result<()> {
+        let (created, updated) = match (self.created_date, self.updated_date) {
+            (None, None) => {
+                return VssItem::put_item(conn, &self.store_id, &self.key, value, self.version)
+            }
+            (Some(created), None) => (created, created),
+            (None, Some(updated)) => (updated, updated),
+            (Some(created), Some(updated)) => (created, updated),
+        };
+
+        VssItem::put_item_with_dates(
+            conn,
+            &self.store_id,
+            &self.key,
+            value,
+            self.version,
+            created.naive_utc(),
+            updated.naive_utc(),
+        )
+    }
+}
+
+enum MigrationError {
+    Invalid,
+    Decode,
+}
+
+impl MigrationReport {
+    fn merge(&mut self, other: MigrationReport) {
+        self.fetched += other.fetched;
+        self.valid += other.valid;
+        self.invalid += other.invalid;
+        self.decode_failures += other.decode_failures;
+    }
+}
+
+/// Fetches, decodes and stores a single batch, returning its report.
+async fn migrate_batch(
+    client: Agent,
+    url: String,
+    api_key: String,
+    offset: usize,
+    limit: usize,
+    dry_run: bool,
+    state: State,
+) -> anyhow::Result<MigrationReport> {
+    info!("Fetching {limit} items from offset {offset}");
+
+    let payload = json!({"limit": limit, "offset": offset});
+
+    let items: Vec<Item> = with_retry("Migration fetch", move || {
+        let resp = client
+            .post(&url)
+            .set("x-api-key", &api_key)
+            .send_string(&payload.to_string())?;
+        Ok(resp.into_json()?)
+    })
+    .await?;
+
+    let mut report = MigrationReport {
+        dry_run,
+        fetched: items.len(),
+        ..Default::default()
+    };
+
+    let mut decoded = Vec::with_capacity(items.len());
+    for item in items {
+        match item.decode() {
+            Ok(value) => decoded.push((item, value)),
+            Err(MigrationError::Invalid) => {
+                warn!("Invalid item {}/{}", item.store_id, item.key);
+                report.invalid += 1;
+            }
+            Err(MigrationError::Decode) => {
+                warn!("Failed to decode {}/{}", item.store_id, item.key);
+                report.decode_failures += 1;
+            }
+        }
+    }
+    report.valid = decoded.len();
+
+    if !dry_run {
+        // Insert values into DB
+        tokio::task::spawn_blocking(move || {
+            // one transaction per database shard the batch's stores live on
+            for shard in state.shards.all() {
+                let items: Vec<_> = decoded
+                    .iter()
+                    .filter(|(item, _)| state.shards.for_store(&item.store_id).name == shard.name)
+                    .collect();
+                if items.is_empty() {
+                    continue;
+                }
+
+                let mut conn = shard.pool.get()?;
+                conn.transaction::<_, anyhow::Error, _>(|conn| {
+                    for (item, value) in items {
+                        item.put(conn, value)?;
+                    }
+
+                    Ok(())
+                })?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+    }
+
+    Ok(report)
+}
+
+pub async fn migration_impl(
+    params: MigrationParams,
+    state: &State,
+) -> anyhow::Result<MigrationReport> {
+    let client = client::agent()?;
     let Ok(url) = std::env::var("MIGRATION_URL") else {
         return Err(anyhow!("MIGRATION_URL not set"));
     };
+    let Ok(api_key) = std::env::var("MIGRATION_API_KEY") else {
+        return Err(anyhow!("MIGRATION_API_KEY not set"));
+    };
 
     let limit = std::env::var("MIGRATION_BATCH_SIZE")
         .ok()
@@ -58,68 +207,81 @@ pub async fn migration_impl(admin_key: String, state: &State) -> anyhow::Result<
         .transpose()?
         .unwrap_or(100);
 
-    let mut offset = std::env::var("MIGRATION_START_INDEX")
+    let start = std::env::var("MIGRATION_START_INDEX")
         .ok()
         .map(|s| s.parse::<usize>())
         .transpose()?
         .unwrap_or(0);
 
-    let mut finished = false;
-
-    info!("Starting migration");
-    while !finished {
-        info!("Fetching {limit} items from offset {offset}");
+    let concurrency = std::env::var("MIGRATION_CONCURRENCY")
+        .ok()
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(4)
+        .max(1);
 
-        let payload = json!({"limit": limit, "offset": offset});
+    let mut report = MigrationReport {
+        dry_run: params.dry_run,
+        ..Default::default()
+    };
 
-        let resp = client
-            .post(&url)
-            .set("x-api-key", &admin_key)
-            .send_string(&payload.to_string())?;
-        let items: Vec<Item> = resp.into_json()?;
+    if params.dry_run {
+        info!("Starting migration dry run");
+    } else {
+        info!("Starting migration");
+    }
 
-        let mut conn = state.db_pool.get().unwrap();
+    // Batches run concurrently but complete in order, so everything before
+    // the logged checkpoint is known to be migrated.
+    let mut batches = stream::iter((start..).step_by(limit))
+        .map(|offset| {
+            migrate_batch(
+                client.clone(),
+                url.clone(),
+                api_key.clone(),
+                offset,
+                limit,
+                params.dry_run,
+                state.clone(),
+            )
+        })
+        .buffered(concurrency);
 
-        // Insert values into DB
-        conn.transaction::<_, anyhow::Error, _>(|conn| {
-            for item in items.iter() {
-                if let Ok(value) = base64::decode(&item.value) {
-                    VssItem::put_item(conn, &item.store_id, &item.key, &value, item.version)?;
-                }
+    let mut offset = start;
+    while let Some(batch) = batches.next().await {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Migration stopped, resume with MIGRATION_START_INDEX={offset}");
+                return Err(e);
             }
+        };
 
-            Ok(())
-        })?;
+        let fetched = batch.fetched;
+        report.merge(batch);
+        offset += limit;
 
-        if items.len() < limit {
-            finished = true;
-        } else {
-            offset += limit;
+        if fetched < limit {
+            break;
         }
+
+        info!("Migration checkpoint: MIGRATION_START_INDEX={offset}");
     }
 
-    info!("Migration complete!");
+    info!("Migration complete! {report:?}");
 
-    Ok(())
+    Ok(report)
 }
 
 pub async fn migration(
     TypedHeader(token): TypedHeader<Authorization<Bearer>>,
     Extension(state): Extension<State>,
-) -> Result<Json<()>, (StatusCode, String)> {
-    let Ok(admin_key) = std::env::var("ADMIN_KEY") else {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "ADMIN_KEY not set".to_string(),
-        ));
-    };
-
-    if token.token() != admin_key {
-        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
-    }
+    Query(params): Query<MigrationParams>,
+) -> Result<Json<()>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
 
     tokio::spawn(async move {
-        if let Err(e) = migration_impl(admin_key, &state).await {
+        if let Err(e) = migration_impl(params, &state).await {
             error!("Migration failed: {e:?}")
         }
     });
@@ -0,0 +1,157 @@
+use crate::auth::verify_admin_token;
+use crate::client::{self, with_retry};
+use crate::routes::{ErrorResponse, PutObjectsRequest};
+use crate::State;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::StatusCode;
+use axum::{Extension, Json, TypedHeader};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use ureq::Agent;
+
+const DEFAULT_QUEUE_SIZE: usize = 10_000;
+
+/// Replays successful writes to a secondary VSS server in the background.
+#[derive(Clone)]
+pub struct Mirror {
+    tx: mpsc::Sender<(Instant, PutObjectsRequest)>,
+    stats: Arc<MirrorStats>,
+}
+
+#[derive(Default)]
+struct MirrorStats {
+    queued: AtomicU64,
+    replayed: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    /// How long the most recently replayed write waited in the queue
+    lag_millis: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorStatus {
+    pub queued: u64,
+    pub replayed: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub pending: u64,
+    pub lag_millis: u64,
+}
+
+impl Mirror {
+    /// Starts the mirroring worker if `MIRROR_URL` is set.
+    pub fn from_env() -> anyhow::Result<Option<Mirror>> {
+        let Ok(base_url) = std::env::var("MIRROR_URL") else {
+            return Ok(None);
+        };
+        let url = format!("{}/v2/putObjects", base_url.trim_end_matches('/'));
+        let auth_token = std::env::var("MIRROR_AUTH_TOKEN")
+            .ok()
+            .map(|t| format!("Bearer {t}"));
+
+        let queue_size = std::env::var("MIRROR_QUEUE_SIZE")
+            .ok()
+            .map(|s| s.parse::<usize>())
+            .transpose()?
+            .unwrap_or(DEFAULT_QUEUE_SIZE);
+
+        let client = client::agent()?;
+        let (tx, rx) = mpsc::channel(queue_size);
+        let stats = Arc::new(MirrorStats::default());
+
+        tokio::spawn(run_worker(client, url, auth_token, rx, stats.clone()));
+
+        info!("Mirroring writes to {base_url}");
+
+        Ok(Some(Mirror { tx, stats }))
+    }
+
+    /// Queues a write for replay, dropping it if the queue is full so the
+    /// primary never waits on the secondary.
+    pub fn enqueue(&self, req: PutObjectsRequest) {
+        match self.tx.try_send((Instant::now(), req)) {
+            Ok(()) => {
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                error!("Dropping mirrored write: {e}");
+            }
+        }
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        let queued = self.stats.queued.load(Ordering::Relaxed);
+        let replayed = self.stats.replayed.load(Ordering::Relaxed);
+        let failed = self.stats.failed.load(Ordering::Relaxed);
+
+        MirrorStatus {
+            queued,
+            replayed,
+            failed,
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            pending: queued.saturating_sub(replayed + failed),
+            lag_millis: self.stats.lag_millis.load(Ordering::Relaxed),
+        }
+    }
+}
+
+async fn run_worker(
+    client: Agent,
+    url: String,
+    auth_token: Option<String>,
+    mut rx: mpsc::Receiver<(Instant, PutObjectsRequest)>,
+    stats: Arc<MirrorStats>,
+) {
+    while let Some((queued_at, req)) = rx.recv().await {
+        let client = client.clone();
+        let url = url.clone();
+        let auth_token = auth_token.clone();
+        let store_id = req.store_id.clone().unwrap_or_default();
+
+        let res = with_retry("Mirror write", move || {
+            let mut request = client.put(&url);
+            if let Some(ref token) = auth_token {
+                request = request.set("Authorization", token);
+            }
+            request.send_json(&req)?;
+            Ok(())
+        })
+        .await;
+
+        match res {
+            Ok(()) => {
+                stats.replayed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                error!("Failed to mirror write for {store_id}: {e:?}");
+            }
+        }
+
+        let lag = queued_at.elapsed();
+        stats
+            .lag_millis
+            .store(lag.as_millis() as u64, Ordering::Relaxed);
+        if lag.as_secs() > 60 {
+            warn!("Mirror is lagging by {lag:?}");
+        }
+    }
+}
+
+pub async fn mirror_status(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<MirrorStatus>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match state.mirror {
+        Some(ref mirror) => Ok(Json(mirror.status())),
+        None => Err((StatusCode::NOT_FOUND, "Mirroring not enabled".to_string()).into()),
+    }
+}
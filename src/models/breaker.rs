@@ -0,0 +1,99 @@
+use log::{info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Fast-fails database operations for a cooldown period after repeated
+/// failures, so an outage isn't made worse by every request retrying.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: AtomicU32,
+    opened_at: Mutex<Option<Instant>>,
+}
+
+/// Returned instead of touching the database while the breaker is open.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Database unavailable, try again later")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            failures: AtomicU32::new(0),
+            opened_at: Mutex::new(None),
+        }
+    }
+
+    /// Fails while the breaker is open. Once the cooldown has passed requests
+    /// are let through again, and the next result decides whether it closes.
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        let opened_at = self.opened_at.lock().expect("breaker lock poisoned");
+        match *opened_at {
+            Some(opened) if opened.elapsed() < self.cooldown => Err(CircuitOpen),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.check().is_err()
+    }
+
+    pub fn record_success(&self) {
+        if self.failures.swap(0, Ordering::SeqCst) >= self.threshold {
+            info!("Database recovered, closing circuit breaker");
+        }
+        *self.opened_at.lock().expect("breaker lock poisoned") = None;
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.threshold {
+            if failures == self.threshold {
+                warn!(
+                    "Opening circuit breaker after {failures} database failures, cooling down for {:?}",
+                    self.cooldown
+                );
+            }
+            *self.opened_at.lock().expect("breaker lock poisoned") = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+
+        // half open after the cooldown, another failure trips it again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+use super::schema::vss_devices;
+use anyhow::anyhow;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Nullable, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// A device that has registered itself as using a store.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_devices)]
+pub struct Device {
+    pub store_id: String,
+    pub device_id: String,
+    pub platform: Option<String>,
+    /// Short hash of the bearer token the device last registered with
+    pub token_fingerprint: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Device {
+    /// Registers a device, or updates its metadata and last seen time if it
+    /// is already registered. Fails if the device has been revoked.
+    pub fn register(
+        conn: &mut PgConnection,
+        store_id: &str,
+        device_id: &str,
+        platform: Option<&str>,
+        token_fingerprint: Option<&str>,
+    ) -> anyhow::Result<Device> {
+        let _span = debug_span!("vss.register_device", store_id).entered();
+
+        sql_query(
+            "INSERT INTO vss_devices (store_id, device_id, platform, token_fingerprint) \
+             VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (store_id, device_id) DO UPDATE \
+             SET platform = COALESCE(excluded.platform, vss_devices.platform), \
+             token_fingerprint = excluded.token_fingerprint, \
+             last_seen_at = CURRENT_TIMESTAMP \
+             WHERE vss_devices.revoked_at IS NULL \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(device_id)
+        .bind::<Nullable<Text>, _>(platform)
+        .bind::<Nullable<Text>, _>(token_fingerprint)
+        .get_result::<Device>(conn)
+        .optional()?
+        .ok_or_else(|| anyhow!("Device {device_id} has been revoked"))
+    }
+
+    /// Who to attribute a write made with the token `token_fingerprint` to:
+    /// `device:<id>` of the unrevoked device that last registered with it,
+    /// or `token:<fingerprint>` when no device has.
+    pub fn writer(
+        conn: &mut PgConnection,
+        store_id: &str,
+        token_fingerprint: &str,
+    ) -> anyhow::Result<String> {
+        let device_id = vss_devices::table
+            .filter(vss_devices::store_id.eq(store_id))
+            .filter(vss_devices::token_fingerprint.eq(token_fingerprint))
+            .filter(vss_devices::revoked_at.is_null())
+            .order(vss_devices::last_seen_at.desc())
+            .select(vss_devices::device_id)
+            .first::<String>(conn)
+            .optional()?;
+
+        Ok(match device_id {
+            Some(id) => format!("device:{id}"),
+            None => format!("token:{token_fingerprint}"),
+        })
+    }
+
+    /// Lists a store's devices, most recently seen first.
+    pub fn list_devices(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Vec<Device>> {
+        Ok(vss_devices::table
+            .filter(vss_devices::store_id.eq(store_id))
+            .order(vss_devices::last_seen_at.desc())
+            .load::<Self>(conn)?)
+    }
+
+    /// Marks a device as revoked, returning None if it was never registered.
+    pub fn revoke(
+        conn: &mut PgConnection,
+        store_id: &str,
+        device_id: &str,
+    ) -> anyhow::Result<Option<Device>> {
+        let _span = debug_span!("vss.revoke_device", store_id).entered();
+
+        Ok(sql_query(
+            "UPDATE vss_devices SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP) \
+             WHERE store_id = $1 AND device_id = $2 \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(device_id)
+        .get_result::<Device>(conn)
+        .optional()?)
+    }
+}
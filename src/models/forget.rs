@@ -0,0 +1,47 @@
+use super::schema::vss_forget_receipts;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// All that is kept of a forgotten store, under a hash of its id so it can
+/// be looked up by whoever knows the id but doesn't reveal it.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_forget_receipts)]
+pub struct ForgetReceipt {
+    pub receipt_id: String,
+    /// Hex SHA-256 of the store id
+    pub store_hash: String,
+    /// `user`, `admin`, or `schedule` for deletions purged after their
+    /// grace period
+    pub requested_by: String,
+    /// Items erased, tombstones included
+    pub items: i64,
+    /// Values erased from object storage
+    pub objects: i64,
+    pub forgotten_at: chrono::NaiveDateTime,
+}
+
+impl ForgetReceipt {
+    pub fn store_hash(store_id: &str) -> String {
+        hex::encode(Sha256::digest(store_id.as_bytes()))
+    }
+
+    pub fn record(conn: &mut PgConnection, receipt: &ForgetReceipt) -> anyhow::Result<()> {
+        diesel::insert_into(vss_forget_receipts::table)
+            .values(receipt)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Receipts of the store with id `store_id`, newest first.
+    pub fn list_for_store(
+        conn: &mut PgConnection,
+        store_id: &str,
+    ) -> anyhow::Result<Vec<ForgetReceipt>> {
+        Ok(vss_forget_receipts::table
+            .filter(vss_forget_receipts::store_hash.eq(Self::store_hash(store_id)))
+            .order(vss_forget_receipts::forgotten_at.desc())
+            .load::<Self>(conn)?)
+    }
+}
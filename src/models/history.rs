@@ -0,0 +1,101 @@
+use super::schema::vss_history;
+use super::DbVersion;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Integer};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+use tracing::field::Empty;
+
+/// A version of an item that has since been overwritten or deleted, kept
+/// while write history is enabled. It was the key's current version from
+/// `updated_date` until `superseded_at`.
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_history)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub store_id: String,
+    pub key: String,
+    /// None for tombstones and values in object storage
+    pub value: Option<Vec<u8>>,
+    /// Object holding the value when it was offloaded to object storage
+    pub object_key: Option<String>,
+    pub value_hash: Option<Vec<u8>>,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    pub metadata: Option<serde_json::Value>,
+    pub content_type: Option<String>,
+    pub created_date: chrono::NaiveDateTime,
+    pub updated_date: chrono::NaiveDateTime,
+    pub superseded_at: chrono::NaiveDateTime,
+}
+
+impl HistoryEntry {
+    /// A key's past versions, oldest first.
+    pub fn list_for_key(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        Ok(vss_history::table
+            .filter(vss_history::store_id.eq(store_id))
+            .filter(vss_history::key.eq(key))
+            .order(vss_history::id.asc())
+            .load::<Self>(conn)?)
+    }
+
+    /// The version of a key that was current at `as_of`, if it has since
+    /// been replaced and is still in the history.
+    pub fn as_of(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        as_of: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Option<HistoryEntry>> {
+        Ok(vss_history::table
+            .filter(vss_history::store_id.eq(store_id))
+            .filter(vss_history::key.eq(key))
+            .filter(vss_history::updated_date.le(as_of))
+            .filter(vss_history::superseded_at.gt(as_of))
+            .order(vss_history::id.desc())
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Deletes up to `limit` entries that are past the policy: beyond the
+    /// `keep_versions` most recent of their key, or superseded more than
+    /// `keep_days` ago. Returns how many were deleted.
+    pub fn prune(
+        conn: &mut PgConnection,
+        keep_versions: Option<i64>,
+        keep_days: Option<i32>,
+        limit: i64,
+    ) -> anyhow::Result<usize> {
+        let span = debug_span!("vss.prune_history", entries = Empty).entered();
+
+        let mut count = 0;
+        if let Some(keep_days) = keep_days {
+            count += sql_query(
+                "DELETE FROM vss_history WHERE id IN (SELECT id FROM vss_history \
+                 WHERE superseded_at < CURRENT_TIMESTAMP - make_interval(days => $1) LIMIT $2)",
+            )
+            .bind::<Integer, _>(keep_days)
+            .bind::<BigInt, _>(limit)
+            .execute(conn)?;
+        }
+        if let Some(keep_versions) = keep_versions.filter(|_| count < limit as usize) {
+            count += sql_query(
+                "DELETE FROM vss_history WHERE id IN (SELECT id FROM \
+                 (SELECT id, row_number() OVER (PARTITION BY store_id, key ORDER BY id DESC) AS newer \
+                 FROM vss_history) AS ranked WHERE newer > $1 LIMIT $2)",
+            )
+            .bind::<BigInt, _>(keep_versions)
+            .bind::<BigInt, _>(limit - count as i64)
+            .execute(conn)?;
+        }
+        span.record("entries", count);
+
+        Ok(count)
+    }
+}
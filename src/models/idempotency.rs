@@ -0,0 +1,86 @@
+use super::schema::vss_idempotency_keys;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Binary, Double, Text};
+use std::fmt;
+use std::time::Duration;
+use tracing::debug_span;
+
+/// Idempotency key of a putObjects request that has already been applied.
+pub struct IdempotencyKey;
+
+/// Returned when an idempotency key is sent again with a different request.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKeyReused(pub String);
+
+impl fmt::Display for IdempotencyKeyReused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Idempotency key {} was already used for a different request",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for IdempotencyKeyReused {}
+
+impl IdempotencyKey {
+    /// Records that the request with `key` and `request_hash` is being
+    /// applied to the store. Returns false if the same key was already used
+    /// within `window`, in which case the request must not be applied again,
+    /// or fails with [`IdempotencyKeyReused`] if it was used for a request
+    /// with a different hash. Should be called inside the write's
+    /// transaction, so a failed write doesn't claim the key.
+    pub fn claim(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        request_hash: &[u8],
+        window: Duration,
+    ) -> anyhow::Result<bool> {
+        let _span = debug_span!("vss.claim_idempotency_key", store_id).entered();
+
+        // expired keys are dropped as the store is written to
+        sql_query(
+            "DELETE FROM vss_idempotency_keys WHERE store_id = $1 \
+             AND created_at < CURRENT_TIMESTAMP - make_interval(secs => $2)",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Double, _>(window.as_secs_f64())
+        .execute(conn)?;
+
+        let inserted = sql_query(
+            "INSERT INTO vss_idempotency_keys (store_id, idempotency_key, request_hash) \
+             VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(key)
+        .bind::<Binary, _>(request_hash)
+        .execute(conn)?;
+        if inserted > 0 {
+            return Ok(true);
+        }
+
+        let claimed_by = vss_idempotency_keys::table
+            .filter(vss_idempotency_keys::store_id.eq(store_id))
+            .filter(vss_idempotency_keys::idempotency_key.eq(key))
+            .select(vss_idempotency_keys::request_hash)
+            .first::<Option<Vec<u8>>>(conn)?;
+        match claimed_by {
+            Some(hash) if hash != request_hash => Err(IdempotencyKeyReused(key.to_string()).into()),
+            _ => Ok(false),
+        }
+    }
+
+    /// Deletes keys older than `window` from every store, including those
+    /// that are no longer written to. Returns how many were deleted.
+    pub fn delete_expired(conn: &mut PgConnection, window: Duration) -> anyhow::Result<usize> {
+        Ok(sql_query(
+            "DELETE FROM vss_idempotency_keys \
+             WHERE created_at < CURRENT_TIMESTAMP - make_interval(secs => $1)",
+        )
+        .bind::<Double, _>(window.as_secs_f64())
+        .execute(conn)?)
+    }
+}
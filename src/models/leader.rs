@@ -0,0 +1,65 @@
+use super::schema::vss_job_leaders;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Double, Text};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug_span;
+
+/// The instance running a singleton background job until `expires_at`.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_job_leaders)]
+pub struct JobLeader {
+    pub job: String,
+    pub holder: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+impl JobLeader {
+    /// Makes `holder` the leader of `job` for `ttl`, if there is no leader,
+    /// the leader's term has expired or `holder` already leads it. Returns
+    /// None if another instance leads the job.
+    pub fn try_lead(
+        conn: &mut PgConnection,
+        job: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> anyhow::Result<Option<JobLeader>> {
+        let _span = debug_span!("vss.try_lead", job).entered();
+
+        Ok(sql_query(
+            "INSERT INTO vss_job_leaders (job, holder, expires_at) \
+             VALUES ($1, $2, CURRENT_TIMESTAMP + make_interval(secs => $3)) \
+             ON CONFLICT (job) DO UPDATE \
+             SET holder = excluded.holder, expires_at = excluded.expires_at \
+             WHERE vss_job_leaders.holder = excluded.holder \
+             OR vss_job_leaders.expires_at <= CURRENT_TIMESTAMP \
+             RETURNING *",
+        )
+        .bind::<Text, _>(job)
+        .bind::<Text, _>(holder)
+        .bind::<Double, _>(ttl.as_secs_f64())
+        .get_result::<JobLeader>(conn)
+        .optional()?)
+    }
+
+    /// Steps down from leading `job`, so another instance can take over
+    /// without waiting for the term to expire.
+    pub fn resign(conn: &mut PgConnection, job: &str, holder: &str) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(
+            vss_job_leaders::table
+                .filter(vss_job_leaders::job.eq(job))
+                .filter(vss_job_leaders::holder.eq(holder)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn list(conn: &mut PgConnection) -> anyhow::Result<Vec<JobLeader>> {
+        Ok(vss_job_leaders::table
+            .order(vss_job_leaders::job.asc())
+            .load::<Self>(conn)?)
+    }
+}
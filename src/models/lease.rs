@@ -0,0 +1,116 @@
+use super::schema::vss_leases;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Double, Text};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tracing::debug_span;
+
+/// A named lease within a store, held by `holder` until `expires_at`.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_leases)]
+pub struct Lease {
+    pub store_id: String,
+    pub name: String,
+    pub holder: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+/// Returned when a lease is held by someone else, or has expired before
+/// its holder renewed it.
+#[derive(Debug, Clone)]
+pub struct LeaseConflict(pub String);
+
+impl fmt::Display for LeaseConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LeaseConflict {}
+
+impl Lease {
+    /// Takes the lease for `holder` for `ttl`, if it is free, expired or
+    /// already held by `holder`.
+    pub fn acquire(
+        conn: &mut PgConnection,
+        store_id: &str,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> anyhow::Result<Lease> {
+        let _span = debug_span!("vss.acquire_lease", store_id).entered();
+
+        sql_query(
+            "INSERT INTO vss_leases (store_id, name, holder, expires_at) \
+             VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4)) \
+             ON CONFLICT (store_id, name) DO UPDATE \
+             SET holder = excluded.holder, expires_at = excluded.expires_at \
+             WHERE vss_leases.holder = excluded.holder \
+             OR vss_leases.expires_at <= CURRENT_TIMESTAMP \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(name)
+        .bind::<Text, _>(holder)
+        .bind::<Double, _>(ttl.as_secs_f64())
+        .get_result::<Lease>(conn)
+        .optional()?
+        .ok_or_else(|| LeaseConflict(format!("Lease {name} is held by another device")).into())
+    }
+
+    /// Extends a lease `holder` still holds to `ttl` from now.
+    pub fn renew(
+        conn: &mut PgConnection,
+        store_id: &str,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> anyhow::Result<Lease> {
+        let _span = debug_span!("vss.renew_lease", store_id).entered();
+
+        sql_query(
+            "UPDATE vss_leases SET expires_at = CURRENT_TIMESTAMP + make_interval(secs => $4) \
+             WHERE store_id = $1 AND name = $2 AND holder = $3 \
+             AND expires_at > CURRENT_TIMESTAMP \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(name)
+        .bind::<Text, _>(holder)
+        .bind::<Double, _>(ttl.as_secs_f64())
+        .get_result::<Lease>(conn)
+        .optional()?
+        .ok_or_else(|| LeaseConflict(format!("Lease {name} is not held by {holder}")).into())
+    }
+
+    /// Gives up a lease, returning false if `holder` didn't hold it.
+    pub fn release(
+        conn: &mut PgConnection,
+        store_id: &str,
+        name: &str,
+        holder: &str,
+    ) -> anyhow::Result<bool> {
+        let _span = debug_span!("vss.release_lease", store_id).entered();
+
+        let deleted = diesel::delete(
+            vss_leases::table
+                .filter(vss_leases::store_id.eq(store_id))
+                .filter(vss_leases::name.eq(name))
+                .filter(vss_leases::holder.eq(holder)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Deletes every expired lease, returning how many there were.
+    pub fn delete_expired(conn: &mut PgConnection) -> anyhow::Result<usize> {
+        Ok(
+            diesel::delete(vss_leases::table.filter(vss_leases::expires_at.le(diesel::dsl::now)))
+                .execute(conn)?,
+        )
+    }
+}
@@ -1,15 +1,107 @@
-use crate::kv::KeyValue;
+use crate::blob::{BlobStore, OffloadedValue};
+use crate::delta::{apply_delta, DeltaOp};
+use crate::kv::{KeyValue, NO_VERSION_CHECK};
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use diesel::connection::{CacheSize, SimpleConnection};
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::{AsExpression, SqlLiteral};
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
+use diesel::r2d2::CustomizeConnection;
+use diesel::serialize::{self, IsNull, Output, ToSql};
 use diesel::sql_query;
-use diesel::sql_types::{BigInt, Bytea, Text};
-use diesel_migrations::{embed_migrations, EmbeddedMigrations};
-use schema::vss_db;
+use diesel::sql_types::{Array, BigInt, Bool, Bytea, Integer, Jsonb, Nullable, Text, Timestamp};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::info;
+use schema::{vss_blobs, vss_chunks, vss_db, vss_history, vss_snapshot_items};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+use tracing::debug_span;
+use tracing::field::Empty;
 
+mod breaker;
+mod device;
+mod forget;
+mod history;
+mod idempotency;
+mod leader;
+mod lease;
+mod mutation_log;
+mod nostr;
+mod org;
+mod outbox;
+pub mod partition;
+#[cfg(test)]
+mod proptests;
+mod quota;
+mod regression;
+mod retention;
+mod retry;
 mod schema;
+mod snapshot;
+mod store;
+mod store_export;
+mod usage;
+
+pub use breaker::{CircuitBreaker, CircuitOpen};
+pub use device::Device;
+pub use forget::ForgetReceipt;
+pub use history::HistoryEntry;
+pub use idempotency::{IdempotencyKey, IdempotencyKeyReused};
+pub use leader::JobLeader;
+pub use lease::{Lease, LeaseConflict};
+pub use mutation_log::{Mutation, MutationWatermark};
+pub use nostr::NostrSubscription;
+pub use org::{Org, OrgStore};
+pub use outbox::ChangeEvent;
+pub use partition::PartitionStatus;
+pub use quota::{Quota, QuotaInvoice, StoreUsage};
+pub use regression::{RegressionStats, VersionRegression};
+pub use retention::RetentionRule;
+pub use retry::{log_if_slow, with_db_retry};
+pub use snapshot::{Snapshot, SnapshotItem};
+pub use store::{StoreBehaviors, StorePendingDeletion, VssStore};
+pub use store_export::{StoreExport, EXPORT_FAILED, EXPORT_PENDING, EXPORT_READY};
+pub use usage::{UsageDay, UsageTotals};
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+/// A version as stored in a BIGINT column. Versions are unsigned 64-bit like
+/// the proto's and are stored by their bits, so those past `i64::MAX` are
+/// negative in the database, where `vss_version_order` compares them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = BigInt)]
+pub struct DbVersion(pub u64);
+
+impl From<u64> for DbVersion {
+    fn from(version: u64) -> Self {
+        DbVersion(version)
+    }
+}
+
+impl From<DbVersion> for u64 {
+    fn from(version: DbVersion) -> Self {
+        version.0
+    }
+}
+
+impl ToSql<BigInt, Pg> for DbVersion {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(&self.0.to_be_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<BigInt, Pg> for DbVersion {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let version = <i64 as FromSql<BigInt, Pg>>::from_sql(bytes)?;
+        Ok(DbVersion(version as u64))
+    }
+}
+
 #[derive(
     QueryableByName,
     Queryable,
@@ -27,28 +119,162 @@ pub struct VssItem {
     pub store_id: String,
     pub key: String,
     pub value: Option<Vec<u8>>,
-    pub version: i64,
+    #[diesel(serialize_as = DbVersion, deserialize_as = DbVersion)]
+    pub version: u64,
+
+    created_date: NaiveDateTime,
+    updated_date: NaiveDateTime,
+    /// `device:<id>` or `token:<fingerprint>` of the last writer, if known
+    pub last_modified_by: Option<String>,
+    /// SHA-256 of the whole value, None for tombstones and items written
+    /// before it was recorded
+    pub value_hash: Option<Vec<u8>>,
+    /// JSON object the client attached with the value
+    pub metadata: Option<serde_json::Value>,
+    /// Media type the client gave the value
+    pub content_type: Option<String>,
+    /// Transaction that last changed the item, set by the database
+    #[serde(skip)]
+    change_id: i64,
+}
+
+/// What a client can attach to a value besides its bytes, replaced with it
+/// on every write.
+#[derive(Queryable, Debug, Clone, Default, PartialEq)]
+pub struct ItemAttributes {
+    pub metadata: Option<serde_json::Value>,
+    pub content_type: Option<String>,
+}
+
+/// A key's version and who last changed it, without its value.
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyMetadata {
+    pub key: String,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    /// The key has been deleted, `version` is that of the tombstone
+    pub deleted: bool,
+    pub last_modified_by: Option<String>,
+    pub updated_date: NaiveDateTime,
+    /// JSON object the client attached with the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Media type the client gave the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
 
-    created_date: chrono::NaiveDateTime,
-    updated_date: chrono::NaiveDateTime,
+/// A key's row as stored after a write, without its value.
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredItem {
+    pub key: String,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    pub created_date: NaiveDateTime,
+    pub updated_date: NaiveDateTime,
 }
 
 impl VssItem {
     pub fn into_kv(self) -> Option<KeyValue> {
-        self.value
-            .map(|value| KeyValue::new(self.key, value, self.version))
+        let (metadata, content_type) = (self.metadata, self.content_type);
+        self.value.map(|value| KeyValue {
+            metadata,
+            content_type,
+            ..KeyValue::new(self.key, value, self.version)
+        })
     }
 
     pub fn get_item(
         conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
         store_id: &str,
         key: &str,
     ) -> anyhow::Result<Option<VssItem>> {
-        Ok(vss_db::table
+        let _span = debug_span!("vss.get_item", store_id, keys = 1).entered();
+
+        vss_db::table
             .filter(vss_db::store_id.eq(store_id))
             .filter(vss_db::key.eq(key))
             .first::<Self>(conn)
-            .optional()?)
+            .optional()?
+            .map(|item| item.with_chunks(conn, blobs))
+            .transpose()
+    }
+
+    /// Returns the key as it was at `as_of`: its current row if it hasn't
+    /// changed since, otherwise the version it had then from the write
+    /// history. None if it didn't exist then, or that version has been pruned.
+    pub fn get_item_as_of(
+        conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
+        store_id: &str,
+        key: &str,
+        as_of: NaiveDateTime,
+    ) -> anyhow::Result<Option<VssItem>> {
+        let _span = debug_span!("vss.get_item_as_of", store_id, keys = 1).entered();
+
+        let current = Self::get_item(conn, blobs, store_id, key)?;
+        if let Some(item) = current.filter(|item| item.updated_date <= as_of) {
+            return Ok(Some(item));
+        }
+
+        let Some(entry) = HistoryEntry::as_of(conn, store_id, key, as_of)? else {
+            return Ok(None);
+        };
+        let value = match (entry.value, &entry.object_key) {
+            (None, Some(object_key)) => {
+                let blobs = blobs.ok_or_else(|| {
+                    anyhow!("Value of {key} is in object storage, but BLOB_STORE_URL isn't set")
+                })?;
+                let value_hash = entry.value_hash.as_deref().unwrap_or_default();
+                Some(blobs.get(object_key, value_hash)?)
+            }
+            (value, _) => value,
+        };
+
+        Ok(Some(VssItem {
+            store_id: entry.store_id,
+            key: entry.key,
+            value,
+            version: entry.version,
+            created_date: entry.created_date,
+            updated_date: entry.updated_date,
+            last_modified_by: None,
+            value_hash: entry.value_hash,
+            metadata: entry.metadata,
+            content_type: entry.content_type,
+            change_id: 0,
+        }))
+    }
+
+    /// Returns the version of a live key without reading its value.
+    pub fn get_version(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<u64>> {
+        let _span = debug_span!("vss.get_version", store_id, keys = 1).entered();
+
+        Ok(vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq(key))
+            .filter(vss_db::value.is_not_null())
+            .select(vss_db::version)
+            .first::<DbVersion>(conn)
+            .optional()?
+            .map(u64::from))
+    }
+
+    /// Reassembles the value of an item that was split into chunks.
+    fn with_chunks(
+        mut self,
+        conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
+    ) -> anyhow::Result<Self> {
+        if let Some(value) = self.value.take() {
+            self.value = Some(unchunk(conn, blobs, &self.store_id, &self.key, value)?);
+        }
+        Ok(self)
     }
 
     pub fn put_item(
@@ -56,187 +282,4512 @@ impl VssItem {
         store_id: &str,
         key: &str,
         value: &[u8],
-        version: i64,
+        version: u64,
+    ) -> anyhow::Result<()> {
+        Self::put_item_with_attributes(conn, store_id, key, value, version, &Default::default())
+    }
+
+    /// Same as [`VssItem::put_item`], replacing the item's metadata and
+    /// content type too.
+    pub fn put_item_with_attributes(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        value: &[u8],
+        version: u64,
+        attributes: &ItemAttributes,
     ) -> anyhow::Result<()> {
-        sql_query("SELECT upsert_vss_db($1, $2, $3, $4)")
+        let _span = debug_span!("vss.put_item", store_id, keys = 1, bytes = value.len()).entered();
+
+        sql_query("SELECT upsert_vss_db($1, $2, $3, $4, $5, $6)")
             .bind::<Text, _>(store_id)
             .bind::<Text, _>(key)
             .bind::<Bytea, _>(value)
-            .bind::<BigInt, _>(version)
+            .bind::<BigInt, _>(DbVersion(version))
+            .bind::<Nullable<Jsonb>, _>(&attributes.metadata)
+            .bind::<Nullable<Text>, _>(&attributes.content_type)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Same as [`VssItem::put_item_with_attributes`] for a value that was
+    /// already uploaded to object storage, storing only a pointer to it and
+    /// carrying the hash of the whole value into the row.
+    pub fn put_offloaded(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        offloaded: &OffloadedValue,
+        version: u64,
+        attributes: &ItemAttributes,
+    ) -> anyhow::Result<()> {
+        let _span = debug_span!("vss.put_offloaded", store_id, keys = 1).entered();
+
+        conn.transaction(|conn| {
+            sql_query("SELECT set_config('vss.value_hash', $1, true)")
+                .bind::<Text, _>(hex::encode(&offloaded.value_hash))
+                .execute(conn)?;
+            sql_query("SELECT upsert_vss_db($1, $2, ''::bytea, $3, $4, $5)")
+                .bind::<Text, _>(store_id)
+                .bind::<Text, _>(key)
+                .bind::<BigInt, _>(DbVersion(version))
+                .bind::<Nullable<Jsonb>, _>(&attributes.metadata)
+                .bind::<Nullable<Text>, _>(&attributes.content_type)
+                .execute(conn)?;
+            sql_query("SELECT set_config('vss.value_hash', '', true)").execute(conn)?;
+
+            // only if the upsert wasn't skipped for an older version
+            sql_query(
+                "INSERT INTO vss_blobs (store_id, key, object_key, size, value_hash) \
+                 SELECT store_id, key, $3, $4, value_hash FROM vss_db \
+                 WHERE store_id = $1 AND key = $2 AND value = '' AND value_hash = $5 \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind::<Text, _>(store_id)
+            .bind::<Text, _>(key)
+            .bind::<Text, _>(&offloaded.object_key)
+            .bind::<BigInt, _>(offloaded.size as i64)
+            .bind::<Bytea, _>(&offloaded.value_hash)
+            .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Writes `items` and reads their rows back in the same transaction, so
+    /// the caller sees exactly what its write left behind. Items older than
+    /// the stored version are ignored by the upsert, so their row shows the
+    /// newer version instead, and items at [`NO_VERSION_CHECK`] are written
+    /// at the next version. `offloaded` has, for each item, where its value
+    /// was uploaded to if it went to object storage. Returns one row per
+    /// item, in order.
+    pub fn put_items(
+        conn: &mut PgConnection,
+        store_id: &str,
+        items: &[KeyValue],
+        offloaded: &[Option<OffloadedValue>],
+    ) -> anyhow::Result<Vec<StoredItem>> {
+        for (kv, offloaded) in items.iter().zip(offloaded) {
+            let version = match kv.version {
+                NO_VERSION_CHECK => Self::next_version(conn, store_id, &kv.key)?,
+                version => version,
+            };
+            let attributes = ItemAttributes {
+                metadata: kv.metadata.clone(),
+                content_type: kv.content_type.clone(),
+            };
+            match offloaded {
+                Some(offloaded) => {
+                    Self::put_offloaded(conn, store_id, &kv.key, offloaded, version, &attributes)?
+                }
+                None => Self::put_item_with_attributes(
+                    conn,
+                    store_id,
+                    &kv.key,
+                    &kv.value.0,
+                    version,
+                    &attributes,
+                )?,
+            }
+        }
+        Self::stored_items(conn, store_id, items)
+    }
+
+    /// The stored rows of `items`' keys, one per item in order.
+    pub fn stored_items(
+        conn: &mut PgConnection,
+        store_id: &str,
+        items: &[KeyValue],
+    ) -> anyhow::Result<Vec<StoredItem>> {
+        let keys: Vec<&str> = items.iter().map(|kv| kv.key.as_str()).collect();
+        let rows: HashMap<String, StoredItem> = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq_any(&keys))
+            .select((
+                vss_db::key,
+                vss_db::version,
+                vss_db::created_date,
+                vss_db::updated_date,
+            ))
+            .load::<StoredItem>(conn)?
+            .into_iter()
+            .map(|row| (row.key.clone(), row))
+            .collect();
+
+        keys.into_iter()
+            .map(|key| {
+                rows.get(key)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Key {key} was not stored"))
+            })
+            .collect()
+    }
+
+    /// Same as [`VssItem::put_item`] but keeps the given dates instead of
+    /// stamping the row with the current time, used when importing data.
+    pub fn put_item_with_dates(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        value: &[u8],
+        version: u64,
+        created_date: NaiveDateTime,
+        updated_date: NaiveDateTime,
+    ) -> anyhow::Result<()> {
+        let _span = debug_span!(
+            "vss.put_item_with_dates",
+            store_id,
+            keys = 1,
+            bytes = value.len()
+        )
+        .entered();
+
+        sql_query("SELECT upsert_vss_db_with_dates($1, $2, $3, $4, $5, $6)")
+            .bind::<Text, _>(store_id)
+            .bind::<Text, _>(key)
+            .bind::<Bytea, _>(value)
+            .bind::<BigInt, _>(DbVersion(version))
+            .bind::<Timestamp, _>(created_date)
+            .bind::<Timestamp, _>(updated_date)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Writes an item exactly as given, with its version, `(created,
+    /// updated)` dates and attributes, as a tombstone when `value` is None.
+    /// Used to load fixtures, should be called inside a transaction.
+    pub fn put_item_verbatim(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key: &str,
+        value: Option<&[u8]>,
+        version: u64,
+        attributes: &ItemAttributes,
+        (created_date, updated_date): (NaiveDateTime, NaiveDateTime),
+    ) -> anyhow::Result<()> {
+        Self::put_item_with_dates(
+            conn,
+            store_id,
+            key,
+            value.unwrap_or_default(),
+            version,
+            created_date,
+            updated_date,
+        )?;
+
+        sql_query("SELECT set_config('vss.preserve_dates', 'on', true)").execute(conn)?;
+        let item = vss_db::table.find((store_id, key));
+        diesel::update(item)
+            .set((
+                vss_db::metadata.eq(&attributes.metadata),
+                vss_db::content_type.eq(&attributes.content_type),
+            ))
             .execute(conn)?;
+        if value.is_none() {
+            diesel::update(item)
+                .set(vss_db::value.eq(None::<Vec<u8>>))
+                .execute(conn)?;
+        }
+        sql_query("SELECT set_config('vss.preserve_dates', 'off', true)").execute(conn)?;
 
         Ok(())
     }
 
+    pub fn created_date(&self) -> NaiveDateTime {
+        self.created_date
+    }
+
+    pub fn updated_date(&self) -> NaiveDateTime {
+        self.updated_date
+    }
+
+    /// Lists the keys and versions of a store, tombstoned keys are omitted.
     pub fn list_key_versions(
         conn: &mut PgConnection,
         store_id: &str,
         prefix: Option<&str>,
-    ) -> anyhow::Result<Vec<(String, i64)>> {
+    ) -> anyhow::Result<Vec<(String, u64)>> {
+        let span = debug_span!("vss.list_key_versions", store_id, keys = Empty).entered();
+
         let table = vss_db::table
             .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::value.is_not_null())
             .select((vss_db::key, vss_db::version));
 
         let res = match prefix {
-            None => table.load::<(String, i64)>(conn)?,
+            None => table.load::<(String, DbVersion)>(conn)?,
             Some(prefix) => table
-                .filter(vss_db::key.ilike(format!("{prefix}%")))
-                .load::<(String, i64)>(conn)?,
+                .filter(key_starts_with(prefix))
+                .load::<(String, DbVersion)>(conn)?,
         };
+        span.record("keys", res.len());
+
+        Ok(res.into_iter().map(|(key, v)| (key, v.0)).collect())
+    }
+
+    /// Lists the keys of a store with their versions and who last changed
+    /// them, optionally including tombstoned keys.
+    pub fn list_key_metadata(
+        conn: &mut PgConnection,
+        store_id: &str,
+        prefix: Option<&str>,
+        include_deleted: bool,
+    ) -> anyhow::Result<Vec<KeyMetadata>> {
+        let span = debug_span!("vss.list_key_metadata", store_id, keys = Empty).entered();
+
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .select((
+                vss_db::key,
+                vss_db::version,
+                vss_db::value.is_null(),
+                vss_db::last_modified_by,
+                vss_db::updated_date,
+                vss_db::metadata,
+                vss_db::content_type,
+            ))
+            .order(vss_db::key)
+            .into_boxed();
+        if !include_deleted {
+            query = query.filter(vss_db::value.is_not_null());
+        }
+        if let Some(prefix) = prefix {
+            query = query.filter(key_starts_with(prefix));
+        }
+
+        let res = query.load::<KeyMetadata>(conn)?;
+        span.record("keys", res.len());
 
         Ok(res)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::State;
-    use diesel::r2d2::{ConnectionManager, Pool};
-    use diesel_migrations::MigrationHarness;
-    use secp256k1::Secp256k1;
-    use std::str::FromStr;
+    /// Keys of a store changed at or after change id `since` and before
+    /// `until`, including tombstoned ones, oldest change first.
+    pub fn list_changed_keys(
+        conn: &mut PgConnection,
+        store_id: &str,
+        since: Option<u64>,
+        until: u64,
+    ) -> anyhow::Result<Vec<KeyMetadata>> {
+        let span = debug_span!("vss.list_changed_keys", store_id, keys = Empty).entered();
 
-    const PUBKEY: &str = "04547d92b618856f4eda84a64ec32f1694c9608a3f9dc73e91f08b5daa087260164fbc9e2a563cf4c5ef9f4c614fd9dfca7582f8de429a4799a4b202fbe80a7db5";
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .select((
+                vss_db::key,
+                vss_db::version,
+                vss_db::value.is_null(),
+                vss_db::last_modified_by,
+                vss_db::updated_date,
+                vss_db::metadata,
+                vss_db::content_type,
+            ))
+            .filter(vss_db::change_id.lt(until as i64))
+            .order((vss_db::change_id, vss_db::key))
+            .into_boxed();
+        if let Some(since) = since {
+            query = query.filter(vss_db::change_id.ge(since as i64));
+        }
 
-    fn init_state() -> State {
-        dotenv::dotenv().ok();
-        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let manager = ConnectionManager::<PgConnection>::new(url);
-        let db_pool = Pool::builder()
-            .max_size(10)
-            .test_on_check_out(true)
-            .build(manager)
-            .expect("Could not build connection pool");
+        let res = query.load::<KeyMetadata>(conn)?;
+        span.record("keys", res.len());
 
-        // run migrations
-        let mut connection = db_pool.get().unwrap();
-        connection
-            .run_pending_migrations(MIGRATIONS)
-            .expect("migrations could not run");
+        Ok(res)
+    }
 
-        let auth_key = secp256k1::PublicKey::from_str(PUBKEY).ok();
+    /// A change id every later write will be at or after: the oldest
+    /// transaction still running, since every one before it has finished.
+    pub fn change_watermark(conn: &mut PgConnection) -> anyhow::Result<u64> {
+        let res =
+            sql_query("SELECT pg_snapshot_xmin(pg_current_snapshot())::TEXT::BIGINT AS watermark")
+                .get_result::<Watermark>(conn)?;
+        Ok(res.watermark as u64)
+    }
 
-        let secp = Secp256k1::new();
+    /// The key, version and value hash of each live key in a store. Items
+    /// without a recorded hash have their value hashed by the database.
+    pub fn value_hashes(
+        conn: &mut PgConnection,
+        store_id: &str,
+        prefix: Option<&str>,
+    ) -> anyhow::Result<Vec<(String, u64, Vec<u8>)>> {
+        let span = debug_span!("vss.value_hashes", store_id, keys = Empty).entered();
 
-        State {
-            db_pool,
-            auth_key,
-            self_hosted: false,
-            secp,
+        let hash = diesel::dsl::sql::<Bytea>(
+            "COALESCE(value_hash, sha256(CASE WHEN value = '' THEN COALESCE(\
+             (SELECT string_agg(data, ''::bytea ORDER BY idx) FROM vss_chunks \
+             WHERE vss_chunks.store_id = vss_db.store_id AND vss_chunks.key = vss_db.key), \
+             ''::bytea) ELSE value END))",
+        );
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::value.is_not_null())
+            .select((vss_db::key, vss_db::version, hash))
+            .into_boxed();
+        if let Some(prefix) = prefix {
+            query = query.filter(key_starts_with(prefix));
         }
-    }
 
-    fn clear_database(state: &State) {
-        let conn = &mut state.db_pool.get().unwrap();
+        let res = query.load::<(String, DbVersion, Vec<u8>)>(conn)?;
+        span.record("keys", res.len());
 
-        conn.transaction::<_, anyhow::Error, _>(|conn| {
-            diesel::delete(vss_db::table).execute(conn)?;
-            Ok(())
-        })
-        .unwrap();
+        Ok(res
+            .into_iter()
+            .map(|(key, v, hash)| (key, v.0, hash))
+            .collect())
     }
 
-    #[tokio::test]
-    async fn test_vss_flow() {
-        let state = init_state();
-        clear_database(&state);
+    /// Looks up the versions of the given keys, including tombstoned ones,
+    /// as `(key, version, deleted)`. Keys that were never written are omitted.
+    pub fn get_versions(
+        conn: &mut PgConnection,
+        store_id: &str,
+        keys: &[String],
+    ) -> anyhow::Result<Vec<(String, u64, bool)>> {
+        let _span = debug_span!("vss.get_versions", store_id, keys = keys.len()).entered();
 
-        let store_id = "test_store_id";
-        let key = "test";
-        let value = [1, 2, 3];
-        let version = 0;
+        Ok(vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq_any(keys))
+            .select((vss_db::key, vss_db::version, vss_db::value.is_null()))
+            .order(vss_db::key)
+            .load::<(String, DbVersion, bool)>(conn)?
+            .into_iter()
+            .map(|(key, v, deleted)| (key, v.0, deleted))
+            .collect())
+    }
 
-        let mut conn = state.db_pool.get().unwrap();
-        VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
+    /// Current versions of the given keys that are live. Tombstoned keys are
+    /// left out, since a put replaces them whatever its version.
+    pub fn current_versions(
+        conn: &mut PgConnection,
+        store_id: &str,
+        items: &[KeyValue],
+    ) -> anyhow::Result<HashMap<String, u64>> {
+        let keys: Vec<String> = items.iter().map(|kv| kv.key.clone()).collect();
+        Ok(Self::get_versions(conn, store_id, &keys)?
+            .into_iter()
+            .filter(|(_, _, deleted)| !deleted)
+            .map(|(key, version, _)| (key, version))
+            .collect())
+    }
 
-        let versions = VssItem::list_key_versions(&mut conn, store_id, None).unwrap();
+    /// Tombstones every live key in the store that starts with `prefix`,
+    /// returning how many keys were affected. When `dry_run` is set nothing
+    /// is written and only the count is returned.
+    pub fn delete_by_prefix(
+        conn: &mut PgConnection,
+        store_id: &str,
+        prefix: &str,
+        dry_run: bool,
+    ) -> anyhow::Result<usize> {
+        let span = debug_span!("vss.delete_by_prefix", store_id, keys = Empty).entered();
 
-        assert_eq!(versions.len(), 1);
-        assert_eq!(versions[0].0, key);
-        assert_eq!(versions[0].1, version);
+        let live = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(key_starts_with(prefix))
+            .filter(vss_db::value.is_not_null());
 
-        let new_value = [4, 5, 6];
-        let new_version = version + 1;
+        if dry_run {
+            let count = live.count().get_result::<i64>(conn)?;
+            span.record("keys", count);
+            return Ok(count as usize);
+        }
 
-        VssItem::put_item(&mut conn, store_id, key, &new_value, new_version).unwrap();
+        // at the next version, so a delete is newer than the last put
+        let count = diesel::update(live)
+            .set((
+                vss_db::value.eq(None::<Vec<u8>>),
+                vss_db::version.eq(bumped_version()),
+            ))
+            .execute(conn)?;
+        span.record("keys", count);
 
-        let item = VssItem::get_item(&mut conn, store_id, key)
-            .unwrap()
-            .unwrap();
+        Ok(count)
+    }
 
-        assert_eq!(item.store_id, store_id);
-        assert_eq!(item.key, key);
-        assert_eq!(item.value.unwrap(), new_value);
-        assert_eq!(item.version, new_version);
+    /// Tombstones every live key in the store that isn't in `keys`, returning
+    /// how many keys were affected.
+    pub fn tombstone_all_except(
+        conn: &mut PgConnection,
+        store_id: &str,
+        keys: &[&str],
+    ) -> anyhow::Result<usize> {
+        let span = debug_span!("vss.tombstone_all_except", store_id, keys = Empty).entered();
 
-        clear_database(&state);
+        let count = diesel::update(
+            vss_db::table
+                .filter(vss_db::store_id.eq(store_id))
+                .filter(vss_db::value.is_not_null())
+                .filter(diesel::dsl::not(vss_db::key.eq_any(keys))),
+        )
+        .set(vss_db::value.eq(None::<Vec<u8>>))
+        .execute(conn)?;
+        span.record("keys", count);
+
+        Ok(count)
     }
 
-    #[tokio::test]
-    async fn test_max_version_number() {
-        let state = init_state();
-        clear_database(&state);
+    /// Copies the value at `from` to `to` within a store, giving `to` the next
+    /// version after whatever it currently holds. If `tombstone_source` is set
+    /// the value at `from` is removed afterwards. Should be called inside a
+    /// transaction. Returns the new version of `to`.
+    pub fn copy_item(
+        conn: &mut PgConnection,
+        store_id: &str,
+        from: &str,
+        to: &str,
+        tombstone_source: bool,
+    ) -> anyhow::Result<u64> {
+        let _span = debug_span!("vss.copy_item", store_id, keys = 2).entered();
 
-        let store_id = "max_test_store_id";
-        let key = "max_test";
-        let value = [1, 2, 3];
-        let version = u32::MAX as i64;
+        if from == to {
+            return Err(anyhow!("Source and destination keys must differ"));
+        }
 
-        let mut conn = state.db_pool.get().unwrap();
-        VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
+        let (value, attributes) = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq(from))
+            .select((vss_db::value, (vss_db::metadata, vss_db::content_type)))
+            .for_update()
+            .first::<(Option<Vec<u8>>, ItemAttributes)>(conn)
+            .optional()?
+            .and_then(|(value, attributes)| value.map(|v| (v, attributes)))
+            .ok_or_else(|| anyhow!("Key {from} not found"))?;
 
-        let item = VssItem::get_item(&mut conn, store_id, key)
-            .unwrap()
-            .unwrap();
+        let version = Self::next_version(conn, store_id, to)?;
+        match offloaded_value(conn, store_id, from)? {
+            // objects are named by store and hash, so the copy shares it
+            Some(offloaded) => {
+                Self::put_offloaded(conn, store_id, to, &offloaded, version, &attributes)?
+            }
+            None => {
+                // not offloaded, so only chunks are read
+                let value = unchunk(conn, None, store_id, from, value)?;
+                Self::put_item_with_attributes(conn, store_id, to, &value, version, &attributes)?
+            }
+        }
 
-        assert_eq!(item.store_id, store_id);
-        assert_eq!(item.key, key);
-        assert_eq!(item.value.unwrap(), value);
+        if tombstone_source {
+            diesel::update(
+                vss_db::table
+                    .filter(vss_db::store_id.eq(store_id))
+                    .filter(vss_db::key.eq(from)),
+            )
+            .set((
+                vss_db::value.eq(None::<Vec<u8>>),
+                vss_db::version.eq(bumped_version()),
+            ))
+            .execute(conn)?;
+        }
 
-        let new_value = [4, 5, 6];
+        Ok(version)
+    }
 
-        VssItem::put_item(&mut conn, store_id, key, &new_value, version).unwrap();
+    /// The version after the one `key` is at, tombstoned or not, or 0 if it
+    /// was never written. Locks the key's row until the transaction ends.
+    pub fn next_version(conn: &mut PgConnection, store_id: &str, key: &str) -> anyhow::Result<u64> {
+        let existing = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq(key))
+            .select(vss_db::version)
+            .for_update()
+            .first::<DbVersion>(conn)
+            .optional()?;
+        Ok(existing.map(|v| v.0.saturating_add(1)).unwrap_or(0))
+    }
 
-        let item = VssItem::get_item(&mut conn, store_id, key)
-            .unwrap()
-            .unwrap();
+    /// Applies a delta to the value of `key`, which must currently be at
+    /// `base_version`, and stores the result at the next version. Should be
+    /// called inside a transaction. Returns the new value and version.
+    pub fn patch_item(
+        conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
+        store_id: &str,
+        key: &str,
+        base_version: u64,
+        delta: &[DeltaOp],
+    ) -> anyhow::Result<KeyValue> {
+        let _span = debug_span!("vss.patch_item", store_id, keys = 1).entered();
 
-        assert_eq!(item.store_id, store_id);
-        assert_eq!(item.key, key);
-        assert_eq!(item.value.unwrap(), new_value);
+        let (value, version, attributes) = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::key.eq(key))
+            .select((
+                vss_db::value,
+                vss_db::version,
+                (vss_db::metadata, vss_db::content_type),
+            ))
+            .for_update()
+            .first::<(Option<Vec<u8>>, DbVersion, ItemAttributes)>(conn)
+            .optional()?
+            .and_then(|(value, version, attributes)| value.map(|v| (v, version.0, attributes)))
+            .ok_or_else(|| anyhow!("Key {key} not found"))?;
+        let value = unchunk(conn, blobs, store_id, key, value)?;
 
-        clear_database(&state);
-    }
+        if version != base_version {
+            return Err(anyhow!(
+                "Key {key} is at version {version}, not {base_version}"
+            ));
+        }
 
-    #[tokio::test]
-    async fn test_list_key_versions() {
-        let state = init_state();
-        clear_database(&state);
+        let value = apply_delta(&value, delta)?;
+        let version = version.saturating_add(1);
+        // patches change the value, the metadata and content type stay
+        Self::put_item_with_attributes(conn, store_id, key, &value, version, &attributes)?;
 
-        let store_id = "list_kv_test_store_id";
-        let key = "kv_test";
-        let key1 = "other_kv_test";
+        Ok(KeyValue {
+            metadata: attributes.metadata,
+            content_type: attributes.content_type,
+            ..KeyValue::new(key.to_string(), value, version)
+        })
+    }
+
+    /// Returns up to `limit` live items of a store, ordered by key and
+    /// starting after `after`.
+    pub fn list_items(
+        conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
+        store_id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<VssItem>> {
+        let span = debug_span!("vss.list_items", store_id, keys = Empty).entered();
+
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .filter(vss_db::value.is_not_null())
+            .order(vss_db::key.asc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(vss_db::key.gt(after));
+        }
+
+        let items = query.load::<Self>(conn)?;
+        span.record("keys", items.len());
+
+        items
+            .into_iter()
+            .map(|item| item.with_chunks(conn, blobs))
+            .collect()
+    }
+
+    /// Like [`VssItem::list_items`], but tombstones are included.
+    pub fn list_items_with_tombstones(
+        conn: &mut PgConnection,
+        blobs: Option<&BlobStore>,
+        store_id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<VssItem>> {
+        let _span = debug_span!("vss.list_items_with_tombstones", store_id).entered();
+
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(store_id))
+            .order(vss_db::key.asc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(vss_db::key.gt(after));
+        }
+
+        query
+            .load::<Self>(conn)?
+            .into_iter()
+            .map(|item| item.with_chunks(conn, blobs))
+            .collect()
+    }
+
+    /// Takes a transaction scoped advisory lock on the store, so concurrent
+    /// write transactions for the same store run one after another instead of
+    /// interleaving. Must be called inside a transaction, the lock is released
+    /// when it commits or rolls back.
+    /// Attributes the writes made by the rest of the current transaction to
+    /// `writer`, stored in their `last_modified_by`.
+    pub fn set_writer(conn: &mut PgConnection, writer: &str) -> anyhow::Result<()> {
+        sql_query("SELECT set_config('vss.writer', $1, true)")
+            .bind::<Text, _>(writer)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn lock_store(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<()> {
+        let _span = debug_span!("vss.lock_store", store_id).entered();
+
+        sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind::<Text, _>(store_id)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Returns true if the store has any rows, including tombstones.
+    pub fn store_exists(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<bool> {
+        let exists = diesel::select(diesel::dsl::exists(
+            vss_db::table.filter(vss_db::store_id.eq(store_id)),
+        ))
+        .get_result::<bool>(conn)?;
+
+        Ok(exists)
+    }
+
+    /// Copies up to `limit` rows, ordered by key and starting after `after`,
+    /// from one store into another, keeping keys, values, versions and dates
+    /// as is. Should be called inside a transaction. Returns the last key
+    /// copied and the number of rows copied, or None once there is nothing left.
+    pub fn clone_store_batch(
+        conn: &mut PgConnection,
+        from_store_id: &str,
+        to_store_id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Option<(String, usize)>> {
+        let _span =
+            debug_span!("vss.clone_store_batch", from_store_id, to_store_id, limit).entered();
+
+        let mut query = vss_db::table
+            .filter(vss_db::store_id.eq(from_store_id))
+            .order(vss_db::key.asc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(vss_db::key.gt(after));
+        }
+
+        let rows = query.load::<Self>(conn)?;
+
+        let Some(last) = rows.last().map(|item| item.key.clone()) else {
+            return Ok(None);
+        };
+
+        let keys: Vec<String> = rows.iter().map(|item| item.key.clone()).collect();
+
+        let rows = rows
+            .into_iter()
+            .map(|item| VssItem {
+                store_id: to_store_id.to_string(),
+                ..item
+            })
+            .collect::<Vec<_>>();
+
+        sql_query("SELECT set_config('vss.preserve_dates', 'on', true)").execute(conn)?;
+        let count = diesel::insert_into(vss_db::table)
+            .values(rows)
+            .execute(conn)?;
+        sql_query("SELECT set_config('vss.preserve_dates', 'off', true)").execute(conn)?;
+
+        // offloaded values point to the same objects
+        sql_query(
+            "INSERT INTO vss_blobs (store_id, key, object_key, size, value_hash) \
+             SELECT $1, key, object_key, size, value_hash FROM vss_blobs \
+             WHERE store_id = $2 AND key = ANY($3)",
+        )
+        .bind::<Text, _>(to_store_id)
+        .bind::<Text, _>(from_store_id)
+        .bind::<Array<Text>, _>(&keys)
+        .execute(conn)?;
+
+        // chunked values only have their manifest row in vss_db
+        sql_query(
+            "INSERT INTO vss_chunks (store_id, key, idx, data) \
+             SELECT $1, key, idx, data FROM vss_chunks WHERE store_id = $2 AND key = ANY($3)",
+        )
+        .bind::<Text, _>(to_store_id)
+        .bind::<Text, _>(from_store_id)
+        .bind::<Array<Text>, _>(keys)
+        .execute(conn)?;
+
+        Ok(Some((last, count)))
+    }
+}
+
+/// Returns the whole value of a key given the value stored in its row. Values
+/// split into chunks or offloaded to object storage leave an empty value in
+/// vss_db, so only those are looked up in vss_blobs and vss_chunks.
+fn unchunk(
+    conn: &mut PgConnection,
+    blobs: Option<&BlobStore>,
+    store_id: &str,
+    key: &str,
+    value: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    if !value.is_empty() {
+        return Ok(value);
+    }
+
+    if let Some(offloaded) = offloaded_value(conn, store_id, key)? {
+        let blobs = blobs.ok_or_else(|| {
+            anyhow!("Value of {key} is in object storage, but BLOB_STORE_URL isn't set")
+        })?;
+        return blobs.get(&offloaded.object_key, &offloaded.value_hash);
+    }
+
+    let chunks = vss_chunks::table
+        .filter(vss_chunks::store_id.eq(store_id))
+        .filter(vss_chunks::key.eq(key))
+        .order(vss_chunks::idx.asc())
+        .select(vss_chunks::data)
+        .load::<Vec<u8>>(conn)?;
+
+    Ok(chunks.concat())
+}
+
+/// Where the value of `key` is in object storage, if it was offloaded.
+fn offloaded_value(
+    conn: &mut PgConnection,
+    store_id: &str,
+    key: &str,
+) -> anyhow::Result<Option<OffloadedValue>> {
+    Ok(vss_blobs::table
+        .filter(vss_blobs::store_id.eq(store_id))
+        .filter(vss_blobs::key.eq(key))
+        .select((
+            vss_blobs::object_key,
+            vss_blobs::size,
+            vss_blobs::value_hash,
+        ))
+        .first::<(String, i64, Vec<u8>)>(conn)
+        .optional()?
+        .map(|(object_key, size, value_hash)| OffloadedValue {
+            object_key,
+            size: size as usize,
+            value_hash,
+        }))
+}
+
+/// Which of `object_keys` are still pointed to, by a live value, a past one
+/// in the write history or a snapshot.
+pub fn referenced_objects(
+    conn: &mut PgConnection,
+    object_keys: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut referenced = vss_blobs::table
+        .filter(vss_blobs::object_key.eq_any(object_keys))
+        .select(vss_blobs::object_key)
+        .load::<String>(conn)?;
+    referenced.extend(
+        vss_history::table
+            .filter(vss_history::object_key.eq_any(object_keys))
+            .select(vss_history::object_key)
+            .load::<Option<String>>(conn)?
+            .into_iter()
+            .flatten(),
+    );
+    referenced.extend(
+        vss_snapshot_items::table
+            .filter(vss_snapshot_items::object_key.eq_any(object_keys))
+            .select(vss_snapshot_items::object_key)
+            .load::<Option<String>>(conn)?
+            .into_iter()
+            .flatten(),
+    );
+    Ok(referenced)
+}
+
+/// The smallest string greater than every string starting with `prefix`,
+/// comparing byte-wise as `text_pattern_ops` does. None if there is no such
+/// string, when the prefix is empty or made up only of `char::MAX`.
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // UTF-8 sorts the same byte-wise as by code point
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// The version after the item's current one, wrapping like [`DbVersion`]
+/// and staying at the largest version, as in [`VssItem::next_version`].
+fn bumped_version() -> SqlLiteral<BigInt> {
+    diesel::dsl::sql::<BigInt>(
+        "CASE WHEN vss_db.version = -1 THEN -1 \
+         WHEN vss_db.version = 9223372036854775807 THEN -9223372036854775807 - 1 \
+         ELSE vss_db.version + 1 END",
+    )
+}
+
+/// Matches keys starting with `prefix` as a range scan over
+/// `vss_db_key_prefix_idx`, with no wildcards to escape.
+fn key_starts_with(prefix: &str) -> Box<dyn BoxableExpression<vss_db::table, Pg, SqlType = Bool>> {
+    let lower = diesel::dsl::sql::<Bool>("vss_db.key ~>=~ ").bind::<Text, _>(prefix.to_string());
+    match prefix_upper_bound(prefix) {
+        Some(upper) => {
+            Box::new(lower.and(diesel::dsl::sql::<Bool>("vss_db.key ~<~ ").bind::<Text, _>(upper)))
+        }
+        None => Box::new(lower),
+    }
+}
+
+/// Session settings applied to every pooled connection when it is opened.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    /// Aborts any statement, including lock waits, running longer than this
+    pub statement_timeout: Duration,
+    /// Closes sessions left idle inside an open transaction for longer than this
+    pub idle_in_transaction_timeout: Duration,
+    /// Values larger than this many bytes are split across rows of vss_chunks
+    pub chunk_size: Option<u32>,
+    /// Record every change to vss_db in vss_outbox for the change stream
+    pub capture_changes: bool,
+    /// Keep versions of items that are overwritten or deleted in vss_history
+    pub keep_history: bool,
+    /// Record every change to vss_db in vss_mutation_log
+    pub log_mutations: bool,
+    /// Connections go through PgBouncer in transaction mode, where sessions
+    /// are shared, so statements aren't cached and the settings above are
+    /// kept on the role by [`ConnectionOptions::apply_to_role`] instead
+    pub transaction_pooling: bool,
+}
+
+impl ConnectionOptions {
+    /// Stores the settings as defaults of the connecting role, so every
+    /// server connection PgBouncer opens picks them up. Connections that
+    /// are already open keep their old settings until they are replaced.
+    pub fn apply_to_role(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        conn.batch_execute(&format!(
+            "ALTER ROLE CURRENT_USER SET statement_timeout = {}; \
+             ALTER ROLE CURRENT_USER SET idle_in_transaction_session_timeout = {}",
+            self.statement_timeout.as_millis(),
+            self.idle_in_transaction_timeout.as_millis()
+        ))?;
+
+        match self.chunk_size {
+            Some(chunk_size) => conn.batch_execute(&format!(
+                "ALTER ROLE CURRENT_USER SET vss.chunk_size = {chunk_size}"
+            ))?,
+            None => conn.batch_execute("ALTER ROLE CURRENT_USER RESET vss.chunk_size")?,
+        }
+
+        if self.capture_changes {
+            conn.batch_execute("ALTER ROLE CURRENT_USER SET vss.capture_changes = 'on'")?;
+        } else {
+            conn.batch_execute("ALTER ROLE CURRENT_USER RESET vss.capture_changes")?;
+        }
+
+        if self.keep_history {
+            conn.batch_execute("ALTER ROLE CURRENT_USER SET vss.keep_history = 'on'")?;
+        } else {
+            conn.batch_execute("ALTER ROLE CURRENT_USER RESET vss.keep_history")?;
+        }
+
+        if self.log_mutations {
+            conn.batch_execute("ALTER ROLE CURRENT_USER SET vss.log_mutations = 'on'")?;
+        } else {
+            conn.batch_execute("ALTER ROLE CURRENT_USER RESET vss.log_mutations")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        // named statements and session settings would be left on whichever
+        // server connection PgBouncer happened to pick
+        if self.transaction_pooling {
+            conn.set_prepared_statement_cache_size(CacheSize::Disabled);
+            return Ok(());
+        }
+
+        conn.batch_execute(&format!(
+            "SET statement_timeout = {}; SET idle_in_transaction_session_timeout = {}",
+            self.statement_timeout.as_millis(),
+            self.idle_in_transaction_timeout.as_millis()
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)?;
+
+        if let Some(chunk_size) = self.chunk_size {
+            conn.batch_execute(&format!("SET vss.chunk_size = {chunk_size}"))
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+
+        if self.capture_changes {
+            conn.batch_execute("SET vss.capture_changes = 'on'")
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+
+        if self.keep_history {
+            conn.batch_execute("SET vss.keep_history = 'on'")
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+
+        if self.log_mutations {
+            conn.batch_execute("SET vss.log_mutations = 'on'")
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Columns the server expects to exist, keep in sync with `schema.rs`.
+const EXPECTED_COLUMNS: [(&str, &[&str]); 23] = [
+    (
+        "vss_db",
+        &[
+            "store_id",
+            "key",
+            "value",
+            "version",
+            "created_date",
+            "updated_date",
+            "last_modified_by",
+            "value_hash",
+            "metadata",
+            "content_type",
+            "change_id",
+        ],
+    ),
+    (
+        "vss_stores",
+        &[
+            "store_id",
+            "created_at",
+            "last_write_at",
+            "label",
+            "flags",
+            "v1_base64_encoding",
+            "lazy_versions",
+            "null_for_missing",
+            "alias",
+            "delete_requested_at",
+            "purge_at",
+            "deletion_token_hash",
+        ],
+    ),
+    (
+        "vss_idempotency_keys",
+        &["store_id", "idempotency_key", "created_at"],
+    ),
+    ("vss_chunks", &["store_id", "key", "idx", "data"]),
+    (
+        "vss_blobs",
+        &["store_id", "key", "object_key", "size", "value_hash"],
+    ),
+    ("vss_leases", &["store_id", "name", "holder", "expires_at"]),
+    ("vss_job_leaders", &["job", "holder", "expires_at"]),
+    (
+        "vss_devices",
+        &[
+            "store_id",
+            "device_id",
+            "platform",
+            "token_fingerprint",
+            "created_at",
+            "last_seen_at",
+            "revoked_at",
+        ],
+    ),
+    (
+        "vss_usage",
+        &["store_id", "day", "requests", "bytes_read", "bytes_written"],
+    ),
+    ("vss_quotas", &["store_id", "purchased_bytes", "updated_at"]),
+    (
+        "vss_quota_invoices",
+        &[
+            "payment_hash",
+            "store_id",
+            "bytes",
+            "amount_msat",
+            "bolt11",
+            "created_at",
+            "paid_at",
+        ],
+    ),
+    (
+        "vss_nostr_subscriptions",
+        &["store_id", "pubkey", "created_at"],
+    ),
+    (
+        "vss_outbox",
+        &["id", "store_id", "key", "version", "op", "created_at"],
+    ),
+    (
+        "vss_mutation_log",
+        &[
+            "id",
+            "txid",
+            "store_id",
+            "key",
+            "version",
+            "op",
+            "value_hash",
+            "created_at",
+        ],
+    ),
+    (
+        "vss_orgs",
+        &["org_id", "name", "max_stores", "max_bytes", "created_at"],
+    ),
+    ("vss_org_stores", &["store_id", "org_id", "added_at"]),
+    (
+        "vss_retention_rules",
+        &["store_id", "key_prefix", "max_age_days", "created_at"],
+    ),
+    (
+        "vss_snapshots",
+        &[
+            "snapshot_id",
+            "store_id",
+            "label",
+            "items",
+            "size",
+            "created_at",
+        ],
+    ),
+    (
+        "vss_snapshot_items",
+        &[
+            "snapshot_id",
+            "key",
+            "value",
+            "object_key",
+            "size",
+            "value_hash",
+            "version",
+            "last_modified_by",
+            "metadata",
+            "content_type",
+            "created_date",
+            "updated_date",
+        ],
+    ),
+    (
+        "vss_history",
+        &[
+            "id",
+            "store_id",
+            "key",
+            "value",
+            "object_key",
+            "value_hash",
+            "version",
+            "metadata",
+            "content_type",
+            "created_date",
+            "updated_date",
+            "superseded_at",
+        ],
+    ),
+    (
+        "vss_forget_receipts",
+        &[
+            "receipt_id",
+            "store_hash",
+            "requested_by",
+            "items",
+            "objects",
+            "forgotten_at",
+        ],
+    ),
+    (
+        "vss_store_exports",
+        &[
+            "export_id",
+            "store_id",
+            "token_hash",
+            "status",
+            "error",
+            "bundle",
+            "size",
+            "created_at",
+            "completed_at",
+            "expires_at",
+        ],
+    ),
+    (
+        "vss_version_regressions",
+        &[
+            "id",
+            "store_id",
+            "key",
+            "client_id",
+            "attempted_version",
+            "current_version",
+            "delta",
+            "created_at",
+        ],
+    ),
+];
+
+/// Database functions the server calls directly.
+const EXPECTED_FUNCTIONS: [&str; 2] = ["upsert_vss_db", "upsert_vss_db_with_dates"];
+
+#[derive(QueryableByName)]
+struct ColumnName {
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+#[derive(QueryableByName)]
+struct Exists {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    exists: bool,
+}
+
+/// Names of embedded migrations that haven't been applied to the database.
+pub fn pending_migrations(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+    let pending = conn
+        .pending_migrations(MIGRATIONS)
+        .map_err(|e| anyhow!("Could not check migrations: {e}"))?;
+
+    Ok(pending.iter().map(|m| m.name().to_string()).collect())
+}
+
+/// Advisory lock held while migrating, in the two-key namespace so it can't
+/// collide with the per-store locks.
+const MIGRATION_LOCK: (&str, i32) = ("vss-rs", 1);
+const MIGRATION_LOCK_POLL: Duration = Duration::from_secs(1);
+
+#[derive(QueryableByName)]
+struct Watermark {
+    #[diesel(sql_type = BigInt)]
+    watermark: i64,
+}
+
+#[derive(QueryableByName)]
+struct Setting {
+    #[diesel(sql_type = Text)]
+    setting: String,
+}
+
+#[derive(QueryableByName)]
+struct Locked {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    locked: bool,
+}
+
+/// Applies pending migrations while holding a Postgres advisory lock, so when
+/// several instances start at once only one migrates and the rest wait for it
+/// to finish. Returns the names of the migrations this instance applied.
+pub fn run_migrations(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+    let (class, id) = MIGRATION_LOCK;
+    let mut waiting = false;
+    // polled rather than blocking so the wait isn't cut short by statement_timeout
+    while !sql_query("SELECT pg_try_advisory_lock(hashtext($1), $2) AS locked")
+        .bind::<Text, _>(class)
+        .bind::<Integer, _>(id)
+        .get_result::<Locked>(conn)?
+        .locked
+    {
+        if !waiting {
+            info!("Waiting for another instance to finish running migrations");
+            waiting = true;
+        }
+        std::thread::sleep(MIGRATION_LOCK_POLL);
+    }
+
+    // some migrations build indexes, which can take longer than the usual
+    // statement timeout on large installs
+    let timeout = sql_query("SELECT current_setting('statement_timeout') AS setting")
+        .get_result::<Setting>(conn)?
+        .setting;
+    conn.batch_execute("SET statement_timeout = 0")?;
+
+    let res = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map(|applied| applied.iter().map(|m| m.to_string()).collect::<Vec<_>>())
+        .map_err(|e| anyhow!("Migrations could not run: {e}"));
+
+    conn.batch_execute(&format!("SET statement_timeout = '{timeout}'"))?;
+    sql_query("SELECT pg_advisory_unlock(hashtext($1), $2)")
+        .bind::<Text, _>(class)
+        .bind::<Integer, _>(id)
+        .execute(conn)?;
+
+    let applied = res?;
+    for name in applied.iter() {
+        info!("Applied migration {name}");
+    }
+    Ok(applied)
+}
+
+/// Checks that every migration has been applied and the tables and functions
+/// the server relies on exist, so a stale schema fails at startup instead of
+/// surfacing as errors on requests.
+pub fn validate_schema(conn: &mut PgConnection) -> anyhow::Result<()> {
+    let pending = pending_migrations(conn)?;
+    if !pending.is_empty() {
+        return Err(anyhow!("Pending migrations: {}", pending.join(", ")));
+    }
+
+    for (table, columns) in EXPECTED_COLUMNS {
+        let existing: Vec<String> = sql_query(
+            "SELECT column_name::TEXT AS column_name FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = $1",
+        )
+        .bind::<Text, _>(table)
+        .load::<ColumnName>(conn)?
+        .into_iter()
+        .map(|c| c.column_name)
+        .collect();
+
+        if existing.is_empty() {
+            return Err(anyhow!("Missing table {table}"));
+        }
+
+        let missing: Vec<&str> = columns
+            .iter()
+            .filter(|c| !existing.iter().any(|e| e == *c))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Table {table} is missing columns: {}",
+                missing.join(", ")
+            ));
+        }
+    }
+
+    for function in EXPECTED_FUNCTIONS {
+        let found = sql_query("SELECT EXISTS(SELECT 1 FROM pg_proc WHERE proname = $1) AS exists")
+            .bind::<Text, _>(function)
+            .get_result::<Exists>(conn)?;
+        if !found.exists {
+            return Err(anyhow!("Missing database function {function}"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::anomaly::{self, AnomalyDetector, Thresholds, WriteActivity};
+    use crate::fixtures::{load_fixtures, parse_fixtures, FixtureMode, Fixtures};
+    use crate::kv::{ByteData, KeyVersion};
+    use crate::nostr::{encrypt_nip04, parse_pubkey, sign_event};
+    use crate::quota::{FreeTier, QuotaExceeded};
+    use crate::routes::ErrorResponse;
+    use crate::usage::UsageCounts;
+    use crate::validation::{Charset, InvalidRequest, StoreIdPolicy};
+    use crate::State;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use secp256k1::Secp256k1;
+    use sha2::{Digest, Sha256};
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    const PUBKEY: &str = "04547d92b618856f4eda84a64ec32f1694c9608a3f9dc73e91f08b5daa087260164fbc9e2a563cf4c5ef9f4c614fd9dfca7582f8de429a4799a4b202fbe80a7db5";
+
+    fn init_state() -> State {
+        dotenv::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let manager = ConnectionManager::<PgConnection>::new(url);
+        let db_pool = Pool::builder()
+            .max_size(10)
+            .test_on_check_out(true)
+            .build(manager)
+            .expect("Could not build connection pool");
+
+        // run migrations
+        let mut connection = db_pool.get().unwrap();
+        run_migrations(&mut connection).expect("migrations could not run");
+
+        let auth_key = secp256k1::PublicKey::from_str(PUBKEY).ok();
+
+        let secp = Secp256k1::new();
+
+        State {
+            shards: crate::shard::Shards::single(db_pool.clone()),
+            db_pool,
+            auth_key,
+            admin_auth_key: None,
+            self_hosted: false,
+            secp,
+            mirror: None,
+            blobs: None,
+            read_only: Default::default(),
+            read_only_stores: Default::default(),
+            timeouts: Default::default(),
+            limits: crate::limit::ConcurrencyLimits::new(crate::limit::LimitSizes::for_pool(10)),
+            breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(30))),
+            pool_metrics: Default::default(),
+            slow_op_threshold: Duration::from_secs(1),
+            idempotency_window: Duration::from_secs(60),
+            usage: Default::default(),
+            quota: None,
+            free_tier: Default::default(),
+            nostr: None,
+            anomaly: None,
+            key_policy: Default::default(),
+            strict_versions: false,
+            get_object_not_found: false,
+            reject_lazy_versions: false,
+            v1_base64_encoding: Default::default(),
+            allow_empty_values: false,
+            strict_json: false,
+            store_id_policy: Default::default(),
+            cors: Default::default(),
+            read_cache_secs: None,
+            trusted_proxies: Default::default(),
+            deletion_grace: Duration::from_secs(7 * 24 * 60 * 60),
+            export_retention: Duration::from_secs(24 * 60 * 60),
+            history: None,
+            mutation_log: None,
+            faults: None,
+            latency: None,
+            leader: crate::leader::LeaderElection::new(
+                "test-instance".to_string(),
+                Duration::from_secs(30),
+            ),
+        }
+    }
+
+    fn clear_database(state: &State) {
+        let conn = &mut state.db_pool.get().unwrap();
+
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            diesel::delete(vss_db::table).execute(conn)?;
+            diesel::delete(schema::vss_stores::table).execute(conn)?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vss_flow() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "test_store_id";
+        let key = "test";
         let value = [1, 2, 3];
         let version = 0;
 
         let mut conn = state.db_pool.get().unwrap();
         VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
 
-        VssItem::put_item(&mut conn, store_id, key1, &value, version).unwrap();
-
         let versions = VssItem::list_key_versions(&mut conn, store_id, None).unwrap();
-        assert_eq!(versions.len(), 2);
 
-        let versions = VssItem::list_key_versions(&mut conn, store_id, Some("kv")).unwrap();
         assert_eq!(versions.len(), 1);
         assert_eq!(versions[0].0, key);
         assert_eq!(versions[0].1, version);
 
-        let versions = VssItem::list_key_versions(&mut conn, store_id, Some("other")).unwrap();
-        assert_eq!(versions.len(), 1);
-        assert_eq!(versions[0].0, key1);
-        assert_eq!(versions[0].1, version);
+        let new_value = [4, 5, 6];
+        let new_version = version + 1;
+
+        VssItem::put_item(&mut conn, store_id, key, &new_value, new_version).unwrap();
+
+        let item = VssItem::get_item(&mut conn, None, store_id, key)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(item.store_id, store_id);
+        assert_eq!(item.key, key);
+        assert_eq!(item.value.unwrap(), new_value);
+        assert_eq!(item.version, new_version);
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_max_version_number() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "max_test_store_id";
+        let key = "max_test";
+        let value = [1, 2, 3];
+        let version = u32::MAX as u64;
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
+
+        let item = VssItem::get_item(&mut conn, None, store_id, key)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(item.store_id, store_id);
+        assert_eq!(item.key, key);
+        assert_eq!(item.value.unwrap(), value);
+
+        let new_value = [4, 5, 6];
+
+        VssItem::put_item(&mut conn, store_id, key, &new_value, version).unwrap();
+
+        let item = VssItem::get_item(&mut conn, None, store_id, key)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(item.store_id, store_id);
+        assert_eq!(item.key, key);
+        assert_eq!(item.value.unwrap(), new_value);
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_list_key_versions() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "list_kv_test_store_id";
+        let key = "kv_test";
+        let key1 = "other_kv_test";
+        let value = [1, 2, 3];
+        let version = 0;
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
+
+        VssItem::put_item(&mut conn, store_id, key1, &value, version).unwrap();
+
+        let versions = VssItem::list_key_versions(&mut conn, store_id, None).unwrap();
+        assert_eq!(versions.len(), 2);
+
+        let versions = VssItem::list_key_versions(&mut conn, store_id, Some("kv")).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].0, key);
+        assert_eq!(versions[0].1, version);
+
+        let versions = VssItem::list_key_versions(&mut conn, store_id, Some("other")).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].0, key1);
+        assert_eq!(versions[0].1, version);
+
+        // prefixes match literally and case-sensitively
+        for key in ["a_b", "a%b", "aXb", "A_b", "a\u{10FFFF}b", "b"] {
+            VssItem::put_item(&mut conn, store_id, key, &value, version).unwrap();
+        }
+        let keys = |prefix: &str, conn: &mut PgConnection| {
+            let mut keys: Vec<String> = VssItem::list_key_versions(conn, store_id, Some(prefix))
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys("a_", &mut conn), vec!["a_b"]);
+        assert_eq!(keys("a%", &mut conn), vec!["a%b"]);
+        assert_eq!(keys("a\u{10FFFF}", &mut conn), vec!["a\u{10FFFF}b"]);
+        assert_eq!(keys("a", &mut conn).len(), 4);
+
+        clear_database(&state);
+    }
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound("abc").as_deref(), Some("abd"));
+        assert_eq!(prefix_upper_bound("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(prefix_upper_bound("\u{D7FF}").as_deref(), Some("\u{E000}"));
+        assert_eq!(prefix_upper_bound("\u{10FFFF}"), None);
+        assert_eq!(prefix_upper_bound(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_prefix() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "delete_prefix_test_store_id";
+        let value = [1, 2, 3];
+        let version = 0;
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "old/a", &value, version).unwrap();
+        VssItem::put_item(&mut conn, store_id, "old/b", &value, version).unwrap();
+        VssItem::put_item(&mut conn, store_id, "old_c", &value, version).unwrap();
+        VssItem::put_item(&mut conn, store_id, "new/a", &value, version).unwrap();
+
+        let count = VssItem::delete_by_prefix(&mut conn, store_id, "old/", true).unwrap();
+        assert_eq!(count, 2);
+        let versions = VssItem::list_key_versions(&mut conn, store_id, None).unwrap();
+        assert_eq!(versions.len(), 4);
+
+        let count = VssItem::delete_by_prefix(&mut conn, store_id, "old/", false).unwrap();
+        assert_eq!(count, 2);
+
+        let versions = VssItem::list_key_versions(&mut conn, store_id, None).unwrap();
+        assert_eq!(versions.len(), 2);
+
+        let item = VssItem::get_item(&mut conn, None, store_id, "old/a")
+            .unwrap()
+            .unwrap();
+        assert!(item.value.is_none());
+        assert_eq!(item.version, version + 1);
+
+        // already tombstoned keys are not counted again
+        let count = VssItem::delete_by_prefix(&mut conn, store_id, "old/", false).unwrap();
+        assert_eq!(count, 0);
+
+        // the client's next put is at the tombstone's version, and a put
+        // brings a deleted key back whatever its version
+        VssItem::put_item(&mut conn, store_id, "old/a", &value, version + 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "old/b", &value, 0).unwrap();
+        for (key, version) in [("old/a", version + 1), ("old/b", 0)] {
+            let item = VssItem::get_item(&mut conn, None, store_id, key)
+                .unwrap()
+                .unwrap();
+            assert_eq!((item.value, item.version), (Some(value.to_vec()), version));
+        }
+        let current = VssItem::current_versions(
+            &mut conn,
+            store_id,
+            &[KeyValue::new("old/a".to_string(), vec![], 0)],
+        )
+        .unwrap();
+        assert_eq!(current.get("old/a"), Some(&(version + 1)));
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_copy_item() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "copy_test_store_id";
+        let value = [1, 2, 3];
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "from", &value, 5).unwrap();
+
+        let version = VssItem::copy_item(&mut conn, store_id, "from", "to", false).unwrap();
+        assert_eq!(version, 0);
+
+        let item = VssItem::get_item(&mut conn, None, store_id, "to")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value.unwrap(), value);
+        assert_eq!(item.version, 0);
+
+        // copying again bumps the destination version, rename removes the source
+        let version = VssItem::copy_item(&mut conn, store_id, "from", "to", true).unwrap();
+        assert_eq!(version, 1);
+
+        // at the next version, so the delete is newer than the last put
+        let item = VssItem::get_item(&mut conn, None, store_id, "from")
+            .unwrap()
+            .unwrap();
+        assert!(item.value.is_none());
+        assert_eq!(item.version, 6);
+
+        assert!(VssItem::copy_item(&mut conn, store_id, "from", "to", false).is_err());
+        assert!(VssItem::copy_item(&mut conn, store_id, "to", "to", false).is_err());
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_referenced_objects() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "referenced_objects_test_store_id";
+        let offloaded = OffloadedValue {
+            object_key: "objects/a".to_string(),
+            size: 3,
+            value_hash: Sha256::digest([1, 2, 3]).to_vec(),
+        };
+        let object_keys = ["objects/a".to_string(), "objects/b".to_string()];
+
+        let mut conn = state.db_pool.get().unwrap();
+        let attributes = ItemAttributes::default();
+        VssItem::put_offloaded(&mut conn, store_id, "a", &offloaded, 1, &attributes).unwrap();
+        // the copy points to the same object
+        VssItem::copy_item(&mut conn, store_id, "a", "b", false).unwrap();
+        let referenced = referenced_objects(&mut conn, &object_keys).unwrap();
+        assert_eq!(referenced, vec!["objects/a", "objects/a"]);
+
+        // orphaned once neither key points to it
+        VssItem::put_item(&mut conn, store_id, "a", &[4], 2).unwrap();
+        assert!(!referenced_objects(&mut conn, &object_keys)
+            .unwrap()
+            .is_empty());
+        VssItem::put_item(&mut conn, store_id, "b", &[5], 1).unwrap();
+        assert!(referenced_objects(&mut conn, &object_keys)
+            .unwrap()
+            .is_empty());
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_get_version() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "version_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        assert_eq!(
+            VssItem::get_version(&mut conn, store_id, "key").unwrap(),
+            None
+        );
+
+        VssItem::put_item(&mut conn, store_id, "key", &[1, 2, 3], 7).unwrap();
+        assert_eq!(
+            VssItem::get_version(&mut conn, store_id, "key").unwrap(),
+            Some(7)
+        );
+
+        // deleted keys have no version
+        VssItem::delete_by_prefix(&mut conn, store_id, "key", false).unwrap();
+        assert_eq!(
+            VssItem::get_version(&mut conn, store_id, "key").unwrap(),
+            None
+        );
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_get_versions() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "versions_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 5).unwrap();
+        VssItem::put_item(&mut conn, "other_store_id", "c", &[3], 1).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "b", false).unwrap();
+
+        let keys = ["b", "a", "c", "missing"].map(String::from);
+        let versions = VssItem::get_versions(&mut conn, store_id, &keys).unwrap();
+        assert_eq!(
+            versions,
+            vec![("a".to_string(), 1, false), ("b".to_string(), 6, true)]
+        );
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_leases() {
+        let state = init_state();
+        let store_id = "lease_test_store_id";
+        let ttl = Duration::from_secs(60);
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(schema::vss_leases::table)
+            .execute(&mut conn)
+            .unwrap();
+
+        let lease = Lease::acquire(&mut conn, store_id, "writer", "phone", ttl).unwrap();
+        assert_eq!(lease.holder, "phone");
+
+        // held leases can't be taken or renewed by another device
+        let err = Lease::acquire(&mut conn, store_id, "writer", "laptop", ttl).unwrap_err();
+        assert!(err.downcast_ref::<LeaseConflict>().is_some());
+        assert!(Lease::renew(&mut conn, store_id, "writer", "laptop", ttl).is_err());
+        assert!(!Lease::release(&mut conn, store_id, "writer", "laptop").unwrap());
+
+        // but the holder can, and the same name is free in other stores
+        let renewed = Lease::renew(&mut conn, store_id, "writer", "phone", ttl).unwrap();
+        assert!(renewed.expires_at >= lease.expires_at);
+        Lease::acquire(&mut conn, "other_store_id", "writer", "laptop", ttl).unwrap();
+
+        // expired leases can be taken over
+        diesel::update(schema::vss_leases::table)
+            .set(
+                schema::vss_leases::expires_at.eq(NaiveDateTime::from_timestamp_opt(0, 0).unwrap()),
+            )
+            .execute(&mut conn)
+            .unwrap();
+        assert!(Lease::renew(&mut conn, store_id, "writer", "phone", ttl).is_err());
+        let lease = Lease::acquire(&mut conn, store_id, "writer", "laptop", ttl).unwrap();
+        assert_eq!(lease.holder, "laptop");
+
+        assert!(Lease::release(&mut conn, store_id, "writer", "laptop").unwrap());
+        Lease::acquire(&mut conn, store_id, "writer", "phone", ttl).unwrap();
+
+        diesel::delete(schema::vss_leases::table)
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_devices() {
+        let state = init_state();
+        let store_id = "device_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(schema::vss_devices::table)
+            .execute(&mut conn)
+            .unwrap();
+
+        let phone =
+            Device::register(&mut conn, store_id, "phone", Some("ios"), Some("aa")).unwrap();
+        assert_eq!(phone.platform.as_deref(), Some("ios"));
+        Device::register(&mut conn, store_id, "laptop", None, None).unwrap();
+        Device::register(&mut conn, "other_store_id", "tablet", None, None).unwrap();
+
+        // registering again keeps the platform unless a new one is given
+        let phone = Device::register(&mut conn, store_id, "phone", None, Some("bb")).unwrap();
+        assert_eq!(phone.platform.as_deref(), Some("ios"));
+        assert_eq!(phone.token_fingerprint.as_deref(), Some("bb"));
+
+        let devices = Device::list_devices(&mut conn, store_id).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_id, "phone");
+
+        let revoked = Device::revoke(&mut conn, store_id, "laptop")
+            .unwrap()
+            .unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(Device::register(&mut conn, store_id, "laptop", None, None).is_err());
+        assert!(Device::revoke(&mut conn, store_id, "missing")
+            .unwrap()
+            .is_none());
+
+        diesel::delete(schema::vss_devices::table)
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_usage() {
+        let state = init_state();
+        let store_id = "usage_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(schema::vss_usage::table)
+            .execute(&mut conn)
+            .unwrap();
+
+        let counts = UsageCounts {
+            requests: 1,
+            bytes_read: 100,
+            bytes_written: 10,
+        };
+        state.usage.record(store_id, counts);
+        state.usage.record(store_id, counts);
+        state.usage.flush(&state).await;
+        state.usage.record(store_id, counts);
+        state.usage.flush(&state).await;
+
+        let usage = UsageDay::list_usage(&mut conn, store_id, 30).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].requests, 3);
+        assert_eq!(usage[0].bytes_read, 300);
+        assert_eq!(usage[0].bytes_written, 30);
+        assert!(UsageDay::list_usage(&mut conn, "other_store_id", 30)
+            .unwrap()
+            .is_empty());
+
+        diesel::delete(schema::vss_usage::table)
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quota_invoices() {
+        let state = init_state();
+        let store_id = "quota_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(schema::vss_quota_invoices::table)
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(schema::vss_quotas::table)
+            .execute(&mut conn)
+            .unwrap();
+
+        let invoice = |payment_hash: &str| QuotaInvoice {
+            payment_hash: payment_hash.to_string(),
+            store_id: store_id.to_string(),
+            bytes: 1_000,
+            amount_msat: 1_000,
+            bolt11: "lnbc".to_string(),
+            created_at: chrono::Utc::now().naive_utc(),
+            paid_at: None,
+        };
+        QuotaInvoice::insert(&mut conn, &invoice("a")).unwrap();
+        QuotaInvoice::insert(&mut conn, &invoice("b")).unwrap();
+        assert_eq!(Quota::get_quota(&mut conn, store_id).unwrap(), None);
+
+        let quota = QuotaInvoice::mark_paid(&mut conn, "a").unwrap().unwrap();
+        assert_eq!(quota.purchased_bytes, 1_000);
+
+        // invoices are only credited once
+        let quota = QuotaInvoice::mark_paid(&mut conn, "a").unwrap().unwrap();
+        assert_eq!(quota.purchased_bytes, 1_000);
+        assert!(QuotaInvoice::get_invoice(&mut conn, "a")
+            .unwrap()
+            .unwrap()
+            .paid_at
+            .is_some());
+
+        let quota = QuotaInvoice::mark_paid(&mut conn, "b").unwrap().unwrap();
+        assert_eq!(quota.purchased_bytes, 2_000);
+        assert_eq!(QuotaInvoice::mark_paid(&mut conn, "missing").unwrap(), None);
+
+        diesel::delete(schema::vss_quota_invoices::table)
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(schema::vss_quotas::table)
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_free_tier() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "free_tier_store_id";
+        let free_tier = FreeTier {
+            keys: Some(2),
+            bytes: Some(10),
+        };
+        // failed checks rely on the transaction to roll the write back
+        let put = |conn: &mut PgConnection, key: &str, value: &[u8], version: u64| {
+            conn.transaction(|conn| {
+                free_tier.enforce(conn, store_id, &[key], true, |conn| {
+                    VssItem::put_item(conn, store_id, key, value, version)
+                })
+            })
+        };
+
+        let mut conn = state.db_pool.get().unwrap();
+        put(&mut conn, "a", &[0; 4], 1).unwrap();
+        put(&mut conn, "b", &[0; 4], 1).unwrap();
+
+        // too many keys
+        let err = put(&mut conn, "c", &[0; 1], 1).unwrap_err();
+        let err = err.downcast_ref::<QuotaExceeded>().unwrap();
+        assert_eq!(err.error, "QUOTA_EXCEEDED");
+        assert_eq!(err.key_limit, Some(2));
+        assert_eq!(err.upgrade_endpoint.as_deref(), Some("/v2/quota/invoice"));
+
+        // too many bytes
+        assert!(put(&mut conn, "b", &[0; 7], 2).is_err());
+        put(&mut conn, "b", &[0; 6], 2).unwrap();
+        assert_eq!(
+            Quota::store_usage(&mut conn, store_id).unwrap(),
+            StoreUsage { keys: 2, bytes: 10 }
+        );
+
+        // buying storage raises the byte limit and lifts the key limit
+        diesel::insert_into(schema::vss_quotas::table)
+            .values((
+                schema::vss_quotas::store_id.eq(store_id),
+                schema::vss_quotas::purchased_bytes.eq(5),
+            ))
+            .execute(&mut conn)
+            .unwrap();
+        put(&mut conn, "c", &[0; 5], 1).unwrap();
+        assert!(put(&mut conn, "d", &[0; 1], 1).is_err());
+
+        // shrinking an over limit store is allowed
+        let shrink = FreeTier {
+            keys: Some(1),
+            bytes: Some(1),
+        };
+        shrink
+            .enforce(&mut conn, store_id, &["c"], false, |conn| {
+                VssItem::put_item(conn, store_id, "c", &[0; 1], 2)
+            })
+            .unwrap();
+
+        diesel::delete(schema::vss_quotas::table)
+            .execute(&mut conn)
+            .unwrap();
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_nostr_notifications() {
+        let state = init_state();
+        let store_id = "nostr_test_store_id";
+
+        let secp = Secp256k1::new();
+        let server = secp256k1::KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let wallet = secp256k1::KeyPair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let wallet_pubkey = wallet.x_only_public_key().0;
+
+        // npubs and hex keys are both accepted
+        let npub = bech32::encode(
+            "npub",
+            bech32::ToBase32::to_base32(&wallet_pubkey.serialize()),
+            bech32::Variant::Bech32,
+        )
+        .unwrap();
+        assert_eq!(parse_pubkey(&npub).unwrap(), wallet_pubkey);
+        assert_eq!(
+            parse_pubkey(&wallet_pubkey.to_string()).unwrap(),
+            wallet_pubkey
+        );
+        assert!(parse_pubkey("npub1invalid").is_err());
+
+        let mut conn = state.db_pool.get().unwrap();
+        NostrSubscription::unsubscribe(&mut conn, store_id).unwrap();
+
+        NostrSubscription::subscribe(&mut conn, store_id, "aa").unwrap();
+        let sub =
+            NostrSubscription::subscribe(&mut conn, store_id, &wallet_pubkey.to_string()).unwrap();
+        assert_eq!(
+            NostrSubscription::get_subscription(&mut conn, store_id).unwrap(),
+            Some(sub)
+        );
+
+        // the wallet can decrypt the message and verify who sent it
+        let content = encrypt_nip04(&server.secret_key(), &wallet_pubkey, "updated").unwrap();
+        let event = sign_event(
+            &secp,
+            &server,
+            1_700_000_000,
+            4,
+            vec![vec!["p".to_string(), wallet_pubkey.to_string()]],
+            content,
+        )
+        .unwrap();
+        let id = hex::decode(&event.id).unwrap();
+        let sig = secp256k1::schnorr::Signature::from_str(&event.sig).unwrap();
+        secp.verify_schnorr(
+            &sig,
+            &secp256k1::Message::from_slice(&id).unwrap(),
+            &server.x_only_public_key().0,
+        )
+        .unwrap();
+
+        let (ciphertext, iv) = event.content.split_once("?iv=").unwrap();
+        let sender = secp256k1::PublicKey::from_x_only_public_key(
+            server.x_only_public_key().0,
+            secp256k1::Parity::Even,
+        );
+        let point = secp256k1::ecdh::shared_secret_point(&sender, &wallet.secret_key());
+        use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+        let plaintext = cbc::Decryptor::<aes::Aes256>::new(
+            point[..32].into(),
+            base64::decode(iv).unwrap().as_slice().into(),
+        )
+        .decrypt_padded_vec_mut::<Pkcs7>(&base64::decode(ciphertext).unwrap())
+        .unwrap();
+        assert_eq!(plaintext, b"updated");
+
+        assert!(NostrSubscription::unsubscribe(&mut conn, store_id).unwrap());
+        assert!(!NostrSubscription::unsubscribe(&mut conn, store_id).unwrap());
+        assert!(NostrSubscription::get_subscription(&mut conn, store_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_anomaly_detection() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "anomaly_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 5).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[1], 10).unwrap();
+        let items = vec![
+            KeyValue::new("a".to_string(), vec![2], 2_005),
+            KeyValue::new("b".to_string(), vec![2], 11),
+            // new keys and overwrite markers don't count as jumps
+            KeyValue::new("c".to_string(), vec![2], 50_000),
+            KeyValue::new("b".to_string(), vec![2], u32::MAX as u64),
+        ];
+        let current = VssItem::current_versions(&mut conn, store_id, &items).unwrap();
+        assert_eq!(anomaly::max_version_jump(&current, &items), 2_000);
+        clear_database(&state);
+
+        let detector = AnomalyDetector::new(Thresholds {
+            writes_per_min: 10,
+            deletes_per_min: 10,
+            version_jump: 100,
+        });
+        for _ in 0..3 {
+            let activity = WriteActivity {
+                writes: 4,
+                ..Default::default()
+            };
+            detector.record(store_id, activity);
+        }
+        let activity = WriteActivity {
+            writes: 1,
+            deletes: 10,
+            max_version_jump: 100,
+        };
+        detector.record("quiet_store_id", activity);
+
+        let alerts = detector.evaluate();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].store_id, store_id);
+        assert_eq!(alerts[0].writes, 12);
+        assert_eq!(alerts[0].reasons, vec!["12 writes in a minute"]);
+
+        // windows start over, and a store isn't alerted on twice in a row
+        assert!(detector.evaluate().is_empty());
+        let activity = WriteActivity {
+            writes: 1,
+            deletes: 50,
+            max_version_jump: 0,
+        };
+        detector.record(store_id, activity);
+        assert!(detector.evaluate().is_empty());
+
+        // RFC 4231 test case 2
+        assert_eq!(
+            anomaly::sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_version_regressions() {
+        let state = init_state();
+        let store_id = "regression_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(
+            schema::vss_version_regressions::table
+                .filter(schema::vss_version_regressions::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |key: &str, version: u64| KeyValue::new(key.to_string(), vec![1], version);
+
+        crate::routes::put_objects_impl(req(vec![kv("a", 10), kv("b", 3)]), None, None, &state)
+            .await
+            .unwrap();
+        // same or newer versions aren't regressions
+        crate::routes::put_objects_impl(req(vec![kv("a", 10), kv("b", 4)]), None, None, &state)
+            .await
+            .unwrap();
+        assert!(VersionRegression::list_for_store(&mut conn, store_id, 10)
+            .unwrap()
+            .is_empty());
+
+        crate::routes::put_objects_impl(
+            req(vec![kv("a", 7), kv("b", 1), kv("c", 0)]),
+            None,
+            Some("client"),
+            &state,
+        )
+        .await
+        .unwrap();
+
+        // the stale writes were recorded and not applied
+        let regressions = VersionRegression::list_for_store(&mut conn, store_id, 10).unwrap();
+        assert_eq!(regressions.len(), 2);
+        let a = regressions.iter().find(|r| r.key == "a").unwrap();
+        assert_eq!(a.client_id.as_deref(), Some("client"));
+        assert_eq!((a.attempted_version, a.current_version), (7, 10));
+        assert_eq!(a.delta, 3);
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.version, 10);
+
+        let since = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let stats = VersionRegression::stats(&mut conn, since, 1).unwrap();
+        assert!(stats.total >= 2);
+        assert!(stats.stores >= 1);
+        assert_eq!(stats.recent.len(), 1);
+
+        diesel::delete(
+            schema::vss_version_regressions::table
+                .filter(schema::vss_version_regressions::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_objects_invalid_key() {
+        // rejected keys fail the whole request with the offending key
+        let state = init_state();
+        let store_id = "key_policy_store_id";
+        let req = crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: vec![
+                KeyValue::new("ok".to_string(), vec![1], 0),
+                KeyValue::new("bad\u{7}".to_string(), vec![1], 0),
+            ],
+        };
+        let err = crate::routes::put_objects_impl(req, None, None, &state)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidRequest>().unwrap().key.as_deref(),
+            Some("bad\u{7}")
+        );
+        let ErrorResponse { status, body, json } =
+            crate::routes::handle_anyhow_error("put_objects", err);
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        assert!(json);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "INVALID_REQUEST");
+        assert_eq!(body["items"][0]["status"], "ok");
+        assert_eq!(body["items"][1]["status"], "invalid");
+        assert_eq!(
+            body["items"][1]["reason"],
+            "Key contains control characters"
+        );
+        assert_eq!(
+            body["field_errors"],
+            serde_json::json!([{
+                "field": "transaction_items[1].key",
+                "code": "invalid_characters",
+                "message": "Key contains control characters",
+            }])
+        );
+
+        let mut conn = state.db_pool.get().unwrap();
+        assert!(VssItem::get_item(&mut conn, None, store_id, "ok")
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_devices_invalid_store_id() {
+        // the policy applies to store ids from the request body, before any work is done
+        let mut state = init_state();
+        state.store_id_policy = StoreIdPolicy {
+            min_len: 66,
+            max_len: 66,
+            allowed: Some("0-9a-f".parse::<Charset>().unwrap()),
+            pubkey: true,
+        };
+        let res = crate::routes::list_devices(
+            None,
+            None,
+            axum::Extension(state),
+            axum::Extension(crate::access_log::AccessLog::default()),
+            crate::codec::Negotiated {
+                body: crate::routes::ListDevicesRequest {
+                    store_id: Some("junk".to_string()),
+                },
+                accept: crate::codec::Format::Json,
+            },
+        )
+        .await;
+        let ErrorResponse { status, body, .. } = res.err().unwrap();
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        let body: InvalidRequest = serde_json::from_str(&body).unwrap();
+        assert_eq!(body.store_id.as_deref(), Some("junk"));
+        assert_eq!(body.field_errors[0].field, "store_id");
+        assert_eq!(
+            body.field_errors[0].code,
+            crate::validation::FieldErrorCode::InvalidLength
+        );
+    }
+
+    #[test]
+    fn test_migrations_wait_for_lock() {
+        let state = init_state();
+        let (class, id) = MIGRATION_LOCK;
+
+        let mut holder = state.db_pool.get().unwrap();
+        sql_query("SELECT pg_advisory_lock(hashtext($1), $2)")
+            .bind::<Text, _>(class)
+            .bind::<Integer, _>(id)
+            .execute(&mut holder)
+            .unwrap();
+
+        let pool = state.db_pool.clone();
+        let started = std::time::Instant::now();
+        let waiter = std::thread::spawn(move || {
+            let mut conn = pool.get().unwrap();
+            let applied = run_migrations(&mut conn).unwrap();
+            (applied, started.elapsed())
+        });
+
+        std::thread::sleep(Duration::from_millis(1_500));
+        assert!(!waiter.is_finished());
+        sql_query("SELECT pg_advisory_unlock(hashtext($1), $2)")
+            .bind::<Text, _>(class)
+            .bind::<Integer, _>(id)
+            .execute(&mut holder)
+            .unwrap();
+
+        let (applied, waited) = waiter.join().unwrap();
+        assert!(applied.is_empty());
+        assert!(waited >= Duration::from_millis(1_500));
+    }
+
+    #[test]
+    fn test_job_leader_takeover() {
+        let state = init_state();
+        let conn = &mut state.db_pool.get().unwrap();
+        let job = "test_leader_job";
+        let term = Duration::from_millis(500);
+        JobLeader::resign(conn, job, "a").unwrap();
+        JobLeader::resign(conn, job, "b").unwrap();
+
+        let leader = JobLeader::try_lead(conn, job, "a", term).unwrap().unwrap();
+        assert_eq!(leader.holder, "a");
+        // only one instance leads at a time, and the leader can renew
+        assert!(JobLeader::try_lead(conn, job, "b", term).unwrap().is_none());
+        assert!(JobLeader::try_lead(conn, job, "a", term).unwrap().is_some());
+
+        // another instance takes over once the leader stops renewing
+        std::thread::sleep(Duration::from_millis(600));
+        let leader = JobLeader::try_lead(conn, job, "b", term).unwrap().unwrap();
+        assert_eq!(leader.holder, "b");
+        assert!(JobLeader::try_lead(conn, job, "a", term).unwrap().is_none());
+
+        // resigning hands over straight away
+        assert!(!JobLeader::resign(conn, job, "a").unwrap());
+        assert!(JobLeader::resign(conn, job, "b").unwrap());
+        assert!(JobLeader::try_lead(conn, job, "a", term).unwrap().is_some());
+        JobLeader::resign(conn, job, "a").unwrap();
+    }
+
+    #[test]
+    fn test_change_capture() {
+        let state = init_state();
+        let conn = &mut state.db_pool.get().unwrap();
+        let store_id = "test_change_capture";
+        let changes = |conn: &mut PgConnection| {
+            schema::vss_outbox::table
+                .filter(schema::vss_outbox::store_id.eq(store_id))
+                .order(schema::vss_outbox::id.asc())
+                .load::<ChangeEvent>(conn)
+                .unwrap()
+        };
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(conn)
+            .unwrap();
+        let stale: Vec<i64> = changes(conn).iter().map(|e| e.id).collect();
+        ChangeEvent::acknowledge(conn, &stale).unwrap();
+
+        // nothing is captured unless the connection asks for it
+        VssItem::put_item(conn, store_id, "uncaptured", &[1], 0).unwrap();
+        assert!(changes(conn).is_empty());
+
+        conn.batch_execute("SET vss.capture_changes = 'on'")
+            .unwrap();
+        VssItem::put_item(conn, store_id, "key", &[1], 0).unwrap();
+        VssItem::put_item(conn, store_id, "key", &[2], 1).unwrap();
+        // stale writes don't change anything, so aren't captured
+        VssItem::put_item(conn, store_id, "key", &[3], 0).unwrap();
+        for key in ["uncaptured", "key"] {
+            diesel::delete(vss_db::table.find((store_id, key)))
+                .execute(conn)
+                .unwrap();
+        }
+        conn.batch_execute("RESET vss.capture_changes").unwrap();
+
+        let events = changes(conn);
+        let summary: Vec<(&str, u64, &str)> = events
+            .iter()
+            .map(|e| (e.key.as_str(), e.version, e.op.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("key", 0, "put"),
+                ("key", 1, "put"),
+                ("uncaptured", 0, "delete"),
+                ("key", 1, "delete"),
+            ]
+        );
+
+        let ids: Vec<i64> = events.iter().map(|e| e.id).collect();
+        assert_eq!(ChangeEvent::acknowledge(conn, &ids).unwrap(), 4);
+        assert!(changes(conn).is_empty());
+    }
+
+    #[test]
+    fn test_mutation_log() {
+        let state = init_state();
+        let conn = &mut state.db_pool.get().unwrap();
+        let store_id = "test_mutation_log";
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(conn)
+            .unwrap();
+        diesel::delete(
+            schema::vss_mutation_log::table.filter(schema::vss_mutation_log::store_id.eq(store_id)),
+        )
+        .execute(conn)
+        .unwrap();
+        let logged = |conn: &mut PgConnection, after| {
+            Mutation::list_after(conn, after, Some(store_id), 100).unwrap()
+        };
+
+        // nothing is logged unless the connection asks for it
+        VssItem::put_item(conn, store_id, "unlogged", &[0], 0).unwrap();
+        assert!(logged(conn, MutationWatermark::default()).is_empty());
+
+        conn.batch_execute("SET vss.log_mutations = 'on'").unwrap();
+        VssItem::put_item(conn, store_id, "key", &[1], 0).unwrap();
+        VssItem::put_item(conn, store_id, "key", &[2], 1).unwrap();
+        // stale writes don't change anything, so aren't logged
+        VssItem::put_item(conn, store_id, "key", &[3], 0).unwrap();
+        diesel::delete(vss_db::table.find((store_id, "unlogged")))
+            .execute(conn)
+            .unwrap();
+
+        let mutations = logged(conn, MutationWatermark::default());
+        let summary: Vec<(&str, u64, &str, Option<Vec<u8>>)> = mutations
+            .iter()
+            .map(|m| {
+                (
+                    m.key.as_str(),
+                    m.version,
+                    m.op.as_str(),
+                    m.value_hash.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("key", 0, "put", Some(Sha256::digest([1]).to_vec())),
+                ("key", 1, "put", Some(Sha256::digest([2]).to_vec())),
+                ("unlogged", 0, "delete", None),
+            ]
+        );
+        let watermark = mutations[0].watermark();
+        assert_eq!(
+            watermark.to_string().parse::<MutationWatermark>().unwrap(),
+            watermark
+        );
+        assert_eq!(logged(conn, watermark), mutations[1..]);
+
+        // a transaction still open holds back everything logged after it
+        // started, so it can't show up behind mutations already read
+        let last = mutations.last().unwrap().watermark();
+        let mut open = state.db_pool.get().unwrap();
+        open.transaction::<_, anyhow::Error, _>(|open| {
+            open.batch_execute("SET LOCAL vss.log_mutations = 'on'")?;
+            sql_query("SELECT pg_current_xact_id()").execute(open)?;
+            VssItem::put_item(conn, store_id, "key", &[4], 2)?;
+            assert!(logged(conn, last).is_empty());
+            VssItem::put_item(open, store_id, "open", &[5], 0)?;
+            Ok(())
+        })
+        .unwrap();
+        let keys: Vec<String> = logged(conn, last).into_iter().map(|m| m.key).collect();
+        assert_eq!(keys, vec!["open", "key"]);
+        conn.batch_execute("RESET vss.log_mutations").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_fixtures() {
+        let state = init_state();
+        let store_id = "test_load_fixtures";
+        let file = format!(
+            r#"{{"store_id":"{store_id}","items":[{{"key":"a","value":"AQI=","version":7,"created_date":"2024-01-02T03:04:05","metadata":{{"label":"demo"}},"content_type":"application/json"}},{{"key":"gone","deleted":true,"version":3}}]}}
+{{"version":1,"store":{{"store_id":"test_load_fixtures_2"}},"items":[]}}"#
+        );
+        let stores = parse_fixtures(std::path::Path::new("fixtures.ndjson"), &file).unwrap();
+        assert_eq!(stores.len(), 2);
+        assert!(parse_fixtures(std::path::Path::new("fixtures.json"), &file).is_err());
+        let mut fixtures = Fixtures {
+            path: "fixtures.ndjson".into(),
+            mode: FixtureMode::CopyOnWrite,
+            stores,
+            overwrite: false,
+        };
+        assert!(fixtures.read_only_stores().is_empty());
+
+        // a store that wasn't loaded from fixtures is only replaced when asked
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[9], 20).unwrap();
+        assert!(load_fixtures(&fixtures, &state).await.is_err());
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(vec![9]));
+        fixtures.overwrite = true;
+        assert_eq!(load_fixtures(&fixtures, &state).await.unwrap(), 2);
+
+        // after that, whatever clients wrote is replaced on every load
+        fixtures.overwrite = false;
+        VssItem::put_item(&mut conn, store_id, "a", &[9], 20).unwrap();
+        VssItem::put_item(&mut conn, store_id, "extra", &[9], 0).unwrap();
+        assert_eq!(load_fixtures(&fixtures, &state).await.unwrap(), 2);
+
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(vec![1, 2]));
+        assert_eq!(item.version, 7);
+        let date =
+            NaiveDateTime::parse_from_str("2024-01-02 03:04:05", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!((item.created_date(), item.updated_date()), (date, date));
+        assert_eq!(item.metadata, Some(serde_json::json!({"label": "demo"})));
+        assert_eq!(item.content_type.as_deref(), Some("application/json"));
+        let gone = VssItem::get_item(&mut conn, None, store_id, "gone")
+            .unwrap()
+            .unwrap();
+        assert_eq!((gone.value, gone.version), (None, 3));
+        assert!(VssItem::get_item(&mut conn, None, store_id, "extra")
+            .unwrap()
+            .is_none());
+
+        fixtures.mode = FixtureMode::ReadOnly;
+        assert_eq!(
+            fixtures.read_only_stores(),
+            [store_id, "test_load_fixtures_2"]
+                .map(String::from)
+                .into_iter()
+                .collect()
+        );
+
+        diesel::delete(schema::vss_fixture_stores::table)
+            .execute(&mut conn)
+            .unwrap();
+        clear_database(&state);
+    }
+
+    #[test]
+    fn test_write_history() {
+        let state = init_state();
+        let conn = &mut state.db_pool.get().unwrap();
+        let store_id = "test_write_history";
+        let versions = |conn: &mut PgConnection| -> Vec<(u64, Option<Vec<u8>>)> {
+            HistoryEntry::list_for_key(conn, store_id, "key")
+                .unwrap()
+                .into_iter()
+                .map(|e| (e.version, e.value))
+                .collect()
+        };
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(conn)
+            .unwrap();
+        diesel::delete(
+            schema::vss_history::table.filter(schema::vss_history::store_id.eq(store_id)),
+        )
+        .execute(conn)
+        .unwrap();
+
+        // nothing is kept unless the connection asks for it
+        VssItem::put_item(conn, store_id, "key", &[0], 0).unwrap();
+        VssItem::put_item(conn, store_id, "key", &[1], 1).unwrap();
+        assert!(versions(conn).is_empty());
+
+        conn.batch_execute("SET vss.keep_history = 'on'; SET vss.chunk_size = 2")
+            .unwrap();
+        VssItem::put_item(conn, store_id, "key", &[2, 2, 2, 2, 2], 2).unwrap();
+        // chunked values are archived whole
+        VssItem::put_item(conn, store_id, "key", &[3], 3).unwrap();
+        // stale writes don't change anything, so aren't kept
+        VssItem::put_item(conn, store_id, "key", &[9], 0).unwrap();
+        diesel::delete(vss_db::table.find((store_id, "key")))
+            .execute(conn)
+            .unwrap();
+        conn.batch_execute("RESET vss.keep_history; RESET vss.chunk_size")
+            .unwrap();
+        assert_eq!(
+            versions(conn),
+            vec![
+                (1, Some(vec![1])),
+                (2, Some(vec![2, 2, 2, 2, 2])),
+                (3, Some(vec![3])),
+            ]
+        );
+
+        // only the newest versions of each key are kept
+        assert!(HistoryEntry::prune(conn, Some(2), None, 1_000).unwrap() >= 1);
+        assert_eq!(
+            versions(conn).iter().map(|v| v.0).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        // and only for so long
+        diesel::update(
+            schema::vss_history::table
+                .filter(schema::vss_history::store_id.eq(store_id))
+                .filter(schema::vss_history::version.eq(2)),
+        )
+        .set(
+            schema::vss_history::superseded_at
+                .eq(diesel::dsl::now - diesel::dsl::IntervalDsl::days(10)),
+        )
+        .execute(conn)
+        .unwrap();
+        assert!(HistoryEntry::prune(conn, Some(2), Some(7), 1_000).unwrap() >= 1);
+        assert_eq!(
+            versions(conn).iter().map(|v| v.0).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        // the last version goes with the store
+        VssStore::erase(conn, store_id).unwrap();
+        assert!(versions(conn).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_object_as_of() {
+        use crate::routes::{get_object_v3_impl, GetObjectRequest};
+
+        let mut state = init_state();
+        let store_id = "test_get_object_as_of";
+        let conn = &mut state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(conn)
+            .unwrap();
+        let now = |conn: &mut PgConnection| {
+            diesel::select(diesel::dsl::now)
+                .get_result::<NaiveDateTime>(conn)
+                .unwrap()
+        };
+
+        conn.batch_execute("SET vss.keep_history = 'on'").unwrap();
+        let before = now(conn);
+        VssItem::put_item(conn, store_id, "key", &[0], 0).unwrap();
+        let first = now(conn);
+        VssItem::put_item(conn, store_id, "key", &[1], 1).unwrap();
+        let second = now(conn);
+        VssItem::delete_by_prefix(conn, store_id, "key", false).unwrap();
+        conn.batch_execute("RESET vss.keep_history").unwrap();
+
+        let get = |as_of: Option<NaiveDateTime>| GetObjectRequest {
+            store_id: Some(store_id.to_string()),
+            key: "key".to_string(),
+            as_of,
+        };
+        let err = get_object_v3_impl(get(Some(first)), &state)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+
+        state.history = Some(Default::default());
+        let read = |as_of: Option<NaiveDateTime>| {
+            let state = state.clone();
+            async move {
+                get_object_v3_impl(get(as_of), &state)
+                    .await
+                    .unwrap()
+                    .map(|o| (o.value.map(|v| v.0), o.version))
+            }
+        };
+        assert_eq!(read(Some(before)).await, None);
+        assert_eq!(read(Some(first)).await, Some((Some(vec![0]), 0)));
+        assert_eq!(read(Some(second)).await, Some((Some(vec![1]), 1)));
+        assert_eq!(read(None).await, Some((None, 2)));
+
+        VssStore::erase(conn, store_id).unwrap();
+    }
+
+    #[test]
+    fn test_partition_vss_db() {
+        dotenv::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        // migrate a copy of the tables in their own schema, with a connection
+        // outside the pool so the search_path doesn't leak into other tests
+        let conn = &mut PgConnection::establish(&url).unwrap();
+        init_state();
+        conn.batch_execute(
+            "DROP SCHEMA IF EXISTS partition_test CASCADE; \
+             CREATE SCHEMA partition_test; \
+             SET search_path = partition_test, public; \
+             CREATE TABLE vss_db (LIKE public.vss_db INCLUDING ALL); \
+             CREATE TABLE vss_chunks (LIKE public.vss_chunks INCLUDING ALL, \
+                 FOREIGN KEY (store_id, key) REFERENCES vss_db (store_id, key) ON DELETE CASCADE); \
+             CREATE TRIGGER tr_set_dates_after_update BEFORE UPDATE ON vss_db \
+                 FOR EACH ROW EXECUTE FUNCTION set_updated_date();",
+        )
+        .unwrap();
+        let insert = |conn: &mut PgConnection, store_id: &str, key: &str, version: i64| {
+            sql_query(
+                "INSERT INTO vss_db (store_id, key, value, version) VALUES ($1, $2, '\\x01', $3)",
+            )
+            .bind::<Text, _>(store_id)
+            .bind::<Text, _>(key)
+            .bind::<BigInt, _>(version)
+            .execute(conn)
+            .unwrap();
+        };
+
+        insert(conn, "a", "k1", 1);
+        insert(conn, "a", "k2", 1);
+        insert(conn, "b", "k1", 1);
+        conn.batch_execute("INSERT INTO vss_chunks VALUES ('a', 'k1', 0, '\\x01')")
+            .unwrap();
+
+        partition::prepare(conn, 4).unwrap();
+        let status = partition::status(conn).unwrap();
+        assert!(status.in_progress && !status.partitioned);
+
+        // writes during the conversion are mirrored, before and after copying
+        insert(conn, "a", "k3", 1);
+        assert_eq!(partition::list_stores(conn).unwrap(), vec!["a", "b"]);
+        assert_eq!(partition::copy_store(conn, "a").unwrap(), 3);
+        assert_eq!(partition::copy_store(conn, "b").unwrap(), 1);
+        insert(conn, "c", "k1", 1);
+        conn.batch_execute(
+            "UPDATE vss_db SET version = 2 WHERE store_id = 'b'; \
+             DELETE FROM vss_db WHERE store_id = 'a' AND key = 'k2';",
+        )
+        .unwrap();
+
+        partition::swap(conn).unwrap();
+        let status = partition::status(conn).unwrap();
+        assert_eq!(
+            status,
+            PartitionStatus {
+                partitioned: true,
+                partitions: 4,
+                in_progress: false,
+                unpartitioned_table: true,
+            }
+        );
+
+        let rows = vss_db::table
+            .select((vss_db::store_id, vss_db::key, vss_db::version))
+            .order((vss_db::store_id, vss_db::key))
+            .load::<(String, String, i64)>(conn)
+            .unwrap();
+        let expected = [
+            ("a", "k1", 1),
+            ("a", "k3", 1),
+            ("b", "k1", 2),
+            ("c", "k1", 1),
+        ];
+        assert_eq!(
+            rows,
+            expected
+                .iter()
+                .map(|(s, k, v)| (s.to_string(), k.to_string(), *v))
+                .collect::<Vec<_>>()
+        );
+
+        // triggers and foreign keys now belong to the partitioned table
+        conn.batch_execute("UPDATE vss_db SET updated_date = '2000-01-01' WHERE store_id = 'c'")
+            .unwrap();
+        let updated = vss_db::table
+            .filter(vss_db::store_id.eq("c"))
+            .select(vss_db::updated_date)
+            .first::<NaiveDateTime>(conn)
+            .unwrap();
+        assert!(updated.format("%Y").to_string() != "2000");
+        assert!(conn
+            .batch_execute("INSERT INTO vss_chunks VALUES ('a', 'k2', 0, '\\x01')")
+            .is_err());
+        conn.batch_execute("DELETE FROM vss_db WHERE store_id = 'a' AND key = 'k1'")
+            .unwrap();
+        let chunks: i64 = schema::vss_chunks::table.count().get_result(conn).unwrap();
+        assert_eq!(chunks, 0);
+
+        assert!(partition::prepare(conn, 4).is_err());
+        conn.batch_execute("DROP SCHEMA partition_test CASCADE")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_last_modified_by() {
+        let state = init_state();
+        let store_id = "writer_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        // writes are attributed to the token when no device uses it
+        let req = crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: vec![
+                KeyValue::new("a".to_string(), vec![1], 0),
+                KeyValue::new("b".to_string(), vec![2], 0),
+            ],
+        };
+        crate::routes::put_objects_impl(req, None, Some("aa"), &state)
+            .await
+            .unwrap();
+
+        // and to the device when one registered with it
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Device::register(conn, store_id, "phone", None, Some("bb"))?;
+            let writer = Device::writer(conn, store_id, "bb")?;
+            VssItem::set_writer(conn, &writer)?;
+            VssItem::put_item(conn, store_id, "b", &[3], 1)
+        })
+        .unwrap();
+
+        // writes without a token don't record a writer
+        VssItem::put_item(&mut conn, store_id, "c", &[4], 0).unwrap();
+
+        let req = crate::routes::DeleteByPrefixRequest {
+            store_id: Some(store_id.to_string()),
+            key_prefix: "a".to_string(),
+            dry_run: false,
+        };
+        crate::routes::delete_by_prefix_impl(req, Some("cc"), &state)
+            .await
+            .unwrap();
+
+        let keys = VssItem::list_key_metadata(&mut conn, store_id, None, true).unwrap();
+        let writers: Vec<_> = keys
+            .iter()
+            .map(|k| (k.key.as_str(), k.deleted, k.last_modified_by.as_deref()))
+            .collect();
+        assert_eq!(
+            writers,
+            vec![
+                ("a", true, Some("token:cc")),
+                ("b", false, Some("device:phone")),
+                ("c", false, None),
+            ]
+        );
+        let live = VssItem::list_key_metadata(&mut conn, store_id, None, false).unwrap();
+        assert_eq!(live.len(), 2);
+
+        let req = crate::routes::GetObjectRequest {
+            store_id: Some(store_id.to_string()),
+            key: "a".to_string(),
+            as_of: None,
+        };
+        let object = crate::routes::get_object_v3_impl(req, &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(object.deleted);
+        assert!(object.value.is_none());
+        assert_eq!(object.last_modified_by.as_deref(), Some("token:cc"));
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(
+            schema::vss_devices::table.filter(schema::vss_devices::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_changed_keys() {
+        let state = init_state();
+        let store_id = "changed_keys_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 0).unwrap();
+
+        let req = |since| crate::routes::ListChangedKeysRequest {
+            store_id: Some(store_id.to_string()),
+            since,
+        };
+        let all = crate::routes::list_changed_keys_impl(req(None), &state)
+            .await
+            .unwrap();
+        assert_eq!(all.keys.len(), 2);
+
+        // later writes and deletes are listed after the watermark, even
+        // imported ones dated long before it
+        VssItem::put_item(&mut conn, store_id, "c", &[3], 0).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "b", false).unwrap();
+        let old =
+            NaiveDateTime::parse_from_str("2020-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        VssItem::put_item_with_dates(&mut conn, store_id, "d", &[4], 0, old, old).unwrap();
+        let changed = crate::routes::list_changed_keys_impl(req(Some(all.watermark)), &state)
+            .await
+            .unwrap();
+        let keys: Vec<_> = changed
+            .keys
+            .iter()
+            .map(|k| (k.key.as_str(), k.deleted))
+            .collect();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.contains(&("b", true)));
+        assert!(keys.contains(&("c", false)));
+        assert!(keys.contains(&("d", false)));
+        assert!(changed.watermark > all.watermark);
+
+        let none = crate::routes::list_changed_keys_impl(req(Some(changed.watermark)), &state)
+            .await
+            .unwrap();
+        assert!(none.keys.is_empty());
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_diff_manifest() {
+        use crate::manifest::{diff_manifest_impl, manifest_hash, DiffManifestRequest};
+
+        let state = init_state();
+        let store_id = "manifest_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 2).unwrap();
+        VssItem::put_item(&mut conn, store_id, "c", &[3], 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "d", &[4], 0).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "c", false).unwrap();
+
+        let kv = |key: &str, version| KeyVersion {
+            key: key.to_string(),
+            version,
+        };
+        let req = |manifest, manifest_hash| DiffManifestRequest {
+            store_id: Some(store_id.to_string()),
+            key_prefix: None,
+            manifest,
+            manifest_hash,
+        };
+
+        let manifest = vec![kv("a", 1), kv("b", 1), kv("c", 0)];
+        let diff = diff_manifest_impl(req(Some(manifest), None), &state)
+            .await
+            .unwrap();
+        assert!(!diff.in_sync);
+        let missing: Vec<_> = diff.missing.iter().map(|k| k.key.as_str()).collect();
+        assert_eq!(missing, vec!["d"]);
+        let stale: Vec<_> = diff
+            .stale
+            .iter()
+            .map(|k| (k.key.as_str(), k.version, k.deleted))
+            .collect();
+        assert_eq!(stale, vec![("b", 2, false), ("c", 1, true)]);
+
+        // a client that has caught up matches by hash alone
+        let manifest = vec![kv("b", 2), kv("a", 1), kv("d", 0)];
+        let hash = manifest_hash(manifest.iter().map(|k| (k.key.as_str(), k.version)));
+        assert_eq!(hash, diff.manifest_hash);
+        let diff = diff_manifest_impl(req(None, Some(hash)), &state)
+            .await
+            .unwrap();
+        assert!(diff.in_sync);
+        let diff = diff_manifest_impl(req(Some(manifest), None), &state)
+            .await
+            .unwrap();
+        assert!(diff.in_sync);
+
+        let diff = diff_manifest_impl(req(None, Some("00".to_string())), &state)
+            .await
+            .unwrap();
+        assert!(!diff.in_sync);
+        assert!(diff_manifest_impl(req(None, None), &state).await.is_err());
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_digest() {
+        use crate::manifest::{get_store_digest_impl, merkle_root, StoreDigestRequest};
+        use sha2::{Digest, Sha256};
+
+        let state = init_state();
+        let store_id = "digest_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = || StoreDigestRequest {
+            store_id: Some(store_id.to_string()),
+            key_prefix: None,
+        };
+        let empty = get_store_digest_impl(req(), &state).await.unwrap();
+        assert_eq!(empty.keys, 0);
+        assert_eq!(empty.root, hex::encode(Sha256::digest([])));
+
+        let big: Vec<u8> = (0..10).collect();
+        conn.batch_execute("SET vss.chunk_size = 4").unwrap();
+        VssItem::put_item(&mut conn, store_id, "big", &big, 2).unwrap();
+        conn.batch_execute("RESET vss.chunk_size").unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 5).unwrap();
+        VssItem::put_item(&mut conn, store_id, "deleted", &[3], 0).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "deleted", false).unwrap();
+
+        // what a client holding the same items computes
+        let leaf = |key: &str, version, value: &[u8]| {
+            (key.to_string(), version, Sha256::digest(value).to_vec())
+        };
+        let expected = hex::encode(merkle_root(vec![
+            leaf("b", 5, &[2]),
+            leaf("a", 0, &[1]),
+            leaf("big", 2, &big),
+        ]));
+        let digest = get_store_digest_impl(req(), &state).await.unwrap();
+        assert_eq!(digest.keys, 3);
+        assert_eq!(digest.root, expected);
+
+        // items written before hashes were recorded are hashed on read
+        diesel::update(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .set(vss_db::value_hash.eq(None::<Vec<u8>>))
+            .execute(&mut conn)
+            .unwrap();
+        let digest = get_store_digest_impl(req(), &state).await.unwrap();
+        assert_eq!(digest.root, expected);
+
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 1).unwrap();
+        let digest = get_store_digest_impl(req(), &state).await.unwrap();
+        assert_ne!(digest.root, expected);
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_shard_routing() {
+        use crate::shard::{Shard, Shards};
+
+        let state = init_state();
+        let shards = |names: &[&str]| {
+            Shards::new(
+                names
+                    .iter()
+                    .map(|name| Shard {
+                        name: name.to_string(),
+                        pool: state.db_pool.clone(),
+                    })
+                    .collect(),
+            )
+        };
+        assert!(shards(&[]).is_err());
+        assert!(shards(&["a", "a"]).is_err());
+
+        let two = shards(&["a", "b"]).unwrap();
+        let reordered = shards(&["b", "a"]).unwrap();
+        let three = shards(&["a", "b", "c"]).unwrap();
+
+        let mut counts = HashMap::new();
+        for i in 0..1_000 {
+            let store_id = format!("store-{i}");
+            let shard = &two.for_store(&store_id).name;
+            *counts.entry(shard.clone()).or_insert(0) += 1;
+
+            assert_eq!(&reordered.for_store(&store_id).name, shard);
+            // a new shard only takes stores, it never moves them between
+            // the existing ones
+            let moved = &three.for_store(&store_id).name;
+            assert!(moved == shard || moved == "c");
+        }
+        assert!(counts.values().all(|n| *n > 300), "{counts:?}");
+    }
+
+    #[tokio::test]
+    async fn test_put_objects_returns_stored() {
+        let state = init_state();
+        let store_id = "put_returning_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |key: &str, version: u64| KeyValue::new(key.to_string(), vec![1], version);
+
+        let stored =
+            crate::routes::put_objects_impl(req(vec![kv("b", 3), kv("a", 1)]), None, None, &state)
+                .await
+                .unwrap();
+        assert_eq!(stored.len(), 2);
+        for (row, key) in stored.iter().zip(["b", "a"]) {
+            let item = VssItem::get_item(&mut conn, None, store_id, key)
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.key, key);
+            assert_eq!(row.version, item.version);
+            assert_eq!(row.created_date, item.created_date);
+            assert_eq!(row.updated_date, item.updated_date);
+        }
+
+        // a stale item shows the version that is actually stored
+        let stored =
+            crate::routes::put_objects_impl(req(vec![kv("b", 2), kv("a", 2)]), None, None, &state)
+                .await
+                .unwrap();
+        assert_eq!(stored[0].version, 3);
+        assert_eq!(stored[1].version, 2);
+
+        // replays return the rows without writing again
+        let items = || vec![kv("c", 1)];
+        let first = crate::routes::put_objects_impl(req(items()), Some("idem"), None, &state)
+            .await
+            .unwrap();
+        let replay = crate::routes::put_objects_impl(req(items()), Some("idem"), None, &state)
+            .await
+            .unwrap();
+        assert_eq!(first, replay);
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(
+            schema::vss_idempotency_keys::table
+                .filter(schema::vss_idempotency_keys::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_strict_versions() {
+        use crate::validation::{ItemStatus, VersionConflict};
+
+        let mut state = init_state();
+        state.strict_versions = true;
+        let store_id = "strict_versions_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |key: &str, version: u64| KeyValue::new(key.to_string(), vec![1], version);
+
+        crate::routes::put_objects_impl(req(vec![kv("a", 5)]), None, None, &state)
+            .await
+            .unwrap();
+
+        // the stale item rolls back the whole batch and is named
+        let err =
+            crate::routes::put_objects_impl(req(vec![kv("b", 1), kv("a", 4)]), None, None, &state)
+                .await
+                .unwrap_err();
+        let conflict = err.downcast_ref::<VersionConflict>().unwrap();
+        assert_eq!(conflict.key.as_deref(), Some("a"));
+        let statuses: Vec<ItemStatus> = conflict.items.iter().map(|i| i.status).collect();
+        assert_eq!(statuses, vec![ItemStatus::Ok, ItemStatus::Conflict]);
+        let res = crate::routes::handle_anyhow_error("put_objects", err);
+        assert_eq!(res.status, axum::http::StatusCode::CONFLICT);
+        assert!(VssItem::get_item(&mut conn, None, store_id, "b")
+            .unwrap()
+            .is_none());
+
+        // rewriting the same version is not a conflict
+        crate::routes::put_objects_impl(req(vec![kv("b", 1), kv("a", 5)]), None, None, &state)
+            .await
+            .unwrap();
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_object_not_found() {
+        use crate::routes::{get_object_v2_impl, GetObjectRequest, NoSuchKey};
+        use axum::response::IntoResponse;
+
+        let mut state = init_state();
+        let store_id = "get_object_not_found_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        VssItem::put_item(&mut conn, store_id, "live", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "gone", &[2], 1).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "gone", false).unwrap();
+
+        let req = |key: &str| GetObjectRequest {
+            store_id: Some(store_id.to_string()),
+            key: key.to_string(),
+            as_of: None,
+        };
+
+        // off by default, missing keys are null
+        assert!(get_object_v2_impl(req("missing"), &state)
+            .await
+            .unwrap()
+            .is_none());
+
+        state.get_object_not_found = true;
+        let item = get_object_v2_impl(req("live"), &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value.0, vec![1]);
+
+        let err = get_object_v2_impl(req("missing"), &state)
+            .await
+            .unwrap_err();
+        assert!(!err.downcast_ref::<NoSuchKey>().unwrap().deleted);
+        let res = crate::routes::handle_anyhow_error("get_object_v2", err).into_response();
+        assert_eq!(res.status(), axum::http::StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["content-type"], "application/json");
+
+        let err = get_object_v2_impl(req("gone"), &state).await.unwrap_err();
+        assert!(err.downcast_ref::<NoSuchKey>().unwrap().deleted);
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_behaviors() {
+        use crate::admin::set_store_behaviors_impl;
+        use crate::kv::NO_VERSION_CHECK;
+        use crate::routes::{
+            get_object_v2_impl, put_objects_impl, GetObjectRequest, NoSuchKey, PutObjectsRequest,
+        };
+
+        let mut state = init_state();
+        let store_id = "store_behaviors_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(
+            VssStore::get_behaviors(&mut conn, store_id).unwrap(),
+            StoreBehaviors::default()
+        );
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 1).unwrap();
+
+        let get = || GetObjectRequest {
+            store_id: Some(store_id.to_string()),
+            key: "missing".to_string(),
+            as_of: None,
+        };
+        let put = || PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: vec![KeyValue::new("a".to_string(), vec![2], NO_VERSION_CHECK)],
+        };
+
+        let pinned = StoreBehaviors {
+            v1_base64_encoding: Some("url-nopad".to_string()),
+            lazy_versions: Some(true),
+            null_for_missing: Some(true),
+        };
+        let store = set_store_behaviors_impl(store_id, pinned.clone(), &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.v1_base64_encoding.as_deref(), Some("url-nopad"));
+        assert_eq!(
+            VssStore::get_behaviors(&mut conn, store_id).unwrap(),
+            pinned
+        );
+
+        let invalid = StoreBehaviors {
+            v1_base64_encoding: Some("base64url".to_string()),
+            ..Default::default()
+        };
+        let err = set_store_behaviors_impl(store_id, invalid, &state)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+        assert!(set_store_behaviors_impl("no_such_store", pinned, &state)
+            .await
+            .unwrap()
+            .is_none());
+
+        // tightened defaults don't apply to the pinned store
+        state.get_object_not_found = true;
+        state.reject_lazy_versions = true;
+        assert_eq!(state.default_behaviors().lazy_versions, Some(false));
+        assert!(get_object_v2_impl(get(), &state).await.unwrap().is_none());
+        put_objects_impl(put(), None, None, &state).await.unwrap();
+
+        set_store_behaviors_impl(store_id, StoreBehaviors::default(), &state)
+            .await
+            .unwrap()
+            .unwrap();
+        let err = get_object_v2_impl(get(), &state).await.unwrap_err();
+        assert!(err.downcast_ref::<NoSuchKey>().is_some());
+        let err = put_objects_impl(put(), None, None, &state)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<InvalidRequest>().unwrap();
+        assert_eq!(err.field_errors[0].field, "transaction_items[0].version");
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(schema::vss_stores::table.filter(schema::vss_stores::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_values() {
+        use crate::routes::{patch_object_impl, put_objects_impl, PatchObjectRequest};
+
+        let mut state = init_state();
+        let store_id = "empty_values_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |key: &str, value: Vec<u8>| KeyValue::new(key.to_string(), value, 1);
+
+        let err = put_objects_impl(
+            req(vec![kv("a", vec![1]), kv("b", vec![])]),
+            None,
+            None,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        let err = err.downcast_ref::<InvalidRequest>().unwrap();
+        assert_eq!(err.key.as_deref(), Some("b"));
+        assert_eq!(err.field_errors[0].field, "transaction_items[1].value");
+        assert!(VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .is_none());
+
+        // a delta can't empty a value either
+        put_objects_impl(req(vec![kv("a", vec![1])]), None, None, &state)
+            .await
+            .unwrap();
+        let patch = || PatchObjectRequest {
+            store_id: Some(store_id.to_string()),
+            key: "a".to_string(),
+            base_version: 1,
+            delta: vec![],
+        };
+        let err = patch_object_impl(patch(), None, &state).await.unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(vec![1]));
+
+        state.allow_empty_values = true;
+        put_objects_impl(req(vec![kv("b", vec![])]), None, None, &state)
+            .await
+            .unwrap();
+        patch_object_impl(patch(), None, &state).await.unwrap();
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_versions() {
+        use crate::kv::NO_VERSION_CHECK;
+
+        let mut state = init_state();
+        state.strict_versions = true;
+        let store_id = "unsigned_versions_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        // -1 is the sentinel for signed clients, other negatives are invalid
+        let parse = |version: &str| {
+            serde_json::from_str::<KeyValue>(&format!(
+                r#"{{"key": "a", "value": [1], "version": {version}}}"#
+            ))
+        };
+        assert_eq!(parse("-1").unwrap().version, NO_VERSION_CHECK);
+        assert_eq!(parse("18446744073709551614").unwrap().version, u64::MAX - 1);
+        assert!(parse("-2").is_err());
+
+        // versions past i64::MAX still compare as newer
+        let big = i64::MAX as u64 + 1;
+        VssItem::put_item(&mut conn, store_id, "a", &[1], i64::MAX as u64).unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[2], big).unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[3], 5).unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!((item.value, item.version), (Some(vec![2]), big));
+
+        // the sentinel writes at the next version without conflicting
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |key: &str| KeyValue::new(key.to_string(), vec![4], NO_VERSION_CHECK);
+        let stored =
+            crate::routes::put_objects_impl(req(vec![kv("a"), kv("b")]), None, None, &state)
+                .await
+                .unwrap();
+        let versions: Vec<u64> = stored.iter().map(|s| s.version).collect();
+        assert_eq!(versions, vec![big + 1, 0]);
+
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_strict_json() {
+        use crate::codec::Negotiated;
+        use crate::routes::PutObjectsRequest;
+        use axum::extract::FromRequest;
+
+        let mut state = init_state();
+        let body = r#"{"storeId": "s", "transaction_items": [{"key": "a", "value": [1], "version": 0, "versoin": 1}]}"#;
+        let request = |state: &State| {
+            let mut req = axum::http::Request::builder()
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap();
+            req.extensions_mut().insert(state.clone());
+            req
+        };
+
+        // unknown fields are ignored by default
+        let req = Negotiated::<PutObjectsRequest>::from_request(request(&state), &())
+            .await
+            .unwrap();
+        assert!(req.body.store_id.is_none());
+
+        state.strict_json = true;
+        let Err(ErrorResponse { status, body, .. }) =
+            Negotiated::<PutObjectsRequest>::from_request(request(&state), &()).await
+        else {
+            panic!("unknown fields should be rejected");
+        };
+        assert_eq!(status, axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+        let err: InvalidRequest = serde_json::from_str(&body).unwrap();
+        let fields: Vec<&str> = err.field_errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["storeId", "transaction_items[0].versoin"]);
+        assert_eq!(
+            err.field_errors[0].message,
+            "Unknown field storeId, did you mean store_id?"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_item() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "patch_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "key", &[1, 2, 3, 4, 5], 3).unwrap();
+
+        let delta = [
+            DeltaOp::Copy { offset: 0, len: 2 },
+            DeltaOp::Insert(ByteData(vec![9, 9])),
+            DeltaOp::Copy { offset: 4, len: 1 },
+        ];
+        let kv = VssItem::patch_item(&mut conn, None, store_id, "key", 3, &delta).unwrap();
+        assert_eq!(kv.value.0, vec![1, 2, 9, 9, 5]);
+        assert_eq!(kv.version, 4);
+
+        let item = VssItem::get_item(&mut conn, None, store_id, "key")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value.unwrap(), vec![1, 2, 9, 9, 5]);
+        assert_eq!(item.version, 4);
+
+        // stale base versions, out of bounds copies and missing keys fail
+        assert!(VssItem::patch_item(&mut conn, None, store_id, "key", 3, &delta).is_err());
+        let out_of_bounds = [DeltaOp::Copy { offset: 3, len: 3 }];
+        assert!(VssItem::patch_item(&mut conn, None, store_id, "key", 4, &out_of_bounds).is_err());
+        assert!(VssItem::patch_item(&mut conn, None, store_id, "missing", 0, &delta).is_err());
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_value_chunks() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "chunks_store_id";
+        let value: Vec<u8> = (0..10).collect();
+        let chunk_count = |conn: &mut PgConnection, store_id: &str| {
+            vss_chunks::table
+                .filter(vss_chunks::store_id.eq(store_id))
+                .count()
+                .get_result::<i64>(conn)
+                .unwrap()
+        };
+
+        let mut conn = state.db_pool.get().unwrap();
+        conn.batch_execute("SET vss.chunk_size = 4").unwrap();
+
+        VssItem::put_item(&mut conn, store_id, "big", &value, 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "small", &[1, 2], 0).unwrap();
+        assert_eq!(chunk_count(&mut conn, store_id), 3);
+
+        // reads see the whole value
+        let item = VssItem::get_item(&mut conn, None, store_id, "big")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(value.clone()));
+        let items = VssItem::list_items(&mut conn, None, store_id, None, 10).unwrap();
+        assert_eq!(items[0].value, Some(value.clone()));
+        assert_eq!(items[1].value, Some(vec![1, 2]));
+
+        // stale writes keep the chunks, clones and copies carry them over
+        VssItem::put_item(&mut conn, store_id, "big", &[1], 0).unwrap();
+        assert_eq!(chunk_count(&mut conn, store_id), 3);
+        VssItem::clone_store_batch(&mut conn, store_id, "chunks_clone_id", None, 10).unwrap();
+        let item = VssItem::get_item(&mut conn, None, "chunks_clone_id", "big")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(value.clone()));
+        VssItem::copy_item(&mut conn, store_id, "big", "copy", false).unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "copy")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(value));
+
+        // overwriting with a small value or tombstoning drops the chunks
+        VssItem::put_item(&mut conn, store_id, "big", &[1], 1).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "copy", false).unwrap();
+        assert_eq!(chunk_count(&mut conn, store_id), 0);
+        let item = VssItem::get_item(&mut conn, None, store_id, "big")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(vec![1]));
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_clone_store_batch() {
+        let state = init_state();
+        clear_database(&state);
+
+        let from = "clone_from_store_id";
+        let to = "clone_to_store_id";
+        let value = [1, 2, 3];
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, from, "a", &value, 1).unwrap();
+        VssItem::put_item(&mut conn, from, "b", &value, 2).unwrap();
+        VssItem::put_item(&mut conn, from, "c", &value, 3).unwrap();
+        VssItem::delete_by_prefix(&mut conn, from, "c", false).unwrap();
+
+        assert!(!VssItem::store_exists(&mut conn, to).unwrap());
+
+        let batch = VssItem::clone_store_batch(&mut conn, from, to, None, 2).unwrap();
+        assert_eq!(batch, Some(("b".to_string(), 2)));
+        let batch = VssItem::clone_store_batch(&mut conn, from, to, Some("b"), 2).unwrap();
+        assert_eq!(batch, Some(("c".to_string(), 1)));
+        let batch = VssItem::clone_store_batch(&mut conn, from, to, Some("c"), 2).unwrap();
+        assert!(batch.is_none());
+
+        assert!(VssItem::store_exists(&mut conn, to).unwrap());
+
+        let mut versions = VssItem::list_key_versions(&mut conn, to, None).unwrap();
+        versions.sort();
+        assert_eq!(versions, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+
+        // tombstones are carried over with their versions
+        let item = VssItem::get_item(&mut conn, None, to, "c")
+            .unwrap()
+            .unwrap();
+        assert!(item.value.is_none());
+        assert_eq!(item.version, 4);
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_seed_store() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "seed_store_id";
+
+        let count = crate::seed::seed_store(store_id, 5, 100, 2, &state)
+            .await
+            .unwrap();
+        assert_eq!(count, Some(5));
+
+        let mut conn = state.db_pool.get().unwrap();
+        let versions = VssItem::list_key_versions(&mut conn, store_id, Some("seed/")).unwrap();
+        assert_eq!(versions.len(), 5);
+
+        let item = VssItem::get_item(&mut conn, None, store_id, "seed/00000004")
+            .unwrap()
+            .unwrap();
+        let value = crate::seed::seed_value(store_id, "seed/00000004", 100);
+        assert_eq!(item.value, Some(value));
+
+        // existing stores are left alone
+        let count = crate::seed::seed_store(store_id, 5, 100, 2, &state)
+            .await
+            .unwrap();
+        assert_eq!(count, None);
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_lock_store() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "lock_store_id";
+
+        // hold the lock while writing slowly from another connection
+        let mut conn = state.db_pool.get().unwrap();
+        let writer = std::thread::spawn(move || {
+            conn.transaction::<_, anyhow::Error, _>(|conn| {
+                VssItem::lock_store(conn, store_id)?;
+                std::thread::sleep(Duration::from_millis(500));
+                VssItem::put_item(conn, store_id, "a", &[1], 1)
+            })
+            .unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(100));
+
+        // waits for the writer to commit, so sees its write
+        let mut conn = state.db_pool.get().unwrap();
+        let item = conn
+            .transaction::<_, anyhow::Error, _>(|conn| {
+                VssItem::lock_store(conn, store_id)?;
+                VssItem::get_item(conn, None, store_id, "a")
+            })
+            .unwrap();
+        assert_eq!(item.map(|i| i.version), Some(1));
+
+        writer.join().unwrap();
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "idempotency_store_id";
+        let req = |value: Vec<u8>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: vec![KeyValue::new("a".to_string(), value, 0)],
+        };
+
+        crate::routes::put_objects_impl(req(vec![1]), Some("retry"), None, &state)
+            .await
+            .unwrap();
+        // a retry with the same key is not applied again
+        crate::routes::put_objects_impl(req(vec![1]), Some("retry"), None, &state)
+            .await
+            .unwrap();
+        // and a different request can't reuse it
+        let err = crate::routes::put_objects_impl(req(vec![2]), Some("retry"), None, &state)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<IdempotencyKeyReused>().is_some());
+
+        let mut conn = state.db_pool.get().unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.value, Some(vec![1]));
+
+        // keys are per store and expire after the window
+        let window = Duration::from_secs(60);
+        let hash = [7; 32];
+        assert!(
+            IdempotencyKey::claim(&mut conn, "other_store_id", "retry", &hash, window).unwrap()
+        );
+        assert!(
+            !IdempotencyKey::claim(&mut conn, "other_store_id", "retry", &hash, window).unwrap()
+        );
+        assert!(
+            IdempotencyKey::claim(&mut conn, "other_store_id", "retry", &[8; 32], window)
+                .unwrap_err()
+                .is::<IdempotencyKeyReused>()
+        );
+        assert!(
+            IdempotencyKey::claim(&mut conn, store_id, "retry", &hash, Duration::ZERO).unwrap()
+        );
+
+        diesel::delete(schema::vss_idempotency_keys::table)
+            .execute(&mut conn)
+            .unwrap();
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_store_metadata() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "metadata_test_store_id";
+        let value = [1, 2, 3];
+
+        let mut conn = state.db_pool.get().unwrap();
+        assert!(VssStore::get_store(&mut conn, store_id).unwrap().is_none());
+
+        VssItem::put_item(&mut conn, store_id, "key", &value, 0).unwrap();
+
+        let store = VssStore::get_store(&mut conn, store_id).unwrap().unwrap();
+        assert_eq!(store.store_id, store_id);
+        assert_eq!(store.label, None);
+        assert_eq!(store.flags, 0);
+
+        VssItem::put_item(&mut conn, store_id, "key", &value, 1).unwrap();
+        let touched = VssStore::get_store(&mut conn, store_id).unwrap().unwrap();
+        assert_eq!(touched.created_at, store.created_at);
+        assert!(touched.last_write_at >= store.last_write_at);
+
+        let updated = VssStore::update_store(&mut conn, store_id, Some("alice"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.label.as_deref(), Some("alice"));
+        assert_eq!(updated.flags, 0);
+
+        let updated = VssStore::update_store(&mut conn, store_id, None, Some(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.label.as_deref(), Some("alice"));
+        assert_eq!(updated.flags, 3);
+
+        assert!(
+            VssStore::update_store(&mut conn, "missing", Some("x"), None)
+                .unwrap()
+                .is_none()
+        );
+
+        let stores = VssStore::list_stores(&mut conn, None, 10).unwrap();
+        assert_eq!(stores.len(), 1);
+        let stores = VssStore::list_stores(&mut conn, Some(store_id), 10).unwrap();
+        assert!(stores.is_empty());
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_list_items() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "list_items_test_store_id";
+        let value = [1, 2, 3];
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &value, 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &value, 0).unwrap();
+        VssItem::put_item(&mut conn, store_id, "c", &value, 0).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "b", false).unwrap();
+
+        let items = VssItem::list_items(&mut conn, None, store_id, None, 10).unwrap();
+        let keys: Vec<_> = items.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "c"]);
+
+        let items = VssItem::list_items(&mut conn, None, store_id, Some("a"), 1).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].key, "c");
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_put_item_with_dates() {
+        let state = init_state();
+        clear_database(&state);
+
+        let store_id = "dates_test_store_id";
+        let key = "dates_test";
+        let value = [1, 2, 3];
+        let created =
+            NaiveDateTime::parse_from_str("2023-01-02 03:04:05", "%Y-%m-%d %H:%M:%S").unwrap();
+        let updated =
+            NaiveDateTime::parse_from_str("2023-06-07 08:09:10", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item_with_dates(&mut conn, store_id, key, &value, 0, created, updated)
+            .unwrap();
+
+        let item = VssItem::get_item(&mut conn, None, store_id, key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.created_date(), created);
+        assert_eq!(item.updated_date(), updated);
+
+        // normal writes afterwards are stamped with the current time again
+        VssItem::put_item(&mut conn, store_id, key, &value, 1).unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.created_date(), created);
+        assert!(item.updated_date() > updated);
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_validate_schema() {
+        let state = init_state();
+
+        let mut conn = state.db_pool.get().unwrap();
+        validate_schema(&mut conn).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_statement_timeout() {
+        dotenv::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let manager = ConnectionManager::<PgConnection>::new(url);
+        let db_pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(ConnectionOptions {
+                statement_timeout: Duration::from_millis(100),
+                idle_in_transaction_timeout: Duration::from_secs(1),
+                chunk_size: None,
+                capture_changes: false,
+                keep_history: false,
+                log_mutations: false,
+                transaction_pooling: false,
+            }))
+            .build(manager)
+            .expect("Could not build connection pool");
+
+        let mut conn = db_pool.get().unwrap();
+        assert!(sql_query("SELECT pg_sleep(1)").execute(&mut conn).is_err());
+        sql_query("SELECT pg_sleep(0.01)")
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transaction_pooling() {
+        dotenv::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let manager = ConnectionManager::<PgConnection>::new(url);
+        let options = ConnectionOptions {
+            statement_timeout: Duration::from_millis(100),
+            idle_in_transaction_timeout: Duration::from_secs(1),
+            chunk_size: Some(4),
+            capture_changes: false,
+            keep_history: true,
+            log_mutations: false,
+            transaction_pooling: true,
+        };
+        let db_pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(options))
+            .build(manager)
+            .expect("Could not build connection pool");
+
+        // nothing is set on the session
+        let mut conn = db_pool.get().unwrap();
+        sql_query("SELECT pg_sleep(0.2)")
+            .execute(&mut conn)
+            .unwrap();
+        for _ in 0..2 {
+            assert!(!VssItem::store_exists(&mut conn, "transaction_pooling").unwrap());
+        }
+
+        // the settings go on the role instead, rolled back to leave it as is
+        conn.test_transaction::<_, anyhow::Error, _>(|conn| {
+            options.apply_to_role(conn)?;
+            let settings = sql_query(
+                "SELECT array_to_string(setconfig, ',') AS setting FROM pg_db_role_setting \
+                 WHERE setdatabase = 0 AND setrole = (SELECT oid FROM pg_roles WHERE rolname = current_user)",
+            )
+            .get_result::<Setting>(conn)?
+            .setting;
+            assert!(settings.contains("statement_timeout=100"), "{settings}");
+            assert!(settings.contains("vss.chunk_size=4"), "{settings}");
+            assert!(!settings.contains("vss.capture_changes"), "{settings}");
+            assert!(settings.contains("vss.keep_history=on"), "{settings}");
+            assert!(!settings.contains("vss.log_mutations"), "{settings}");
+            Ok(())
+        });
+    }
+
+    #[tokio::test]
+    async fn test_item_metadata() {
+        use crate::routes::put_objects_impl;
+
+        let state = init_state();
+        let store_id = "item_metadata_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |version: u64, metadata: Option<serde_json::Value>| KeyValue {
+            metadata,
+            ..KeyValue::new("a".to_string(), vec![1], version)
+        };
+        let metadata = serde_json::json!({"app_version": "1.2.3", "device": "phone"});
+
+        put_objects_impl(req(vec![kv(1, Some(metadata.clone()))]), None, None, &state)
+            .await
+            .unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap()
+            .into_kv()
+            .unwrap();
+        assert_eq!(item.metadata, Some(metadata.clone()));
+        let listed = VssItem::list_key_metadata(&mut conn, store_id, None, false).unwrap();
+        assert_eq!(listed[0].metadata, Some(metadata.clone()));
+
+        // copies carry it, writes without any clear it
+        VssItem::copy_item(&mut conn, store_id, "a", "b", false).unwrap();
+        let copy = VssItem::get_item(&mut conn, None, store_id, "b")
+            .unwrap()
+            .unwrap();
+        assert_eq!(copy.metadata, Some(metadata));
+        put_objects_impl(req(vec![kv(2, None)]), None, None, &state)
+            .await
+            .unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.metadata, None);
+        let json = serde_json::to_string(&item.into_kv().unwrap()).unwrap();
+        assert!(!json.contains("metadata"));
+
+        for (metadata, code) in [
+            (
+                serde_json::json!("phone"),
+                crate::validation::FieldErrorCode::InvalidFormat,
+            ),
+            (
+                serde_json::json!({"note": "a".repeat(5_000)}),
+                crate::validation::FieldErrorCode::InvalidLength,
+            ),
+        ] {
+            let err = put_objects_impl(req(vec![kv(3, Some(metadata))]), None, None, &state)
+                .await
+                .unwrap_err();
+            let err = err.downcast_ref::<InvalidRequest>().unwrap();
+            assert_eq!(err.field_errors[0].field, "transaction_items[0].metadata");
+            assert_eq!(err.field_errors[0].code, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_type() {
+        use crate::routes::put_objects_impl;
+        use crate::validation::valid_content_type;
+
+        assert!(valid_content_type("application/json"));
+        assert!(valid_content_type(
+            "application/x-protobuf; proto=ChannelMonitor"
+        ));
+        assert!(!valid_content_type("json"));
+        assert!(!valid_content_type("text/plain\r\nX-Evil: 1"));
+        assert!(!valid_content_type(&format!("text/{}", "a".repeat(300))));
+
+        let state = init_state();
+        let store_id = "content_type_test_store_id";
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+            .execute(&mut conn)
+            .unwrap();
+
+        let req = |items: Vec<KeyValue>| crate::routes::PutObjectsRequest {
+            store_id: Some(store_id.to_string()),
+            global_version: None,
+            transaction_items: items,
+        };
+        let kv = |version: u64, content_type: &str| KeyValue {
+            content_type: Some(content_type.to_string()),
+            ..KeyValue::new("monitor".to_string(), vec![1, 2], version)
+        };
+
+        put_objects_impl(
+            req(vec![kv(1, "application/x-protobuf")]),
+            None,
+            None,
+            &state,
+        )
+        .await
+        .unwrap();
+        let item = VssItem::get_item(&mut conn, None, store_id, "monitor")
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.content_type.as_deref(), Some("application/x-protobuf"));
+
+        // patches keep it
+        let delta = [DeltaOp::Insert(ByteData(vec![3]))];
+        let patched = VssItem::patch_item(&mut conn, None, store_id, "monitor", 1, &delta).unwrap();
+        assert_eq!(
+            patched.content_type.as_deref(),
+            Some("application/x-protobuf")
+        );
+
+        let err = put_objects_impl(req(vec![kv(3, "protobuf")]), None, None, &state)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<InvalidRequest>().unwrap();
+        assert_eq!(
+            err.field_errors[0].field,
+            "transaction_items[0].content_type"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_orgs() {
+        use crate::org::{enforce_org_quota, get_org_stats_impl, OrgStatsQuery};
+
+        let state = init_state();
+        let (org_id, store_a, store_b) = ("org_test", "org_store_a", "org_store_b");
+
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(schema::vss_orgs::table)
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq_any([store_a, store_b])))
+            .execute(&mut conn)
+            .unwrap();
+
+        Org::save(&mut conn, org_id, Some("LSP"), Some(2), Some(10)).unwrap();
+        Org::save(&mut conn, "other_org", None, None, None).unwrap();
+        Org::add_store(&mut conn, org_id, store_a).unwrap();
+        Org::add_store(&mut conn, org_id, store_a).unwrap();
+        assert!(Org::add_store(&mut conn, "other_org", store_a).is_err());
+        Org::add_store(&mut conn, org_id, store_b).unwrap();
+        assert!(Org::add_store(&mut conn, org_id, "org_store_c").is_err());
+        assert_eq!(
+            Org::for_store(&mut conn, store_b).unwrap().unwrap().org_id,
+            org_id
+        );
+
+        // the byte limit is across every store of the org
+        enforce_org_quota(store_a, &state).await.unwrap();
+        VssItem::put_item(&mut conn, store_a, "a", &[0; 6], 1).unwrap();
+        VssItem::put_item(&mut conn, store_b, "b", &[0; 4], 1).unwrap();
+        let err = enforce_org_quota(store_a, &state).await.unwrap_err();
+        let err = err.downcast_ref::<QuotaExceeded>().unwrap();
+        assert_eq!((err.bytes, err.byte_limit), (10, Some(10)));
+        enforce_org_quota("org_store_c", &state).await.unwrap();
+
+        let stats = get_org_stats_impl(org_id, OrgStatsQuery { days: None }, &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.stores, 2);
+        assert_eq!(stats.usage, StoreUsage { keys: 2, bytes: 10 });
+
+        assert!(Org::remove_store(&mut conn, org_id, store_b).unwrap());
+        assert!(!Org::remove_store(&mut conn, org_id, store_b).unwrap());
+        enforce_org_quota(store_a, &state).await.unwrap();
+
+        diesel::delete(schema::vss_orgs::table)
+            .execute(&mut conn)
+            .unwrap();
+        diesel::delete(vss_db::table.filter(vss_db::store_id.eq_any([store_a, store_b])))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_aliases() {
+        use crate::routes::{resolve_store_id, set_store_alias_impl, SetStoreAliasRequest};
+        use crate::validation::valid_alias;
+
+        assert!(valid_alias("alice"));
+        assert!(valid_alias("lsp-user_42"));
+        assert!(!valid_alias("al"));
+        assert!(!valid_alias("Alice"));
+        assert!(!valid_alias("42alice"));
+        assert!(!valid_alias(&"a".repeat(33)));
+
+        let state = init_state();
+        clear_database(&state);
+        let set = |store_id: &str, alias: Option<&str>| {
+            set_store_alias_impl(
+                SetStoreAliasRequest {
+                    store_id: Some(store_id.to_string()),
+                    alias: alias.map(str::to_string),
+                },
+                &state,
+            )
+        };
+
+        // the store has to exist first
+        assert!(set("alias_store_a", Some("alice")).await.is_err());
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, "alias_store_a", "k", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, "alias_store_b", "k", &[1], 1).unwrap();
+
+        let alias = set("alias_store_a", Some("alice")).await.unwrap();
+        assert_eq!(alias.alias.as_deref(), Some("alice"));
+        assert_eq!(
+            resolve_store_id("@alice", &state).await.unwrap(),
+            "alias_store_a"
+        );
+        assert_eq!(
+            resolve_store_id("alias_store_b", &state).await.unwrap(),
+            "alias_store_b"
+        );
+        assert!(resolve_store_id("@bob", &state).await.is_err());
+
+        assert!(set("alias_store_b", Some("alice")).await.is_err());
+        assert!(set("alias_store_b", Some("Bob")).await.is_err());
+
+        // clearing frees it up
+        set("alias_store_a", None).await.unwrap();
+        set("alias_store_b", Some("alice")).await.unwrap();
+        assert_eq!(
+            resolve_store_id("@alice", &state).await.unwrap(),
+            "alias_store_b"
+        );
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_retention_rules() {
+        use crate::retention::*;
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "retention_store";
+        let set = |key_prefix: &str, max_age_days: i32| {
+            set_retention_rule_impl(
+                SetRetentionRuleRequest {
+                    store_id: Some(store_id.to_string()),
+                    key_prefix: key_prefix.to_string(),
+                    max_age_days,
+                },
+                &state,
+            )
+        };
+
+        let err = set("cache/", 0).await.unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+        set("cache/", 7).await.unwrap();
+        assert_eq!(set("cache/", 1).await.unwrap().max_age_days, 1);
+        for i in 1..MAX_RETENTION_RULES {
+            set(&format!("tmp{i}/"), 30).await.unwrap();
+        }
+        assert!(set("one/too/many", 30).await.is_err());
+        let rules = remove_retention_rule_impl(
+            RemoveRetentionRuleRequest {
+                store_id: Some(store_id.to_string()),
+                key_prefix: "tmp1/".to_string(),
+            },
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rules.len(), MAX_RETENTION_RULES - 1);
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "cache/old", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "cache/new", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "keep", &[1], 1).unwrap();
+        conn.batch_execute(
+            "BEGIN; \
+             SELECT set_config('vss.preserve_dates', 'on', true); \
+             UPDATE vss_db SET updated_date = updated_date - interval '2 days' \
+             WHERE store_id = 'retention_store' AND key IN ('cache/old', 'keep'); \
+             COMMIT;",
+        )
+        .unwrap();
+
+        crate::retention::apply_retention(state.clone()).await;
+        let live: Vec<String> = VssItem::list_items(&mut conn, None, store_id, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.key)
+            .collect();
+        assert_eq!(live, vec!["cache/new", "keep"]);
+
+        let rules = list_retention_rules_impl(
+            ListRetentionRulesRequest {
+                store_id: Some(store_id.to_string()),
+            },
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rules[0].key_prefix, "cache/");
+
+        diesel::delete(schema::vss_retention_rules::table)
+            .execute(&mut conn)
+            .unwrap();
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        use crate::snapshot::*;
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "snapshot_store";
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(
+            schema::vss_snapshots::table.filter(schema::vss_snapshots::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+        let create = |label: &str| {
+            create_snapshot_impl(
+                CreateSnapshotRequest {
+                    store_id: Some(store_id.to_string()),
+                    label: label.to_string(),
+                },
+                &state,
+            )
+        };
+        let request = |snapshot_id: &str| SnapshotRequest {
+            store_id: Some(store_id.to_string()),
+            snapshot_id: snapshot_id.to_string(),
+        };
+
+        conn.batch_execute("SET vss.chunk_size = 2").unwrap();
+        VssItem::put_item(&mut conn, store_id, "big", &[1, 2, 3, 4, 5], 1).unwrap();
+        conn.batch_execute("RESET vss.chunk_size").unwrap();
+        VssItem::put_item(&mut conn, store_id, "small", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "gone", &[1], 1).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "gone", false).unwrap();
+
+        let err = create("").await.unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().is_some());
+        let snapshot = create("before upgrade").await.unwrap();
+        assert_eq!(snapshot.items, 2);
+        assert_eq!(snapshot.size, 6);
+        assert!(create("before upgrade").await.is_err());
+
+        // later writes don't change the snapshot
+        VssItem::put_item(&mut conn, store_id, "small", &[2], 2).unwrap();
+        let bundle = download_snapshot_impl(request(&snapshot.snapshot_id), &state)
+            .await
+            .unwrap();
+        let items: Vec<(String, Option<String>, u64)> = bundle
+            .items
+            .into_iter()
+            .map(|i| (i.key, i.value, i.version))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                ("big".to_string(), Some(base64::encode([1, 2, 3, 4, 5])), 1),
+                ("small".to_string(), Some(base64::encode([1])), 1),
+            ]
+        );
+
+        let other = create("after upgrade").await.unwrap();
+        let snapshots = list_snapshots_impl(
+            ListSnapshotsRequest {
+                store_id: Some(store_id.to_string()),
+            },
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(snapshots.len(), 2);
+
+        let left = delete_snapshot_impl(request(&snapshot.snapshot_id), &state)
+            .await
+            .unwrap();
+        assert_eq!(left, vec![other]);
+        assert!(delete_snapshot_impl(request(&snapshot.snapshot_id), &state)
+            .await
+            .is_err());
+
+        VssStore::erase(&mut conn, store_id).unwrap();
+        assert!(Snapshot::list_for_store(&mut conn, store_id)
+            .unwrap()
+            .is_empty());
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_restore_snapshot() {
+        use crate::snapshot::*;
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "restore_store";
+        let mut conn = state.db_pool.get().unwrap();
+        diesel::delete(
+            schema::vss_snapshots::table.filter(schema::vss_snapshots::store_id.eq(store_id)),
+        )
+        .execute(&mut conn)
+        .unwrap();
+        let live = |conn: &mut PgConnection, store_id: &str| -> Vec<(String, Vec<u8>, u64)> {
+            VssItem::list_items(conn, None, store_id, None, 10)
+                .unwrap()
+                .into_iter()
+                .map(|i| (i.key, i.value.unwrap(), i.version))
+                .collect()
+        };
+        let restore = |new_store_id: Option<&str>, snapshot_id: &str| {
+            restore_snapshot_impl(
+                RestoreSnapshotRequest {
+                    store_id: Some(store_id.to_string()),
+                    snapshot_id: snapshot_id.to_string(),
+                    new_store_id: new_store_id.map(|s| s.to_string()),
+                },
+                &state,
+            )
+        };
+
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 3).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 1).unwrap();
+        let snapshot = create_snapshot_impl(
+            CreateSnapshotRequest {
+                store_id: Some(store_id.to_string()),
+                label: "good".to_string(),
+            },
+            &state,
+        )
+        .await
+        .unwrap();
+
+        VssItem::put_item(&mut conn, store_id, "a", &[9], 4).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "b", false).unwrap();
+        VssItem::put_item(&mut conn, store_id, "c", &[3], 0).unwrap();
+
+        // restored values are newer than anything clients have seen
+        let res = restore(None, &snapshot.snapshot_id).await.unwrap();
+        assert_eq!((res.restored, res.deleted), (2, 1));
+        assert_eq!(
+            live(&mut conn, store_id),
+            vec![("a".to_string(), vec![1], 5), ("b".to_string(), vec![2], 3)]
+        );
+
+        let res = restore(Some("restore_fork"), &snapshot.snapshot_id)
+            .await
+            .unwrap();
+        assert_eq!(res.store_id, "restore_fork");
+        assert_eq!(
+            live(&mut conn, "restore_fork"),
+            vec![("a".to_string(), vec![1], 0), ("b".to_string(), vec![2], 0)]
+        );
+        // forks only go into new stores
+        assert!(restore(Some("restore_fork"), &snapshot.snapshot_id)
+            .await
+            .is_err());
+        assert!(restore(Some(store_id), &snapshot.snapshot_id)
+            .await
+            .is_err());
+        assert!(restore(None, "missing").await.is_err());
+
+        VssStore::erase(&mut conn, store_id).unwrap();
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_forget_store() {
+        use crate::deletion::{
+            forget_store_impl, list_forget_receipts_impl, request_deletion_impl,
+        };
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "forgotten_store";
+        // receipts outlive clear_database
+        let before = list_forget_receipts_impl(store_id, &state)
+            .await
+            .unwrap()
+            .len();
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[1], 1).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[2], 1).unwrap();
+        Device::register(&mut conn, store_id, "phone", None, None).unwrap();
+        UsageDay::add(&mut conn, store_id, 1, 10, 20).unwrap();
+        Org::save(&mut conn, "forget_org", None, None, None).unwrap();
+        Org::add_store(&mut conn, "forget_org", store_id).unwrap();
+
+        // users can only forget stores they have asked to delete
+        assert!(forget_store_impl(store_id, Some("token"), &state)
+            .await
+            .is_err());
+        let deletion = request_deletion_impl(store_id, &state).await.unwrap();
+        assert!(forget_store_impl(store_id, Some("wrong"), &state)
+            .await
+            .is_err());
+        assert!(VssStore::get_store(&mut conn, store_id).unwrap().is_some());
+
+        let token = deletion.confirmation_token.unwrap();
+        let receipt = forget_store_impl(store_id, Some(&token), &state)
+            .await
+            .unwrap();
+        assert_eq!(receipt.requested_by, "user");
+        assert_eq!(receipt.items, 2);
+        assert_eq!(receipt.store_hash, ForgetReceipt::store_hash(store_id));
+
+        assert!(VssStore::get_store(&mut conn, store_id).unwrap().is_none());
+        assert!(
+            VssItem::list_items_with_tombstones(&mut conn, None, store_id, None, 10)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(Device::list_devices(&mut conn, store_id)
+            .unwrap()
+            .is_empty());
+        assert!(UsageDay::list_all(&mut conn, store_id).unwrap().is_empty());
+        assert!(Org::for_store(&mut conn, store_id).unwrap().is_none());
+
+        // admins don't need a pending deletion
+        VssItem::put_item(&mut conn, store_id, "c", &[3], 1).unwrap();
+        let receipt = forget_store_impl(store_id, None, &state).await.unwrap();
+        assert_eq!(receipt.requested_by, "admin");
+        assert_eq!(receipt.items, 1);
+
+        let receipts = list_forget_receipts_impl(store_id, &state).await.unwrap();
+        assert_eq!(receipts.len(), before + 2);
+        assert_eq!(receipts[0].requested_by, "admin");
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_store_export() {
+        use crate::data_export::{get_export_impl, start_export_impl, ExportBundle};
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "exported_store";
+
+        assert!(start_export_impl(store_id, &state).await.is_err());
+
+        let mut conn = state.db_pool.get().unwrap();
+        VssItem::put_item(&mut conn, store_id, "a", &[1, 2], 3).unwrap();
+        VssItem::put_item(&mut conn, store_id, "b", &[3], 1).unwrap();
+        VssItem::delete_by_prefix(&mut conn, store_id, "b", false).unwrap();
+        Device::register(&mut conn, store_id, "phone", Some("ios"), None).unwrap();
+
+        let ticket = start_export_impl(store_id, &state).await.unwrap();
+        assert_eq!(ticket.status, EXPORT_PENDING);
+        assert!(get_export_impl(&ticket.export_id, "wrong", &state)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut export = None;
+        for _ in 0..50 {
+            let found = get_export_impl(&ticket.export_id, &ticket.retrieval_token, &state)
+                .await
+                .unwrap()
+                .unwrap();
+            if found.status != EXPORT_PENDING {
+                export = Some(found);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let export = export.expect("export never finished");
+        assert_eq!(export.status, EXPORT_READY);
+
+        let bundle: ExportBundle = serde_json::from_slice(&export.bundle.unwrap()).unwrap();
+        assert_eq!(bundle.store.store_id, store_id);
+        assert_eq!(bundle.items.len(), 2);
+        assert_eq!(bundle.items[0].key, "a");
+        assert_eq!(bundle.items[0].value.as_deref(), Some("AQI="));
+        assert_eq!(bundle.items[0].version, 3);
+        assert!(bundle.items[1].deleted);
+        assert_eq!(bundle.items[1].value, None);
+        assert_eq!(bundle.devices.len(), 1);
+        assert_eq!(bundle.devices[0].device_id, "phone");
+
+        clear_database(&state);
+    }
+
+    #[tokio::test]
+    async fn test_store_deletion() {
+        use crate::deletion::{cancel_deletion_impl, purge_deleted_stores, request_deletion_impl};
+        use crate::routes::{put_objects_impl, PutObjectsRequest};
+
+        let state = init_state();
+        clear_database(&state);
+        let store_id = "deleted_store";
+        let put = |version: u64| {
+            put_objects_impl(
+                PutObjectsRequest {
+                    store_id: Some(store_id.to_string()),
+                    global_version: None,
+                    transaction_items: vec![KeyValue::new("k".to_string(), vec![1], version)],
+                },
+                None,
+                None,
+                &state,
+            )
+        };
+
+        // only existing stores can be deleted
+        assert!(request_deletion_impl(store_id, &state).await.is_err());
+        put(0).await.unwrap();
+
+        let deletion = request_deletion_impl(store_id, &state).await.unwrap();
+        assert!(deletion.purge_at.is_some());
+        let token = deletion.confirmation_token.unwrap();
+        // already scheduled
+        assert!(request_deletion_impl(store_id, &state).await.is_err());
+
+        let err = put(1).await.unwrap_err();
+        assert!(err.downcast_ref::<StorePendingDeletion>().is_some());
+
+        assert!(cancel_deletion_impl(store_id, Some("wrong"), &state)
+            .await
+            .is_err());
+        let cancelled = cancel_deletion_impl(store_id, Some(&token), &state)
+            .await
+            .unwrap();
+        assert_eq!(cancelled.purge_at, None);
+        put(1).await.unwrap();
+
+        // not purged during the grace period
+        request_deletion_impl(store_id, &state).await.unwrap();
+        purge_deleted_stores(state.clone()).await;
+        let mut conn = state.db_pool.get().unwrap();
+        assert!(VssItem::get_item(&mut conn, None, store_id, "k")
+            .unwrap()
+            .is_some());
+
+        cancel_deletion_impl(store_id, None, &state).await.unwrap();
+        VssStore::schedule_deletion(&mut conn, store_id, "hash", Duration::ZERO)
+            .unwrap()
+            .unwrap();
+        purge_deleted_stores(state.clone()).await;
+        assert!(VssItem::get_item(&mut conn, None, store_id, "k")
+            .unwrap()
+            .is_none());
+        assert!(VssStore::get_store(&mut conn, store_id).unwrap().is_none());
 
         clear_database(&state);
     }
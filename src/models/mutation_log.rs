@@ -0,0 +1,117 @@
+use super::schema::vss_mutation_log;
+use super::DbVersion;
+use anyhow::anyhow;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Integer};
+use std::fmt;
+use std::str::FromStr;
+use tracing::debug_span;
+use tracing::field::Empty;
+
+/// A committed change to an item, recorded in `vss_mutation_log` while the
+/// mutation log is enabled.
+#[derive(Queryable, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_mutation_log)]
+pub struct Mutation {
+    pub id: i64,
+    /// Transaction that made the change
+    pub txid: i64,
+    pub store_id: String,
+    pub key: String,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    /// `put`, or `delete` for tombstones and removed rows
+    pub op: String,
+    /// SHA-256 of the value written, None for deletes
+    pub value_hash: Option<Vec<u8>>,
+    /// Start of the transaction that made the change
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// A position in the mutation log, written as `<txid>.<id>`. Reading after
+/// it returns every mutation that comes later in the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MutationWatermark {
+    pub txid: i64,
+    pub id: i64,
+}
+
+impl fmt::Display for MutationWatermark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.txid, self.id)
+    }
+}
+
+impl FromStr for MutationWatermark {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .split_once('.')
+            .and_then(|(txid, id)| Some((txid.parse().ok()?, id.parse().ok()?)));
+        match parsed {
+            Some((txid, id)) => Ok(MutationWatermark { txid, id }),
+            None => Err(anyhow!("Invalid watermark {s:?}, expected <txid>.<id>")),
+        }
+    }
+}
+
+impl Mutation {
+    pub fn watermark(&self) -> MutationWatermark {
+        MutationWatermark {
+            txid: self.txid,
+            id: self.id,
+        }
+    }
+
+    /// Up to `limit` mutations after `after`, in log order. Only mutations
+    /// of transactions older than every one still open are returned, so no
+    /// mutation can later show up before the last one returned.
+    pub fn list_after(
+        conn: &mut PgConnection,
+        after: MutationWatermark,
+        store_id: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Mutation>> {
+        let span = debug_span!("vss.list_mutations", mutations = Empty).entered();
+
+        let oldest_open =
+            diesel::dsl::sql::<BigInt>("pg_snapshot_xmin(pg_current_snapshot())::TEXT::BIGINT");
+        let mut query = vss_mutation_log::table
+            .filter(
+                vss_mutation_log::txid
+                    .gt(after.txid)
+                    .or(vss_mutation_log::txid
+                        .eq(after.txid)
+                        .and(vss_mutation_log::id.gt(after.id))),
+            )
+            .filter(vss_mutation_log::txid.lt(oldest_open))
+            .order((vss_mutation_log::txid.asc(), vss_mutation_log::id.asc()))
+            .limit(limit)
+            .into_boxed();
+        if let Some(store_id) = store_id {
+            query = query.filter(vss_mutation_log::store_id.eq(store_id));
+        }
+
+        let res = query.load::<Mutation>(conn)?;
+        span.record("mutations", res.len());
+
+        Ok(res)
+    }
+
+    /// Deletes up to `limit` mutations logged more than `keep_days` ago,
+    /// returning how many were deleted.
+    pub fn prune(conn: &mut PgConnection, keep_days: i32, limit: i64) -> anyhow::Result<usize> {
+        let _span = debug_span!("vss.prune_mutation_log").entered();
+
+        Ok(sql_query(
+            "DELETE FROM vss_mutation_log WHERE id IN (SELECT id FROM vss_mutation_log \
+             WHERE created_at < CURRENT_TIMESTAMP - make_interval(days => $1) LIMIT $2)",
+        )
+        .bind::<Integer, _>(keep_days)
+        .bind::<BigInt, _>(limit)
+        .execute(conn)?)
+    }
+}
@@ -0,0 +1,53 @@
+use super::schema::vss_nostr_subscriptions;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The nostr pubkey, hex encoded, notified of a store's backups.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_nostr_subscriptions)]
+pub struct NostrSubscription {
+    pub store_id: String,
+    pub pubkey: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl NostrSubscription {
+    /// Sets the pubkey to notify for the store, replacing any previous one.
+    pub fn subscribe(
+        conn: &mut PgConnection,
+        store_id: &str,
+        pubkey: &str,
+    ) -> anyhow::Result<NostrSubscription> {
+        Ok(diesel::insert_into(vss_nostr_subscriptions::table)
+            .values((
+                vss_nostr_subscriptions::store_id.eq(store_id),
+                vss_nostr_subscriptions::pubkey.eq(pubkey),
+            ))
+            .on_conflict(vss_nostr_subscriptions::store_id)
+            .do_update()
+            .set((
+                vss_nostr_subscriptions::pubkey.eq(pubkey),
+                vss_nostr_subscriptions::created_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<Self>(conn)?)
+    }
+
+    pub fn unsubscribe(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(
+            vss_nostr_subscriptions::table.filter(vss_nostr_subscriptions::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+        Ok(deleted > 0)
+    }
+
+    pub fn get_subscription(
+        conn: &mut PgConnection,
+        store_id: &str,
+    ) -> anyhow::Result<Option<NostrSubscription>> {
+        Ok(vss_nostr_subscriptions::table
+            .filter(vss_nostr_subscriptions::store_id.eq(store_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+}
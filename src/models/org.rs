@@ -0,0 +1,159 @@
+use super::schema::{vss_org_stores, vss_orgs};
+use anyhow::anyhow;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// An organization managing many stores, e.g. an LSP's or wallet provider's
+/// users. Its limits apply across all of its stores.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_orgs)]
+pub struct Org {
+    pub org_id: String,
+    pub name: Option<String>,
+    /// Most stores the org can have, unlimited if None
+    pub max_stores: Option<i64>,
+    /// Most bytes the org's stores can hold together, unlimited if None
+    pub max_bytes: Option<i64>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// A store's membership of an org.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_org_stores)]
+pub struct OrgStore {
+    pub store_id: String,
+    pub org_id: String,
+    pub added_at: chrono::NaiveDateTime,
+}
+
+impl Org {
+    /// Creates an org, or replaces the name and limits of an existing one.
+    pub fn save(
+        conn: &mut PgConnection,
+        org_id: &str,
+        name: Option<&str>,
+        max_stores: Option<i64>,
+        max_bytes: Option<i64>,
+    ) -> anyhow::Result<Org> {
+        Ok(sql_query(
+            "INSERT INTO vss_orgs (org_id, name, max_stores, max_bytes) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (org_id) DO UPDATE \
+             SET name = excluded.name, max_stores = excluded.max_stores, \
+             max_bytes = excluded.max_bytes \
+             RETURNING *",
+        )
+        .bind::<Text, _>(org_id)
+        .bind::<Nullable<Text>, _>(name)
+        .bind::<Nullable<BigInt>, _>(max_stores)
+        .bind::<Nullable<BigInt>, _>(max_bytes)
+        .get_result::<Org>(conn)?)
+    }
+
+    pub fn get_org(conn: &mut PgConnection, org_id: &str) -> anyhow::Result<Option<Org>> {
+        Ok(vss_orgs::table
+            .filter(vss_orgs::org_id.eq(org_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    pub fn list_orgs(conn: &mut PgConnection) -> anyhow::Result<Vec<Org>> {
+        Ok(vss_orgs::table
+            .order(vss_orgs::org_id.asc())
+            .load::<Self>(conn)?)
+    }
+
+    /// The org `store_id` belongs to, if any.
+    pub fn for_store(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Option<Org>> {
+        let _span = debug_span!("vss.org_for_store", store_id).entered();
+
+        Ok(vss_org_stores::table
+            .inner_join(vss_orgs::table)
+            .filter(vss_org_stores::store_id.eq(store_id))
+            .select(vss_orgs::all_columns)
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Ids of the org's stores, in order.
+    pub fn list_store_ids(conn: &mut PgConnection, org_id: &str) -> anyhow::Result<Vec<String>> {
+        Ok(vss_org_stores::table
+            .filter(vss_org_stores::org_id.eq(org_id))
+            .order(vss_org_stores::store_id.asc())
+            .select(vss_org_stores::store_id)
+            .load::<String>(conn)?)
+    }
+
+    /// Adds a store to the org, failing if it belongs to another org or the
+    /// org already has as many stores as it is allowed. Adding a store
+    /// twice is a no-op.
+    pub fn add_store(
+        conn: &mut PgConnection,
+        org_id: &str,
+        store_id: &str,
+    ) -> anyhow::Result<OrgStore> {
+        let _span = debug_span!("vss.add_org_store", store_id).entered();
+
+        conn.transaction(|conn| {
+            // serializes adds so the store limit holds
+            let org = vss_orgs::table
+                .filter(vss_orgs::org_id.eq(org_id))
+                .for_update()
+                .first::<Self>(conn)
+                .optional()?
+                .ok_or_else(|| anyhow!("Org {org_id} not found"))?;
+
+            let existing = vss_org_stores::table
+                .filter(vss_org_stores::store_id.eq(store_id))
+                .first::<OrgStore>(conn)
+                .optional()?;
+            match existing {
+                Some(member) if member.org_id == org_id => return Ok(member),
+                Some(member) => {
+                    return Err(anyhow!(
+                        "Store {store_id} already belongs to org {}",
+                        member.org_id
+                    ))
+                }
+                None => {}
+            }
+
+            if let Some(max_stores) = org.max_stores {
+                let stores: i64 = vss_org_stores::table
+                    .filter(vss_org_stores::org_id.eq(org_id))
+                    .count()
+                    .get_result(conn)?;
+                if stores >= max_stores {
+                    return Err(anyhow!("Org {org_id} is limited to {max_stores} stores"));
+                }
+            }
+
+            Ok(diesel::insert_into(vss_org_stores::table)
+                .values((
+                    vss_org_stores::store_id.eq(store_id),
+                    vss_org_stores::org_id.eq(org_id),
+                ))
+                .get_result::<OrgStore>(conn)?)
+        })
+    }
+
+    /// Removes a store from the org, returning whether it was a member.
+    pub fn remove_store(
+        conn: &mut PgConnection,
+        org_id: &str,
+        store_id: &str,
+    ) -> anyhow::Result<bool> {
+        let removed = diesel::delete(
+            vss_org_stores::table
+                .filter(vss_org_stores::org_id.eq(org_id))
+                .filter(vss_org_stores::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+
+        Ok(removed > 0)
+    }
+}
@@ -0,0 +1,43 @@
+use super::schema::vss_outbox;
+use super::DbVersion;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// A committed change to an item, waiting in `vss_outbox` to be published.
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_outbox)]
+pub struct ChangeEvent {
+    /// Increases with every change, so consumers can drop redelivered events
+    pub id: i64,
+    pub store_id: String,
+    pub key: String,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    /// `put`, or `delete` for tombstones and removed rows
+    pub op: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl ChangeEvent {
+    /// The oldest changes not yet published, in the order they were made.
+    pub fn pending(conn: &mut PgConnection, limit: i64) -> anyhow::Result<Vec<ChangeEvent>> {
+        Ok(vss_outbox::table
+            .order(vss_outbox::id.asc())
+            .limit(limit)
+            .load::<Self>(conn)?)
+    }
+
+    /// Removes changes that have been published, returning how many there were.
+    pub fn acknowledge(conn: &mut PgConnection, ids: &[i64]) -> anyhow::Result<usize> {
+        let _span = debug_span!("vss.acknowledge_changes", events = ids.len()).entered();
+
+        Ok(diesel::delete(vss_outbox::table.filter(vss_outbox::id.eq_any(ids))).execute(conn)?)
+    }
+
+    /// Number of changes waiting to be published.
+    pub fn backlog(conn: &mut PgConnection) -> anyhow::Result<i64> {
+        Ok(vss_outbox::table.count().get_result(conn)?)
+    }
+}
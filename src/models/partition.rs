@@ -0,0 +1,195 @@
+//! Converts vss_db into a table hash-partitioned by store_id, in three steps
+//! that can each be retried: [`prepare`] creates the partitioned table and
+//! starts mirroring writes into it, [`copy_store`] copies existing stores
+//! over one at a time, and [`swap`] replaces vss_db with it.
+
+use super::VssItem;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// How far along vss_db is in being hash-partitioned by store_id.
+#[derive(QueryableByName, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionStatus {
+    #[diesel(sql_type = Bool)]
+    pub partitioned: bool,
+    #[diesel(sql_type = BigInt)]
+    pub partitions: i64,
+    /// A conversion has started and not yet been swapped in
+    #[diesel(sql_type = Bool)]
+    pub in_progress: bool,
+    /// The table from before the conversion is still around, it can be
+    /// dropped once the partitioned table has been checked
+    #[diesel(sql_type = Bool)]
+    pub unpartitioned_table: bool,
+}
+
+#[derive(QueryableByName)]
+struct StoreId {
+    #[diesel(sql_type = Text)]
+    store_id: String,
+}
+
+#[derive(QueryableByName)]
+struct Trigger {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    def: String,
+}
+
+#[derive(QueryableByName)]
+struct ForeignKey {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    def: String,
+}
+
+pub fn status(conn: &mut PgConnection) -> anyhow::Result<PartitionStatus> {
+    Ok(sql_query(
+        "SELECT COALESCE((SELECT relkind = 'p' FROM pg_class WHERE oid = to_regclass('vss_db')), false) AS partitioned, \
+         (SELECT COUNT(*) FROM pg_inherits WHERE inhparent = to_regclass('vss_db')) AS partitions, \
+         to_regclass('vss_db_partitioned') IS NOT NULL AS in_progress, \
+         to_regclass('vss_db_unpartitioned') IS NOT NULL AS unpartitioned_table",
+    )
+    .get_result::<PartitionStatus>(conn)?)
+}
+
+/// Creates `vss_db_partitioned` with `partitions` hash partitions, and a
+/// trigger copying writes to vss_db into it. Does nothing if a conversion is
+/// already in progress.
+pub fn prepare(conn: &mut PgConnection, partitions: u32) -> anyhow::Result<()> {
+    let current = status(conn)?;
+    if current.partitioned {
+        anyhow::bail!("vss_db is already partitioned");
+    }
+    if current.in_progress {
+        return Ok(());
+    }
+
+    let mut ddl = String::from(
+        "CREATE TABLE vss_db_partitioned (LIKE vss_db INCLUDING ALL) PARTITION BY HASH (store_id);",
+    );
+    for i in 0..partitions {
+        ddl.push_str(&format!(
+            "CREATE TABLE vss_db_p{i} PARTITION OF vss_db_partitioned \
+             FOR VALUES WITH (MODULUS {partitions}, REMAINDER {i});"
+        ));
+    }
+    ddl.push_str(
+        "CREATE TRIGGER tr_mirror_vss_db AFTER INSERT OR UPDATE OR DELETE ON vss_db \
+         FOR EACH ROW EXECUTE FUNCTION mirror_vss_db();",
+    );
+
+    conn.transaction(|conn| conn.batch_execute(&ddl))?;
+    Ok(())
+}
+
+/// Every store with rows in vss_db.
+pub fn list_stores(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+    Ok(
+        sql_query("SELECT DISTINCT store_id FROM vss_db ORDER BY store_id")
+            .load::<StoreId>(conn)?
+            .into_iter()
+            .map(|s| s.store_id)
+            .collect(),
+    )
+}
+
+/// Replaces a store's rows in `vss_db_partitioned` with its rows in vss_db,
+/// returning how many were copied. Holds the store's lock so no write to it
+/// lands part way through.
+pub fn copy_store(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<usize> {
+    let _span = debug_span!("vss.copy_store_partition", store_id).entered();
+
+    conn.transaction(|conn| {
+        VssItem::lock_store(conn, store_id)?;
+        // large stores can take longer than the usual statement timeout
+        conn.batch_execute("SET LOCAL statement_timeout = 0")?;
+
+        sql_query("DELETE FROM vss_db_partitioned WHERE store_id = $1")
+            .bind::<Text, _>(store_id)
+            .execute(conn)?;
+        Ok(sql_query(
+            "INSERT INTO vss_db_partitioned \
+             (store_id, key, value, version, created_date, updated_date, last_modified_by, value_hash, \
+             metadata, content_type, change_id) \
+             SELECT store_id, key, value, version, created_date, updated_date, last_modified_by, value_hash, \
+             metadata, content_type, change_id \
+             FROM vss_db WHERE store_id = $1",
+        )
+        .bind::<Text, _>(store_id)
+        .execute(conn)?)
+    })
+}
+
+/// Swaps `vss_db_partitioned` in as vss_db, moving over the triggers on
+/// vss_db and the foreign keys pointing at it. The old table is kept as
+/// `vss_db_unpartitioned`. Every store must have been copied first.
+pub fn swap(conn: &mut PgConnection) -> anyhow::Result<()> {
+    let foreign_keys = conn.transaction::<_, anyhow::Error, _>(|conn| {
+        conn.batch_execute(
+            "SET LOCAL lock_timeout = '5s'; \
+             LOCK TABLE vss_db IN ACCESS EXCLUSIVE MODE; \
+             DROP TRIGGER tr_mirror_vss_db ON vss_db;",
+        )?;
+
+        let triggers = sql_query(
+            "SELECT tgname::TEXT AS name, pg_get_triggerdef(oid) AS def FROM pg_trigger \
+             WHERE tgrelid = 'vss_db'::regclass AND NOT tgisinternal",
+        )
+        .load::<Trigger>(conn)?;
+        let foreign_keys = sql_query(
+            "SELECT conrelid::regclass::TEXT AS table_name, conname::TEXT AS name, \
+             pg_get_constraintdef(oid) AS def FROM pg_constraint \
+             WHERE confrelid = 'vss_db'::regclass AND contype = 'f'",
+        )
+        .load::<ForeignKey>(conn)?;
+
+        let mut ddl = String::new();
+        for trigger in triggers.iter() {
+            ddl.push_str(&format!("DROP TRIGGER {} ON vss_db;", trigger.name));
+        }
+        for fk in foreign_keys.iter() {
+            ddl.push_str(&format!(
+                "ALTER TABLE {} DROP CONSTRAINT {};",
+                fk.table_name, fk.name
+            ));
+        }
+        ddl.push_str(
+            "ALTER TABLE vss_db RENAME TO vss_db_unpartitioned; \
+             ALTER INDEX vss_db_pkey RENAME TO vss_db_unpartitioned_pkey; \
+             ALTER TABLE vss_db_partitioned RENAME TO vss_db; \
+             ALTER INDEX vss_db_partitioned_pkey RENAME TO vss_db_pkey;",
+        );
+        // the definitions name vss_db, which is now the partitioned table
+        for trigger in triggers.iter() {
+            ddl.push_str(&format!("{};", trigger.def));
+        }
+        // checked after the swap, so the lock isn't held while they're validated
+        for fk in foreign_keys.iter() {
+            ddl.push_str(&format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} {} NOT VALID;",
+                fk.table_name, fk.name, fk.def
+            ));
+        }
+        conn.batch_execute(&ddl)?;
+
+        Ok(foreign_keys)
+    })?;
+
+    for fk in foreign_keys {
+        conn.batch_execute(&format!(
+            "ALTER TABLE {} VALIDATE CONSTRAINT {}",
+            fk.table_name, fk.name
+        ))?;
+    }
+
+    Ok(())
+}
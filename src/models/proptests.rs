@@ -0,0 +1,143 @@
+//! Property tests for versioning: random interleavings of puts and deletes
+//! are checked against a simple in-memory model of the rules.
+
+use super::schema::vss_db;
+use super::VssItem;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+use proptest::prelude::*;
+use std::collections::HashMap;
+
+const KEYS: [&str; 3] = ["a", "b", "c"];
+const MAX_VERSION: u64 = u32::MAX as u64;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Put { key: usize, value: u8, version: u64 },
+    Delete { key: usize },
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    let version = prop_oneof![
+        8 => 0u64..6,
+        1 => Just(MAX_VERSION),
+        1 => Just(MAX_VERSION + 1),
+        // stored as negative BIGINTs
+        1 => Just(i64::MAX as u64 + 1),
+        1 => Just(u64::MAX - 1),
+    ];
+
+    prop_oneof![
+        4 => (0..KEYS.len(), any::<u8>(), version)
+            .prop_map(|(key, value, version)| Op::Put { key, value, version }),
+        1 => (0..KEYS.len()).prop_map(|key| Op::Delete { key }),
+    ]
+}
+
+/// The stored value (None once deleted) and version of a key.
+type Model = HashMap<&'static str, (Option<Vec<u8>>, u64)>;
+
+/// Whether a write at `version` replaces what is stored, versions at or
+/// above u32::MAX may be rewritten without incrementing.
+fn accepts(existing: Option<u64>, version: u64) -> bool {
+    let Some(existing) = existing else {
+        return true;
+    };
+    if version >= MAX_VERSION {
+        version >= existing
+    } else {
+        version > existing
+    }
+}
+
+/// Applies `ops` to both the database and the model, checking after every
+/// step that live versions never go backwards and that the last accepted
+/// write is the one visible.
+fn check_ops(conn: &mut PgConnection, store_id: &str, ops: &[Op]) -> Result<(), String> {
+    let mut model = Model::new();
+
+    for op in ops {
+        match *op {
+            Op::Put {
+                key,
+                value,
+                version,
+            } => {
+                let key = KEYS[key];
+                VssItem::put_item(conn, store_id, key, &[value], version)
+                    .map_err(|e| e.to_string())?;
+
+                // tombstones don't hold back puts
+                let existing = model
+                    .get(key)
+                    .filter(|(value, _)| value.is_some())
+                    .map(|(_, v)| *v);
+                if accepts(existing, version) {
+                    model.insert(key, (Some(vec![value]), version));
+                }
+            }
+            Op::Delete { key } => {
+                let key = KEYS[key];
+                VssItem::delete_by_prefix(conn, store_id, key, false).map_err(|e| e.to_string())?;
+
+                // live keys are deleted at the next version
+                if let Some((value, version)) = model.get_mut(key).filter(|(v, _)| v.is_some()) {
+                    *value = None;
+                    *version = version.saturating_add(1);
+                }
+            }
+        }
+
+        for key in KEYS {
+            let stored = VssItem::get_item(conn, None, store_id, key).map_err(|e| e.to_string())?;
+            let stored = stored.map(|i| (i.value, i.version));
+            let expected = model.get(key).cloned();
+            if stored != expected {
+                return Err(format!(
+                    "after {op:?}, {key} is {stored:?}, expected {expected:?}"
+                ));
+            }
+        }
+    }
+
+    // listings only show live keys, at their latest version
+    let mut listed = VssItem::list_key_versions(conn, store_id, None).map_err(|e| e.to_string())?;
+    listed.sort();
+    let mut live: Vec<(String, u64)> = model
+        .iter()
+        .filter(|(_, (value, _))| value.is_some())
+        .map(|(key, (_, version))| (key.to_string(), *version))
+        .collect();
+    live.sort();
+    if listed != live {
+        return Err(format!("listed {listed:?}, expected {live:?}"));
+    }
+
+    Ok(())
+}
+
+fn clear_store(conn: &mut PgConnection, store_id: &str) {
+    diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id)))
+        .execute(conn)
+        .unwrap();
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn versions_follow_model(ops in prop::collection::vec(op_strategy(), 1..40)) {
+        dotenv::dotenv().ok();
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let manager = ConnectionManager::<PgConnection>::new(url);
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+
+        let store_id = "proptest_store_id";
+        clear_store(&mut conn, store_id);
+        let res = check_ops(&mut conn, store_id, &ops);
+        clear_store(&mut conn, store_id);
+
+        prop_assert!(res.is_ok(), "{}", res.unwrap_err());
+    }
+}
@@ -0,0 +1,148 @@
+use super::schema::{vss_quota_invoices, vss_quotas};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// Storage a store has bought on top of the default allowance.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_quotas)]
+pub struct Quota {
+    pub store_id: String,
+    pub purchased_bytes: i64,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+/// A lightning invoice for `bytes` of extra storage.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_quota_invoices)]
+pub struct QuotaInvoice {
+    pub payment_hash: String,
+    pub store_id: String,
+    pub bytes: i64,
+    pub amount_msat: i64,
+    pub bolt11: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub paid_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Live keys in a store and the bytes their values take up.
+#[derive(QueryableByName, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreUsage {
+    #[diesel(sql_type = BigInt)]
+    pub keys: i64,
+    #[diesel(sql_type = BigInt)]
+    pub bytes: i64,
+}
+
+impl Quota {
+    pub fn store_usage(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<StoreUsage> {
+        let _span = debug_span!("vss.store_usage", store_id).entered();
+
+        Self::stores_usage(conn, &[store_id.to_string()])
+    }
+
+    /// Usage of several stores added together.
+    pub fn stores_usage(
+        conn: &mut PgConnection,
+        store_ids: &[String],
+    ) -> anyhow::Result<StoreUsage> {
+        // chunked and offloaded values are stored empty, their bytes are in
+        // vss_chunks and object storage
+        Ok(sql_query(
+            "SELECT COUNT(*)::BIGINT AS keys, \
+             (COALESCE(SUM(octet_length(value)), 0) + COALESCE((SELECT SUM(octet_length(data)) \
+             FROM vss_chunks WHERE store_id = ANY($1)), 0) + COALESCE((SELECT SUM(size) \
+             FROM vss_blobs WHERE store_id = ANY($1)), 0))::BIGINT AS bytes \
+             FROM vss_db WHERE store_id = ANY($1) AND value IS NOT NULL",
+        )
+        .bind::<Array<Text>, _>(store_ids)
+        .get_result::<StoreUsage>(conn)?)
+    }
+
+    /// The part of a store's usage that is down to `keys`, looked up by key
+    /// rather than adding up the whole store.
+    pub fn keys_usage(
+        conn: &mut PgConnection,
+        store_id: &str,
+        keys: &[&str],
+    ) -> anyhow::Result<StoreUsage> {
+        Ok(sql_query(
+            "SELECT COUNT(*)::BIGINT AS keys, \
+             (COALESCE(SUM(octet_length(value)), 0) + COALESCE((SELECT SUM(octet_length(data)) \
+             FROM vss_chunks WHERE store_id = $1 AND key = ANY($2)), 0) + COALESCE((SELECT SUM(size) \
+             FROM vss_blobs WHERE store_id = $1 AND key = ANY($2)), 0))::BIGINT AS bytes \
+             FROM vss_db WHERE store_id = $1 AND key = ANY($2) AND value IS NOT NULL",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Array<Text>, _>(keys)
+        .get_result::<StoreUsage>(conn)?)
+    }
+
+    pub fn get_quota(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Option<Quota>> {
+        Ok(vss_quotas::table
+            .filter(vss_quotas::store_id.eq(store_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn add_bytes(conn: &mut PgConnection, store_id: &str, bytes: i64) -> anyhow::Result<Quota> {
+        Ok(sql_query(
+            "INSERT INTO vss_quotas (store_id, purchased_bytes) VALUES ($1, $2) \
+             ON CONFLICT (store_id) DO UPDATE \
+             SET purchased_bytes = vss_quotas.purchased_bytes + excluded.purchased_bytes, \
+             updated_at = CURRENT_TIMESTAMP \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<BigInt, _>(bytes)
+        .get_result::<Quota>(conn)?)
+    }
+}
+
+impl QuotaInvoice {
+    pub fn insert(conn: &mut PgConnection, invoice: &QuotaInvoice) -> anyhow::Result<()> {
+        diesel::insert_into(vss_quota_invoices::table)
+            .values(invoice)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn get_invoice(
+        conn: &mut PgConnection,
+        payment_hash: &str,
+    ) -> anyhow::Result<Option<QuotaInvoice>> {
+        Ok(vss_quota_invoices::table
+            .filter(vss_quota_invoices::payment_hash.eq(payment_hash))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Marks the invoice paid and credits its bytes to the store's quota.
+    /// Invoices are only credited once, later calls return the quota as is.
+    pub fn mark_paid(conn: &mut PgConnection, payment_hash: &str) -> anyhow::Result<Option<Quota>> {
+        let _span = debug_span!("vss.mark_quota_invoice_paid").entered();
+
+        conn.transaction(|conn| {
+            let invoice = diesel::update(
+                vss_quota_invoices::table
+                    .filter(vss_quota_invoices::payment_hash.eq(payment_hash))
+                    .filter(vss_quota_invoices::paid_at.is_null()),
+            )
+            .set(vss_quota_invoices::paid_at.eq(diesel::dsl::now))
+            .get_result::<QuotaInvoice>(conn)
+            .optional()?;
+
+            match invoice {
+                Some(invoice) => Quota::add_bytes(conn, &invoice.store_id, invoice.bytes).map(Some),
+                None => match Self::get_invoice(conn, payment_hash)? {
+                    Some(invoice) => Quota::get_quota(conn, &invoice.store_id),
+                    None => Ok(None),
+                },
+            }
+        })
+    }
+}
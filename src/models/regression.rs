@@ -0,0 +1,128 @@
+use super::schema::vss_version_regressions;
+use super::DbVersion;
+use crate::kv::{KeyValue, NO_VERSION_CHECK};
+use diesel::dsl::count_distinct;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::debug_span;
+
+/// A write that arrived with an older version than the key already had.
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_version_regressions)]
+pub struct VersionRegression {
+    pub id: i64,
+    pub store_id: String,
+    pub key: String,
+    /// Short hash of the bearer token the write was sent with
+    pub client_id: Option<String>,
+    #[diesel(deserialize_as = DbVersion)]
+    pub attempted_version: u64,
+    #[diesel(deserialize_as = DbVersion)]
+    pub current_version: u64,
+    /// How far behind the attempted version was
+    #[diesel(deserialize_as = DbVersion)]
+    pub delta: u64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = vss_version_regressions)]
+struct NewVersionRegression<'a> {
+    store_id: &'a str,
+    key: &'a str,
+    client_id: Option<&'a str>,
+    attempted_version: DbVersion,
+    current_version: DbVersion,
+    delta: DbVersion,
+}
+
+/// Version regressions across all stores since a point in time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegressionStats {
+    pub since: chrono::NaiveDateTime,
+    pub total: i64,
+    /// Number of distinct stores with a regression
+    pub stores: i64,
+    /// Most recent regressions, newest first
+    pub recent: Vec<VersionRegression>,
+}
+
+impl VersionRegression {
+    /// Records every item older than the key's `current` version, returning
+    /// how many were found. Should be called before the write. Items at
+    /// [`NO_VERSION_CHECK`] never regress.
+    pub fn record(
+        conn: &mut PgConnection,
+        store_id: &str,
+        client_id: Option<&str>,
+        current: &HashMap<String, u64>,
+        items: &[KeyValue],
+    ) -> anyhow::Result<usize> {
+        let rows: Vec<NewVersionRegression> = items
+            .iter()
+            .filter(|kv| kv.version != NO_VERSION_CHECK)
+            .filter_map(|kv| {
+                let current_version = *current.get(&kv.key)?;
+                (kv.version < current_version).then(|| NewVersionRegression {
+                    store_id,
+                    key: &kv.key,
+                    client_id,
+                    attempted_version: DbVersion(kv.version),
+                    current_version: DbVersion(current_version),
+                    delta: DbVersion(current_version - kv.version),
+                })
+            })
+            .collect();
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let _span = debug_span!("vss.record_regressions", store_id, keys = rows.len()).entered();
+
+        Ok(diesel::insert_into(vss_version_regressions::table)
+            .values(&rows)
+            .execute(conn)?)
+    }
+
+    /// Lists a store's regressions, newest first.
+    pub fn list_for_store(
+        conn: &mut PgConnection,
+        store_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<VersionRegression>> {
+        Ok(vss_version_regressions::table
+            .filter(vss_version_regressions::store_id.eq(store_id))
+            .order(vss_version_regressions::id.desc())
+            .limit(limit)
+            .load::<Self>(conn)?)
+    }
+
+    pub fn stats(
+        conn: &mut PgConnection,
+        since: chrono::NaiveDateTime,
+        limit: i64,
+    ) -> anyhow::Result<RegressionStats> {
+        let recent_rows =
+            vss_version_regressions::table.filter(vss_version_regressions::created_at.ge(since));
+
+        let (total, stores) = recent_rows
+            .select((
+                diesel::dsl::count_star(),
+                count_distinct(vss_version_regressions::store_id),
+            ))
+            .first::<(i64, i64)>(conn)?;
+        let recent = recent_rows
+            .order(vss_version_regressions::id.desc())
+            .limit(limit)
+            .load::<Self>(conn)?;
+
+        Ok(RegressionStats {
+            since,
+            total,
+            stores,
+            recent,
+        })
+    }
+}
@@ -0,0 +1,118 @@
+use super::schema::{vss_db, vss_retention_rules, vss_stores};
+use super::{bumped_version, key_starts_with};
+use diesel::dsl::{now, IntervalDsl};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+use tracing::field::Empty;
+
+/// Deletes a store's keys under a prefix once they haven't been updated for
+/// a while, so clients' ephemeral data cleans itself up.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_retention_rules)]
+pub struct RetentionRule {
+    pub store_id: String,
+    /// Keys starting with this are covered, every key if empty
+    pub key_prefix: String,
+    /// Keys not updated for this many days are deleted
+    pub max_age_days: i32,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl RetentionRule {
+    /// Adds a rule for the prefix, or replaces its max age.
+    pub fn set(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key_prefix: &str,
+        max_age_days: i32,
+    ) -> anyhow::Result<RetentionRule> {
+        Ok(diesel::insert_into(vss_retention_rules::table)
+            .values((
+                vss_retention_rules::store_id.eq(store_id),
+                vss_retention_rules::key_prefix.eq(key_prefix),
+                vss_retention_rules::max_age_days.eq(max_age_days),
+            ))
+            .on_conflict((
+                vss_retention_rules::store_id,
+                vss_retention_rules::key_prefix,
+            ))
+            .do_update()
+            .set(vss_retention_rules::max_age_days.eq(max_age_days))
+            .get_result::<Self>(conn)?)
+    }
+
+    /// Removes the rule for the prefix, returning whether there was one.
+    pub fn remove(
+        conn: &mut PgConnection,
+        store_id: &str,
+        key_prefix: &str,
+    ) -> anyhow::Result<bool> {
+        let removed = diesel::delete(
+            vss_retention_rules::table
+                .filter(vss_retention_rules::store_id.eq(store_id))
+                .filter(vss_retention_rules::key_prefix.eq(key_prefix)),
+        )
+        .execute(conn)?;
+
+        Ok(removed > 0)
+    }
+
+    pub fn list_for_store(
+        conn: &mut PgConnection,
+        store_id: &str,
+    ) -> anyhow::Result<Vec<RetentionRule>> {
+        Ok(vss_retention_rules::table
+            .filter(vss_retention_rules::store_id.eq(store_id))
+            .order(vss_retention_rules::key_prefix.asc())
+            .load::<Self>(conn)?)
+    }
+
+    /// Every store's rules, for the retention job.
+    pub fn list_all(conn: &mut PgConnection) -> anyhow::Result<Vec<RetentionRule>> {
+        Ok(vss_retention_rules::table
+            .order((
+                vss_retention_rules::store_id.asc(),
+                vss_retention_rules::key_prefix.asc(),
+            ))
+            .load::<Self>(conn)?)
+    }
+
+    /// Tombstones the live keys the rule has expired, returning how many.
+    /// Stores scheduled for deletion are left alone as they are read-only.
+    pub fn apply(&self, conn: &mut PgConnection) -> anyhow::Result<usize> {
+        let span = debug_span!(
+            "vss.apply_retention",
+            store_id = self.store_id,
+            keys = Empty
+        )
+        .entered();
+
+        let pending_deletion = diesel::select(diesel::dsl::exists(
+            vss_stores::table
+                .filter(vss_stores::store_id.eq(&self.store_id))
+                .filter(vss_stores::purge_at.is_not_null()),
+        ))
+        .get_result::<bool>(conn)?;
+        if pending_deletion {
+            return Ok(0);
+        }
+
+        let count = diesel::update(
+            vss_db::table
+                .filter(vss_db::store_id.eq(&self.store_id))
+                .filter(key_starts_with(&self.key_prefix))
+                .filter(vss_db::value.is_not_null())
+                .filter(vss_db::updated_date.lt(now - self.max_age_days.days())),
+        )
+        .set((
+            vss_db::value.eq(None::<Vec<u8>>),
+            vss_db::version.eq(bumped_version()),
+        ))
+        .execute(conn)?;
+        span.record("keys", count);
+
+        Ok(count)
+    }
+}
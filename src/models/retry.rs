@@ -0,0 +1,90 @@
+use super::CircuitBreaker;
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use log::warn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_BACKOFF_MILLIS: u64 = 50;
+
+/// Whether an error is likely to go away on its own, like a serialization
+/// conflict, a dropped connection or the pool timing out during a failover.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    if err.downcast_ref::<PoolError>().is_some() {
+        return true;
+    }
+
+    matches!(
+        err.downcast_ref::<DieselError>(),
+        Some(DieselError::DatabaseError(
+            DatabaseErrorKind::SerializationFailure
+                | DatabaseErrorKind::ClosedConnection
+                | DatabaseErrorKind::UnableToSendCommand,
+            _
+        ))
+    )
+}
+
+/// Runs a database operation, retrying transient failures with jittered
+/// exponential backoff. `f` must be safe to run again from the start, so it
+/// should be a read or a whole transaction and check out its own connection.
+///
+/// Operations that still fail transiently count towards tripping `breaker`,
+/// and nothing is attempted while it is open.
+pub async fn with_db_retry<T, F>(
+    name: &str,
+    breaker: &CircuitBreaker,
+    mut f: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    breaker.check()?;
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        match f() {
+            Err(e) if attempt < MAX_ATTEMPTS && is_transient(&e) => {
+                let backoff = backoff(attempt);
+                warn!("{name} hit a transient error (attempt {attempt}), retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) if is_transient(&e) => {
+                breaker.record_failure();
+                return Err(e);
+            }
+            res => {
+                breaker.record_success();
+                return res;
+            }
+        }
+    }
+}
+
+/// Exponential backoff with up to 100% jitter so retries from concurrent
+/// requests don't all land on the database at once.
+fn backoff(attempt: u32) -> Duration {
+    let base = BASE_BACKOFF_MILLIS * 2u64.pow(attempt - 1);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or_default();
+
+    Duration::from_millis(base + nanos % base)
+}
+
+/// Warns when a database operation started at `start` took longer than
+/// `threshold`, so pathological prefix scans or huge transactions show up
+/// in the logs.
+pub fn log_if_slow(op: &str, store_id: &str, items: usize, start: Instant, threshold: Duration) {
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        warn!(
+            target: "vss_rs::slow",
+            "op={op} store_id={store_id} items={items} elapsed_ms={}",
+            elapsed.as_millis()
+        );
+    }
+}
@@ -1,5 +1,24 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    vss_blobs (store_id, key) {
+        store_id -> Text,
+        key -> Text,
+        object_key -> Text,
+        size -> Int8,
+        value_hash -> Bytea,
+    }
+}
+
+diesel::table! {
+    vss_chunks (store_id, key, idx) {
+        store_id -> Text,
+        key -> Text,
+        idx -> Int4,
+        data -> Bytea,
+    }
+}
+
 diesel::table! {
     vss_db (store_id, key) {
         store_id -> Text,
@@ -8,5 +27,275 @@ diesel::table! {
         version -> Int8,
         created_date -> Timestamp,
         updated_date -> Timestamp,
+        last_modified_by -> Nullable<Text>,
+        value_hash -> Nullable<Bytea>,
+        metadata -> Nullable<Jsonb>,
+        content_type -> Nullable<Text>,
+        change_id -> Int8,
+    }
+}
+
+diesel::table! {
+    vss_devices (store_id, device_id) {
+        store_id -> Text,
+        device_id -> Text,
+        platform -> Nullable<Text>,
+        token_fingerprint -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    vss_fixture_stores (store_id) {
+        store_id -> Text,
+        loaded_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_forget_receipts (receipt_id) {
+        receipt_id -> Text,
+        store_hash -> Text,
+        requested_by -> Text,
+        items -> Int8,
+        objects -> Int8,
+        forgotten_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_history (id) {
+        id -> Int8,
+        store_id -> Text,
+        key -> Text,
+        value -> Nullable<Bytea>,
+        object_key -> Nullable<Text>,
+        value_hash -> Nullable<Bytea>,
+        version -> Int8,
+        metadata -> Nullable<Jsonb>,
+        content_type -> Nullable<Text>,
+        created_date -> Timestamp,
+        updated_date -> Timestamp,
+        superseded_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_idempotency_keys (store_id, idempotency_key) {
+        store_id -> Text,
+        idempotency_key -> Text,
+        created_at -> Timestamp,
+        request_hash -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    vss_job_leaders (job) {
+        job -> Text,
+        holder -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_leases (store_id, name) {
+        store_id -> Text,
+        name -> Text,
+        holder -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_mutation_log (id) {
+        id -> Int8,
+        txid -> Int8,
+        store_id -> Text,
+        key -> Text,
+        version -> Int8,
+        op -> Text,
+        value_hash -> Nullable<Bytea>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_nostr_subscriptions (store_id) {
+        store_id -> Text,
+        pubkey -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_org_stores (store_id) {
+        store_id -> Text,
+        org_id -> Text,
+        added_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_orgs (org_id) {
+        org_id -> Text,
+        name -> Nullable<Text>,
+        max_stores -> Nullable<Int8>,
+        max_bytes -> Nullable<Int8>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_outbox (id) {
+        id -> Int8,
+        store_id -> Text,
+        key -> Text,
+        version -> Int8,
+        op -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_quota_invoices (payment_hash) {
+        payment_hash -> Text,
+        store_id -> Text,
+        bytes -> Int8,
+        amount_msat -> Int8,
+        bolt11 -> Text,
+        created_at -> Timestamp,
+        paid_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    vss_quotas (store_id) {
+        store_id -> Text,
+        purchased_bytes -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_retention_rules (store_id, key_prefix) {
+        store_id -> Text,
+        key_prefix -> Text,
+        max_age_days -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_snapshot_items (snapshot_id, key) {
+        snapshot_id -> Text,
+        key -> Text,
+        value -> Nullable<Bytea>,
+        object_key -> Nullable<Text>,
+        size -> Int8,
+        value_hash -> Nullable<Bytea>,
+        version -> Int8,
+        last_modified_by -> Nullable<Text>,
+        metadata -> Nullable<Jsonb>,
+        content_type -> Nullable<Text>,
+        created_date -> Timestamp,
+        updated_date -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_snapshots (snapshot_id) {
+        snapshot_id -> Text,
+        store_id -> Text,
+        label -> Text,
+        items -> Int8,
+        size -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_store_exports (export_id) {
+        export_id -> Text,
+        store_id -> Text,
+        token_hash -> Text,
+        status -> Text,
+        error -> Nullable<Text>,
+        bundle -> Nullable<Bytea>,
+        size -> Nullable<Int8>,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vss_stores (store_id) {
+        store_id -> Text,
+        created_at -> Timestamp,
+        last_write_at -> Timestamp,
+        label -> Nullable<Text>,
+        flags -> Int8,
+        v1_base64_encoding -> Nullable<Text>,
+        lazy_versions -> Nullable<Bool>,
+        null_for_missing -> Nullable<Bool>,
+        alias -> Nullable<Text>,
+        delete_requested_at -> Nullable<Timestamp>,
+        purge_at -> Nullable<Timestamp>,
+        deletion_token_hash -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    vss_usage (store_id, day) {
+        store_id -> Text,
+        day -> Date,
+        requests -> Int8,
+        bytes_read -> Int8,
+        bytes_written -> Int8,
     }
 }
+
+diesel::table! {
+    vss_version_regressions (id) {
+        id -> Int8,
+        store_id -> Text,
+        key -> Text,
+        client_id -> Nullable<Text>,
+        attempted_version -> Int8,
+        current_version -> Int8,
+        delta -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(vss_org_stores -> vss_orgs (org_id));
+diesel::joinable!(vss_snapshot_items -> vss_snapshots (snapshot_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    vss_blobs,
+    vss_chunks,
+    vss_db,
+    vss_devices,
+    vss_fixture_stores,
+    vss_forget_receipts,
+    vss_history,
+    vss_idempotency_keys,
+    vss_job_leaders,
+    vss_leases,
+    vss_mutation_log,
+    vss_nostr_subscriptions,
+    vss_org_stores,
+    vss_orgs,
+    vss_outbox,
+    vss_quota_invoices,
+    vss_quotas,
+    vss_retention_rules,
+    vss_snapshot_items,
+    vss_snapshots,
+    vss_store_exports,
+    vss_stores,
+    vss_usage,
+    vss_version_regressions,
+);
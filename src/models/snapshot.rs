@@ -0,0 +1,155 @@
+use super::schema::{vss_snapshot_items, vss_snapshots};
+use super::DbVersion;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+use tracing::field::Empty;
+
+/// A labeled copy of a store's live items at a point in time.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_snapshots)]
+pub struct Snapshot {
+    pub snapshot_id: String,
+    pub store_id: String,
+    /// Unique within the store
+    pub label: String,
+    /// Live items copied, tombstones aren't
+    pub items: i64,
+    /// Total size of the copied values in bytes
+    pub size: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// An item as it was when its snapshot was taken.
+#[derive(Queryable, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_snapshot_items)]
+pub struct SnapshotItem {
+    pub snapshot_id: String,
+    pub key: String,
+    /// None when the value is in object storage
+    pub value: Option<Vec<u8>>,
+    pub object_key: Option<String>,
+    pub size: i64,
+    pub value_hash: Option<Vec<u8>>,
+    #[diesel(deserialize_as = DbVersion)]
+    pub version: u64,
+    pub last_modified_by: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    pub content_type: Option<String>,
+    pub created_date: chrono::NaiveDateTime,
+    pub updated_date: chrono::NaiveDateTime,
+}
+
+impl Snapshot {
+    /// Copies the store's live items into a new snapshot in one statement,
+    /// so it sees them as they were at a single point in time.
+    pub fn create(
+        conn: &mut PgConnection,
+        snapshot_id: &str,
+        store_id: &str,
+        label: &str,
+    ) -> anyhow::Result<Snapshot> {
+        let span = debug_span!("vss.create_snapshot", store_id, items = Empty).entered();
+
+        diesel::insert_into(vss_snapshots::table)
+            .values((
+                vss_snapshots::snapshot_id.eq(snapshot_id),
+                vss_snapshots::store_id.eq(store_id),
+                vss_snapshots::label.eq(label),
+            ))
+            .execute(conn)?;
+
+        let items = sql_query(
+            "INSERT INTO vss_snapshot_items (snapshot_id, key, value, object_key, size, value_hash, \
+             version, last_modified_by, metadata, content_type, created_date, updated_date) \
+             SELECT $1, d.key, \
+             CASE WHEN b.object_key IS NOT NULL THEN NULL \
+             WHEN d.value = ''::bytea THEN COALESCE((SELECT string_agg(c.data, ''::bytea ORDER BY c.idx) \
+             FROM vss_chunks c WHERE c.store_id = d.store_id AND c.key = d.key), ''::bytea) \
+             ELSE d.value END, \
+             b.object_key, \
+             COALESCE(b.size, (SELECT SUM(octet_length(c.data)) FROM vss_chunks c \
+             WHERE c.store_id = d.store_id AND c.key = d.key), octet_length(d.value)), \
+             d.value_hash, d.version, d.last_modified_by, d.metadata, d.content_type, \
+             d.created_date, d.updated_date \
+             FROM vss_db d LEFT JOIN vss_blobs b ON b.store_id = d.store_id AND b.key = d.key \
+             WHERE d.store_id = $2 AND d.value IS NOT NULL",
+        )
+        .bind::<Text, _>(snapshot_id)
+        .bind::<Text, _>(store_id)
+        .execute(conn)?;
+        span.record("items", items);
+
+        Ok(sql_query(
+            "UPDATE vss_snapshots SET items = $2, size = (SELECT COALESCE(SUM(size), 0)::BIGINT \
+             FROM vss_snapshot_items WHERE snapshot_id = $1) WHERE snapshot_id = $1 RETURNING *",
+        )
+        .bind::<Text, _>(snapshot_id)
+        .bind::<BigInt, _>(items as i64)
+        .get_result::<Self>(conn)?)
+    }
+
+    /// A store's snapshots, oldest first.
+    pub fn list_for_store(
+        conn: &mut PgConnection,
+        store_id: &str,
+    ) -> anyhow::Result<Vec<Snapshot>> {
+        Ok(vss_snapshots::table
+            .filter(vss_snapshots::store_id.eq(store_id))
+            .order((vss_snapshots::created_at.asc(), vss_snapshots::label.asc()))
+            .load::<Self>(conn)?)
+    }
+
+    pub fn get_snapshot(
+        conn: &mut PgConnection,
+        store_id: &str,
+        snapshot_id: &str,
+    ) -> anyhow::Result<Option<Snapshot>> {
+        Ok(vss_snapshots::table
+            .filter(vss_snapshots::store_id.eq(store_id))
+            .filter(vss_snapshots::snapshot_id.eq(snapshot_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Up to `limit` of the snapshot's items, ordered by key and starting
+    /// after `after`.
+    pub fn list_items(
+        conn: &mut PgConnection,
+        snapshot_id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SnapshotItem>> {
+        let mut query = vss_snapshot_items::table
+            .filter(vss_snapshot_items::snapshot_id.eq(snapshot_id))
+            .order(vss_snapshot_items::key.asc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(vss_snapshot_items::key.gt(after));
+        }
+
+        Ok(query.load::<SnapshotItem>(conn)?)
+    }
+
+    /// Deletes the snapshot and its items, returning whether there was one.
+    pub fn delete(
+        conn: &mut PgConnection,
+        store_id: &str,
+        snapshot_id: &str,
+    ) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(
+            vss_snapshots::table
+                .filter(vss_snapshots::store_id.eq(store_id))
+                .filter(vss_snapshots::snapshot_id.eq(snapshot_id)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
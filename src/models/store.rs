@@ -0,0 +1,420 @@
+use super::schema::{
+    vss_db, vss_devices, vss_fixture_stores, vss_history, vss_idempotency_keys, vss_leases,
+    vss_mutation_log, vss_nostr_subscriptions, vss_outbox, vss_quota_invoices, vss_quotas,
+    vss_retention_rules, vss_snapshots, vss_store_exports, vss_stores, vss_usage,
+    vss_version_regressions,
+};
+use anyhow::anyhow;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Nullable, Text};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tracing::debug_span;
+
+/// Store level metadata, the row is created and touched by a trigger whenever
+/// one of the store's items is written.
+#[derive(
+    QueryableByName,
+    Queryable,
+    Insertable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_stores)]
+pub struct VssStore {
+    pub store_id: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_write_at: chrono::NaiveDateTime,
+    pub label: Option<String>,
+    pub flags: i64,
+    /// Pinned [`StoreBehaviors::v1_base64_encoding`]
+    pub v1_base64_encoding: Option<String>,
+    /// Pinned [`StoreBehaviors::lazy_versions`]
+    pub lazy_versions: Option<bool>,
+    /// Pinned [`StoreBehaviors::null_for_missing`]
+    pub null_for_missing: Option<bool>,
+    /// Unique name the store can be referred to by as `@alias`
+    pub alias: Option<String>,
+    /// When deletion of the store was requested, if it is pending
+    pub delete_requested_at: Option<chrono::NaiveDateTime>,
+    /// When the store will be purged, it is read-only until then
+    pub purge_at: Option<chrono::NaiveDateTime>,
+    /// Hex SHA-256 of the token that cancels a pending deletion
+    #[serde(skip)]
+    pub deletion_token_hash: Option<String>,
+}
+
+/// Returned when writing to a store that is scheduled for deletion.
+#[derive(Debug, Clone)]
+pub struct StorePendingDeletion {
+    pub store_id: String,
+    pub purge_at: chrono::NaiveDateTime,
+}
+
+impl fmt::Display for StorePendingDeletion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Store {} is scheduled for deletion at {} and is read-only",
+            self.store_id, self.purge_at
+        )
+    }
+}
+
+impl std::error::Error for StorePendingDeletion {}
+
+/// Protocol behaviors a store is pinned to, so the server's defaults can be
+/// tightened without breaking clients of existing stores. None follows the
+/// server's default.
+#[derive(Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[diesel(table_name = vss_stores, treat_none_as_null = true)]
+pub struct StoreBehaviors {
+    /// Base64 variant of v1 getObject values when the request doesn't name
+    /// one, see [`crate::kv::Base64Encoding`]
+    pub v1_base64_encoding: Option<String>,
+    /// Accept putObjects items that skip the version check
+    pub lazy_versions: Option<bool>,
+    /// v2 getObject returns null for missing keys instead of a 404
+    pub null_for_missing: Option<bool>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = vss_stores)]
+struct StoreChangeset<'a> {
+    label: Option<&'a str>,
+    flags: Option<i64>,
+}
+
+impl VssStore {
+    pub fn get_store(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Option<VssStore>> {
+        Ok(vss_stores::table
+            .filter(vss_stores::store_id.eq(store_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Lists stores ordered by store_id, starting after `after`.
+    pub fn list_stores(
+        conn: &mut PgConnection,
+        after: Option<&str>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<VssStore>> {
+        let mut query = vss_stores::table
+            .order(vss_stores::store_id.asc())
+            .limit(limit)
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(vss_stores::store_id.gt(after));
+        }
+
+        Ok(query.load::<Self>(conn)?)
+    }
+
+    /// Ids of every store in the database.
+    pub fn list_ids(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+        Ok(vss_stores::table
+            .select(vss_stores::store_id)
+            .load::<String>(conn)?)
+    }
+
+    /// Behaviors `store_id` is pinned to, none if it has never been written.
+    pub fn get_behaviors(
+        conn: &mut PgConnection,
+        store_id: &str,
+    ) -> anyhow::Result<StoreBehaviors> {
+        let behaviors = vss_stores::table
+            .filter(vss_stores::store_id.eq(store_id))
+            .select((
+                vss_stores::v1_base64_encoding,
+                vss_stores::lazy_versions,
+                vss_stores::null_for_missing,
+            ))
+            .first::<StoreBehaviors>(conn)
+            .optional()?;
+
+        Ok(behaviors.unwrap_or_default())
+    }
+
+    /// Replaces the behaviors an existing store is pinned to, returning the
+    /// updated row or None if the store does not exist.
+    pub fn set_behaviors(
+        conn: &mut PgConnection,
+        store_id: &str,
+        behaviors: &StoreBehaviors,
+    ) -> anyhow::Result<Option<VssStore>> {
+        let res = diesel::update(vss_stores::table.filter(vss_stores::store_id.eq(store_id)))
+            .set(behaviors)
+            .get_result::<Self>(conn);
+
+        Ok(res.optional()?)
+    }
+
+    /// Pins every behavior stores haven't pinned yet to `defaults`, returning
+    /// how many stores changed.
+    pub fn pin_behaviors(
+        conn: &mut PgConnection,
+        defaults: &StoreBehaviors,
+    ) -> anyhow::Result<usize> {
+        Ok(diesel::sql_query(
+            "UPDATE vss_stores SET \
+                v1_base64_encoding = COALESCE(v1_base64_encoding, $1), \
+                lazy_versions = COALESCE(lazy_versions, $2), \
+                null_for_missing = COALESCE(null_for_missing, $3) \
+            WHERE v1_base64_encoding IS NULL OR lazy_versions IS NULL OR null_for_missing IS NULL",
+        )
+        .bind::<Nullable<Text>, _>(defaults.v1_base64_encoding.as_deref())
+        .bind::<Nullable<Bool>, _>(defaults.lazy_versions)
+        .bind::<Nullable<Bool>, _>(defaults.null_for_missing)
+        .execute(conn)?)
+    }
+
+    /// Updates the label and/or flags of an existing store, returning the
+    /// updated row or None if the store does not exist.
+    pub fn update_store(
+        conn: &mut PgConnection,
+        store_id: &str,
+        label: Option<&str>,
+        flags: Option<i64>,
+    ) -> anyhow::Result<Option<VssStore>> {
+        let changes = StoreChangeset { label, flags };
+        if changes.label.is_none() && changes.flags.is_none() {
+            return Self::get_store(conn, store_id);
+        }
+
+        let res = diesel::update(vss_stores::table.filter(vss_stores::store_id.eq(store_id)))
+            .set(&changes)
+            .get_result::<Self>(conn);
+
+        Ok(res.optional()?)
+    }
+
+    /// Id of the store with `alias` in this database, if any.
+    pub fn find_by_alias(conn: &mut PgConnection, alias: &str) -> anyhow::Result<Option<String>> {
+        Ok(vss_stores::table
+            .filter(vss_stores::alias.eq(alias))
+            .select(vss_stores::store_id)
+            .first::<String>(conn)
+            .optional()?)
+    }
+
+    /// Sets or clears the alias of an existing store, returning the updated
+    /// row or None if the store does not exist. Fails if another store in
+    /// this database has the alias.
+    pub fn set_alias(
+        conn: &mut PgConnection,
+        store_id: &str,
+        alias: Option<&str>,
+    ) -> anyhow::Result<Option<VssStore>> {
+        let res = diesel::update(vss_stores::table.filter(vss_stores::store_id.eq(store_id)))
+            .set(vss_stores::alias.eq(alias))
+            .get_result::<Self>(conn);
+
+        match res {
+            Err(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            )) => Err(anyhow!("Alias {} is taken", alias.unwrap_or_default())),
+            res => Ok(res.optional()?),
+        }
+    }
+
+    /// Fails with [`StorePendingDeletion`] if the store is scheduled for
+    /// deletion. Should be called after taking the store's write lock.
+    pub fn check_writable(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<()> {
+        let purge_at = vss_stores::table
+            .filter(vss_stores::store_id.eq(store_id))
+            .select(vss_stores::purge_at)
+            .first::<Option<chrono::NaiveDateTime>>(conn)
+            .optional()?
+            .flatten();
+
+        match purge_at {
+            Some(purge_at) => Err(StorePendingDeletion {
+                store_id: store_id.to_string(),
+                purge_at,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Schedules an existing store to be purged `grace` from now, returning
+    /// the updated row or None if the store does not exist or is already
+    /// scheduled.
+    pub fn schedule_deletion(
+        conn: &mut PgConnection,
+        store_id: &str,
+        token_hash: &str,
+        grace: Duration,
+    ) -> anyhow::Result<Option<VssStore>> {
+        Ok(sql_query(
+            "UPDATE vss_stores SET delete_requested_at = CURRENT_TIMESTAMP, \
+             purge_at = CURRENT_TIMESTAMP + make_interval(secs => $3), \
+             deletion_token_hash = $2 \
+             WHERE store_id = $1 AND purge_at IS NULL \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(token_hash)
+        .bind::<BigInt, _>(grace.as_secs() as i64)
+        .get_result::<Self>(conn)
+        .optional()?)
+    }
+
+    /// Cancels a pending deletion, returning the updated row or None if the
+    /// store isn't scheduled for deletion or `token_hash` doesn't match.
+    /// Without a `token_hash` any pending deletion is cancelled.
+    pub fn cancel_deletion(
+        conn: &mut PgConnection,
+        store_id: &str,
+        token_hash: Option<&str>,
+    ) -> anyhow::Result<Option<VssStore>> {
+        Ok(sql_query(
+            "UPDATE vss_stores SET delete_requested_at = NULL, purge_at = NULL, \
+             deletion_token_hash = NULL \
+             WHERE store_id = $1 AND purge_at IS NOT NULL \
+             AND ($2 IS NULL OR deletion_token_hash = $2) \
+             RETURNING *",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Nullable<Text>, _>(token_hash)
+        .get_result::<Self>(conn)
+        .optional()?)
+    }
+
+    /// Ids of stores whose deletion grace period is over.
+    pub fn due_for_purge(conn: &mut PgConnection) -> anyhow::Result<Vec<String>> {
+        Ok(vss_stores::table
+            .filter(vss_stores::purge_at.le(diesel::dsl::now))
+            .select(vss_stores::store_id)
+            .load::<String>(conn)?)
+    }
+
+    /// Permanently deletes a store that is due for purging, see
+    /// [`VssStore::erase`], returning how many items were deleted or None if
+    /// it isn't due, e.g. because the deletion was cancelled. Must be called
+    /// inside a transaction holding the store's write lock.
+    pub fn purge(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Option<usize>> {
+        let due = diesel::select(diesel::dsl::exists(
+            vss_stores::table
+                .filter(vss_stores::store_id.eq(store_id))
+                .filter(vss_stores::purge_at.le(diesel::dsl::now)),
+        ))
+        .get_result::<bool>(conn)?;
+        if !due {
+            return Ok(None);
+        }
+
+        Self::erase(conn, store_id).map(Some)
+    }
+
+    /// Deletes a store scheduled for deletion with `token_hash` right away,
+    /// returning how many items were deleted or None if it isn't scheduled
+    /// with that token. Must be called inside a transaction holding the
+    /// store's write lock.
+    pub fn forget(
+        conn: &mut PgConnection,
+        store_id: &str,
+        token_hash: &str,
+    ) -> anyhow::Result<Option<usize>> {
+        let confirmed = diesel::select(diesel::dsl::exists(
+            vss_stores::table
+                .filter(vss_stores::store_id.eq(store_id))
+                .filter(vss_stores::purge_at.is_not_null())
+                .filter(vss_stores::deletion_token_hash.eq(token_hash)),
+        ))
+        .get_result::<bool>(conn)?;
+        if !confirmed {
+            return Ok(None);
+        }
+
+        Self::erase(conn, store_id).map(Some)
+    }
+
+    /// Deletes every row of the store in this database: its items, devices,
+    /// leases, idempotency keys, nostr subscription, exports, retention rules,
+    /// usage, quota, invoices, version regressions, unpublished changes and
+    /// logged mutations, its fixture mark, and the store itself. Returns how many items were
+    /// deleted. Values in object storage are left for the caller.
+    pub fn erase(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<usize> {
+        let _span = debug_span!("vss.erase_store", store_id).entered();
+
+        // earlier changes go, but deleting the items queues a delete for each
+        // of them so change stream consumers drop their copies too
+        diesel::delete(vss_outbox::table.filter(vss_outbox::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(vss_mutation_log::table.filter(vss_mutation_log::store_id.eq(store_id)))
+            .execute(conn)?;
+        // chunks and offloaded values go with their items through triggers
+        let items =
+            diesel::delete(vss_db::table.filter(vss_db::store_id.eq(store_id))).execute(conn)?;
+        // after the items, which are archived here as they are deleted
+        diesel::delete(vss_history::table.filter(vss_history::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(vss_devices::table.filter(vss_devices::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(vss_leases::table.filter(vss_leases::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(
+            vss_idempotency_keys::table.filter(vss_idempotency_keys::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(
+            vss_nostr_subscriptions::table.filter(vss_nostr_subscriptions::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(vss_store_exports::table.filter(vss_store_exports::store_id.eq(store_id)))
+            .execute(conn)?;
+        // their items go with them
+        diesel::delete(vss_snapshots::table.filter(vss_snapshots::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(
+            vss_retention_rules::table.filter(vss_retention_rules::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(vss_usage::table.filter(vss_usage::store_id.eq(store_id))).execute(conn)?;
+        diesel::delete(vss_quotas::table.filter(vss_quotas::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(vss_quota_invoices::table.filter(vss_quota_invoices::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(
+            vss_version_regressions::table.filter(vss_version_regressions::store_id.eq(store_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(vss_fixture_stores::table.filter(vss_fixture_stores::store_id.eq(store_id)))
+            .execute(conn)?;
+        diesel::delete(vss_stores::table.filter(vss_stores::store_id.eq(store_id)))
+            .execute(conn)?;
+
+        Ok(items)
+    }
+
+    /// Whether the store's contents were loaded from a fixtures file, since
+    /// it was last erased.
+    pub fn is_fixture(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<bool> {
+        Ok(diesel::select(diesel::dsl::exists(
+            vss_fixture_stores::table.filter(vss_fixture_stores::store_id.eq(store_id)),
+        ))
+        .get_result(conn)?)
+    }
+
+    /// Records that the store's contents were just loaded from a fixtures
+    /// file.
+    pub fn mark_fixture(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<()> {
+        diesel::insert_into(vss_fixture_stores::table)
+            .values(vss_fixture_stores::store_id.eq(store_id))
+            .on_conflict(vss_fixture_stores::store_id)
+            .do_update()
+            .set(vss_fixture_stores::loaded_at.eq(diesel::dsl::now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use super::schema::vss_store_exports;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bytea, Text};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug_span;
+
+pub const EXPORT_PENDING: &str = "pending";
+pub const EXPORT_READY: &str = "ready";
+pub const EXPORT_FAILED: &str = "failed";
+
+/// A bundle of everything kept about a store, built in the background for
+/// data portability requests.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_store_exports)]
+pub struct StoreExport {
+    pub export_id: String,
+    pub store_id: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    /// `pending` while the bundle is built, then `ready` or `failed`
+    pub status: String,
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub bundle: Option<Vec<u8>>,
+    /// Size of the bundle in bytes, once it is ready
+    pub size: Option<i64>,
+    pub created_at: chrono::NaiveDateTime,
+    pub completed_at: Option<chrono::NaiveDateTime>,
+    /// When the export and its bundle are deleted
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+impl StoreExport {
+    /// Starts a pending export kept for `retention`, or returns None if one
+    /// of the store is already pending.
+    pub fn create(
+        conn: &mut PgConnection,
+        export_id: &str,
+        store_id: &str,
+        token_hash: &str,
+        retention: Duration,
+    ) -> anyhow::Result<Option<StoreExport>> {
+        let _span = debug_span!("vss.create_store_export", store_id).entered();
+
+        Ok(sql_query(
+            "INSERT INTO vss_store_exports (export_id, store_id, token_hash, expires_at) \
+             SELECT $1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4) \
+             WHERE NOT EXISTS (SELECT 1 FROM vss_store_exports \
+             WHERE store_id = $2 AND status = 'pending' AND expires_at > CURRENT_TIMESTAMP) \
+             RETURNING *",
+        )
+        .bind::<Text, _>(export_id)
+        .bind::<Text, _>(store_id)
+        .bind::<Text, _>(token_hash)
+        .bind::<BigInt, _>(retention.as_secs() as i64)
+        .get_result::<Self>(conn)
+        .optional()?)
+    }
+
+    /// Stores the finished bundle, keeping it for `retention` from now.
+    pub fn complete(
+        conn: &mut PgConnection,
+        export_id: &str,
+        bundle: &[u8],
+        retention: Duration,
+    ) -> anyhow::Result<()> {
+        sql_query(
+            "UPDATE vss_store_exports SET status = 'ready', bundle = $2, size = $3, \
+             completed_at = CURRENT_TIMESTAMP, \
+             expires_at = CURRENT_TIMESTAMP + make_interval(secs => $4) \
+             WHERE export_id = $1",
+        )
+        .bind::<Text, _>(export_id)
+        .bind::<Bytea, _>(bundle)
+        .bind::<BigInt, _>(bundle.len() as i64)
+        .bind::<BigInt, _>(retention.as_secs() as i64)
+        .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn fail(conn: &mut PgConnection, export_id: &str, error: &str) -> anyhow::Result<()> {
+        diesel::update(vss_store_exports::table.filter(vss_store_exports::export_id.eq(export_id)))
+            .set((
+                vss_store_exports::status.eq(EXPORT_FAILED),
+                vss_store_exports::error.eq(error),
+                vss_store_exports::completed_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// An unexpired export, only if `token_hash` is its retrieval token's.
+    pub fn get_export(
+        conn: &mut PgConnection,
+        export_id: &str,
+        token_hash: &str,
+    ) -> anyhow::Result<Option<StoreExport>> {
+        Ok(vss_store_exports::table
+            .filter(vss_store_exports::export_id.eq(export_id))
+            .filter(vss_store_exports::token_hash.eq(token_hash))
+            .filter(vss_store_exports::expires_at.gt(diesel::dsl::now))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Deletes expired exports, returning how many there were.
+    pub fn delete_expired(conn: &mut PgConnection) -> anyhow::Result<usize> {
+        Ok(diesel::delete(
+            vss_store_exports::table.filter(vss_store_exports::expires_at.le(diesel::dsl::now)),
+        )
+        .execute(conn)?)
+    }
+}
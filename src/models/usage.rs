@@ -0,0 +1,108 @@
+use super::schema::vss_usage;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Integer, Text};
+use serde::{Deserialize, Serialize};
+use tracing::debug_span;
+
+/// A store's request count and traffic for one day.
+#[derive(QueryableByName, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = vss_usage)]
+pub struct UsageDay {
+    pub store_id: String,
+    pub day: chrono::NaiveDate,
+    pub requests: i64,
+    /// Bytes of response bodies sent for the store
+    pub bytes_read: i64,
+    /// Bytes of request bodies received for the store
+    pub bytes_written: i64,
+}
+
+/// Requests and traffic added up over several stores and days.
+#[derive(QueryableByName, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageTotals {
+    #[diesel(sql_type = BigInt)]
+    pub requests: i64,
+    #[diesel(sql_type = BigInt)]
+    pub bytes_read: i64,
+    #[diesel(sql_type = BigInt)]
+    pub bytes_written: i64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, other: UsageTotals) {
+        self.requests += other.requests;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+impl UsageDay {
+    /// Adds to today's totals for the store.
+    pub fn add(
+        conn: &mut PgConnection,
+        store_id: &str,
+        requests: i64,
+        bytes_read: i64,
+        bytes_written: i64,
+    ) -> anyhow::Result<()> {
+        let _span = debug_span!("vss.add_usage", store_id).entered();
+
+        sql_query(
+            "INSERT INTO vss_usage (store_id, day, requests, bytes_read, bytes_written) \
+             VALUES ($1, CURRENT_DATE, $2, $3, $4) \
+             ON CONFLICT (store_id, day) DO UPDATE \
+             SET requests = vss_usage.requests + excluded.requests, \
+             bytes_read = vss_usage.bytes_read + excluded.bytes_read, \
+             bytes_written = vss_usage.bytes_written + excluded.bytes_written",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<BigInt, _>(requests)
+        .bind::<BigInt, _>(bytes_read)
+        .bind::<BigInt, _>(bytes_written)
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Lists the store's totals for the last `days` days, newest first.
+    pub fn list_usage(
+        conn: &mut PgConnection,
+        store_id: &str,
+        days: i32,
+    ) -> anyhow::Result<Vec<UsageDay>> {
+        Ok(sql_query(
+            "SELECT * FROM vss_usage WHERE store_id = $1 AND day > CURRENT_DATE - $2 \
+             ORDER BY day DESC",
+        )
+        .bind::<Text, _>(store_id)
+        .bind::<Integer, _>(days)
+        .load::<UsageDay>(conn)?)
+    }
+
+    /// Every day the store has been used on, oldest first.
+    pub fn list_all(conn: &mut PgConnection, store_id: &str) -> anyhow::Result<Vec<UsageDay>> {
+        Ok(vss_usage::table
+            .filter(vss_usage::store_id.eq(store_id))
+            .order(vss_usage::day.asc())
+            .load::<Self>(conn)?)
+    }
+
+    /// Totals of the stores over the last `days` days.
+    pub fn sum_usage(
+        conn: &mut PgConnection,
+        store_ids: &[String],
+        days: i32,
+    ) -> anyhow::Result<UsageTotals> {
+        Ok(sql_query(
+            "SELECT COALESCE(SUM(requests), 0)::BIGINT AS requests, \
+             COALESCE(SUM(bytes_read), 0)::BIGINT AS bytes_read, \
+             COALESCE(SUM(bytes_written), 0)::BIGINT AS bytes_written \
+             FROM vss_usage WHERE store_id = ANY($1) AND day > CURRENT_DATE - $2",
+        )
+        .bind::<Array<Text>, _>(store_ids)
+        .bind::<Integer, _>(days)
+        .get_result::<UsageTotals>(conn)?)
+    }
+}
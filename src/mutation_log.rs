@@ -0,0 +1,244 @@
+use crate::auth::verify_admin_token;
+use crate::history::env_limit;
+use crate::models::{with_db_retry, Mutation, MutationWatermark};
+use crate::routes::{handle_anyhow_error, resolve_store_id, ErrorResponse};
+use crate::shard::DbPool;
+use crate::State;
+use anyhow::anyhow;
+use axum::body::{Bytes, StreamBody};
+use axum::extract::Query;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, TypedHeader};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// Mutations read from the database at a time while streaming
+const PAGE_SIZE: i64 = 500;
+/// Mutations deleted by one statement when pruning
+const PRUNE_BATCH_SIZE: i64 = 1_000;
+
+/// Records every committed change to an item in vss_mutation_log, so
+/// replication tools and audits can read them back in order.
+#[derive(Debug, Clone, Default)]
+pub struct MutationLog {
+    /// Mutations are kept for this many days, forever when None
+    pub keep_days: Option<i32>,
+}
+
+impl MutationLog {
+    pub fn from_env() -> anyhow::Result<Option<MutationLog>> {
+        let enabled = std::env::var("MUTATION_LOG")
+            .ok()
+            .map(|s| s == "true" || s == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+
+        let log = MutationLog {
+            keep_days: env_limit("MUTATION_LOG_KEEP_DAYS")?,
+        };
+        match log.keep_days {
+            Some(days) => info!("Logging mutations, kept for {days} days"),
+            None => info!("Logging mutations, kept forever"),
+        }
+
+        Ok(Some(log))
+    }
+}
+
+/// Deletes mutations older than the configured number of days on every
+/// shard, in batches.
+pub async fn prune_mutation_log(state: State) {
+    let Some(keep_days) = state.mutation_log.as_ref().and_then(|l| l.keep_days) else {
+        return;
+    };
+
+    for shard in state.shards.all() {
+        let mut pruned = 0;
+        loop {
+            let res = with_db_retry("prune_mutation_log", &state.breaker, || {
+                let mut conn = shard.pool.get()?;
+                Mutation::prune(&mut conn, keep_days, PRUNE_BATCH_SIZE)
+            })
+            .await;
+
+            match res {
+                Ok(count) => {
+                    pruned += count;
+                    if (count as i64) < PRUNE_BATCH_SIZE {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to prune mutation log on {}: {e}", shard.name);
+                    break;
+                }
+            }
+        }
+        if pruned > 0 {
+            info!("Pruned {pruned} logged mutations on {}", shard.name);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MutationsQuery {
+    /// Watermark of the last mutation already read, the stream starts at the
+    /// beginning of the log otherwise
+    pub after: Option<String>,
+    /// Only this store's mutations
+    pub store_id: Option<String>,
+    /// Database shard to read, needed when there is more than one unless
+    /// `store_id` is given
+    pub shard: Option<String>,
+    /// Most mutations to stream, otherwise the stream ends once it has
+    /// caught up with the log
+    pub limit: Option<usize>,
+}
+
+/// A line of the mutation stream.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MutationRecord {
+    /// Pass as `after` to carry on from this mutation
+    pub watermark: String,
+    pub store_id: String,
+    pub key: String,
+    pub version: u64,
+    /// `put`, or `delete` for tombstones and removed items
+    pub op: String,
+    /// Hex SHA-256 of the value written, missing for deletes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_hash: Option<String>,
+    /// Start of the transaction that made the change
+    pub timestamp: chrono::NaiveDateTime,
+}
+
+impl From<Mutation> for MutationRecord {
+    fn from(mutation: Mutation) -> Self {
+        MutationRecord {
+            watermark: mutation.watermark().to_string(),
+            store_id: mutation.store_id,
+            key: mutation.key,
+            version: mutation.version,
+            op: mutation.op,
+            value_hash: mutation.value_hash.map(hex::encode),
+            timestamp: mutation.created_at,
+        }
+    }
+}
+
+/// Where a stream reads from and how far it has got.
+struct Cursor {
+    state: State,
+    pool: DbPool,
+    store_id: Option<String>,
+    after: MutationWatermark,
+    remaining: usize,
+}
+
+impl Cursor {
+    async fn new(query: MutationsQuery, state: State) -> anyhow::Result<Cursor> {
+        let after = query
+            .after
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let store_id = match query.store_id {
+            Some(ref store_id) => Some(resolve_store_id(store_id, &state).await?),
+            None => None,
+        };
+
+        let shard = match (&store_id, &query.shard) {
+            (Some(store_id), _) => state.shards.for_store(store_id),
+            (None, Some(name)) => state
+                .shards
+                .all()
+                .iter()
+                .find(|s| &s.name == name)
+                .ok_or_else(|| anyhow!("Unknown shard {name}"))?,
+            (None, None) => match state.shards.all() {
+                [shard] => shard,
+                _ => return Err(anyhow!("shard is required with more than one shard")),
+            },
+        };
+
+        Ok(Cursor {
+            pool: shard.pool.clone(),
+            state: state.clone(),
+            store_id,
+            after,
+            remaining: query.limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    /// The next page of the stream as NDJSON, None once it has ended.
+    async fn next_page(&mut self) -> anyhow::Result<Option<Bytes>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        let limit = PAGE_SIZE.min(self.remaining.try_into().unwrap_or(PAGE_SIZE));
+        let mutations = with_db_retry("list_mutations", &self.state.breaker, || {
+            let mut conn = self.pool.get()?;
+            Mutation::list_after(&mut conn, self.after, self.store_id.as_deref(), limit)
+        })
+        .await?;
+        let Some(last) = mutations.last() else {
+            return Ok(None);
+        };
+        self.after = last.watermark();
+        self.remaining -= mutations.len();
+
+        let mut page = vec![];
+        for mutation in mutations {
+            serde_json::to_writer(&mut page, &MutationRecord::from(mutation))?;
+            page.push(b'\n');
+        }
+        Ok(Some(page.into()))
+    }
+}
+
+/// Streams the mutation log as NDJSON, in the order the mutations were
+/// committed, starting after the `after` watermark.
+pub async fn stream_mutations(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Query(query): Query<MutationsQuery>,
+) -> Result<Response, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+    if state.mutation_log.is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Mutation log not enabled".to_string(),
+        )
+            .into());
+    }
+
+    let cursor = Cursor::new(query, state)
+        .await
+        .map_err(|e| handle_anyhow_error("stream_mutations", e))?;
+
+    let pages = futures::stream::unfold(Some(cursor), |cursor| async move {
+        let mut cursor = cursor?;
+        match cursor.next_page().await {
+            Ok(Some(page)) => Some((Ok(page), Some(cursor))),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to stream mutations: {e}");
+                // ends the response early, so the client sees it cut off
+                Some((Err(e), None))
+            }
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(pages),
+    )
+        .into_response())
+}
@@ -0,0 +1,312 @@
+use crate::models::{with_db_retry, NostrSubscription};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use anyhow::anyhow;
+use bech32::FromBase32;
+use log::{debug, error, info, warn};
+use secp256k1::{ecdh, KeyPair, Message, Parity, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+
+/// NIP-04 encrypted direct message
+const ENCRYPTED_DM_KIND: u32 = 4;
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A signed nostr event, as described in NIP-01.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+/// Parses an `npub` or hex encoded nostr pubkey.
+pub fn parse_pubkey(pubkey: &str) -> anyhow::Result<XOnlyPublicKey> {
+    if pubkey.starts_with("npub") {
+        let (hrp, data, _) = bech32::decode(pubkey)?;
+        if hrp != "npub" {
+            return Err(anyhow!("Invalid npub"));
+        }
+        return Ok(XOnlyPublicKey::from_slice(&Vec::<u8>::from_base32(&data)?)?);
+    }
+    Ok(XOnlyPublicKey::from_str(pubkey)?)
+}
+
+/// Encrypts `plaintext` for `recipient` as NIP-04 content.
+pub fn encrypt_nip04(
+    secret_key: &SecretKey,
+    recipient: &XOnlyPublicKey,
+    plaintext: &str,
+) -> anyhow::Result<String> {
+    // nostr pubkeys are x-only, NIP-04 treats them as having even y
+    let recipient = PublicKey::from_x_only_public_key(*recipient, Parity::Even);
+    let point = ecdh::shared_secret_point(&recipient, secret_key);
+    let key = &point[..32];
+
+    let mut iv = [0u8; 16];
+    getrandom::getrandom(&mut iv).map_err(|e| anyhow!("Failed to generate iv: {e}"))?;
+
+    let ciphertext = cbc::Encryptor::<aes::Aes256>::new(key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+    Ok(format!(
+        "{}?iv={}",
+        base64::encode(ciphertext),
+        base64::encode(iv)
+    ))
+}
+
+/// Builds and signs an event with the id and signature NIP-01 requires.
+pub fn sign_event(
+    secp: &Secp256k1<secp256k1::All>,
+    keys: &KeyPair,
+    created_at: u64,
+    kind: u32,
+    tags: Vec<Vec<String>>,
+    content: String,
+) -> anyhow::Result<Event> {
+    let pubkey = keys.x_only_public_key().0.to_string();
+    let serialized = serde_json::to_string(&json!([0, pubkey, created_at, kind, tags, content]))?;
+    let id = Sha256::digest(serialized.as_bytes());
+    let sig = secp.sign_schnorr_no_aux_rand(&Message::from_slice(&id)?, keys);
+
+    Ok(Event {
+        id: hex::encode(id),
+        pubkey,
+        created_at,
+        kind,
+        tags,
+        content,
+        sig: sig.to_string(),
+    })
+}
+
+/// Sends encrypted nostr messages to stores' registered pubkeys when their
+/// backups are updated. Updates are collected and sent every
+/// `NOSTR_NOTIFY_INTERVAL_SECS`, so a burst of writes sends one message.
+#[derive(Clone)]
+pub struct Notifier {
+    keys: KeyPair,
+    relays: Vec<String>,
+    interval: Duration,
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Notifier {
+    /// Configured by `NOSTR_SECRET_KEY` (hex) and `NOSTR_RELAYS` (comma
+    /// separated relay urls), returns None if no key is set.
+    pub fn from_env(secp: &Secp256k1<secp256k1::All>) -> anyhow::Result<Option<Notifier>> {
+        let Ok(secret_key) = std::env::var("NOSTR_SECRET_KEY") else {
+            return Ok(None);
+        };
+        let keys = KeyPair::from_seckey_str(secp, &secret_key)?;
+
+        let relays: Vec<String> = std::env::var("NOSTR_RELAYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|r| r.trim().to_string())
+            .filter(|r| !r.is_empty())
+            .collect();
+        if relays.is_empty() {
+            return Err(anyhow!("NOSTR_RELAYS must be set with NOSTR_SECRET_KEY"));
+        }
+
+        let interval = std::env::var("NOSTR_NOTIFY_INTERVAL_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(60);
+
+        info!(
+            "Sending backup notifications as {} to {} relays",
+            keys.x_only_public_key().0,
+            relays.len()
+        );
+
+        Ok(Some(Notifier {
+            keys,
+            relays,
+            interval: Duration::from_secs(interval.max(1)),
+            pending: Default::default(),
+        }))
+    }
+
+    /// Queues a notification that the store's backup was updated.
+    pub fn notify(&self, store_id: &str) {
+        self.pending
+            .lock()
+            .expect("nostr lock poisoned")
+            .insert(store_id.to_string());
+    }
+
+    fn take(&self) -> HashSet<String> {
+        std::mem::take(&mut *self.pending.lock().expect("nostr lock poisoned"))
+    }
+
+    async fn send(&self, state: &State, store_id: &str) -> anyhow::Result<()> {
+        let subscription = with_db_retry("get_nostr_subscription", &state.breaker, || {
+            let mut conn = state.db(store_id).get()?;
+            NostrSubscription::get_subscription(&mut conn, store_id)
+        })
+        .await?;
+        let Some(subscription) = subscription else {
+            return Ok(());
+        };
+
+        let recipient = parse_pubkey(&subscription.pubkey)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let updated_at = chrono::NaiveDateTime::from_timestamp_opt(now as i64, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default();
+        let content = encrypt_nip04(
+            &self.keys.secret_key(),
+            &recipient,
+            &format!("Your wallet backup was updated at {updated_at}"),
+        )?;
+        let event = sign_event(
+            &state.secp,
+            &self.keys,
+            now,
+            ENCRYPTED_DM_KIND,
+            vec![vec!["p".to_string(), recipient.to_string()]],
+            content,
+        )?;
+
+        let mut published = 0;
+        for relay in self.relays.iter() {
+            let relay = relay.clone();
+            let event = event.clone();
+            match tokio::task::spawn_blocking(move || publish(&relay, &event)).await? {
+                Ok(()) => published += 1,
+                Err(e) => warn!("Failed to publish backup notification: {e}"),
+            }
+        }
+        if published == 0 {
+            return Err(anyhow!("No relay accepted the notification"));
+        }
+
+        debug!("Sent backup notification to {published} relays");
+        Ok(())
+    }
+}
+
+/// Publishes `event` to a relay and waits for it to be accepted.
+fn publish(relay: &str, event: &Event) -> anyhow::Result<()> {
+    let (mut socket, _) = tungstenite::connect(relay)?;
+    set_read_timeout(&mut socket)?;
+
+    socket.send(tungstenite::Message::Text(
+        json!(["EVENT", event]).to_string(),
+    ))?;
+
+    // relays answer with ["OK", <id>, <accepted>, <message>]
+    let res = loop {
+        if let tungstenite::Message::Text(text) = socket.read()? {
+            let msg: serde_json::Value = serde_json::from_str(&text)?;
+            if msg[0] == "OK" && msg[1] == event.id.as_str() {
+                break match msg[2].as_bool() {
+                    Some(true) => Ok(()),
+                    _ => Err(anyhow!("{relay} rejected the event: {}", msg[3])),
+                };
+            }
+        }
+    };
+
+    let _ = socket.close(None);
+    res
+}
+
+fn set_read_timeout(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> anyhow::Result<()> {
+    let stream = match socket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream,
+        MaybeTlsStream::Rustls(stream) => stream.get_mut(),
+        _ => return Ok(()),
+    };
+    stream.set_read_timeout(Some(RELAY_TIMEOUT))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NostrSubscribeRequest {
+    pub store_id: Option<String>,
+    /// `npub` or hex pubkey to message when the backup is updated
+    pub pubkey: String,
+}
+
+pub async fn subscribe_impl(
+    req: NostrSubscribeRequest,
+    state: &State,
+) -> anyhow::Result<NostrSubscription> {
+    if state.nostr.is_none() {
+        return Err(anyhow!("Nostr notifications are not enabled"));
+    }
+    // store the hex form so a malformed key is rejected up front
+    let pubkey = parse_pubkey(&req.pubkey)
+        .map_err(|e| {
+            InvalidRequest::field(
+                "pubkey",
+                FieldErrorCode::InvalidFormat,
+                format!("Invalid pubkey: {e}"),
+            )
+        })?
+        .to_string();
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("nostr_subscribe", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        NostrSubscription::subscribe(&mut conn, &store_id, &pubkey)
+    })
+    .await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NostrUnsubscribeRequest {
+    pub store_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NostrUnsubscribeResponse {
+    pub removed: bool,
+}
+
+pub async fn unsubscribe_impl(
+    req: NostrUnsubscribeRequest,
+    state: &State,
+) -> anyhow::Result<NostrUnsubscribeResponse> {
+    let store_id = req.store_id.expect("must have");
+
+    let removed = with_db_retry("nostr_unsubscribe", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        NostrSubscription::unsubscribe(&mut conn, &store_id)
+    })
+    .await?;
+
+    Ok(NostrUnsubscribeResponse { removed })
+}
+
+/// Sends queued notifications every interval until the server shuts down.
+pub async fn run_notifier(state: State, notifier: Notifier) {
+    let mut ticker = tokio::time::interval(notifier.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        for store_id in notifier.take() {
+            if let Err(e) = notifier.send(&state, &store_id).await {
+                error!("Failed to send backup notification: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use crate::auth::{verify_admin_token, verify_org_token};
+use crate::models::{with_db_retry, Org, OrgStore, Quota, StoreUsage, UsageDay, UsageTotals};
+use crate::quota::QuotaExceeded;
+use crate::routes::{handle_anyhow_error, resolve_store_id, ErrorResponse};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use axum::extract::{Path, Query};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::StatusCode;
+use axum::{Extension, Json, TypedHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const MAX_ORG_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveOrgRequest {
+    pub org_id: String,
+    pub name: Option<String>,
+    /// Most stores the org can have, unlimited if unset
+    pub max_stores: Option<i64>,
+    /// Most bytes the org's stores can hold together, unlimited if unset
+    pub max_bytes: Option<i64>,
+}
+
+impl SaveOrgRequest {
+    fn validate(&self) -> Result<(), InvalidRequest> {
+        if self.org_id.is_empty() || self.org_id.len() > MAX_ORG_ID_LEN {
+            return Err(InvalidRequest::field(
+                "org_id",
+                FieldErrorCode::InvalidFormat,
+                format!("org_id must be 1 to {MAX_ORG_ID_LEN} bytes"),
+            ));
+        }
+        for (field, limit) in [
+            ("max_stores", self.max_stores),
+            ("max_bytes", self.max_bytes),
+        ] {
+            if limit.map_or(false, |l| l < 0) {
+                return Err(InvalidRequest::field(
+                    field,
+                    FieldErrorCode::OutOfRange,
+                    format!("{field} can't be negative"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn save_org_impl(req: SaveOrgRequest, state: &State) -> anyhow::Result<Org> {
+    req.validate()?;
+
+    with_db_retry("save_org", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::save(
+            &mut conn,
+            &req.org_id,
+            req.name.as_deref(),
+            req.max_stores,
+            req.max_bytes,
+        )
+    })
+    .await
+}
+
+/// Creates an org, or replaces the name and limits of an existing one.
+pub async fn save_org(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Json(payload): Json<SaveOrgRequest>,
+) -> Result<Json<Org>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match save_org_impl(payload, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("save_org", e)),
+    }
+}
+
+pub async fn list_orgs(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<Org>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    let res = with_db_retry("list_orgs", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::list_orgs(&mut conn)
+    })
+    .await;
+
+    match res {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_orgs", e)),
+    }
+}
+
+pub async fn get_org_impl(org_id: &str, state: &State) -> anyhow::Result<Option<Org>> {
+    with_db_retry("get_org", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::get_org(&mut conn, org_id)
+    })
+    .await
+}
+
+pub async fn get_org(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(org_id): Path<String>,
+) -> Result<Json<Org>, ErrorResponse> {
+    verify_org_token(token.token(), &org_id, &state)?;
+
+    match get_org_impl(&org_id, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Org {org_id} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("get_org", e)),
+    }
+}
+
+pub async fn list_org_store_ids(org_id: &str, state: &State) -> anyhow::Result<Vec<String>> {
+    with_db_retry("list_org_stores", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::list_store_ids(&mut conn, org_id)
+    })
+    .await
+}
+
+pub async fn list_org_stores(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(org_id): Path<String>,
+) -> Result<Json<Vec<String>>, ErrorResponse> {
+    verify_org_token(token.token(), &org_id, &state)?;
+
+    match list_org_store_ids(&org_id, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_org_stores", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddOrgStoreRequest {
+    pub store_id: String,
+}
+
+pub async fn add_org_store_impl(
+    org_id: &str,
+    req: AddOrgStoreRequest,
+    state: &State,
+) -> anyhow::Result<OrgStore> {
+    let store_id = resolve_store_id(&req.store_id, state).await?;
+    state.store_id_policy.validate(&store_id)?;
+
+    with_db_retry("add_org_store", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::add_store(&mut conn, org_id, &store_id)
+    })
+    .await
+}
+
+/// Puts a store under the org, which needn't have been written to yet.
+pub async fn add_org_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(org_id): Path<String>,
+    Json(payload): Json<AddOrgStoreRequest>,
+) -> Result<Json<OrgStore>, ErrorResponse> {
+    verify_org_token(token.token(), &org_id, &state)?;
+
+    match add_org_store_impl(&org_id, payload, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("add_org_store", e)),
+    }
+}
+
+pub async fn remove_org_store_impl(
+    org_id: &str,
+    store_id: &str,
+    state: &State,
+) -> anyhow::Result<bool> {
+    let store_id = resolve_store_id(store_id, state).await?;
+
+    with_db_retry("remove_org_store", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::remove_store(&mut conn, org_id, &store_id)
+    })
+    .await
+}
+
+/// Takes a store out of the org, leaving its items alone.
+pub async fn remove_org_store(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path((org_id, store_id)): Path<(String, String)>,
+) -> Result<Json<()>, ErrorResponse> {
+    verify_org_token(token.token(), &org_id, &state)?;
+
+    match remove_org_store_impl(&org_id, &store_id, &state).await {
+        Ok(true) => Ok(Json(())),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Store {store_id} is not in org {org_id}"),
+        )
+            .into()),
+        Err(e) => Err(handle_anyhow_error("remove_org_store", e)),
+    }
+}
+
+/// Live keys and bytes across the stores, added up shard by shard.
+pub async fn stores_usage(store_ids: &[String], state: &State) -> anyhow::Result<StoreUsage> {
+    let mut usage = StoreUsage::default();
+    for store_ids in by_shard(store_ids, state).into_values() {
+        let shard_usage = with_db_retry("org_usage", &state.breaker, || {
+            let mut conn = state.db(&store_ids[0]).get()?;
+            Quota::stores_usage(&mut conn, &store_ids)
+        })
+        .await?;
+        usage.keys += shard_usage.keys;
+        usage.bytes += shard_usage.bytes;
+    }
+    Ok(usage)
+}
+
+/// Groups store ids by the name of the shard holding them.
+fn by_shard(store_ids: &[String], state: &State) -> HashMap<String, Vec<String>> {
+    let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+    for store_id in store_ids {
+        let shard = state.shards.for_store(store_id).name.clone();
+        groups.entry(shard).or_default().push(store_id.clone());
+    }
+    groups
+}
+
+/// Fails with [`QuotaExceeded`] if `store_id` belongs to an org whose stores
+/// already hold its byte limit. Checked before writing, so the write that
+/// takes an org past its limit still succeeds and later ones fail.
+pub async fn enforce_org_quota(store_id: &str, state: &State) -> anyhow::Result<()> {
+    let org = with_db_retry("get_store_org", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        Org::for_store(&mut conn, store_id)
+    })
+    .await?;
+    let Some((org, max_bytes)) = org.and_then(|org| org.max_bytes.map(|max| (org, max))) else {
+        return Ok(());
+    };
+
+    let store_ids = list_org_store_ids(&org.org_id, state).await?;
+    let usage = stores_usage(&store_ids, state).await?;
+    if usage.bytes >= max_bytes {
+        return Err(QuotaExceeded::org(&org.org_id, usage, max_bytes).into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgStatsQuery {
+    /// How many days of traffic to add up, defaults to 30
+    pub days: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgStats {
+    pub org: Org,
+    pub stores: usize,
+    /// Live keys and bytes across the org's stores
+    pub usage: StoreUsage,
+    /// Requests and traffic across the org's stores over the last `days`
+    pub traffic: UsageTotals,
+    pub days: i32,
+}
+
+pub async fn get_org_stats_impl(
+    org_id: &str,
+    query: OrgStatsQuery,
+    state: &State,
+) -> anyhow::Result<Option<OrgStats>> {
+    let days = query.days.unwrap_or(30).clamp(1, 366);
+    let Some(org) = get_org_impl(org_id, state).await? else {
+        return Ok(None);
+    };
+    let store_ids = list_org_store_ids(org_id, state).await?;
+
+    let usage = stores_usage(&store_ids, state).await?;
+    let mut traffic = UsageTotals::default();
+    for store_ids in by_shard(&store_ids, state).into_values() {
+        traffic.add(
+            with_db_retry("org_traffic", &state.breaker, || {
+                let mut conn = state.db(&store_ids[0]).get()?;
+                UsageDay::sum_usage(&mut conn, &store_ids, days)
+            })
+            .await?,
+        );
+    }
+
+    Ok(Some(OrgStats {
+        org,
+        stores: store_ids.len(),
+        usage,
+        traffic,
+        days,
+    }))
+}
+
+pub async fn get_org_stats(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(org_id): Path<String>,
+    Query(query): Query<OrgStatsQuery>,
+) -> Result<Json<OrgStats>, ErrorResponse> {
+    verify_org_token(token.token(), &org_id, &state)?;
+
+    match get_org_stats_impl(&org_id, query, &state).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Org {org_id} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("get_org_stats", e)),
+    }
+}
@@ -0,0 +1,133 @@
+use crate::auth::verify_admin_token;
+use crate::models::{partition, with_db_retry, PartitionStatus};
+use crate::routes::{handle_anyhow_error, ErrorResponse};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_PARTITIONS: u32 = 16;
+const MAX_PARTITIONS: u32 = 1_024;
+/// Progress is logged every this many stores
+const LOG_EVERY: usize = 1_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionRequest {
+    /// Number of hash partitions, defaults to 16. Ignored when resuming a
+    /// conversion that has already created its partitions.
+    pub partitions: Option<u32>,
+}
+
+impl PartitionRequest {
+    fn validate(&self) -> Result<(), InvalidRequest> {
+        match self.partitions {
+            Some(n) if !(2..=MAX_PARTITIONS).contains(&n) => Err(InvalidRequest::field(
+                "partitions",
+                FieldErrorCode::OutOfRange,
+                format!("partitions must be between 2 and {MAX_PARTITIONS}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PartitionReport {
+    pub stores: usize,
+    pub items: usize,
+}
+
+/// Converts vss_db into a hash-partitioned table while the server keeps
+/// serving requests, on every database shard in turn. Safe to run again if
+/// it was interrupted.
+pub async fn partition_impl(
+    req: PartitionRequest,
+    state: &State,
+) -> anyhow::Result<PartitionReport> {
+    let partitions = req.partitions.unwrap_or(DEFAULT_PARTITIONS);
+
+    let mut report = PartitionReport::default();
+    for shard in state.shards.all() {
+        with_db_retry("prepare_partitioning", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            partition::prepare(&mut conn, partitions)
+        })
+        .await?;
+
+        let stores = with_db_retry("list_partition_stores", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            partition::list_stores(&mut conn)
+        })
+        .await?;
+        info!(
+            "Copying {} stores on {} into the partitioned table",
+            stores.len(),
+            shard.name
+        );
+
+        for (i, store_id) in stores.iter().enumerate() {
+            report.items += with_db_retry("copy_store_partition", &state.breaker, || {
+                let mut conn = shard.pool.get()?;
+                partition::copy_store(&mut conn, store_id)
+            })
+            .await?;
+            report.stores += 1;
+
+            if (i + 1) % LOG_EVERY == 0 {
+                info!("Copied {}/{} stores on {}", i + 1, stores.len(), shard.name);
+            }
+        }
+
+        with_db_retry("swap_partitioned_table", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            partition::swap(&mut conn)
+        })
+        .await?;
+    }
+
+    info!("Partitioning complete! {report:?}");
+
+    Ok(report)
+}
+
+pub async fn partition(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Json(payload): Json<PartitionRequest>,
+) -> Result<Json<()>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    if let Err(e) = payload.validate() {
+        return Err(e.to_response());
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = partition_impl(payload, &state).await {
+            error!("Partitioning failed: {e:?}")
+        }
+    });
+
+    Ok(Json(()))
+}
+
+/// Progress of partitioning the main database.
+pub async fn partition_status(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<PartitionStatus>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    let res = with_db_retry("partition_status", &state.breaker, || {
+        let mut conn = state.db_pool.get()?;
+        partition::status(&mut conn)
+    })
+    .await;
+
+    match res {
+        Ok(status) => Ok(Json(status)),
+        Err(e) => Err(handle_anyhow_error("partition_status", e)),
+    }
+}
@@ -0,0 +1,167 @@
+use crate::auth::verify_admin_token;
+use crate::routes::ErrorResponse;
+use crate::State;
+use axum::extract::Query;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::{Extension, TypedHeader};
+use serde::Deserialize;
+
+const DEFAULT_SECONDS: u64 = 10;
+/// Longest profile that can be taken in one request
+pub const MAX_SECONDS: u64 = 60;
+/// Samples a second, just off 100 so sampling doesn't line up with timers
+const DEFAULT_FREQUENCY: i32 = 99;
+const MAX_FREQUENCY: i32 = 1_000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// Protobuf profile for `go tool pprof`
+    #[default]
+    Pprof,
+    /// SVG flamegraph
+    Flamegraph,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProfileQuery {
+    /// How long to sample for
+    pub seconds: Option<u64>,
+    /// Samples taken a second
+    pub frequency: Option<i32>,
+    #[serde(default)]
+    pub format: ProfileFormat,
+}
+
+#[cfg(feature = "profiling")]
+mod sampler {
+    use super::ProfileFormat;
+    use anyhow::anyhow;
+    use pprof::protos::Message;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    /// The profiler samples the whole process, so only one runs at a time
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    pub struct Busy;
+
+    /// Samples every thread for `duration` and encodes the result. Blocks
+    /// the calling thread for the whole time.
+    pub fn capture(
+        duration: Duration,
+        frequency: i32,
+        format: ProfileFormat,
+    ) -> Result<anyhow::Result<Vec<u8>>, Busy> {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return Err(Busy);
+        }
+        let res = sample(duration, frequency, format);
+        RUNNING.store(false, Ordering::Release);
+        Ok(res)
+    }
+
+    fn sample(
+        duration: Duration,
+        frequency: i32,
+        format: ProfileFormat,
+    ) -> anyhow::Result<Vec<u8>> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| anyhow!("Failed to start the profiler: {e}"))?;
+        std::thread::sleep(duration);
+        let report = guard.report().build()?;
+
+        match format {
+            ProfileFormat::Pprof => Ok(report.pprof()?.encode_to_vec()),
+            ProfileFormat::Flamegraph => {
+                let mut svg = vec![];
+                report.flamegraph(&mut svg)?;
+                Ok(svg)
+            }
+        }
+    }
+}
+
+/// Samples the server's CPU use for `seconds` and returns the profile, as
+/// pprof protobuf or an SVG flamegraph. Needs the `profiling` feature.
+pub async fn cpu_profile(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Query(query): Query<ProfileQuery>,
+) -> Result<Response, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    let seconds = query.seconds.unwrap_or(DEFAULT_SECONDS);
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be 1 to {MAX_SECONDS}"),
+        )
+            .into());
+    }
+    let frequency = query.frequency.unwrap_or(DEFAULT_FREQUENCY);
+    if !(1..=MAX_FREQUENCY).contains(&frequency) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("frequency must be 1 to {MAX_FREQUENCY}"),
+        )
+            .into());
+    }
+
+    capture(seconds, frequency, query.format).await
+}
+
+#[cfg(feature = "profiling")]
+async fn capture(
+    seconds: u64,
+    frequency: i32,
+    format: ProfileFormat,
+) -> Result<Response, ErrorResponse> {
+    use axum::http::header;
+    use axum::response::IntoResponse;
+    use log::{error, info};
+    use std::time::Duration;
+
+    info!("Taking a {seconds}s CPU profile at {frequency}Hz");
+    let duration = Duration::from_secs(seconds);
+    let res = tokio::task::spawn_blocking(move || sampler::capture(duration, frequency, format))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    match res {
+        Ok(Ok(profile)) => {
+            let content_type = match format {
+                ProfileFormat::Pprof => "application/octet-stream",
+                ProfileFormat::Flamegraph => "image/svg+xml",
+            };
+            Ok(([(header::CONTENT_TYPE, content_type)], profile).into_response())
+        }
+        Ok(Err(e)) => {
+            error!("Failed to take a CPU profile: {e:?}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+        Err(sampler::Busy) => Err((
+            StatusCode::CONFLICT,
+            "A CPU profile is already being taken".to_string(),
+        )),
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+async fn capture(
+    _seconds: u64,
+    _frequency: i32,
+    _format: ProfileFormat,
+) -> Result<Response, ErrorResponse> {
+    Err((
+        StatusCode::NOT_FOUND,
+        "CPU profiling not enabled, build with --features profiling".to_string(),
+    )
+        .into())
+}
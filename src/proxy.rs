@@ -0,0 +1,218 @@
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, Request};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Address of the client a request came from, behind any trusted proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// A range of addresses like `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(net.into(), ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(a: u128, b: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    // shifting a u128 by 128 overflows
+    shift >= 128 || a >> shift == b >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim())
+            .map_err(|_| anyhow!("{s} isn't an address or address range"))?
+            .to_canonical();
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= bits)
+                .ok_or_else(|| anyhow!("{s} has an invalid prefix length"))?,
+            None => bits,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed,
+/// e.g. a load balancer or Cloudflare's address ranges.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<Cidr>) -> TrustedProxies {
+        TrustedProxies(proxies)
+    }
+
+    /// Comma-separated addresses and ranges in `TRUSTED_PROXIES`.
+    pub fn from_env() -> anyhow::Result<TrustedProxies> {
+        let Ok(proxies) = std::env::var("TRUSTED_PROXIES") else {
+            return Ok(TrustedProxies::default());
+        };
+        proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(Cidr::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(TrustedProxies)
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client behind `peer`. Forwarding headers are followed from the
+    /// nearest hop back only while each hop is a trusted proxy, so a client
+    /// can't claim another address by sending the headers itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        for hop in forwarded_for(headers).into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            match hop {
+                Some(ip) => client = ip.to_canonical(),
+                // obfuscated or unknown, the proxy is as far as we can see
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The addresses a request was forwarded for, furthest first, from the
+/// `Forwarded` header or else `X-Forwarded-For`. None for hops that aren't
+/// an address, like `for=unknown`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<Option<IpAddr>> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then(|| parse_node(value))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// An address, optionally quoted, bracketed or with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The client address of a request served with connect info, behind the
+/// proxies the server trusts.
+pub fn client_ip<B>(req: &Request<B>) -> Option<IpAddr> {
+    let ConnectInfo(peer) = req.extensions().get::<ConnectInfo<SocketAddr>>()?;
+    match req.extensions().get::<State>() {
+        Some(state) => Some(state.trusted_proxies.client_ip(peer.ip(), req.headers())),
+        None => Some(peer.ip()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trusted_proxies() {
+        let ip = |s: &str| IpAddr::from_str(s).unwrap();
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(Cidr::from_str("2001:db8::/32")
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("cloudflare").is_err());
+
+        let proxies = TrustedProxies::new(vec![
+            Cidr::from_str("10.0.0.0/8").unwrap(),
+            Cidr::from_str("2001:db8::/32").unwrap(),
+        ]);
+        let xff = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+
+        // untrusted peers can't claim another address
+        assert_eq!(proxies.client_ip(ip("5.5.5.5"), &xff), ip("5.5.5.5"));
+        // the first untrusted hop is the client, not whatever it claimed
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &xff), ip("1.2.3.4"));
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+
+        let forwarded = headers(&[
+            ("forwarded", "for=1.2.3.4"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::5]:4711\";proto=https, for=10.0.0.2:80",
+            ),
+            ("x-forwarded-for", "7.7.7.7"),
+        ]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &forwarded), ip("1.2.3.4"));
+
+        let hidden = headers(&[("forwarded", "for=1.2.3.4, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &hidden), ip("10.0.0.2"));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::client::{self, with_retry};
+use crate::models::{with_db_retry, Quota, QuotaInvoice, StoreUsage};
+use crate::routes::{handle_anyhow_error, ErrorResponse};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use anyhow::anyhow;
+use axum::{Extension, Json};
+use diesel::PgConnection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use ureq::Agent;
+
+const BYTES_PER_MB: u64 = 1_000_000;
+/// Most storage that can be bought with a single invoice
+const MAX_PURCHASE_BYTES: u64 = 100_000 * BYTES_PER_MB;
+const DEFAULT_PRICE_MSAT_PER_MB: u64 = 1_000;
+const INVOICE_EXPIRY_SECS: u64 = 3_600;
+
+/// Issues and checks invoices for quota purchases through an LND node's REST
+/// API.
+#[derive(Clone)]
+pub struct QuotaProvider {
+    client: Agent,
+    url: String,
+    macaroon: String,
+    price_msat_per_mb: u64,
+}
+
+impl QuotaProvider {
+    /// Configured by `QUOTA_LND_URL` and `QUOTA_LND_MACAROON` (hex encoded,
+    /// needs invoice read and write permissions), returns None if unset.
+    pub fn from_env() -> anyhow::Result<Option<QuotaProvider>> {
+        let Ok(url) = std::env::var("QUOTA_LND_URL") else {
+            return Ok(None);
+        };
+        let macaroon = std::env::var("QUOTA_LND_MACAROON")
+            .map_err(|_| anyhow!("QUOTA_LND_MACAROON must be set with QUOTA_LND_URL"))?;
+
+        let price_msat_per_mb = std::env::var("QUOTA_PRICE_MSAT_PER_MB")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_PRICE_MSAT_PER_MB);
+
+        info!("Selling storage quota through {url}");
+
+        Ok(Some(QuotaProvider {
+            client: client::agent()?,
+            url: url.trim_end_matches('/').to_string(),
+            macaroon,
+            price_msat_per_mb,
+        }))
+    }
+
+    /// Price of `bytes` of storage, charged per started megabyte.
+    pub fn price_msat(&self, bytes: u64) -> u64 {
+        let mb = (bytes + BYTES_PER_MB - 1) / BYTES_PER_MB;
+        mb.saturating_mul(self.price_msat_per_mb)
+    }
+
+    /// Creates an invoice, returning its hex payment hash and bolt11.
+    async fn add_invoice(
+        &self,
+        amount_msat: u64,
+        memo: String,
+    ) -> anyhow::Result<(String, String)> {
+        let client = self.client.clone();
+        let url = format!("{}/v1/invoices", self.url);
+        let macaroon = self.macaroon.clone();
+
+        let res: Value = with_retry("Create quota invoice", move || {
+            Ok(client
+                .post(&url)
+                .set("Grpc-Metadata-macaroon", &macaroon)
+                .send_json(json!({
+                    "value_msat": amount_msat.to_string(),
+                    "memo": memo,
+                    "expiry": INVOICE_EXPIRY_SECS.to_string(),
+                }))?
+                .into_json()?)
+        })
+        .await?;
+
+        let r_hash = res["r_hash"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Invoice response is missing r_hash"))?;
+        let bolt11 = res["payment_request"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Invoice response is missing payment_request"))?;
+
+        Ok((hex::encode(base64::decode(r_hash)?), bolt11.to_string()))
+    }
+
+    async fn is_settled(&self, payment_hash: &str) -> anyhow::Result<bool> {
+        let client = self.client.clone();
+        let url = format!("{}/v1/invoice/{payment_hash}", self.url);
+        let macaroon = self.macaroon.clone();
+
+        let res: Value = with_retry("Look up quota invoice", move || {
+            Ok(client
+                .get(&url)
+                .set("Grpc-Metadata-macaroon", &macaroon)
+                .call()?
+                .into_json()?)
+        })
+        .await?;
+
+        Ok(res["state"].as_str() == Some("SETTLED"))
+    }
+}
+
+/// Limits on stores that haven't bought any storage. Stores that have are
+/// only limited to the free bytes plus what they bought.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreeTier {
+    pub keys: Option<i64>,
+    pub bytes: Option<i64>,
+}
+
+impl FreeTier {
+    /// Configured by `FREE_TIER_KEYS` and `FREE_TIER_BYTES`, unset limits
+    /// aren't enforced.
+    pub fn from_env() -> anyhow::Result<FreeTier> {
+        let limit = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|s| s.parse::<i64>())
+                .transpose()
+        };
+
+        Ok(FreeTier {
+            keys: limit("FREE_TIER_KEYS")?,
+            bytes: limit("FREE_TIER_BYTES")?,
+        })
+    }
+
+    /// Runs `write`, which may only change `keys`, failing with
+    /// [`QuotaExceeded`] if it grew the store past its limits. Must be called
+    /// inside the write's transaction so a failed check rolls the write back.
+    /// Writes that don't grow a store already over its limits, e.g. deletes,
+    /// are still allowed.
+    pub fn enforce<T>(
+        &self,
+        conn: &mut PgConnection,
+        store_id: &str,
+        keys: &[&str],
+        upgradable: bool,
+        write: impl FnOnce(&mut PgConnection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if self.keys.is_none() && self.bytes.is_none() {
+            return write(conn);
+        }
+
+        // the whole store is only added up once, the write's change to it
+        // comes from the keys it wrote
+        let before = Quota::store_usage(conn, store_id)?;
+        let written_before = Quota::keys_usage(conn, store_id, keys)?;
+        let res = write(conn)?;
+        let written_after = Quota::keys_usage(conn, store_id, keys)?;
+        let after = StoreUsage {
+            keys: before.keys + written_after.keys - written_before.keys,
+            bytes: before.bytes + written_after.bytes - written_before.bytes,
+        };
+
+        let purchased = Quota::get_quota(conn, store_id)?
+            .map(|q| q.purchased_bytes)
+            .unwrap_or(0);
+        let key_limit = self.keys.filter(|_| purchased == 0);
+        let byte_limit = self.bytes.map(|b| b.saturating_add(purchased));
+
+        let over = |usage: i64, before: i64, limit: Option<i64>| {
+            limit.map_or(false, |limit| usage > limit && usage > before)
+        };
+        if over(after.keys, before.keys, key_limit) || over(after.bytes, before.bytes, byte_limit) {
+            return Err(QuotaExceeded::new(after, key_limit, byte_limit, upgradable).into());
+        }
+
+        Ok(res)
+    }
+}
+
+/// Returned when a write would take a store past its limits, sent to the
+/// client as JSON so wallets can offer an upgrade.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaExceeded {
+    /// Always `QUOTA_EXCEEDED`
+    pub error: String,
+    pub message: String,
+    pub keys: i64,
+    pub key_limit: Option<i64>,
+    pub bytes: i64,
+    pub byte_limit: Option<i64>,
+    /// Where to buy more storage, if purchases are enabled
+    pub upgrade_endpoint: Option<String>,
+}
+
+impl QuotaExceeded {
+    fn new(
+        usage: StoreUsage,
+        key_limit: Option<i64>,
+        byte_limit: Option<i64>,
+        upgradable: bool,
+    ) -> Self {
+        let message = match (key_limit, byte_limit) {
+            (Some(limit), _) if usage.keys > limit => {
+                format!("Store is limited to {limit} keys")
+            }
+            (_, Some(limit)) => format!("Store is limited to {limit} bytes"),
+            _ => "Store quota exceeded".to_string(),
+        };
+
+        QuotaExceeded {
+            error: "QUOTA_EXCEEDED".to_string(),
+            message,
+            keys: usage.keys,
+            key_limit,
+            bytes: usage.bytes,
+            byte_limit,
+            upgrade_endpoint: upgradable.then(|| "/v2/quota/invoice".to_string()),
+        }
+    }
+
+    /// The stores of `org_id` together hold `usage`, which has reached the
+    /// org's byte limit.
+    pub fn org(org_id: &str, usage: StoreUsage, byte_limit: i64) -> Self {
+        QuotaExceeded {
+            error: "QUOTA_EXCEEDED".to_string(),
+            message: format!("Org {org_id} is limited to {byte_limit} bytes"),
+            keys: usage.keys,
+            key_limit: None,
+            bytes: usage.bytes,
+            byte_limit: Some(byte_limit),
+            upgrade_endpoint: None,
+        }
+    }
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaInvoiceRequest {
+    pub store_id: Option<String>,
+    /// Storage to buy, rounded up to whole megabytes when priced
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaInvoiceResponse {
+    pub payment_hash: String,
+    pub bolt11: String,
+    pub amount_msat: u64,
+    pub bytes: u64,
+}
+
+pub async fn create_invoice_impl(
+    req: QuotaInvoiceRequest,
+    state: &State,
+) -> anyhow::Result<QuotaInvoiceResponse> {
+    let Some(provider) = state.quota.as_ref() else {
+        return Err(anyhow!("Quota purchases are not enabled"));
+    };
+    if req.bytes == 0 || req.bytes > MAX_PURCHASE_BYTES {
+        return Err(InvalidRequest::field(
+            "bytes",
+            FieldErrorCode::OutOfRange,
+            format!("bytes must be between 1 and {MAX_PURCHASE_BYTES}"),
+        )
+        .into());
+    }
+    let store_id = req.store_id.expect("must have");
+
+    let amount_msat = provider.price_msat(req.bytes);
+    let memo = format!(
+        "VSS storage: {} MB",
+        (req.bytes + BYTES_PER_MB - 1) / BYTES_PER_MB
+    );
+    let (payment_hash, bolt11) = provider.add_invoice(amount_msat, memo).await?;
+
+    let invoice = QuotaInvoice {
+        payment_hash,
+        store_id,
+        bytes: req.bytes as i64,
+        amount_msat: amount_msat as i64,
+        bolt11,
+        created_at: chrono::Utc::now().naive_utc(),
+        paid_at: None,
+    };
+    with_db_retry("create_quota_invoice", &state.breaker, || {
+        let mut conn = state.db(&invoice.store_id).get()?;
+        QuotaInvoice::insert(&mut conn, &invoice)
+    })
+    .await?;
+
+    Ok(QuotaInvoiceResponse {
+        payment_hash: invoice.payment_hash,
+        bolt11: invoice.bolt11,
+        amount_msat,
+        bytes: req.bytes,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaPaidRequest {
+    pub payment_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaPaidResponse {
+    pub paid: bool,
+    /// The store's quota after crediting the invoice
+    pub quota: Option<Quota>,
+}
+
+pub async fn quota_paid_impl(
+    req: QuotaPaidRequest,
+    state: &State,
+) -> anyhow::Result<QuotaPaidResponse> {
+    let Some(provider) = state.quota.as_ref() else {
+        return Err(anyhow!("Quota purchases are not enabled"));
+    };
+
+    // invoices live with their store, which the callback doesn't name
+    let mut found = None;
+    for shard in state.shards.all() {
+        found = with_db_retry("get_quota_invoice", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            QuotaInvoice::get_invoice(&mut conn, &req.payment_hash)
+        })
+        .await?;
+        if found.is_some() {
+            break;
+        }
+    }
+    let invoice = found.ok_or_else(|| anyhow!("Unknown invoice {}", req.payment_hash))?;
+
+    // the callback isn't trusted, the node has the final say
+    if invoice.paid_at.is_none() && !provider.is_settled(&invoice.payment_hash).await? {
+        return Ok(QuotaPaidResponse {
+            paid: false,
+            quota: None,
+        });
+    }
+
+    let quota = with_db_retry("mark_quota_invoice_paid", &state.breaker, || {
+        let mut conn = state.db(&invoice.store_id).get()?;
+        QuotaInvoice::mark_paid(&mut conn, &invoice.payment_hash)
+    })
+    .await?;
+
+    if invoice.paid_at.is_none() {
+        info!(
+            "Credited {} bytes of quota for invoice {}",
+            invoice.bytes, invoice.payment_hash
+        );
+    }
+
+    Ok(QuotaPaidResponse { paid: true, quota })
+}
+
+/// Payment confirmation callback, called by the invoice provider's webhook
+/// or by the client once it has paid. Needs no auth as the invoice's state
+/// is checked with the node before crediting it.
+pub async fn quota_paid(
+    Extension(state): Extension<State>,
+    Json(payload): Json<QuotaPaidRequest>,
+) -> Result<Json<QuotaPaidResponse>, ErrorResponse> {
+    match quota_paid_impl(payload, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("quota_paid", e)),
+    }
+}
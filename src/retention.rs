@@ -0,0 +1,153 @@
+use crate::models::{with_db_retry, RetentionRule, VssItem};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use anyhow::anyhow;
+use diesel::Connection;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// Most retention rules a store can have
+pub const MAX_RETENTION_RULES: usize = 16;
+/// Longest max age a rule can have, about a hundred years
+pub const MAX_RETENTION_DAYS: i32 = 36_500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRetentionRuleRequest {
+    pub store_id: Option<String>,
+    /// Keys starting with this are covered, every key if empty
+    pub key_prefix: String,
+    /// Keys not updated for this many days are deleted
+    pub max_age_days: i32,
+}
+
+impl SetRetentionRuleRequest {
+    fn validate(&self, state: &State) -> Result<(), InvalidRequest> {
+        if self.key_prefix.len() > state.key_policy.max_len {
+            return Err(InvalidRequest::field(
+                "key_prefix",
+                FieldErrorCode::InvalidLength,
+                format!(
+                    "key_prefix can be at most {} bytes",
+                    state.key_policy.max_len
+                ),
+            ));
+        }
+        if !(1..=MAX_RETENTION_DAYS).contains(&self.max_age_days) {
+            return Err(InvalidRequest::field(
+                "max_age_days",
+                FieldErrorCode::OutOfRange,
+                format!("max_age_days must be 1 to {MAX_RETENTION_DAYS}"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveRetentionRuleRequest {
+    pub store_id: Option<String>,
+    pub key_prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListRetentionRulesRequest {
+    pub store_id: Option<String>,
+}
+
+pub async fn set_retention_rule_impl(
+    req: SetRetentionRuleRequest,
+    state: &State,
+) -> anyhow::Result<RetentionRule> {
+    req.validate(state)?;
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("set_retention_rule", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            // serializes changes to the store's rules so the limit holds
+            VssItem::lock_store(conn, &store_id)?;
+            let rules = RetentionRule::list_for_store(conn, &store_id)?;
+            let replacing = rules.iter().any(|r| r.key_prefix == req.key_prefix);
+            if !replacing && rules.len() >= MAX_RETENTION_RULES {
+                return Err(InvalidRequest::field(
+                    "key_prefix",
+                    FieldErrorCode::TooMany,
+                    format!("A store can have at most {MAX_RETENTION_RULES} retention rules"),
+                )
+                .into());
+            }
+            RetentionRule::set(conn, &store_id, &req.key_prefix, req.max_age_days)
+        })
+    })
+    .await
+}
+
+pub async fn remove_retention_rule_impl(
+    req: RemoveRetentionRuleRequest,
+    state: &State,
+) -> anyhow::Result<Vec<RetentionRule>> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("remove_retention_rule", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        if !RetentionRule::remove(&mut conn, &store_id, &req.key_prefix)? {
+            return Err(anyhow!("No retention rule for prefix {:?}", req.key_prefix));
+        }
+        RetentionRule::list_for_store(&mut conn, &store_id)
+    })
+    .await
+}
+
+pub async fn list_retention_rules_impl(
+    req: ListRetentionRulesRequest,
+    state: &State,
+) -> anyhow::Result<Vec<RetentionRule>> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("list_retention_rules", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        RetentionRule::list_for_store(&mut conn, &store_id)
+    })
+    .await
+}
+
+/// Deletes the keys every store's retention rules have expired.
+pub async fn apply_retention(state: State) {
+    for shard in state.shards.all() {
+        let rules = with_db_retry("list_retention_rules", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            RetentionRule::list_all(&mut conn)
+        })
+        .await;
+        let rules = match rules {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Failed to list retention rules on {}: {e}", shard.name);
+                continue;
+            }
+        };
+
+        for rule in rules {
+            let res = with_db_retry("apply_retention", &state.breaker, || {
+                let mut conn = shard.pool.get()?;
+                conn.transaction::<_, anyhow::Error, _>(|conn| {
+                    VssItem::lock_store(conn, &rule.store_id)?;
+                    rule.apply(conn)
+                })
+            })
+            .await;
+
+            match res {
+                Ok(0) => {}
+                Ok(count) => info!(
+                    "Retention deleted {count} keys under {:?} of store {}",
+                    rule.key_prefix, rule.store_id
+                ),
+                Err(e) => error!(
+                    "Failed to apply retention to {:?} of store {}: {e}",
+                    rule.key_prefix, rule.store_id
+                ),
+            }
+        }
+    }
+}
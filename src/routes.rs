@@ -1,20 +1,83 @@
-use crate::auth::verify_token;
-use crate::kv::{KeyValue, KeyValueOld};
-use crate::models::VssItem;
-use crate::{
-    State, ALLOWED_LAN, ALLOWED_LOCALHOST, ALLOWED_ORIGINS, ALLOWED_SUBDOMAIN, API_VERSION,
+use crate::access_log::AccessLog;
+use crate::anomaly::{max_version_jump, WriteActivity};
+use crate::auth::{verify_admin_token, verify_store_token, verify_token};
+use crate::blob;
+use crate::codec::{Encoded, Negotiated};
+use crate::cors::CorsRules;
+use crate::data_export::{
+    get_export_impl, start_export_impl, StoreExportRequest, StoreExportTicket,
 };
+use crate::deletion::{
+    cancel_deletion_impl, forget_store_impl, request_deletion_impl, CancelStoreDeletionRequest,
+    DeleteStoreRequest, ForgetStoreRequest, StoreDeletion,
+};
+use crate::delta::DeltaOp;
+use crate::kv::{
+    Base64Encoding, ByteData, KeyValue, KeyValueOld, KeyVersion, BASE64_ENCODING, NO_VERSION_CHECK,
+};
+use crate::manifest::{
+    diff_manifest_impl, get_store_digest_impl, DiffManifestRequest, ManifestDiff, StoreDigest,
+    StoreDigestRequest,
+};
+use crate::models::{
+    log_if_slow, with_db_retry, CircuitOpen, Device, ForgetReceipt, IdempotencyKey,
+    IdempotencyKeyReused, KeyMetadata, Lease, LeaseConflict, NostrSubscription, RetentionRule,
+    Snapshot, StoreBehaviors, StorePendingDeletion, StoredItem, UsageDay, VersionRegression,
+    VssItem, VssStore, EXPORT_PENDING, EXPORT_READY,
+};
+use crate::nostr::{
+    subscribe_impl, unsubscribe_impl, NostrSubscribeRequest, NostrUnsubscribeRequest,
+    NostrUnsubscribeResponse,
+};
+use crate::org::enforce_org_quota;
+use crate::quota::{create_invoice_impl, QuotaExceeded, QuotaInvoiceRequest, QuotaInvoiceResponse};
+use crate::retention::{
+    list_retention_rules_impl, remove_retention_rule_impl, set_retention_rule_impl,
+    ListRetentionRulesRequest, RemoveRetentionRuleRequest, SetRetentionRuleRequest,
+};
+use crate::snapshot::{
+    create_snapshot_impl, delete_snapshot_impl, download_snapshot_impl, list_snapshots_impl,
+    restore_snapshot_impl, CreateSnapshotRequest, ListSnapshotsRequest, RestoreSnapshotRequest,
+    RestoreSnapshotResponse, SnapshotBundle, SnapshotRequest,
+};
+use crate::validation::{
+    valid_alias, validate_attributes, validate_lazy_versions, validate_values, FieldErrorCode,
+    InvalidRequest, ItemResult, ItemStatus, VersionConflict, ALIAS_PREFIX,
+};
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::{Path, Query};
 use axum::headers::authorization::Bearer;
 use axum::headers::{Authorization, Origin};
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json, TypedHeader};
+use chrono::NaiveDateTime;
 use diesel::Connection;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
+/// How long clients are told to wait while the server is read-only
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 60;
+
+/// Resolves the request's store id from the body and the token, making sure
+/// they agree and that it meets the server's store id policy. A body store
+/// id of `@alias` names the store with that alias.
 macro_rules! ensure_store_id {
-    ($payload:ident, $store_id:expr) => {
+    ($payload:ident, $store_id:expr, $state:ident) => {
+        if let Some(id) = $payload.store_id.take() {
+            $payload.store_id = Some(
+                resolve_store_id(&id, &$state)
+                    .await
+                    .map_err(|e| handle_anyhow_error("resolve_store_id", e))?,
+            );
+        }
         match $payload.store_id {
             None => {
                 // if neither has a store id, return an error
@@ -22,7 +85,8 @@ macro_rules! ensure_store_id {
                     return Err((
                         StatusCode::UNAUTHORIZED,
                         format!("Unauthorized: store_id required"),
-                    ));
+                    )
+                        .into());
                 }
                 $payload.store_id = $store_id
             }
@@ -34,11 +98,19 @@ macro_rules! ensure_store_id {
                         return Err((
                             StatusCode::UNAUTHORIZED,
                             format!("Unauthorized: store_id mismatch"),
-                        ));
+                        )
+                            .into());
                     }
                 }
             },
         }
+        if let Some(ref id) = $payload.store_id {
+            $state
+                .store_id_policy
+                .validate(id)
+                .map_err(|e| e.to_response())?;
+            reject_if_store_read_only(id, &$state)?;
+        }
     };
 }
 
@@ -46,6 +118,32 @@ macro_rules! ensure_store_id {
 pub struct GetObjectRequest {
     pub store_id: Option<String>,
     pub key: String,
+    /// Returns the key as it was at this time, from the write history,
+    /// instead of as it is now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<NaiveDateTime>,
+}
+
+/// Reads the key now, or as it was at `as_of` when that is given.
+fn read_item(
+    conn: &mut diesel::PgConnection,
+    store_id: &str,
+    key: &str,
+    as_of: Option<NaiveDateTime>,
+    state: &State,
+) -> anyhow::Result<Option<VssItem>> {
+    let Some(as_of) = as_of else {
+        return VssItem::get_item(conn, state.blobs.as_deref(), store_id, key);
+    };
+    if state.history.is_none() {
+        return Err(InvalidRequest::field(
+            "as_of",
+            FieldErrorCode::NotAllowed,
+            "as_of needs the server's write history, which isn't enabled",
+        )
+        .into());
+    }
+    VssItem::get_item_as_of(conn, state.blobs.as_deref(), store_id, key, as_of)
 }
 
 pub async fn get_object_impl(
@@ -55,36 +153,179 @@ pub async fn get_object_impl(
     trace!("get_object_impl: {req:?}");
     let store_id = req.store_id.expect("must have");
 
-    let mut conn = state.db_pool.get()?;
-
-    let item = VssItem::get_item(&mut conn, &store_id, &req.key)?;
+    let start = Instant::now();
+    let item = with_db_retry("get_object", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        read_item(&mut conn, &store_id, &req.key, req.as_of, state)
+    })
+    .await?;
+    log_if_slow("get_object", &store_id, 1, start, state.slow_op_threshold);
 
     Ok(item.and_then(|i| i.into_kv()))
 }
 
-/// Returns value as base64-encoded string
+/// Behaviors `store_id` has pinned, see [`StoreBehaviors`].
+pub async fn store_behaviors(store_id: &str, state: &State) -> anyhow::Result<StoreBehaviors> {
+    with_db_retry("get_store_behaviors", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        VssStore::get_behaviors(&mut conn, store_id)
+    })
+    .await
+}
+
+/// Id of the store with `alias`, looked up on every shard.
+pub async fn find_store_alias(alias: &str, state: &State) -> anyhow::Result<Option<String>> {
+    for shard in state.shards.all() {
+        let found = with_db_retry("find_store_alias", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            VssStore::find_by_alias(&mut conn, alias)
+        })
+        .await?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+/// The store `store_id` names, which is itself unless it is an `@alias`.
+pub async fn resolve_store_id(store_id: &str, state: &State) -> anyhow::Result<String> {
+    let Some(alias) = store_id.strip_prefix(ALIAS_PREFIX) else {
+        return Ok(store_id.to_string());
+    };
+    find_store_alias(alias, state)
+        .await?
+        .ok_or_else(|| anyhow!("No store has the alias {alias}"))
+}
+
+/// Base64 variant of v1 values the store is pinned to, or the server's.
+async fn v1_base64_encoding(store_id: &str, state: &State) -> anyhow::Result<Base64Encoding> {
+    let pinned = store_behaviors(store_id, state).await?.v1_base64_encoding;
+    Ok(pinned
+        .and_then(|encoding| encoding.parse().ok())
+        .unwrap_or(state.v1_base64_encoding))
+}
+
+/// Returns value as base64-encoded string, in the variant named by the
+/// `Base64-Encoding` header, or else the store's or server's default
 pub async fn get_object(
     origin: Option<TypedHeader<Origin>>,
     auth: Option<TypedHeader<Authorization<Bearer>>>,
     Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    headers: HeaderMap,
     Json(mut payload): Json<GetObjectRequest>,
-) -> Result<Json<Option<KeyValueOld>>, (StatusCode, String)> {
-    debug!("get_object: {payload:?}");
+) -> Result<Json<Option<KeyValueOld>>, ErrorResponse> {
     if !state.self_hosted {
-        validate_cors(origin)?;
+        validate_cors(origin, &state.cors)?;
     }
 
+    let encoding = headers
+        .get(BASE64_ENCODING)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|e| e.to_string())
+                .and_then(|v| v.parse::<Base64Encoding>())
+                .map_err(|e| {
+                    InvalidRequest::field(BASE64_ENCODING, FieldErrorCode::InvalidFormat, e)
+                        .to_response()
+                })
+        })
+        .transpose()?;
+
     let store_id = auth
         .map(|TypedHeader(token)| verify_token(token.token(), &state))
         .transpose()?
         .flatten();
 
-    ensure_store_id!(payload, store_id);
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
 
-    match get_object_impl(payload, &state).await {
-        Ok(Some(res)) => Ok(Json(Some(res.into()))),
-        Ok(None) => Ok(Json(None)),
-        Err(e) => Err(handle_anyhow_error("get_object", e)),
+    let store_id = payload.store_id.clone().expect("must have");
+    let res = match get_object_impl(payload, &state).await {
+        Ok(res) => res,
+        Err(e) => return Err(handle_anyhow_error("get_object", e)),
+    };
+    let Some(res) = res else {
+        return Ok(Json(None));
+    };
+
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => v1_base64_encoding(&store_id, &state)
+            .await
+            .map_err(|e| handle_anyhow_error("get_object", e))?,
+    };
+    Ok(Json(Some(KeyValueOld::encoded(res, encoding))))
+}
+
+/// Returned by v2 getObject for missing keys when `GET_OBJECT_NOT_FOUND` is
+/// set, shaped like the reference server's `ErrorResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoSuchKey {
+    /// Always `NO_SUCH_KEY_EXCEPTION`
+    pub error_code: String,
+    pub message: String,
+    pub key: String,
+    /// The key existed but has been deleted
+    pub deleted: bool,
+}
+
+impl NoSuchKey {
+    fn new(key: String, deleted: bool) -> Self {
+        let message = match deleted {
+            true => format!("Key {key} has been deleted"),
+            false => format!("Key {key} not found"),
+        };
+        NoSuchKey {
+            error_code: "NO_SUCH_KEY_EXCEPTION".to_string(),
+            message,
+            key,
+            deleted,
+        }
+    }
+}
+
+impl std::fmt::Display for NoSuchKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for NoSuchKey {}
+
+/// Same as [`get_object_impl`], but fails with [`NoSuchKey`] instead of
+/// returning None when `GET_OBJECT_NOT_FOUND` is set, unless the store is
+/// pinned to returning null.
+pub async fn get_object_v2_impl(
+    req: GetObjectRequest,
+    state: &State,
+) -> anyhow::Result<Option<KeyValue>> {
+    let key = req.key.clone();
+    let store_id = req.store_id.clone().expect("must have");
+    let deleted = match get_object_v3_impl(req, state).await? {
+        Some(ObjectV3 {
+            value: Some(value),
+            version,
+            metadata,
+            content_type,
+            ..
+        }) => {
+            return Ok(Some(KeyValue {
+                metadata,
+                content_type,
+                ..KeyValue::new(key, value.0, version)
+            }))
+        }
+        Some(_) => true,
+        None => false,
+    };
+
+    let pinned = store_behaviors(&store_id, state).await?.null_for_missing;
+    match pinned.unwrap_or(!state.get_object_not_found) {
+        true => Ok(None),
+        false => Err(NoSuchKey::new(key, deleted).into()),
     }
 }
 
@@ -93,11 +334,14 @@ pub async fn get_object_v2(
     origin: Option<TypedHeader<Origin>>,
     auth: Option<TypedHeader<Authorization<Bearer>>>,
     Extension(state): Extension<State>,
-    Json(mut payload): Json<GetObjectRequest>,
-) -> Result<Json<Option<KeyValue>>, (StatusCode, String)> {
-    debug!("get_object v2: {payload:?}");
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<GetObjectRequest>,
+) -> Result<Encoded<Option<KeyValue>>, ErrorResponse> {
     if !state.self_hosted {
-        validate_cors(origin)?;
+        validate_cors(origin, &state.cors)?;
     }
 
     let store_id = auth
@@ -105,14 +349,221 @@ pub async fn get_object_v2(
         .transpose()?
         .flatten();
 
-    ensure_store_id!(payload, store_id);
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
 
-    match get_object_impl(payload, &state).await {
-        Ok(res) => Ok(Json(res)),
+    match get_object_v2_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
         Err(e) => Err(handle_anyhow_error("get_object_v2", e)),
     }
 }
 
+/// An item as returned by v3 reads, tombstones included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectV3 {
+    pub key: String,
+    /// None when the key has been deleted
+    pub value: Option<ByteData>,
+    /// Version of the value, or of the tombstone if deleted
+    pub version: u64,
+    pub deleted: bool,
+    /// `device:<id>` or `token:<fingerprint>` of the last writer, if known
+    pub last_modified_by: Option<String>,
+    pub created_date: NaiveDateTime,
+    pub updated_date: NaiveDateTime,
+    /// JSON object the client attached with the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Media type the client gave the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl From<VssItem> for ObjectV3 {
+    fn from(item: VssItem) -> Self {
+        ObjectV3 {
+            deleted: item.value.is_none(),
+            created_date: item.created_date(),
+            updated_date: item.updated_date(),
+            key: item.key,
+            value: item.value.map(ByteData),
+            version: item.version,
+            last_modified_by: item.last_modified_by,
+            metadata: item.metadata,
+            content_type: item.content_type,
+        }
+    }
+}
+
+pub async fn get_object_v3_impl(
+    req: GetObjectRequest,
+    state: &State,
+) -> anyhow::Result<Option<ObjectV3>> {
+    trace!("get_object_v3_impl: {req:?}");
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let item = with_db_retry("get_object_v3", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        read_item(&mut conn, &store_id, &req.key, req.as_of, state)
+    })
+    .await?;
+    log_if_slow(
+        "get_object_v3",
+        &store_id,
+        1,
+        start,
+        state.slow_op_threshold,
+    );
+
+    Ok(item.map(ObjectV3::from))
+}
+
+/// Returns the item with who last changed it, including deleted items
+pub async fn get_object_v3(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<GetObjectRequest>,
+) -> Result<Encoded<Option<ObjectV3>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match get_object_v3_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("get_object_v3", e)),
+    }
+}
+
+pub async fn get_object_version_impl(
+    req: GetObjectRequest,
+    state: &State,
+) -> anyhow::Result<Option<KeyVersion>> {
+    trace!("get_object_version_impl: {req:?}");
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let version = with_db_retry("get_object_version", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::get_version(&mut conn, &store_id, &req.key)
+    })
+    .await?;
+    log_if_slow(
+        "get_object_version",
+        &store_id,
+        1,
+        start,
+        state.slow_op_threshold,
+    );
+
+    Ok(version.map(|version| KeyVersion {
+        key: req.key,
+        version,
+    }))
+}
+
+/// Returns only the key's current version, or null if it doesn't exist
+pub async fn get_object_version(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<GetObjectRequest>,
+) -> Result<Encoded<Option<KeyVersion>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match get_object_version_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("get_object_version", e)),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RawObjectParams {
+    pub store_id: Option<String>,
+}
+
+/// Returns the raw value bytes, with its version in the `ETag` header and
+/// the content type it was written with, if any
+pub async fn get_object_raw(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Path(key): Path<String>,
+    Query(params): Query<RawObjectParams>,
+) -> Result<Response, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    let mut payload = GetObjectRequest {
+        store_id: params.store_id,
+        key,
+        as_of: None,
+    };
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let key = payload.key.clone();
+    match get_object_impl(payload, &state).await {
+        Ok(Some(kv)) => Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    kv.content_type
+                        .and_then(|c| HeaderValue::from_str(&c).ok())
+                        .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+                ),
+                (
+                    header::ETAG,
+                    HeaderValue::from_str(&format!("\"{}\"", kv.version))
+                        .expect("version is a valid header value"),
+                ),
+            ],
+            kv.value.0,
+        )
+            .into_response()),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("Key {key} not found")).into()),
+        Err(e) => Err(handle_anyhow_error("get_object_raw", e)),
+    }
+}
+
+/// Header naming a putObjects request so retries of it are only applied once.
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PutObjectsRequest {
     pub store_id: Option<String>,
@@ -120,47 +571,229 @@ pub struct PutObjectsRequest {
     pub transaction_items: Vec<KeyValue>,
 }
 
-pub async fn put_objects_impl(req: PutObjectsRequest, state: &State) -> anyhow::Result<()> {
+/// Applies the request's items atomically. If `idempotency_key` was already
+/// used for this store within the idempotency window, nothing is written and
+/// the request succeeds as it did the first time.
+pub async fn put_objects_impl(
+    req: PutObjectsRequest,
+    idempotency_key: Option<&str>,
+    client_id: Option<&str>,
+    state: &State,
+) -> anyhow::Result<Vec<StoredItem>> {
     if req.transaction_items.is_empty() {
-        return Ok(());
+        return Ok(vec![]);
     }
+    state
+        .key_policy
+        .validate_all(req.transaction_items.iter().map(|kv| kv.key.as_str()))?;
+    if !state.allow_empty_values {
+        validate_values(&req.transaction_items)?;
+    }
+    validate_attributes(&req.transaction_items)?;
 
     // todo do something with global version?
 
+    let mirrored = state.mirror.as_ref().map(|_| req.clone());
+
     let store_id = req.store_id.expect("must have");
+    enforce_org_quota(&store_id, state).await?;
+    if req
+        .transaction_items
+        .iter()
+        .any(|kv| kv.version == NO_VERSION_CHECK)
+    {
+        let pinned = store_behaviors(&store_id, state).await?.lazy_versions;
+        if !pinned.unwrap_or(!state.reject_lazy_versions) {
+            validate_lazy_versions(&req.transaction_items)?;
+        }
+    }
+
+    // large values are uploaded first, so a slow bucket doesn't hold the
+    // store's lock or a connection
+    let offloaded = blob::offload(
+        state.blobs.as_ref(),
+        &store_id,
+        req.transaction_items.iter().map(|kv| kv.value.0.as_slice()),
+    )
+    .await?;
+
+    let start = Instant::now();
+    let (applied_jump, stored) = with_db_retry("put_objects", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            // serialize with other writes to this store, e.g. from another device
+            VssItem::lock_store(conn, &store_id)?;
+            VssStore::check_writable(conn, &store_id)?;
+            record_writer(conn, &store_id, client_id)?;
+
+            if let Some(key) = idempotency_key {
+                let hash = request_hash(&req.transaction_items);
+                if !IdempotencyKey::claim(conn, &store_id, key, &hash, state.idempotency_window)? {
+                    let stored = VssItem::stored_items(conn, &store_id, &req.transaction_items)?;
+                    return Ok((None, stored));
+                }
+            }
+
+            let current = VssItem::current_versions(conn, &store_id, &req.transaction_items)?;
+            if state.strict_versions {
+                check_versions(&current, &req.transaction_items)?;
+            }
+            let regressions = VersionRegression::record(
+                conn,
+                &store_id,
+                client_id,
+                &current,
+                &req.transaction_items,
+            )?;
+            if regressions > 0 {
+                warn!("Store {store_id} was sent {regressions} stale versions");
+            }
+            let version_jump = max_version_jump(&current, &req.transaction_items);
 
-    let mut conn = state.db_pool.get()?;
+            let upgradable = state.quota.is_some();
+            let keys: Vec<&str> = req
+                .transaction_items
+                .iter()
+                .map(|kv| kv.key.as_str())
+                .collect();
+            let stored = state
+                .free_tier
+                .enforce(conn, &store_id, &keys, upgradable, |conn| {
+                    VssItem::put_items(conn, &store_id, &req.transaction_items, &offloaded)
+                })?;
 
-    conn.transaction::<_, anyhow::Error, _>(|conn| {
-        for kv in req.transaction_items {
-            VssItem::put_item(conn, &store_id, &kv.key, &kv.value.0, kv.version)?;
+            Ok((Some(version_jump), stored))
+        })
+    })
+    .await?;
+    log_if_slow(
+        "put_objects",
+        &store_id,
+        req.transaction_items.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    let Some(version_jump) = applied_jump else {
+        debug!("Replaying already applied putObjects for store {store_id}");
+        return Ok(stored);
+    };
+
+    if let (Some(mirror), Some(req)) = (state.mirror.as_ref(), mirrored) {
+        mirror.enqueue(req);
+    }
+    if let Some(nostr) = state.nostr.as_ref() {
+        nostr.notify(&store_id);
+    }
+    if let Some(anomaly) = state.anomaly.as_ref() {
+        let activity = WriteActivity {
+            writes: req.transaction_items.len() as u64,
+            deletes: 0,
+            max_version_jump: version_jump,
+        };
+        anomaly.record(&store_id, activity);
+    }
+
+    Ok(stored)
+}
+
+/// SHA-256 of everything a putObjects request writes, to tell a retry from
+/// a different request reusing its idempotency key.
+fn request_hash(items: &[KeyValue]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: Option<&[u8]>| match bytes {
+        Some(bytes) => {
+            hasher.update([1]);
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
         }
+        None => hasher.update([0]),
+    };
+    for kv in items {
+        field(Some(kv.key.as_bytes()));
+        field(Some(&kv.value.0));
+        field(Some(&kv.version.to_be_bytes()));
+        field(
+            kv.metadata
+                .as_ref()
+                .map(|m| m.to_string())
+                .as_deref()
+                .map(str::as_bytes),
+        );
+        field(kv.content_type.as_deref().map(str::as_bytes));
+    }
+    hasher.finalize().to_vec()
+}
 
-        Ok(())
-    })?;
+/// Fails with a [`VersionConflict`] if any item is older than the version
+/// already stored, rather than letting the upsert skip it. Items at
+/// [`NO_VERSION_CHECK`] never conflict.
+fn check_versions(current: &HashMap<String, u64>, items: &[KeyValue]) -> anyhow::Result<()> {
+    let results: Vec<ItemResult> = items
+        .iter()
+        .map(|kv| match current.get(&kv.key) {
+            Some(stored) if kv.version < *stored && kv.version != NO_VERSION_CHECK => {
+                ItemResult::failed(
+                    &kv.key,
+                    ItemStatus::Conflict,
+                    format!(
+                        "Version {} of {} is older than the stored version {stored}",
+                        kv.version, kv.key
+                    ),
+                )
+            }
+            _ => ItemResult::ok(&kv.key),
+        })
+        .collect();
 
-    Ok(())
+    match results.iter().any(|r| r.status == ItemStatus::Conflict) {
+        true => Err(VersionConflict::new(results).into()),
+        false => Ok(()),
+    }
 }
 
 pub async fn put_objects(
     origin: Option<TypedHeader<Origin>>,
     auth: Option<TypedHeader<Authorization<Bearer>>>,
     Extension(state): Extension<State>,
-    Json(mut payload): Json<PutObjectsRequest>,
-) -> Result<Json<()>, (StatusCode, String)> {
+    Extension(access_log): Extension<AccessLog>,
+    headers: HeaderMap,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<PutObjectsRequest>,
+) -> Result<Encoded<()>, ErrorResponse> {
     if !state.self_hosted {
-        validate_cors(origin)?;
+        validate_cors(origin, &state.cors)?;
     }
 
     let store_id = auth
+        .as_ref()
         .map(|TypedHeader(token)| verify_token(token.token(), &state))
         .transpose()?
         .flatten();
+    let client_id = auth.map(|TypedHeader(token)| token_fingerprint(token.token()));
 
-    ensure_store_id!(payload, store_id);
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY).map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(key)) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Some(key),
+        Some(_) => {
+            return Err(InvalidRequest::field(
+                IDEMPOTENCY_KEY,
+                FieldErrorCode::InvalidLength,
+                format!(
+                    "{IDEMPOTENCY_KEY} must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} visible characters"
+                ),
+            )
+            .to_response())
+        }
+    };
 
-    match put_objects_impl(payload, &state).await {
-        Ok(res) => Ok(Json(res)),
+    match put_objects_impl(payload, idempotency_key, client_id.as_deref(), &state).await {
+        Ok(_) => Ok(Encoded(accept, ())),
         Err(e) => Err(handle_anyhow_error("put_objects", e)),
     }
 }
@@ -180,9 +813,19 @@ pub async fn list_key_versions_impl(
     // todo pagination
     let store_id = req.store_id.expect("must have");
 
-    let mut conn = state.db_pool.get()?;
-
-    let versions = VssItem::list_key_versions(&mut conn, &store_id, req.key_prefix.as_deref())?;
+    let start = Instant::now();
+    let versions = with_db_retry("list_key_versions", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::list_key_versions(&mut conn, &store_id, req.key_prefix.as_deref())
+    })
+    .await?;
+    log_if_slow(
+        "list_key_versions",
+        &store_id,
+        versions.len(),
+        start,
+        state.slow_op_threshold,
+    );
 
     let json = versions
         .into_iter()
@@ -201,10 +844,14 @@ pub async fn list_key_versions(
     origin: Option<TypedHeader<Origin>>,
     auth: Option<TypedHeader<Authorization<Bearer>>>,
     Extension(state): Extension<State>,
-    Json(mut payload): Json<ListKeyVersionsRequest>,
-) -> Result<Json<Vec<Value>>, (StatusCode, String)> {
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListKeyVersionsRequest>,
+) -> Result<Encoded<Vec<Value>>, ErrorResponse> {
     if !state.self_hosted {
-        validate_cors(origin)?;
+        validate_cors(origin, &state.cors)?;
     }
 
     let store_id = auth
@@ -212,62 +859,1911 @@ pub async fn list_key_versions(
         .transpose()?
         .flatten();
 
-    ensure_store_id!(payload, store_id);
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
 
     match list_key_versions_impl(payload, &state).await {
-        Ok(res) => Ok(Json(res)),
+        Ok(res) => Ok(Encoded(accept, res)),
         Err(e) => Err(handle_anyhow_error("list_key_versions", e)),
     }
 }
 
-#[derive(Serialize)]
-pub struct HealthResponse {
-    pub status: String,
-    pub version: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeyVersionsV3Request {
+    pub store_id: Option<String>,
+    pub key_prefix: Option<String>,
+    /// Also list deleted keys
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+pub async fn list_key_versions_v3_impl(
+    req: ListKeyVersionsV3Request,
+    state: &State,
+) -> anyhow::Result<Vec<KeyMetadata>> {
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let keys = with_db_retry("list_key_versions_v3", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::list_key_metadata(
+            &mut conn,
+            &store_id,
+            req.key_prefix.as_deref(),
+            req.include_deleted,
+        )
+    })
+    .await?;
+    log_if_slow(
+        "list_key_versions_v3",
+        &store_id,
+        keys.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    Ok(keys)
 }
 
-impl HealthResponse {
-    /// Fabricate a status: pass response without checking database connectivity
-    pub fn new_ok() -> Self {
-        Self {
-            status: String::from("pass"),
-            version: String::from(API_VERSION),
-        }
+pub async fn list_key_versions_v3(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListKeyVersionsV3Request>,
+) -> Result<Encoded<Vec<KeyMetadata>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match list_key_versions_v3_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("list_key_versions_v3", e)),
     }
 }
 
-/// IETF draft RFC for HTTP API Health Checks:
-/// https://datatracker.ietf.org/doc/html/draft-inadarei-api-health-check
-pub async fn health_check() -> Result<Json<HealthResponse>, (StatusCode, String)> {
-    Ok(Json(HealthResponse::new_ok()))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListChangedKeysRequest {
+    pub store_id: Option<String>,
+    /// `watermark` of the previous call, every key is listed when unset
+    pub since: Option<u64>,
 }
 
-pub fn valid_origin(origin: &str) -> bool {
-    ALLOWED_ORIGINS.contains(&origin)
-        || origin.ends_with(ALLOWED_SUBDOMAIN)
-        || origin.starts_with(ALLOWED_LOCALHOST)
-        || origin.starts_with(ALLOWED_LAN)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangedKeys {
+    /// Keys changed since the watermark, deleted ones included, oldest
+    /// change first
+    pub keys: Vec<KeyMetadata>,
+    /// Pass as `since` on the next call to only get later changes
+    pub watermark: u64,
 }
 
-pub fn validate_cors(origin: Option<TypedHeader<Origin>>) -> Result<(), (StatusCode, String)> {
-    if let Some(TypedHeader(origin)) = origin {
-        if origin.is_null() {
-            return Ok(());
-        }
+pub async fn list_changed_keys_impl(
+    req: ListChangedKeysRequest,
+    state: &State,
+) -> anyhow::Result<ChangedKeys> {
+    let store_id = req.store_id.expect("must have");
 
-        let origin_str = origin.to_string();
-        if valid_origin(&origin_str) {
-            return Ok(());
-        } else {
-            // The origin is not in the allowed list block the request
-            return Err((StatusCode::NOT_FOUND, String::new()));
-        }
+    let start = Instant::now();
+    let res = with_db_retry("list_changed_keys", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        // changes at or past the watermark may still be in progress, they
+        // are listed by the next call
+        let watermark = VssItem::change_watermark(&mut conn)?;
+        let keys = VssItem::list_changed_keys(&mut conn, &store_id, req.since, watermark)?;
+        Ok(ChangedKeys { keys, watermark })
+    })
+    .await?;
+    log_if_slow(
+        "list_changed_keys",
+        &store_id,
+        res.keys.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    Ok(res)
+}
+
+pub async fn list_changed_keys(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListChangedKeysRequest>,
+) -> Result<Encoded<ChangedKeys>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
     }
 
-    Ok(())
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match list_changed_keys_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("list_changed_keys", e)),
+    }
 }
 
-pub(crate) fn handle_anyhow_error(function: &str, err: anyhow::Error) -> (StatusCode, String) {
-    error!("Error in {function}: {err:?}");
-    (StatusCode::BAD_REQUEST, format!("{err}"))
+pub async fn diff_manifest(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<DiffManifestRequest>,
+) -> Result<Encoded<ManifestDiff>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match diff_manifest_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("diff_manifest", e)),
+    }
+}
+
+pub async fn get_store_digest(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<StoreDigestRequest>,
+) -> Result<Encoded<StoreDigest>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match get_store_digest_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("get_store_digest", e)),
+    }
+}
+
+/// Most keys that can be looked up in one getKeyVersions request
+const MAX_VERSION_KEYS: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetKeyVersionsRequest {
+    pub store_id: Option<String>,
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyVersionStatus {
+    pub key: String,
+    pub version: u64,
+    /// The key has been deleted, `version` is that of the tombstone
+    pub deleted: bool,
+}
+
+pub async fn get_key_versions_impl(
+    req: GetKeyVersionsRequest,
+    state: &State,
+) -> anyhow::Result<Vec<KeyVersionStatus>> {
+    if req.keys.len() > MAX_VERSION_KEYS {
+        return Err(InvalidRequest::field(
+            "keys",
+            FieldErrorCode::TooMany,
+            format!("Can look up at most {MAX_VERSION_KEYS} keys"),
+        )
+        .into());
+    }
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let versions = with_db_retry("get_key_versions", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssItem::get_versions(&mut conn, &store_id, &req.keys)
+    })
+    .await?;
+    log_if_slow(
+        "get_key_versions",
+        &store_id,
+        req.keys.len(),
+        start,
+        state.slow_op_threshold,
+    );
+
+    Ok(versions
+        .into_iter()
+        .map(|(key, version, deleted)| KeyVersionStatus {
+            key,
+            version,
+            deleted,
+        })
+        .collect())
+}
+
+pub async fn get_key_versions(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<GetKeyVersionsRequest>,
+) -> Result<Encoded<Vec<KeyVersionStatus>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match get_key_versions_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("get_key_versions", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteByPrefixRequest {
+    pub store_id: Option<String>,
+    pub key_prefix: String,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteByPrefixResponse {
+    pub count: usize,
+    pub dry_run: bool,
+}
+
+pub async fn delete_by_prefix_impl(
+    req: DeleteByPrefixRequest,
+    client_id: Option<&str>,
+    state: &State,
+) -> anyhow::Result<DeleteByPrefixResponse> {
+    let store_id = req.store_id.expect("must have");
+
+    let start = Instant::now();
+    let count = with_db_retry("delete_by_prefix", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::lock_store(conn, &store_id)?;
+            VssStore::check_writable(conn, &store_id)?;
+            record_writer(conn, &store_id, client_id)?;
+            VssItem::delete_by_prefix(conn, &store_id, &req.key_prefix, req.dry_run)
+        })
+    })
+    .await?;
+    log_if_slow(
+        "delete_by_prefix",
+        &store_id,
+        count,
+        start,
+        state.slow_op_threshold,
+    );
+
+    if let (Some(anomaly), false) = (state.anomaly.as_ref(), req.dry_run) {
+        let activity = WriteActivity {
+            deletes: count as u64,
+            ..Default::default()
+        };
+        anomaly.record(&store_id, activity);
+    }
+
+    Ok(DeleteByPrefixResponse {
+        count,
+        dry_run: req.dry_run,
+    })
+}
+
+pub async fn delete_by_prefix(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<DeleteByPrefixRequest>,
+) -> Result<Encoded<DeleteByPrefixResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .as_ref()
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+    let client_id = auth.map(|TypedHeader(token)| token_fingerprint(token.token()));
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match delete_by_prefix_impl(payload, client_id.as_deref(), &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("delete_by_prefix", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyObjectRequest {
+    pub store_id: Option<String>,
+    pub from_key: String,
+    pub to_key: String,
+    /// Tombstone `from_key` at its next version after copying, turning the copy into a rename
+    #[serde(default)]
+    pub delete_source: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyObjectResponse {
+    pub key: String,
+    pub version: u64,
+}
+
+pub async fn copy_object_impl(
+    req: CopyObjectRequest,
+    client_id: Option<&str>,
+    state: &State,
+) -> anyhow::Result<CopyObjectResponse> {
+    state.key_policy.validate(&req.to_key)?;
+    let store_id = req.store_id.expect("must have");
+    enforce_org_quota(&store_id, state).await?;
+
+    let start = Instant::now();
+    let version = with_db_retry("copy_object", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::lock_store(conn, &store_id)?;
+            VssStore::check_writable(conn, &store_id)?;
+            record_writer(conn, &store_id, client_id)?;
+            let upgradable = state.quota.is_some();
+            let keys = [req.from_key.as_str(), req.to_key.as_str()];
+            state
+                .free_tier
+                .enforce(conn, &store_id, &keys, upgradable, |conn| {
+                    VssItem::copy_item(
+                        conn,
+                        &store_id,
+                        &req.from_key,
+                        &req.to_key,
+                        req.delete_source,
+                    )
+                })
+        })
+    })
+    .await?;
+    log_if_slow("copy_object", &store_id, 1, start, state.slow_op_threshold);
+
+    if let Some(nostr) = state.nostr.as_ref() {
+        nostr.notify(&store_id);
+    }
+    if let Some(anomaly) = state.anomaly.as_ref() {
+        let activity = WriteActivity {
+            writes: 1,
+            deletes: req.delete_source as u64,
+            max_version_jump: 0,
+        };
+        anomaly.record(&store_id, activity);
+    }
+
+    Ok(CopyObjectResponse {
+        key: req.to_key,
+        version,
+    })
+}
+
+pub async fn copy_object(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<CopyObjectRequest>,
+) -> Result<Encoded<CopyObjectResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .as_ref()
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+    let client_id = auth.map(|TypedHeader(token)| token_fingerprint(token.token()));
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match copy_object_impl(payload, client_id.as_deref(), &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("copy_object", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchObjectRequest {
+    pub store_id: Option<String>,
+    pub key: String,
+    /// Version of the value the delta was computed against
+    pub base_version: u64,
+    pub delta: Vec<DeltaOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchObjectResponse {
+    pub key: String,
+    pub version: u64,
+}
+
+pub async fn patch_object_impl(
+    req: PatchObjectRequest,
+    client_id: Option<&str>,
+    state: &State,
+) -> anyhow::Result<PatchObjectResponse> {
+    state.key_policy.validate(&req.key)?;
+    let store_id = req.store_id.expect("must have");
+    enforce_org_quota(&store_id, state).await?;
+
+    let start = Instant::now();
+    let kv = with_db_retry("patch_object", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::lock_store(conn, &store_id)?;
+            VssStore::check_writable(conn, &store_id)?;
+            record_writer(conn, &store_id, client_id)?;
+            let upgradable = state.quota.is_some();
+            state
+                .free_tier
+                .enforce(conn, &store_id, &[&req.key], upgradable, |conn| {
+                    let kv = VssItem::patch_item(
+                        conn,
+                        state.blobs.as_deref(),
+                        &store_id,
+                        &req.key,
+                        req.base_version,
+                        &req.delta,
+                    )?;
+                    if kv.value.0.is_empty() && !state.allow_empty_values {
+                        return Err(InvalidRequest::field(
+                            "delta",
+                            FieldErrorCode::Required,
+                            "Delta can't leave the value empty, use deleteByPrefix to delete a key",
+                        )
+                        .into());
+                    }
+                    Ok(kv)
+                })
+        })
+    })
+    .await?;
+    log_if_slow("patch_object", &store_id, 1, start, state.slow_op_threshold);
+
+    let res = PatchObjectResponse {
+        key: kv.key.clone(),
+        version: kv.version,
+    };
+
+    if let Some(nostr) = state.nostr.as_ref() {
+        nostr.notify(&store_id);
+    }
+    if let Some(anomaly) = state.anomaly.as_ref() {
+        let activity = WriteActivity {
+            writes: 1,
+            ..Default::default()
+        };
+        anomaly.record(&store_id, activity);
+    }
+
+    // the mirror only speaks putObjects, so it gets the whole new value
+    if let Some(mirror) = state.mirror.as_ref() {
+        mirror.enqueue(PutObjectsRequest {
+            store_id: Some(store_id),
+            global_version: None,
+            transaction_items: vec![kv],
+        });
+    }
+
+    Ok(res)
+}
+
+pub async fn patch_object(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<PatchObjectRequest>,
+) -> Result<Encoded<PatchObjectResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .as_ref()
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+    let client_id = auth.map(|TypedHeader(token)| token_fingerprint(token.token()));
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match patch_object_impl(payload, client_id.as_deref(), &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("patch_object", e)),
+    }
+}
+
+/// Longest a lease can be taken or renewed for
+const MAX_LEASE_TTL_SECS: u64 = 86_400;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseRequest {
+    pub store_id: Option<String>,
+    pub name: String,
+    /// Identifies the device taking the lease
+    pub holder: String,
+    /// How long the lease is held for, ignored when releasing
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseLeaseResponse {
+    pub released: bool,
+}
+
+impl LeaseRequest {
+    fn ttl(&self) -> anyhow::Result<Duration> {
+        match self.ttl_secs {
+            Some(ttl) if (1..=MAX_LEASE_TTL_SECS).contains(&ttl) => Ok(Duration::from_secs(ttl)),
+            _ => Err(InvalidRequest::field(
+                "ttl_secs",
+                FieldErrorCode::OutOfRange,
+                format!("ttl_secs must be between 1 and {MAX_LEASE_TTL_SECS}"),
+            )
+            .into()),
+        }
+    }
+}
+
+pub async fn acquire_lease_impl(req: LeaseRequest, state: &State) -> anyhow::Result<Lease> {
+    let ttl = req.ttl()?;
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("acquire_lease", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Lease::acquire(&mut conn, &store_id, &req.name, &req.holder, ttl)
+    })
+    .await
+}
+
+pub async fn acquire_lease(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<LeaseRequest>,
+) -> Result<Encoded<Lease>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match acquire_lease_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("acquire_lease", e)),
+    }
+}
+
+pub async fn renew_lease_impl(req: LeaseRequest, state: &State) -> anyhow::Result<Lease> {
+    let ttl = req.ttl()?;
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("renew_lease", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Lease::renew(&mut conn, &store_id, &req.name, &req.holder, ttl)
+    })
+    .await
+}
+
+pub async fn renew_lease(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<LeaseRequest>,
+) -> Result<Encoded<Lease>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match renew_lease_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("renew_lease", e)),
+    }
+}
+
+pub async fn release_lease_impl(
+    req: LeaseRequest,
+    state: &State,
+) -> anyhow::Result<ReleaseLeaseResponse> {
+    let store_id = req.store_id.expect("must have");
+
+    let released = with_db_retry("release_lease", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Lease::release(&mut conn, &store_id, &req.name, &req.holder)
+    })
+    .await?;
+
+    Ok(ReleaseLeaseResponse { released })
+}
+
+pub async fn release_lease(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<LeaseRequest>,
+) -> Result<Encoded<ReleaseLeaseResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match release_lease_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("release_lease", e)),
+    }
+}
+
+const MAX_DEVICE_ID_LEN: usize = 255;
+
+/// Short, non-reversible identifier of a bearer token, so devices can be told
+/// apart by the token they use without storing it.
+fn token_fingerprint(token: &str) -> String {
+    hex::encode(&Sha256::digest(token.as_bytes())[..8])
+}
+
+/// Attributes the rest of the transaction's writes to the device using the
+/// token with fingerprint `client_id`, see [`Device::writer`].
+fn record_writer(
+    conn: &mut diesel::PgConnection,
+    store_id: &str,
+    client_id: Option<&str>,
+) -> anyhow::Result<()> {
+    if let Some(fingerprint) = client_id {
+        let writer = Device::writer(conn, store_id, fingerprint)?;
+        VssItem::set_writer(conn, &writer)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterDeviceRequest {
+    pub store_id: Option<String>,
+    pub device_id: String,
+    /// Free-form description of the device, e.g. `ios` or `web`
+    pub platform: Option<String>,
+}
+
+pub async fn register_device_impl(
+    req: RegisterDeviceRequest,
+    token_fingerprint: Option<String>,
+    state: &State,
+) -> anyhow::Result<Device> {
+    if req.device_id.is_empty() || req.device_id.len() > MAX_DEVICE_ID_LEN {
+        return Err(InvalidRequest::field(
+            "device_id",
+            FieldErrorCode::InvalidLength,
+            format!("device_id must be 1 to {MAX_DEVICE_ID_LEN} bytes"),
+        )
+        .into());
+    }
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("register_device", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Device::register(
+            &mut conn,
+            &store_id,
+            &req.device_id,
+            req.platform.as_deref(),
+            token_fingerprint.as_deref(),
+        )
+    })
+    .await
+}
+
+pub async fn register_device(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<RegisterDeviceRequest>,
+) -> Result<Encoded<Device>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .as_ref()
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+    let fingerprint = auth.map(|TypedHeader(token)| token_fingerprint(token.token()));
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match register_device_impl(payload, fingerprint, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("register_device", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListDevicesRequest {
+    pub store_id: Option<String>,
+}
+
+pub async fn list_devices_impl(
+    req: ListDevicesRequest,
+    state: &State,
+) -> anyhow::Result<Vec<Device>> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("list_devices", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Device::list_devices(&mut conn, &store_id)
+    })
+    .await
+}
+
+pub async fn list_devices(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListDevicesRequest>,
+) -> Result<Encoded<Vec<Device>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match list_devices_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("list_devices", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeDeviceRequest {
+    pub store_id: Option<String>,
+    pub device_id: String,
+}
+
+pub async fn revoke_device_impl(req: RevokeDeviceRequest, state: &State) -> anyhow::Result<Device> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("revoke_device", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Device::revoke(&mut conn, &store_id, &req.device_id)
+    })
+    .await?
+    .ok_or_else(|| anyhow!("Device {} not found", req.device_id))
+}
+
+pub async fn revoke_device(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<RevokeDeviceRequest>,
+) -> Result<Encoded<Device>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match revoke_device_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("revoke_device", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetUsageRequest {
+    pub store_id: Option<String>,
+    /// How many days of history to return, defaults to 30
+    pub days: Option<i32>,
+}
+
+pub async fn get_usage_impl(req: GetUsageRequest, state: &State) -> anyhow::Result<Vec<UsageDay>> {
+    let days = req.days.unwrap_or(30).clamp(1, 366);
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("get_usage", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        UsageDay::list_usage(&mut conn, &store_id, days)
+    })
+    .await
+}
+
+pub async fn get_usage(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<GetUsageRequest>,
+) -> Result<Encoded<Vec<UsageDay>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match get_usage_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("get_usage", e)),
+    }
+}
+
+pub async fn quota_invoice(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<QuotaInvoiceRequest>,
+) -> Result<Encoded<QuotaInvoiceResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match create_invoice_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("quota_invoice", e)),
+    }
+}
+
+pub async fn nostr_subscribe(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<NostrSubscribeRequest>,
+) -> Result<Encoded<NostrSubscription>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match subscribe_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("nostr_subscribe", e)),
+    }
+}
+
+pub async fn nostr_unsubscribe(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<NostrUnsubscribeRequest>,
+) -> Result<Encoded<NostrUnsubscribeResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match unsubscribe_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("nostr_unsubscribe", e)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetStoreAliasRequest {
+    pub store_id: Option<String>,
+    /// The new alias, or null to clear it
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreAlias {
+    pub store_id: String,
+    pub alias: Option<String>,
+}
+
+/// Gives a store that has been written to a unique alias, replacing any it
+/// had. Aliases are unique within each database, so other shards are
+/// checked first.
+pub async fn set_store_alias_impl(
+    req: SetStoreAliasRequest,
+    state: &State,
+) -> anyhow::Result<StoreAlias> {
+    let store_id = req.store_id.expect("must have");
+    if let Some(alias) = req.alias.as_deref() {
+        if !valid_alias(alias) {
+            return Err(InvalidRequest::field(
+                "alias",
+                FieldErrorCode::InvalidFormat,
+                "alias must be 3 to 32 lowercase letters, digits, - and _, starting with a letter",
+            )
+            .into());
+        }
+        match find_store_alias(alias, state).await? {
+            Some(owner) if owner != store_id => return Err(anyhow!("Alias {alias} is taken")),
+            _ => {}
+        }
+    }
+
+    let store = with_db_retry("set_store_alias", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        VssStore::set_alias(&mut conn, &store_id, req.alias.as_deref())
+    })
+    .await?
+    .ok_or_else(|| anyhow!("Store {store_id} has nothing stored yet"))?;
+
+    Ok(StoreAlias {
+        store_id: store.store_id,
+        alias: store.alias,
+    })
+}
+
+pub async fn set_store_alias(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<SetStoreAliasRequest>,
+) -> Result<Encoded<StoreAlias>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match set_store_alias_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("set_store_alias", e)),
+    }
+}
+
+/// Makes the store read-only and schedules it to be purged, returning a token
+/// that cancels the deletion until then.
+pub async fn request_store_deletion(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<DeleteStoreRequest>,
+) -> Result<Encoded<StoreDeletion>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    // deleting is irreversible, so the store must come from a verified token
+    let store_id = Some(verify_store_token(
+        auth.as_ref().map(|TypedHeader(token)| token.token()),
+        &state,
+    )?);
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let store_id = payload.store_id.expect("must have");
+    match request_deletion_impl(&store_id, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("request_store_deletion", e)),
+    }
+}
+
+pub async fn cancel_store_deletion_request(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<CancelStoreDeletionRequest>,
+) -> Result<Encoded<StoreDeletion>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let store_id = payload.store_id.expect("must have");
+    let token = Some(payload.confirmation_token.as_str());
+    match cancel_deletion_impl(&store_id, token, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("cancel_store_deletion", e)),
+    }
+}
+
+/// Adds a retention rule for a key prefix, or replaces its max age.
+pub async fn set_retention_rule(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<SetRetentionRuleRequest>,
+) -> Result<Encoded<RetentionRule>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match set_retention_rule_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("set_retention_rule", e)),
+    }
+}
+
+/// Removes the rule for a key prefix, returning the store's remaining rules.
+pub async fn remove_retention_rule(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<RemoveRetentionRuleRequest>,
+) -> Result<Encoded<Vec<RetentionRule>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match remove_retention_rule_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("remove_retention_rule", e)),
+    }
+}
+
+pub async fn list_retention_rules(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListRetentionRulesRequest>,
+) -> Result<Encoded<Vec<RetentionRule>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match list_retention_rules_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("list_retention_rules", e)),
+    }
+}
+
+/// Copies the store's live items into a new labeled snapshot.
+pub async fn create_snapshot(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<CreateSnapshotRequest>,
+) -> Result<Encoded<Snapshot>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match create_snapshot_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("create_snapshot", e)),
+    }
+}
+
+pub async fn list_snapshots(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ListSnapshotsRequest>,
+) -> Result<Encoded<Vec<Snapshot>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match list_snapshots_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("list_snapshots", e)),
+    }
+}
+
+/// Returns a snapshot with every value it holds.
+pub async fn download_snapshot(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<SnapshotRequest>,
+) -> Result<Encoded<SnapshotBundle>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match download_snapshot_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("download_snapshot", e)),
+    }
+}
+
+/// Deletes a snapshot, returning the store's remaining snapshots.
+pub async fn delete_snapshot(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<SnapshotRequest>,
+) -> Result<Encoded<Vec<Snapshot>>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match delete_snapshot_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("delete_snapshot", e)),
+    }
+}
+
+/// Replaces the store's contents with a snapshot's, or copies them into a
+/// new store.
+pub async fn restore_snapshot(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<RestoreSnapshotRequest>,
+) -> Result<Encoded<RestoreSnapshotResponse>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = match payload.new_store_id {
+        // no client token is bound to the new store, so only admins can fork
+        Some(_) => {
+            let Some(TypedHeader(token)) = auth else {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "Unauthorized: new_store_id needs an admin token".to_string(),
+                )
+                    .into());
+            };
+            verify_admin_token(token.token(), &state)?;
+            None
+        }
+        None => auth
+            .map(|TypedHeader(token)| verify_token(token.token(), &state))
+            .transpose()?
+            .flatten(),
+    };
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    match restore_snapshot_impl(payload, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("restore_snapshot", e)),
+    }
+}
+
+/// Irreversibly erases everything kept about a store scheduled for deletion
+/// without waiting for its grace period, confirmed with the deletion's token.
+pub async fn forget_store(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<ForgetStoreRequest>,
+) -> Result<Encoded<ForgetReceipt>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = Some(verify_store_token(
+        auth.as_ref().map(|TypedHeader(token)| token.token()),
+        &state,
+    )?);
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let store_id = payload.store_id.expect("must have");
+    let token = Some(payload.confirmation_token.as_str());
+    match forget_store_impl(&store_id, token, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("forget_store", e)),
+    }
+}
+
+/// Starts building a bundle of everything kept about the store, returning a
+/// token to download it with from `/v2/store/export/{export_id}`.
+pub async fn start_store_export(
+    origin: Option<TypedHeader<Origin>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Negotiated {
+        body: mut payload,
+        accept,
+    }: Negotiated<StoreExportRequest>,
+) -> Result<Encoded<StoreExportTicket>, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let store_id = auth
+        .map(|TypedHeader(token)| verify_token(token.token(), &state))
+        .transpose()?
+        .flatten();
+
+    ensure_store_id!(payload, store_id, state);
+    access_log.set_store_id(payload.store_id.as_deref());
+
+    let store_id = payload.store_id.expect("must have");
+    match start_export_impl(&store_id, &state).await {
+        Ok(res) => Ok(Encoded(accept, res)),
+        Err(e) => Err(handle_anyhow_error("start_store_export", e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadExportParams {
+    /// The retrieval token returned when the export was started
+    pub token: String,
+}
+
+/// Returns the export's bundle once it is ready, or its status with a 202
+/// while it is still being built. The retrieval token is the only
+/// credential, so the link can be opened directly.
+pub async fn download_store_export(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    Extension(access_log): Extension<AccessLog>,
+    Path(export_id): Path<String>,
+    Query(params): Query<DownloadExportParams>,
+) -> Result<Response, ErrorResponse> {
+    if !state.self_hosted {
+        validate_cors(origin, &state.cors)?;
+    }
+
+    let export = match get_export_impl(&export_id, &params.token, &state).await {
+        Ok(Some(export)) => export,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Export {export_id} not found"),
+            )
+                .into())
+        }
+        Err(e) => return Err(handle_anyhow_error("download_store_export", e)),
+    };
+    access_log.set_store_id(Some(&export.store_id));
+
+    match export.status.as_str() {
+        EXPORT_READY => Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    HeaderValue::from_str(&format!(
+                        "attachment; filename=\"vss-export-{export_id}.json\""
+                    ))
+                    .expect("export id is a valid header value"),
+                ),
+            ],
+            export.bundle.unwrap_or_default(),
+        )
+            .into_response()),
+        EXPORT_PENDING => Ok((StatusCode::ACCEPTED, Json(export)).into_response()),
+        _ => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "Export {export_id} failed: {}",
+                export.error.unwrap_or_default()
+            ),
+        )
+            .into()),
+    }
+}
+
+tokio::task_local! {
+    /// Set while a handler behind [`reject_if_read_only`] runs, so the store
+    /// it writes to can be checked once it is known
+    static MUTATING: ();
+}
+
+/// Rejects writes to one of [`State::read_only_stores`]. Only handlers behind
+/// [`reject_if_read_only`] count as writing.
+pub(crate) fn reject_if_store_read_only(
+    store_id: &str,
+    state: &State,
+) -> Result<(), ErrorResponse> {
+    if MUTATING.try_with(|_| ()).is_ok() && state.read_only_stores.contains(store_id) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Store {store_id} is read-only"),
+        )
+            .into());
+    }
+    Ok(())
+}
+
+/// Route layer for mutating endpoints, rejecting them while the server is in
+/// read-only maintenance mode.
+pub async fn reject_if_read_only<B>(req: Request<B>, next: Next<B>) -> Response {
+    let read_only = req
+        .extensions()
+        .get::<State>()
+        .map(|state| state.read_only.load(Ordering::SeqCst))
+        .unwrap_or(false);
+
+    if read_only {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                MAINTENANCE_RETRY_AFTER_SECS.to_string(),
+            )],
+            "Server is in read-only maintenance mode".to_string(),
+        )
+            .into_response();
+    }
+
+    MUTATING.scope((), next.run(req)).await
+}
+
+/// Most seconds self-hosted servers can let clients cache reads for
+pub const MAX_READ_CACHE_SECS: u64 = 300;
+/// Request headers that change what a read returns
+const READ_VARY: &str = "Authorization, Origin, Accept, Value-Encoding, Base64-Encoding";
+
+/// Cache-Control for getObject and listKeyVersions responses. Stores are
+/// private, so nothing may keep them unless an operator opts in.
+pub fn read_cache_control(max_age_secs: Option<u64>) -> HeaderValue {
+    match max_age_secs {
+        Some(secs) if secs > 0 => {
+            HeaderValue::from_str(&format!("private, max-age={secs}")).expect("valid header value")
+        }
+        _ => HeaderValue::from_static("no-store"),
+    }
+}
+
+/// Tells intermediary caches how to treat read responses.
+pub async fn cache_reads<B>(req: Request<B>, next: Next<B>) -> Response {
+    let max_age = req
+        .extensions()
+        .get::<State>()
+        .and_then(|state| state.read_cache_secs);
+
+    let mut res = next.run(req).await;
+    let cache_control = match res.status().is_success() {
+        true => read_cache_control(max_age),
+        false => read_cache_control(None),
+    };
+    let headers = res.headers_mut();
+    headers.insert(header::CACHE_CONTROL, cache_control);
+    headers.insert(header::VARY, HeaderValue::from_static(READ_VARY));
+    res
+}
+
+/// POST endpoints that only read and so get the read deadline, every other
+/// non-admin POST is treated as a write. Matched as suffixes so the LDK paths
+/// are covered too.
+const READ_PATHS: [&str; 9] = [
+    "/getObject",
+    "/getObjectVersion",
+    "/listKeyVersions",
+    "/getKeyVersions",
+    "/listChangedKeys",
+    "/diffManifest",
+    "/getStoreDigest",
+    "/devices/list",
+    "/v2/usage",
+];
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_ADMIN_TIMEOUT_SECS: u64 = 600;
+
+/// Kinds of request that get their own deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestKind {
+    Read,
+    Write,
+    /// Admin endpoints and migrations, which can take much longer
+    Admin,
+}
+
+impl RequestKind {
+    pub fn of<B>(req: &Request<B>) -> RequestKind {
+        let path = req.uri().path();
+        if path.starts_with("/admin") || path == "/migration" {
+            RequestKind::Admin
+        } else if req.method() == Method::GET || READ_PATHS.iter().any(|read| path.ends_with(read))
+        {
+            RequestKind::Read
+        } else {
+            RequestKind::Write
+        }
+    }
+}
+
+/// Deadlines for handling each kind of request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    pub read: Duration,
+    pub write: Duration,
+    pub admin: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        RequestTimeouts {
+            read: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            write: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            admin: Duration::from_secs(DEFAULT_ADMIN_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl RequestTimeouts {
+    /// Configured by `READ_TIMEOUT_SECS`, `WRITE_TIMEOUT_SECS` and
+    /// `ADMIN_TIMEOUT_SECS`, reads and writes default to
+    /// `REQUEST_TIMEOUT_SECS`.
+    pub fn from_env() -> anyhow::Result<RequestTimeouts> {
+        let secs = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|s| s.parse::<u64>())
+                .transpose()
+        };
+        let request = secs("REQUEST_TIMEOUT_SECS")?.unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS);
+
+        Ok(RequestTimeouts {
+            read: Duration::from_secs(secs("READ_TIMEOUT_SECS")?.unwrap_or(request)),
+            write: Duration::from_secs(secs("WRITE_TIMEOUT_SECS")?.unwrap_or(request)),
+            admin: Duration::from_secs(
+                secs("ADMIN_TIMEOUT_SECS")?.unwrap_or(DEFAULT_ADMIN_TIMEOUT_SECS),
+            ),
+        })
+    }
+
+    pub fn get(&self, kind: RequestKind) -> Duration {
+        match kind {
+            RequestKind::Read => self.read,
+            RequestKind::Write => self.write,
+            RequestKind::Admin => self.admin,
+        }
+    }
+}
+
+/// Sent as JSON when a request isn't handled within its deadline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestTimedOut {
+    /// Always `TIMEOUT`
+    pub error: String,
+    pub message: String,
+    pub kind: RequestKind,
+    pub timeout_secs: u64,
+}
+
+/// Fails requests that take longer than the configured deadline.
+pub async fn enforce_request_timeout<B>(req: Request<B>, next: Next<B>) -> Response {
+    let Some(timeouts) = req.extensions().get::<State>().map(|s| s.timeouts) else {
+        return next.run(req).await;
+    };
+    let kind = RequestKind::of(&req);
+    let timeout = timeouts.get(kind);
+    let path = req.uri().path().to_string();
+
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            error!("Request to {path} timed out after {timeout:?}");
+            let body = RequestTimedOut {
+                error: "TIMEOUT".to_string(),
+                message: "Request timed out".to_string(),
+                kind,
+                timeout_secs: timeout.as_secs(),
+            };
+            (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+        }
+    }
+}
+
+pub fn validate_cors(
+    origin: Option<TypedHeader<Origin>>,
+    rules: &CorsRules,
+) -> Result<(), ErrorResponse> {
+    if let Some(TypedHeader(origin)) = origin {
+        if origin.is_null() {
+            return Ok(());
+        }
+
+        let origin_str = origin.to_string();
+        if rules.allows(&origin_str) {
+            return Ok(());
+        } else {
+            // The origin is not in the allowed list block the request
+            return Err((StatusCode::NOT_FOUND, String::new()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// What a handler fails with: a status and a plain text body, or a JSON one
+/// built from one of the typed errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub status: StatusCode,
+    pub body: String,
+    /// Sent as `application/json` rather than `text/plain`
+    pub json: bool,
+}
+
+impl ErrorResponse {
+    /// `error` serialized as a JSON body, or its message as text if it can't
+    /// be.
+    pub fn json<E: Serialize + std::fmt::Display>(status: StatusCode, error: &E) -> Self {
+        match serde_json::to_string(error) {
+            Ok(body) => ErrorResponse {
+                status,
+                body,
+                json: true,
+            },
+            Err(_) => (status, error.to_string()).into(),
+        }
+    }
+
+    pub fn with_status(self, status: StatusCode) -> Self {
+        ErrorResponse { status, ..self }
+    }
+}
+
+impl From<(StatusCode, String)> for ErrorResponse {
+    fn from((status, body): (StatusCode, String)) -> Self {
+        ErrorResponse {
+            status,
+            body,
+            json: false,
+        }
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        match self.json {
+            true => (
+                self.status,
+                [(header::CONTENT_TYPE, "application/json")],
+                self.body,
+            )
+                .into_response(),
+            false => (self.status, self.body).into_response(),
+        }
+    }
+}
+
+pub(crate) fn handle_anyhow_error(function: &str, err: anyhow::Error) -> ErrorResponse {
+    // the typed errors are answers rather than failures, so aren't logged
+    if let Some(e) = err.downcast_ref::<NoSuchKey>() {
+        return ErrorResponse::json(StatusCode::NOT_FOUND, e);
+    }
+    if err.downcast_ref::<CircuitOpen>().is_some() {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("{err}")).into();
+    }
+    if err.downcast_ref::<LeaseConflict>().is_some() {
+        return (StatusCode::CONFLICT, format!("{err}")).into();
+    }
+    if err.downcast_ref::<IdempotencyKeyReused>().is_some() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, format!("{err}")).into();
+    }
+    if err.downcast_ref::<StorePendingDeletion>().is_some() {
+        return (StatusCode::LOCKED, format!("{err}")).into();
+    }
+    if let Some(e) = err.downcast_ref::<QuotaExceeded>() {
+        return ErrorResponse::json(StatusCode::PAYMENT_REQUIRED, e);
+    }
+    if let Some(e) = err.downcast_ref::<InvalidRequest>() {
+        return e.to_response();
+    }
+    if let Some(e) = err.downcast_ref::<VersionConflict>() {
+        return e.to_response();
+    }
+
+    error!("Error in {function}: {err:?}");
+    #[cfg(feature = "sentry")]
+    sentry::with_scope(
+        |scope| scope.set_tag("function", function),
+        || sentry::integrations::anyhow::capture_anyhow(&err),
+    );
+    (StatusCode::BAD_REQUEST, format!("{err}")).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_kinds() {
+        let kind = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(())
+                .unwrap();
+            RequestKind::of(&req)
+        };
+        assert_eq!(kind(Method::POST, "/v2/getObject"), RequestKind::Read);
+        assert_eq!(
+            kind(Method::POST, "/vss/listKeyVersions"),
+            RequestKind::Read
+        );
+        assert_eq!(kind(Method::POST, "/v2/listChangedKeys"), RequestKind::Read);
+        assert_eq!(kind(Method::GET, "/v2/object/a/b"), RequestKind::Read);
+        assert_eq!(kind(Method::PUT, "/v2/putObjects"), RequestKind::Write);
+        assert_eq!(kind(Method::POST, "/vss/putObjects"), RequestKind::Write);
+        assert_eq!(kind(Method::POST, "/v2/deleteByPrefix"), RequestKind::Write);
+        assert_eq!(kind(Method::POST, "/admin/export"), RequestKind::Admin);
+        assert_eq!(kind(Method::GET, "/admin/stores"), RequestKind::Admin);
+        assert_eq!(kind(Method::GET, "/migration"), RequestKind::Admin);
+
+        let timeouts = RequestTimeouts::default();
+        assert!(timeouts.get(RequestKind::Admin) > timeouts.get(RequestKind::Write));
+    }
+
+    #[test]
+    fn test_read_cache_control() {
+        assert_eq!(read_cache_control(None), "no-store");
+        assert_eq!(read_cache_control(Some(0)), "no-store");
+        assert_eq!(read_cache_control(Some(30)), "private, max-age=30");
+    }
 }
@@ -0,0 +1,178 @@
+use crate::auth::verify_admin_token;
+use crate::models::{with_db_retry, VssItem};
+use crate::routes::ErrorResponse;
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use diesel::Connection;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MAX_STORES: usize = 10_000;
+const MAX_KEYS_PER_STORE: usize = 100_000;
+const MAX_VALUE_SIZE: usize = 1_000_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedRequest {
+    pub stores: usize,
+    pub keys_per_store: usize,
+    /// Size of every generated value in bytes
+    pub value_size: usize,
+    /// Stores are named `{store_prefix}_{n}`, defaults to `seed`
+    pub store_prefix: Option<String>,
+    /// Keys written per transaction, defaults to 100
+    pub batch_size: Option<usize>,
+}
+
+impl SeedRequest {
+    fn validate(&self) -> Result<(), InvalidRequest> {
+        let out_of_range = |field, message| {
+            Err(InvalidRequest::field(
+                field,
+                FieldErrorCode::OutOfRange,
+                message,
+            ))
+        };
+        if self.stores > MAX_STORES {
+            return out_of_range("stores", format!("Can seed at most {MAX_STORES} stores"));
+        }
+        if self.keys_per_store > MAX_KEYS_PER_STORE {
+            return out_of_range(
+                "keys_per_store",
+                format!("Can seed at most {MAX_KEYS_PER_STORE} keys per store"),
+            );
+        }
+        if self.value_size > MAX_VALUE_SIZE {
+            return out_of_range(
+                "value_size",
+                format!("Values can be at most {MAX_VALUE_SIZE} bytes"),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Summary of a seeding run, logged once it finishes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeedReport {
+    pub stores: usize,
+    pub items: usize,
+    pub skipped_stores: Vec<String>,
+}
+
+/// Generates `size` bytes that look random, so values don't compress any
+/// better than real encrypted channel state, but are the same on every run.
+pub fn seed_value(store_id: &str, key: &str, size: usize) -> Vec<u8> {
+    let mut value = Vec::with_capacity(size);
+    let mut counter: u64 = 0;
+    while value.len() < size {
+        let block = Sha256::new()
+            .chain_update(store_id.as_bytes())
+            .chain_update(key.as_bytes())
+            .chain_update(counter.to_be_bytes())
+            .finalize();
+        let take = (size - value.len()).min(block.len());
+        value.extend_from_slice(&block[..take]);
+        counter += 1;
+    }
+    value
+}
+
+/// Writes `keys` generated keys into a store that must not exist yet,
+/// `batch_size` keys per transaction. Returns the number of keys written,
+/// or None if the store already exists.
+pub async fn seed_store(
+    store_id: &str,
+    keys: usize,
+    value_size: usize,
+    batch_size: usize,
+    state: &State,
+) -> anyhow::Result<Option<usize>> {
+    let exists = with_db_retry("seed_store", &state.breaker, || {
+        let mut conn = state.db(store_id).get()?;
+        VssItem::store_exists(&mut conn, store_id)
+    })
+    .await?;
+    if exists {
+        return Ok(None);
+    }
+
+    let key_names: Vec<String> = (0..keys).map(|i| format!("seed/{i:08}")).collect();
+    for batch in key_names.chunks(batch_size.max(1)) {
+        let items: Vec<(&str, Vec<u8>)> = batch
+            .iter()
+            .map(|key| (key.as_str(), seed_value(store_id, key, value_size)))
+            .collect();
+
+        with_db_retry("seed_store", &state.breaker, || {
+            let mut conn = state.db(store_id).get()?;
+            conn.transaction::<_, anyhow::Error, _>(|conn| {
+                for (key, value) in items.iter() {
+                    VssItem::put_item(conn, store_id, key, value, 0)?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+    }
+
+    Ok(Some(keys))
+}
+
+pub async fn seed_impl(req: SeedRequest, state: &State) -> anyhow::Result<SeedReport> {
+    let prefix = req.store_prefix.as_deref().unwrap_or("seed");
+    let batch_size = req.batch_size.unwrap_or(100);
+
+    let mut report = SeedReport::default();
+
+    info!(
+        "Seeding {} stores with {} keys of {} bytes",
+        req.stores, req.keys_per_store, req.value_size
+    );
+
+    for n in 0..req.stores {
+        let store_id = format!("{prefix}_{n}");
+        match seed_store(
+            &store_id,
+            req.keys_per_store,
+            req.value_size,
+            batch_size,
+            state,
+        )
+        .await?
+        {
+            Some(count) => {
+                report.stores += 1;
+                report.items += count;
+            }
+            None => report.skipped_stores.push(store_id),
+        }
+    }
+
+    info!("Seeding complete! {report:?}");
+
+    Ok(report)
+}
+
+pub async fn seed(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Json(payload): Json<SeedRequest>,
+) -> Result<Json<()>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    if let Err(e) = payload.validate() {
+        return Err(e.to_response());
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = seed_impl(payload, &state).await {
+            error!("Seeding failed: {e:?}")
+        }
+    });
+
+    Ok(Json(()))
+}
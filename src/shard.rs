@@ -0,0 +1,168 @@
+use crate::auth::verify_admin_token;
+use crate::models::{with_db_retry, VssStore};
+use crate::routes::{handle_anyhow_error, ErrorResponse};
+use crate::State;
+use anyhow::anyhow;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+
+/// Name of the only shard when `DATABASE_SHARDS` isn't set
+pub const DEFAULT_SHARD: &str = "default";
+/// Points each shard gets on the hash ring, so stores spread evenly
+const VIRTUAL_NODES: u32 = 128;
+
+/// One of the databases stores are spread across.
+#[derive(Clone)]
+pub struct Shard {
+    pub name: String,
+    pub pool: DbPool,
+}
+
+/// Routes each store to one of several databases by consistent hashing of
+/// its store id. Shards are placed on the ring by name, so adding a shard
+/// only moves the stores it takes over and reordering them moves none.
+#[derive(Clone)]
+pub struct Shards {
+    shards: Arc<[Shard]>,
+    /// Ring positions and the index of the shard at each, sorted
+    ring: Arc<[(u64, usize)]>,
+}
+
+impl Shards {
+    pub fn new(shards: Vec<Shard>) -> anyhow::Result<Shards> {
+        if shards.is_empty() {
+            return Err(anyhow!("At least one database shard is required"));
+        }
+        for (i, shard) in shards.iter().enumerate() {
+            if shards[..i].iter().any(|s| s.name == shard.name) {
+                return Err(anyhow!("Duplicate database shard {}", shard.name));
+            }
+        }
+
+        let mut ring: Vec<(u64, usize)> = shards
+            .iter()
+            .enumerate()
+            .flat_map(|(i, shard)| {
+                (0..VIRTUAL_NODES).map(move |v| (ring_position(&format!("{}#{v}", shard.name)), i))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        Ok(Shards {
+            shards: shards.into(),
+            ring: ring.into(),
+        })
+    }
+
+    /// A single database holding every store.
+    pub fn single(pool: DbPool) -> Shards {
+        Shards::new(vec![Shard {
+            name: DEFAULT_SHARD.to_string(),
+            pool,
+        }])
+        .expect("one shard is valid")
+    }
+
+    /// The `name=url` pairs in `DATABASE_SHARDS`, separated by commas. None
+    /// if it isn't set.
+    pub fn urls_from_env() -> anyhow::Result<Option<Vec<(String, String)>>> {
+        let Ok(shards) = std::env::var("DATABASE_SHARDS") else {
+            return Ok(None);
+        };
+        shards
+            .split(',')
+            .map(|s| match s.trim().split_once('=') {
+                Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+                    Ok((name.to_string(), url.to_string()))
+                }
+                _ => Err(anyhow!("DATABASE_SHARDS entries must look like name=url")),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// The shard holding `store_id`.
+    pub fn for_store(&self, store_id: &str) -> &Shard {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+        let position = ring_position(store_id);
+        let idx = self.ring.partition_point(|(p, _)| *p < position) % self.ring.len();
+        &self.shards[self.ring[idx].1]
+    }
+
+    pub fn all(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    /// Lists stores from every shard ordered by store_id, starting after
+    /// `after`.
+    pub fn list_stores(&self, after: Option<&str>, limit: i64) -> anyhow::Result<Vec<VssStore>> {
+        let mut stores = vec![];
+        for shard in self.shards.iter() {
+            let mut conn = shard.pool.get()?;
+            stores.extend(VssStore::list_stores(&mut conn, after, limit)?);
+        }
+        if self.shards.len() > 1 {
+            stores.sort_unstable_by(|a, b| a.store_id.cmp(&b.store_id));
+            stores.truncate(limit.max(0) as usize);
+        }
+        Ok(stores)
+    }
+}
+
+fn ring_position(s: &str) -> u64 {
+    let hash = Sha256::digest(s.as_bytes());
+    u64::from_be_bytes(hash[..8].try_into().expect("8 bytes"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardStatus {
+    pub name: String,
+    pub stores: usize,
+    /// Stores in this database that hash to another shard, left behind
+    /// when shards were added. Clients won't see them until they are moved.
+    pub misplaced: usize,
+}
+
+pub async fn list_shards_impl(state: &State) -> anyhow::Result<Vec<ShardStatus>> {
+    let mut statuses = vec![];
+    for shard in state.shards.all() {
+        let store_ids = with_db_retry("list_shard_stores", &state.breaker, || {
+            let mut conn = shard.pool.get()?;
+            VssStore::list_ids(&mut conn)
+        })
+        .await?;
+        let misplaced = store_ids
+            .iter()
+            .filter(|id| state.shards.for_store(id).name != shard.name)
+            .count();
+        statuses.push(ShardStatus {
+            name: shard.name.clone(),
+            stores: store_ids.len(),
+            misplaced,
+        });
+    }
+    Ok(statuses)
+}
+
+/// How many stores each database shard holds.
+pub async fn list_shards(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<ShardStatus>>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    match list_shards_impl(&state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("list_shards", e)),
+    }
+}
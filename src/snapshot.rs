@@ -0,0 +1,368 @@
+use crate::auth::verify_admin_token;
+use crate::blob::{self, BlobStore};
+use crate::data_export::ExportedItem;
+use crate::models::{with_db_retry, ItemAttributes, Snapshot, SnapshotItem, VssItem, VssStore};
+use crate::routes::{handle_anyhow_error, resolve_store_id, ErrorResponse};
+use crate::validation::{FieldErrorCode, InvalidRequest};
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::Path;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, Json, TypedHeader};
+use diesel::Connection;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Most snapshots a store can have
+pub const MAX_SNAPSHOTS: usize = 10;
+/// Longest label a snapshot can have, in bytes
+pub const MAX_LABEL_LEN: usize = 64;
+const ITEM_BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSnapshotRequest {
+    pub store_id: Option<String>,
+    /// Name of the snapshot, unique within the store
+    pub label: String,
+}
+
+impl CreateSnapshotRequest {
+    fn validate(&self) -> Result<(), InvalidRequest> {
+        if self.label.is_empty() || self.label.len() > MAX_LABEL_LEN {
+            return Err(InvalidRequest::field(
+                "label",
+                FieldErrorCode::InvalidLength,
+                format!("label must be 1 to {MAX_LABEL_LEN} bytes"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListSnapshotsRequest {
+    pub store_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub store_id: Option<String>,
+    pub snapshot_id: String,
+}
+
+/// A snapshot with every item it holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotBundle {
+    pub snapshot: Snapshot,
+    pub items: Vec<ExportedItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreSnapshotRequest {
+    pub store_id: Option<String>,
+    pub snapshot_id: String,
+    /// Restores into this new store instead, leaving the snapshot's store
+    /// as it is
+    pub new_store_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreSnapshotResponse {
+    /// Store the snapshot was restored into
+    pub store_id: String,
+    /// Items written from the snapshot
+    pub restored: usize,
+    /// Live items tombstoned for not being in the snapshot
+    pub deleted: usize,
+}
+
+fn random_id() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Failed to generate id: {e}"))?;
+    Ok(hex::encode(bytes))
+}
+
+/// The item's whole value, read back from object storage when it was
+/// offloaded.
+fn item_value(item: &mut SnapshotItem, blobs: Option<&BlobStore>) -> anyhow::Result<Vec<u8>> {
+    match (item.value.take(), &item.object_key) {
+        (Some(value), _) => Ok(value),
+        (None, Some(object_key)) => {
+            let blobs = blobs.ok_or_else(|| {
+                anyhow!(
+                    "Value of {} is in object storage, but BLOB_STORE_URL isn't set",
+                    item.key
+                )
+            })?;
+            blobs.get(object_key, item.value_hash.as_deref().unwrap_or_default())
+        }
+        (None, None) => Err(anyhow!("Snapshot of {} has no value", item.key)),
+    }
+}
+
+fn exported(mut item: SnapshotItem, blobs: Option<&BlobStore>) -> anyhow::Result<ExportedItem> {
+    let value = item_value(&mut item, blobs)?;
+
+    Ok(ExportedItem {
+        key: item.key,
+        value: Some(base64::encode(value)),
+        version: item.version,
+        deleted: false,
+        created_date: item.created_date,
+        updated_date: item.updated_date,
+        last_modified_by: item.last_modified_by,
+        metadata: item.metadata,
+        content_type: item.content_type,
+    })
+}
+
+pub async fn create_snapshot_impl(
+    req: CreateSnapshotRequest,
+    state: &State,
+) -> anyhow::Result<Snapshot> {
+    req.validate()?;
+    let store_id = req.store_id.expect("must have");
+    let snapshot_id = random_id()?;
+
+    with_db_retry("create_snapshot", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            // waits for writes in flight so the snapshot doesn't split them
+            VssItem::lock_store(conn, &store_id)?;
+            VssStore::check_writable(conn, &store_id)?;
+            if VssStore::get_store(conn, &store_id)?.is_none() {
+                return Err(anyhow!("Store {store_id} doesn't exist"));
+            }
+            let snapshots = Snapshot::list_for_store(conn, &store_id)?;
+            if snapshots.iter().any(|s| s.label == req.label) {
+                return Err(anyhow!("Snapshot {:?} already exists", req.label));
+            }
+            if snapshots.len() >= MAX_SNAPSHOTS {
+                return Err(InvalidRequest::field(
+                    "label",
+                    FieldErrorCode::TooMany,
+                    format!("A store can have at most {MAX_SNAPSHOTS} snapshots"),
+                )
+                .into());
+            }
+            Snapshot::create(conn, &snapshot_id, &store_id, &req.label)
+        })
+    })
+    .await
+}
+
+pub async fn list_snapshots_impl(
+    req: ListSnapshotsRequest,
+    state: &State,
+) -> anyhow::Result<Vec<Snapshot>> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("list_snapshots", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Snapshot::list_for_store(&mut conn, &store_id)
+    })
+    .await
+}
+
+/// The snapshot with every value it holds, for clients to download.
+pub async fn download_snapshot_impl(
+    req: SnapshotRequest,
+    state: &State,
+) -> anyhow::Result<SnapshotBundle> {
+    let store_id = req.store_id.expect("must have");
+
+    let snapshot = with_db_retry("get_snapshot", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Snapshot::get_snapshot(&mut conn, &store_id, &req.snapshot_id)
+    })
+    .await?
+    .ok_or_else(|| anyhow!("Snapshot {} not found", req.snapshot_id))?;
+
+    let mut items = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let batch = with_db_retry("download_snapshot", &state.breaker, || {
+            let mut conn = state.db(&store_id).get()?;
+            Snapshot::list_items(
+                &mut conn,
+                &snapshot.snapshot_id,
+                after.as_deref(),
+                ITEM_BATCH_SIZE,
+            )?
+            .into_iter()
+            .map(|item| exported(item, state.blobs.as_deref()))
+            .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = Some(last.key.clone());
+        items.extend(batch);
+    }
+
+    Ok(SnapshotBundle { snapshot, items })
+}
+
+/// Deletes the snapshot, returning the store's remaining snapshots.
+pub async fn delete_snapshot_impl(
+    req: SnapshotRequest,
+    state: &State,
+) -> anyhow::Result<Vec<Snapshot>> {
+    let store_id = req.store_id.expect("must have");
+
+    with_db_retry("delete_snapshot", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        if !Snapshot::delete(&mut conn, &store_id, &req.snapshot_id)? {
+            return Err(anyhow!("Snapshot {} not found", req.snapshot_id));
+        }
+        Snapshot::list_for_store(&mut conn, &store_id)
+    })
+    .await
+}
+
+/// Replaces the store's contents with the snapshot's, or copies them into a
+/// new store. Every item is written at the version after the one it has, so
+/// clients see the restored values as newer, and items not in the snapshot
+/// are tombstoned, all in one transaction.
+pub async fn restore_snapshot_impl(
+    req: RestoreSnapshotRequest,
+    state: &State,
+) -> anyhow::Result<RestoreSnapshotResponse> {
+    let store_id = req.store_id.expect("must have");
+    let target = match req.new_store_id {
+        Some(new_store_id) => {
+            state.store_id_policy.validate(&new_store_id)?;
+            if new_store_id == store_id {
+                return Err(InvalidRequest::field(
+                    "new_store_id",
+                    FieldErrorCode::NotAllowed,
+                    "new_store_id must differ from store_id",
+                )
+                .into());
+            }
+            if state.shards.for_store(&new_store_id).name != state.shards.for_store(&store_id).name
+            {
+                return Err(anyhow!(
+                    "Stores {store_id} and {new_store_id} are on different database shards"
+                ));
+            }
+            Some(new_store_id)
+        }
+        None => None,
+    };
+
+    let snapshot = with_db_retry("get_snapshot", &state.breaker, || {
+        let mut conn = state.db(&store_id).get()?;
+        Snapshot::get_snapshot(&mut conn, &store_id, &req.snapshot_id)
+    })
+    .await?
+    .ok_or_else(|| anyhow!("Snapshot {} not found", req.snapshot_id))?;
+
+    // values are read up front so object storage isn't waited on while the
+    // store is locked
+    let mut items = vec![];
+    let mut after: Option<String> = None;
+    loop {
+        let batch = with_db_retry("read_snapshot", &state.breaker, || {
+            let mut conn = state.db(&store_id).get()?;
+            Snapshot::list_items(
+                &mut conn,
+                &snapshot.snapshot_id,
+                after.as_deref(),
+                ITEM_BATCH_SIZE,
+            )?
+            .into_iter()
+            .map(|mut item| Ok((item_value(&mut item, state.blobs.as_deref())?, item)))
+            .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .await?;
+        let Some((_, last)) = batch.last() else {
+            break;
+        };
+        after = Some(last.key.clone());
+        items.extend(batch);
+    }
+
+    let target = target.as_deref().unwrap_or(&store_id);
+    let offloaded = blob::offload(
+        state.blobs.as_ref(),
+        target,
+        items.iter().map(|(value, _)| value.as_slice()),
+    )
+    .await?;
+
+    let deleted = with_db_retry("restore_snapshot", &state.breaker, || {
+        let mut conn = state.db(target).get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            VssItem::lock_store(conn, target)?;
+            if target != store_id && VssItem::store_exists(conn, target)? {
+                return Err(anyhow!("Store {target} already exists"));
+            }
+            VssStore::check_writable(conn, target)?;
+
+            for ((value, item), offloaded) in items.iter().zip(&offloaded) {
+                let version = VssItem::next_version(conn, target, &item.key)?;
+                let attributes = ItemAttributes {
+                    metadata: item.metadata.clone(),
+                    content_type: item.content_type.clone(),
+                };
+                match offloaded {
+                    Some(offloaded) => VssItem::put_offloaded(
+                        conn,
+                        target,
+                        &item.key,
+                        offloaded,
+                        version,
+                        &attributes,
+                    )?,
+                    None => VssItem::put_item_with_attributes(
+                        conn,
+                        target,
+                        &item.key,
+                        value,
+                        version,
+                        &attributes,
+                    )?,
+                }
+            }
+
+            let keys: Vec<&str> = items.iter().map(|(_, item)| item.key.as_str()).collect();
+            VssItem::tombstone_all_except(conn, target, &keys)
+        })
+    })
+    .await?;
+
+    info!(
+        "Restored snapshot {} of store {store_id} into {target}, {} items written and {deleted} deleted",
+        snapshot.snapshot_id,
+        items.len()
+    );
+
+    Ok(RestoreSnapshotResponse {
+        store_id: target.to_string(),
+        restored: items.len(),
+        deleted,
+    })
+}
+
+/// Restores a snapshot of the store, into a new store when the body has a
+/// `new_store_id`.
+pub async fn restore_snapshot(
+    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    Extension(state): Extension<State>,
+    Path(store_id): Path<String>,
+    Json(mut payload): Json<RestoreSnapshotRequest>,
+) -> Result<Json<RestoreSnapshotResponse>, ErrorResponse> {
+    verify_admin_token(token.token(), &state)?;
+
+    let store_id = resolve_store_id(&store_id, &state)
+        .await
+        .map_err(|e| handle_anyhow_error("resolve_store_id", e))?;
+    payload.store_id = Some(store_id);
+    match restore_snapshot_impl(payload, &state).await {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error("restore_snapshot", e)),
+    }
+}
@@ -0,0 +1,184 @@
+use crate::auth::{sign_admin_token, sign_store_token};
+use anyhow::anyhow;
+use log::info;
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Command line flag that runs a self-hosted server for your own wallet
+pub const STANDALONE_FLAG: &str = "--standalone";
+/// Command line flag followed by a store id, printing a token for the store
+/// instead of starting the server
+pub const CLIENT_TOKEN_FLAG: &str = "--client-token";
+/// Data directory used when `VSS_DATA_DIR` isn't set
+const DEFAULT_DATA_DIR: &str = "vss-data";
+const ADMIN_KEY_FILE: &str = "admin_key";
+const AUTH_KEY_FILE: &str = "auth_key";
+const ADMIN_TOKEN_FILE: &str = "admin_token";
+/// How long generated tokens are valid for
+const TOKEN_VALID_DAYS: i64 = 365;
+
+/// A self-hosted server keeping its own keys in a local data directory.
+/// Items are still stored in Postgres, since the schema relies on its
+/// functions and triggers.
+pub struct Standalone {
+    pub data_dir: PathBuf,
+    /// Signs admin tokens, generated on first run
+    pub admin_key: SecretKey,
+    /// Signs client tokens, generated on first run
+    pub auth_key: SecretKey,
+}
+
+impl Standalone {
+    /// None unless the server was started with [`STANDALONE_FLAG`].
+    pub fn from_env() -> anyhow::Result<Option<Standalone>> {
+        if !std::env::args().any(|arg| arg == STANDALONE_FLAG) {
+            return Ok(None);
+        }
+        let data_dir = std::env::var("VSS_DATA_DIR").unwrap_or(DEFAULT_DATA_DIR.to_string());
+        Standalone::open(PathBuf::from(data_dir)).map(Some)
+    }
+
+    /// Loads the keys from `data_dir`, creating them on first run.
+    pub fn open(data_dir: PathBuf) -> anyhow::Result<Standalone> {
+        std::fs::create_dir_all(&data_dir)?;
+
+        Ok(Standalone {
+            admin_key: load_or_generate_key(&data_dir.join(ADMIN_KEY_FILE), "an admin")?,
+            auth_key: load_or_generate_key(&data_dir.join(AUTH_KEY_FILE), "a client auth")?,
+            data_dir,
+        })
+    }
+
+    pub fn admin_pubkey(&self, secp: &Secp256k1<All>) -> PublicKey {
+        PublicKey::from_secret_key(secp, &self.admin_key)
+    }
+
+    pub fn auth_pubkey(&self, secp: &Secp256k1<All>) -> PublicKey {
+        PublicKey::from_secret_key(secp, &self.auth_key)
+    }
+
+    /// The store id given after [`CLIENT_TOKEN_FLAG`], if any.
+    pub fn client_token_arg() -> anyhow::Result<Option<String>> {
+        let mut args = std::env::args().skip_while(|arg| arg != CLIENT_TOKEN_FLAG);
+        if args.next().is_none() {
+            return Ok(None);
+        }
+        match args.next() {
+            Some(store_id) => Ok(Some(store_id)),
+            None => Err(anyhow!("{CLIENT_TOKEN_FLAG} needs a store id")),
+        }
+    }
+
+    /// A token for the wallet to use with `store_id`, signed with the
+    /// generated client key.
+    pub fn client_token(&self, secp: &Secp256k1<All>, store_id: &str) -> anyhow::Result<String> {
+        sign_store_token(
+            &self.auth_key,
+            secp,
+            store_id,
+            chrono::Duration::days(TOKEN_VALID_DAYS),
+        )
+    }
+
+    /// Signs a new admin token with the generated admin key and writes it to
+    /// a file only the owner can read, returning its path.
+    pub fn write_admin_token(&self, secp: &Secp256k1<All>) -> anyhow::Result<PathBuf> {
+        let token = sign_admin_token(
+            &self.admin_key,
+            secp,
+            chrono::Duration::days(TOKEN_VALID_DAYS),
+        )?;
+        let path = self.data_dir.join(ADMIN_TOKEN_FILE);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        write_private(&path, &token)?;
+        Ok(path)
+    }
+
+    /// What to point a wallet at and where to find a token for the admin
+    /// API, unless `ADMIN_AUTH_KEY` overrides the generated key.
+    pub fn instructions(&self, port: u16, admin_token: Option<&Path>) -> String {
+        let mut instructions = format!(
+            "VSS is running in standalone mode.\n\
+             \n\
+             Set your wallet's VSS URL to http://<this machine's address>:{port}\n\
+             Get a token for your wallet's store with `{STANDALONE_FLAG} {CLIENT_TOKEN_FLAG} <store_id>`\n\
+             The keys are kept in {}, keep them safe.\n",
+            self.data_dir.display()
+        );
+        if let Some(path) = admin_token {
+            instructions.push_str(&format!(
+                "An admin token valid for a year was written to {}\n",
+                path.display()
+            ));
+        }
+        instructions
+    }
+}
+
+/// Reads the hex key in `path`, generating and saving a new one if there is
+/// no file yet.
+fn load_or_generate_key(path: &Path, what: &str) -> anyhow::Result<SecretKey> {
+    match std::fs::read_to_string(path) {
+        Ok(key) => Ok(SecretKey::from_slice(&hex::decode(key.trim())?)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = generate_key()?;
+            write_private(path, &hex::encode(key.secret_bytes()))?;
+            info!("Generated {what} key in {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn generate_key() -> anyhow::Result<SecretKey> {
+    let mut bytes = [0u8; 32];
+    loop {
+        getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Failed to generate key: {e}"))?;
+        // all but a negligible fraction of 32 byte strings are valid keys
+        if let Ok(key) = SecretKey::from_slice(&bytes) {
+            return Ok(key);
+        }
+    }
+}
+
+/// Writes a file only its owner can read, where the platform supports it.
+fn write_private(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_standalone_admin_key() {
+        let data_dir = std::env::temp_dir().join("vss_standalone_test");
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let first = Standalone::open(data_dir.clone()).unwrap();
+        let second = Standalone::open(data_dir.clone()).unwrap();
+        assert_eq!(first.admin_key, second.admin_key);
+        assert_eq!(first.auth_key, second.auth_key);
+        assert_ne!(first.admin_key, first.auth_key);
+
+        let path = data_dir.join("admin_token");
+        let instructions = first.instructions(8080, Some(&path));
+        assert!(instructions.contains(":8080"));
+        assert!(instructions.contains(&path.display().to_string()));
+        assert!(!first.instructions(8080, None).contains("admin token"));
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
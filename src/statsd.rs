@@ -0,0 +1,193 @@
+use crate::metrics::{self, MetricKind};
+use crate::State;
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const DEFAULT_PORT: u16 = 8125;
+const DEFAULT_PREFIX: &str = "vss";
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+/// Largest datagram sent, so packets aren't fragmented on common networks
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Pushes the server's metrics to a StatsD or DogStatsD agent over UDP, for
+/// monitoring stacks that don't scrape `/metrics`.
+#[derive(Debug, Clone)]
+pub struct StatsdSink {
+    /// `host:port` of the agent
+    pub addr: String,
+    /// Put in front of every metric name, followed by a dot
+    pub prefix: String,
+    /// DogStatsD tags sent with every metric, like `env:prod`
+    pub tags: Vec<String>,
+    pub interval: Duration,
+}
+
+impl StatsdSink {
+    /// Enabled by `STATSD_HOST`, pushing every `STATSD_INTERVAL_SECS` with
+    /// names starting with `STATSD_PREFIX` and tagged with `STATSD_TAGS`.
+    pub fn from_env() -> anyhow::Result<Option<StatsdSink>> {
+        let Ok(host) = std::env::var("STATSD_HOST") else {
+            return Ok(None);
+        };
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.clone(),
+            Some(_) => return Err(anyhow!("Invalid port in STATSD_HOST {host}")),
+            None => format!("{host}:{DEFAULT_PORT}"),
+        };
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| DEFAULT_PREFIX.to_string());
+        let tags = std::env::var("STATSD_TAGS")
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let interval = std::env::var("STATSD_INTERVAL_SECS")
+            .ok()
+            .map(|s| s.parse::<u64>())
+            .transpose()?
+            .unwrap_or(DEFAULT_INTERVAL_SECS);
+
+        info!("Pushing metrics to StatsD at {addr} every {interval}s");
+
+        Ok(Some(StatsdSink {
+            addr,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags,
+            interval: Duration::from_secs(interval.max(1)),
+        }))
+    }
+
+    /// StatsD name of a metric: its Prometheus name without the `vss_`
+    /// prefix or `_total` suffix, after the configured prefix.
+    fn name(&self, metric: &str) -> String {
+        let metric = metric.strip_prefix("vss_").unwrap_or(metric);
+        let metric = metric.strip_suffix("_total").unwrap_or(metric);
+        if self.prefix.is_empty() {
+            metric.to_string()
+        } else {
+            format!("{}.{metric}", self.prefix)
+        }
+    }
+
+    /// A line per metric, gauges with their value and counters with how much
+    /// they went up since `last`, which is updated to the new totals.
+    pub fn lines(
+        &self,
+        samples: &[(&'static str, MetricKind, u64)],
+        last: &mut HashMap<&'static str, u64>,
+    ) -> Vec<String> {
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", self.tags.join(","))
+        };
+
+        samples
+            .iter()
+            .map(|&(metric, kind, value)| {
+                let name = self.name(metric);
+                match kind {
+                    MetricKind::Gauge => format!("{name}:{value}|g{tags}"),
+                    MetricKind::Counter => {
+                        // a total that went down was reset, so all of it is new
+                        let previous = last.insert(metric, value).unwrap_or(0);
+                        let delta = value.checked_sub(previous).unwrap_or(value);
+                        format!("{name}:{delta}|c{tags}")
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Lines joined into as few datagrams as fit within [`MAX_PACKET_SIZE`].
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = vec![];
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+/// Pushes the metrics every interval until the server shuts down. Metrics
+/// are sent over UDP, so pushes the agent misses are dropped, counters
+/// included.
+pub async fn run_pusher(state: State, sink: StatsdSink) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Failed to open a socket for StatsD, not pushing metrics: {e}");
+            return;
+        }
+    };
+
+    let mut last = HashMap::new();
+    let mut ticker = tokio::time::interval(sink.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let lines = sink.lines(&metrics::collect(&state), &mut last);
+        for packet in packets(&lines) {
+            if let Err(e) = socket.send_to(packet.as_bytes(), &sink.addr).await {
+                debug!("Failed to push metrics to StatsD at {}: {e}", sink.addr);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statsd_lines() {
+        let sink = StatsdSink {
+            addr: "localhost:8125".to_string(),
+            prefix: "wallet.vss".to_string(),
+            tags: vec!["env:test".to_string()],
+            interval: Duration::from_secs(10),
+        };
+        let mut last = HashMap::new();
+        let samples = |checkouts| {
+            vec![
+                ("vss_db_pool_in_use", MetricKind::Gauge, 3),
+                (
+                    "vss_db_pool_checkouts_total",
+                    MetricKind::Counter,
+                    checkouts,
+                ),
+            ]
+        };
+
+        assert_eq!(
+            sink.lines(&samples(5), &mut last),
+            vec![
+                "wallet.vss.db_pool_in_use:3|g|#env:test",
+                "wallet.vss.db_pool_checkouts:5|c|#env:test",
+            ]
+        );
+        // counters are sent as the increase since the last push
+        assert_eq!(
+            sink.lines(&samples(8), &mut last)[1],
+            "wallet.vss.db_pool_checkouts:3|c|#env:test"
+        );
+        // and a total that went down was reset, so all of it is new
+        assert_eq!(
+            sink.lines(&samples(2), &mut last)[1],
+            "wallet.vss.db_pool_checkouts:2|c|#env:test"
+        );
+    }
+}
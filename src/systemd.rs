@@ -0,0 +1,111 @@
+use log::{debug, warn};
+use std::net::TcpListener;
+
+/// First file descriptor systemd passes to socket activated services
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listening socket systemd passed in with socket activation, if any.
+/// Only the first socket is used.
+pub fn listener_from_env() -> anyhow::Result<Option<TcpListener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .unwrap_or(0);
+    if !for_us || fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {fds} sockets, only the first is used");
+    }
+
+    // so processes we start don't think the sockets are theirs
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    listener_from_fd()
+}
+
+#[cfg(unix)]
+fn listener_from_fd() -> anyhow::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    // safe as systemd hands this descriptor to us alone
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn listener_from_fd() -> anyhow::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Tells systemd about a change of state, e.g. `READY=1` or `STOPPING=1`,
+/// when started as a `Type=notify` service.
+pub fn notify(state: &str) {
+    let Ok(socket) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    match notify_socket(&socket, state) {
+        Ok(()) => debug!("Notified systemd: {state}"),
+        Err(e) => warn!("Failed to notify systemd of {state}: {e}"),
+    }
+}
+
+/// Sends `state` to the notification socket at `path`, where a leading `@`
+/// names a socket in the abstract namespace.
+#[cfg(unix)]
+pub fn notify_socket(path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify_socket(_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_systemd_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join("vss_notify_test.sock");
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        assert!(notify_socket("@vss_notify_test_missing", "READY=1").is_err());
+        // not started by systemd
+        assert!(listener_from_env().unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+//! Random tokens handed out to users and the hashes they're stored under.
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+/// A random token of `len` bytes, hex encoded.
+pub fn random_hex(len: usize) -> anyhow::Result<String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow!("Failed to generate random bytes: {e}"))?;
+    Ok(hex::encode(bytes))
+}
+
+/// The hash a token is stored and looked up by, so the token itself is never
+/// kept.
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,113 @@
+use crate::access_log::AccessLog;
+use crate::models::{with_db_retry, UsageDay};
+use crate::State;
+use axum::body::HttpBody;
+use axum::http::{header, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use log::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageCounts {
+    pub requests: i64,
+    pub bytes_read: i64,
+    pub bytes_written: i64,
+}
+
+impl UsageCounts {
+    fn merge(&mut self, other: UsageCounts) {
+        self.requests += other.requests;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Per-store usage accumulated in memory and periodically added to the
+/// `vss_usage` table, so metering doesn't cost a write on every request.
+#[derive(Debug, Clone, Default)]
+pub struct UsageMeter {
+    pending: Arc<Mutex<HashMap<String, UsageCounts>>>,
+}
+
+impl UsageMeter {
+    pub fn record(&self, store_id: &str, counts: UsageCounts) {
+        let mut pending = self.pending.lock().expect("usage lock poisoned");
+        match pending.get_mut(store_id) {
+            Some(existing) => existing.merge(counts),
+            None => {
+                pending.insert(store_id.to_string(), counts);
+            }
+        }
+    }
+
+    fn take(&self) -> HashMap<String, UsageCounts> {
+        std::mem::take(&mut *self.pending.lock().expect("usage lock poisoned"))
+    }
+
+    /// Writes out everything recorded so far. Counts that couldn't be
+    /// written are kept for the next flush.
+    pub async fn flush(&self, state: &State) {
+        for (store_id, counts) in self.take() {
+            let res = with_db_retry("flush_usage", &state.breaker, || {
+                let mut conn = state.db(&store_id).get()?;
+                UsageDay::add(
+                    &mut conn,
+                    &store_id,
+                    counts.requests,
+                    counts.bytes_read,
+                    counts.bytes_written,
+                )
+            })
+            .await;
+
+            if let Err(e) = res {
+                error!("Failed to record usage: {e}");
+                self.record(&store_id, counts);
+            }
+        }
+    }
+}
+
+/// Flushes the meter every `interval` until the server shuts down.
+pub async fn run_flusher(state: State, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        state.usage.flush(&state).await;
+    }
+}
+
+/// Records each request against the store its handler acted on. Must run
+/// inside [`crate::access_log::access_log`], which provides the store id.
+pub async fn meter_usage<B>(req: Request<B>, next: Next<B>) -> Response {
+    let log = req.extensions().get::<AccessLog>().cloned();
+    let meter = req.extensions().get::<State>().map(|s| s.usage.clone());
+    let bytes_written = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0);
+
+    let res = next.run(req).await;
+
+    if let (Some(log), Some(meter)) = (log, meter) {
+        if let Some(store_id) = log.store_id() {
+            let bytes_read = res.body().size_hint().exact().unwrap_or(0);
+            meter.record(
+                &store_id,
+                UsageCounts {
+                    requests: 1,
+                    bytes_read: bytes_read as i64,
+                    bytes_written,
+                },
+            );
+        }
+    }
+
+    res
+}
@@ -0,0 +1,672 @@
+use crate::kv::{KeyValue, NO_VERSION_CHECK};
+use crate::routes::ErrorResponse;
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use unicode_normalization::is_nfc;
+
+const DEFAULT_MAX_KEY_LEN: usize = 1_024;
+const DEFAULT_MAX_STORE_ID_LEN: usize = 255;
+/// Most bytes an item's metadata can take as JSON
+pub const MAX_METADATA_BYTES: usize = 4_096;
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+/// Store ids starting with this name a store by its alias
+pub const ALIAS_PREFIX: char = '@';
+const MAX_ALIAS_LEN: usize = 32;
+
+/// Set of allowed characters, written like a regex character class without
+/// the brackets, e.g. `a-zA-Z0-9_/.-`. A `-` that isn't between two
+/// characters is taken literally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Charset(Vec<(char, char)>);
+
+impl Charset {
+    pub fn contains(&self, c: char) -> bool {
+        self.0.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c))
+    }
+}
+
+impl FromStr for Charset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut ranges = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                let (lo, hi) = (chars[i], chars[i + 2]);
+                if lo > hi {
+                    return Err(anyhow!("Invalid character range {lo}-{hi}"));
+                }
+                ranges.push((lo, hi));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        if ranges.is_empty() {
+            return Err(anyhow!("Character set can't be empty"));
+        }
+        Ok(Charset(ranges))
+    }
+}
+
+/// Rules keys must follow to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Longest key allowed, in bytes
+    pub max_len: usize,
+    pub allowed: Option<Charset>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        KeyPolicy {
+            max_len: DEFAULT_MAX_KEY_LEN,
+            allowed: None,
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Configured by `KEY_MAX_LENGTH` and `KEY_ALLOWED_CHARS`.
+    pub fn from_env() -> anyhow::Result<KeyPolicy> {
+        let max_len = std::env::var("KEY_MAX_LENGTH")
+            .ok()
+            .map(|s| s.parse::<usize>())
+            .transpose()?
+            .unwrap_or(DEFAULT_MAX_KEY_LEN);
+        let allowed = std::env::var("KEY_ALLOWED_CHARS")
+            .ok()
+            .map(|s| s.parse::<Charset>())
+            .transpose()?;
+
+        Ok(KeyPolicy { max_len, allowed })
+    }
+
+    /// Checks `key` can be written, failing with [`InvalidRequest`] naming
+    /// the key if not.
+    pub fn validate(&self, key: &str) -> Result<(), InvalidRequest> {
+        use FieldErrorCode::*;
+        let problem = if key.is_empty() {
+            Some((InvalidLength, "Key can't be empty".to_string()))
+        } else if key.len() > self.max_len {
+            Some((
+                InvalidLength,
+                format!("Key is longer than {} bytes", self.max_len),
+            ))
+        } else if key.chars().any(char::is_control) {
+            Some((
+                InvalidCharacters,
+                "Key contains control characters".to_string(),
+            ))
+        } else if !is_nfc(key) {
+            Some((NotNormalized, "Key is not NFC normalized".to_string()))
+        } else {
+            self.allowed
+                .as_ref()
+                .and_then(|allowed| key.chars().find(|c| !allowed.contains(*c)))
+                .map(|c| {
+                    (
+                        InvalidCharacters,
+                        format!("Key contains disallowed character {c:?}"),
+                    )
+                })
+        };
+
+        match problem {
+            Some((code, message)) => Err(InvalidRequest::key(code, message, key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks every key of a batch, failing with an [`InvalidRequest`] that
+    /// lists how each key fared if any can't be written.
+    pub fn validate_all<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), InvalidRequest> {
+        let mut items = vec![];
+        let mut field_errors = vec![];
+        for (i, key) in keys.into_iter().enumerate() {
+            match self.validate(key) {
+                Ok(()) => items.push(ItemResult::ok(key)),
+                Err(e) => {
+                    items.push(ItemResult::failed(key, ItemStatus::Invalid, e.message));
+                    field_errors.extend(e.field_errors.into_iter().map(|error| FieldError {
+                        field: format!("transaction_items[{i}].key"),
+                        ..error
+                    }));
+                }
+            }
+        }
+
+        match field_errors.is_empty() {
+            true => Ok(()),
+            false => Err(InvalidRequest::items(items, field_errors)),
+        }
+    }
+}
+
+/// Checks no item of a batch has an empty value, which would read back as
+/// neither a value nor a deleted key. Failing items are listed the same way
+/// as by [`KeyPolicy::validate_all`].
+pub fn validate_values(items: &[KeyValue]) -> Result<(), InvalidRequest> {
+    let message = "Value can't be empty, use deleteByPrefix to delete a key";
+    let mut results = vec![];
+    let mut field_errors = vec![];
+    for (i, kv) in items.iter().enumerate() {
+        if kv.value.0.is_empty() {
+            results.push(ItemResult::failed(
+                &kv.key,
+                ItemStatus::Invalid,
+                message.to_string(),
+            ));
+            field_errors.push(FieldError {
+                field: format!("transaction_items[{i}].value"),
+                code: FieldErrorCode::Required,
+                message: message.to_string(),
+            });
+        } else {
+            results.push(ItemResult::ok(&kv.key));
+        }
+    }
+
+    match field_errors.is_empty() {
+        true => Ok(()),
+        false => Err(InvalidRequest::items(results, field_errors)),
+    }
+}
+
+/// Whether `content_type` looks like `type/subtype`, optionally followed by
+/// parameters, and can be sent back as a header.
+pub fn valid_content_type(content_type: &str) -> bool {
+    if content_type.len() > MAX_CONTENT_TYPE_LEN
+        || !content_type
+            .bytes()
+            .all(|b| b == b' ' || b.is_ascii_graphic())
+    {
+        return false;
+    }
+    let token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) => token(kind) && token(subtype),
+        None => false,
+    }
+}
+
+/// Whether `alias` can name a store: 3 to 32 lowercase letters, digits, `-`
+/// and `_`, starting with a letter.
+pub fn valid_alias(alias: &str) -> bool {
+    (3..=MAX_ALIAS_LEN).contains(&alias.len())
+        && alias.starts_with(|c: char| c.is_ascii_lowercase())
+        && alias
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Checks each item's metadata is a JSON object of at most
+/// [`MAX_METADATA_BYTES`] once serialized, and its content type is a media
+/// type. Failing items are listed the same way as by
+/// [`KeyPolicy::validate_all`].
+pub fn validate_attributes(items: &[KeyValue]) -> Result<(), InvalidRequest> {
+    let mut results = vec![];
+    let mut field_errors = vec![];
+    for (i, kv) in items.iter().enumerate() {
+        let problem = match (&kv.metadata, &kv.content_type) {
+            (Some(metadata), _) if !metadata.is_object() => Some((
+                "metadata",
+                FieldErrorCode::InvalidFormat,
+                "Metadata must be a JSON object".to_string(),
+            )),
+            (Some(metadata), _) if metadata.to_string().len() > MAX_METADATA_BYTES => Some((
+                "metadata",
+                FieldErrorCode::InvalidLength,
+                format!("Metadata can be at most {MAX_METADATA_BYTES} bytes"),
+            )),
+            (_, Some(content_type)) if !valid_content_type(content_type) => Some((
+                "content_type",
+                FieldErrorCode::InvalidFormat,
+                format!(
+                    "Content type must look like type/subtype and be at most {MAX_CONTENT_TYPE_LEN} characters"
+                ),
+            )),
+            _ => None,
+        };
+        match problem {
+            Some((field, code, message)) => {
+                results.push(ItemResult::failed(
+                    &kv.key,
+                    ItemStatus::Invalid,
+                    message.clone(),
+                ));
+                field_errors.push(FieldError {
+                    field: format!("transaction_items[{i}].{field}"),
+                    code,
+                    message,
+                });
+            }
+            None => results.push(ItemResult::ok(&kv.key)),
+        }
+    }
+
+    match field_errors.is_empty() {
+        true => Ok(()),
+        false => Err(InvalidRequest::items(results, field_errors)),
+    }
+}
+
+/// Checks no item of a batch skips the version check, for stores that
+/// don't allow lazy versioning. Failing items are listed the same way as by
+/// [`KeyPolicy::validate_all`].
+pub fn validate_lazy_versions(items: &[KeyValue]) -> Result<(), InvalidRequest> {
+    let message =
+        "Version is required, this store doesn't accept writes that skip the version check";
+    let mut results = vec![];
+    let mut field_errors = vec![];
+    for (i, kv) in items.iter().enumerate() {
+        if kv.version == NO_VERSION_CHECK {
+            results.push(ItemResult::failed(
+                &kv.key,
+                ItemStatus::Invalid,
+                message.to_string(),
+            ));
+            field_errors.push(FieldError {
+                field: format!("transaction_items[{i}].version"),
+                code: FieldErrorCode::NotAllowed,
+                message: message.to_string(),
+            });
+        } else {
+            results.push(ItemResult::ok(&kv.key));
+        }
+    }
+
+    match field_errors.is_empty() {
+        true => Ok(()),
+        false => Err(InvalidRequest::items(results, field_errors)),
+    }
+}
+
+/// Rules store ids must follow, whether they come from the token or the
+/// request body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreIdPolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub allowed: Option<Charset>,
+    /// Require a hex-encoded secp256k1 public key
+    pub pubkey: bool,
+}
+
+impl Default for StoreIdPolicy {
+    fn default() -> Self {
+        StoreIdPolicy {
+            min_len: 1,
+            max_len: DEFAULT_MAX_STORE_ID_LEN,
+            allowed: None,
+            pubkey: false,
+        }
+    }
+}
+
+impl StoreIdPolicy {
+    /// Configured by `STORE_ID_MIN_LENGTH`, `STORE_ID_MAX_LENGTH`,
+    /// `STORE_ID_ALLOWED_CHARS` and `STORE_ID_PUBKEY`.
+    pub fn from_env() -> anyhow::Result<StoreIdPolicy> {
+        let length = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|s| s.parse::<usize>())
+                .transpose()
+        };
+        let defaults = StoreIdPolicy::default();
+
+        let policy = StoreIdPolicy {
+            min_len: length("STORE_ID_MIN_LENGTH")?.unwrap_or(defaults.min_len),
+            max_len: length("STORE_ID_MAX_LENGTH")?.unwrap_or(defaults.max_len),
+            allowed: std::env::var("STORE_ID_ALLOWED_CHARS")
+                .ok()
+                .map(|s| s.parse::<Charset>())
+                .transpose()?,
+            pubkey: std::env::var("STORE_ID_PUBKEY")
+                .ok()
+                .map(|s| s == "true" || s == "1")
+                .unwrap_or(false),
+        };
+        if policy.min_len > policy.max_len {
+            return Err(anyhow!(
+                "STORE_ID_MIN_LENGTH can't be more than STORE_ID_MAX_LENGTH"
+            ));
+        }
+
+        Ok(policy)
+    }
+
+    /// Checks `store_id` is acceptable, failing with [`InvalidRequest`]
+    /// naming the store id if not.
+    pub fn validate(&self, store_id: &str) -> Result<(), InvalidRequest> {
+        use FieldErrorCode::*;
+        let len = store_id.len();
+        let problem = if len < self.min_len || len > self.max_len {
+            Some((
+                InvalidLength,
+                format!(
+                    "store_id must be {} to {} bytes",
+                    self.min_len, self.max_len
+                ),
+            ))
+        } else if store_id.chars().any(char::is_control) {
+            Some((
+                InvalidCharacters,
+                "store_id contains control characters".to_string(),
+            ))
+        } else if let Some(c) = self
+            .allowed
+            .as_ref()
+            .and_then(|allowed| store_id.chars().find(|c| !allowed.contains(*c)))
+        {
+            Some((
+                InvalidCharacters,
+                format!("store_id contains disallowed character {c:?}"),
+            ))
+        } else if self.pubkey && !is_hex_pubkey(store_id) {
+            Some((
+                InvalidFormat,
+                "store_id must be a hex encoded public key".to_string(),
+            ))
+        } else {
+            None
+        };
+
+        match problem {
+            Some((code, message)) => Err(InvalidRequest::store_id(code, message, store_id)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_hex_pubkey(s: &str) -> bool {
+    hex::decode(s)
+        .ok()
+        .map_or(false, |bytes| PublicKey::from_slice(&bytes).is_ok())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    Ok,
+    /// Older than the version already stored
+    Conflict,
+    Invalid,
+}
+
+/// How one item of a rejected batch fared. Batches are all or nothing, so
+/// items marked ok weren't written either.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemResult {
+    pub key: String,
+    pub status: ItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ItemResult {
+    pub fn ok(key: &str) -> Self {
+        ItemResult {
+            key: key.to_string(),
+            status: ItemStatus::Ok,
+            reason: None,
+        }
+    }
+
+    pub fn failed(key: &str, status: ItemStatus, reason: String) -> Self {
+        ItemResult {
+            key: key.to_string(),
+            status,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// The first failed item's key and reason, and how many failed.
+fn first_failure(items: &[ItemResult]) -> (Option<String>, String) {
+    let failed: Vec<&ItemResult> = items
+        .iter()
+        .filter(|i| i.status != ItemStatus::Ok)
+        .collect();
+    let Some(first) = failed.first() else {
+        return (None, "No items failed".to_string());
+    };
+
+    let reason = first.reason.clone().unwrap_or_default();
+    let message = match failed.len() {
+        1 => reason,
+        n => format!("{reason}, and {} more items failed", n - 1),
+    };
+    (Some(first.key.clone()), message)
+}
+
+/// Why a field was rejected, for clients to act on without parsing messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldErrorCode {
+    Required,
+    InvalidLength,
+    InvalidCharacters,
+    NotNormalized,
+    InvalidFormat,
+    OutOfRange,
+    TooMany,
+    NotAllowed,
+}
+
+/// A single rejected field of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// Path of the field in the request, e.g. `transaction_items[2].key`
+    pub field: String,
+    pub code: FieldErrorCode,
+    pub message: String,
+}
+
+/// The `error` of an [`InvalidRequest`] body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum InvalidRequestError {
+    InvalidRequest,
+}
+
+/// Returned for requests with malformed input, sent to the client as JSON so
+/// it can tell which value was rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvalidRequest {
+    /// Always `INVALID_REQUEST`
+    pub error: InvalidRequestError,
+    /// The first field error's message
+    pub message: String,
+    #[serde(default)]
+    pub field_errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store_id: Option<String>,
+    /// Every item of a rejected batch, `key` being the first invalid one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<ItemResult>>,
+}
+
+impl InvalidRequest {
+    /// A request rejected because of a single field.
+    pub fn field(field: &str, code: FieldErrorCode, message: impl Into<String>) -> Self {
+        let message = message.into();
+        InvalidRequest {
+            error: InvalidRequestError::InvalidRequest,
+            message: message.clone(),
+            field_errors: vec![FieldError {
+                field: field.to_string(),
+                code,
+                message,
+            }],
+            key: None,
+            store_id: None,
+            items: None,
+        }
+    }
+
+    pub fn key(code: FieldErrorCode, message: String, key: &str) -> Self {
+        InvalidRequest {
+            key: Some(key.to_string()),
+            ..Self::field("key", code, message)
+        }
+    }
+
+    pub fn store_id(code: FieldErrorCode, message: String, store_id: &str) -> Self {
+        InvalidRequest {
+            store_id: Some(store_id.to_string()),
+            ..Self::field("store_id", code, message)
+        }
+    }
+
+    /// A request rejected because of several fields, summarized by the
+    /// first.
+    pub fn fields(field_errors: Vec<FieldError>) -> Self {
+        let first = field_errors
+            .first()
+            .map(|e| e.message.clone())
+            .unwrap_or_default();
+        let message = match field_errors.len() {
+            0 | 1 => first,
+            n => format!("{first}, and {} more fields were rejected", n - 1),
+        };
+        InvalidRequest {
+            error: InvalidRequestError::InvalidRequest,
+            message,
+            field_errors,
+            key: None,
+            store_id: None,
+            items: None,
+        }
+    }
+
+    pub fn items(items: Vec<ItemResult>, field_errors: Vec<FieldError>) -> Self {
+        let (key, message) = first_failure(&items);
+        InvalidRequest {
+            error: InvalidRequestError::InvalidRequest,
+            message,
+            field_errors,
+            key,
+            store_id: None,
+            items: Some(items),
+        }
+    }
+
+    /// The status and JSON body it is sent to clients as.
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse::json(StatusCode::BAD_REQUEST, self)
+    }
+}
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+/// Returned when a batch is rejected because some of its items are older
+/// than the versions already stored, see `STRICT_VERSIONS`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionConflict {
+    /// Always `CONFLICT`
+    pub error: String,
+    pub message: String,
+    /// The first conflicting key
+    pub key: Option<String>,
+    pub items: Vec<ItemResult>,
+}
+
+impl VersionConflict {
+    pub fn new(items: Vec<ItemResult>) -> Self {
+        let (key, message) = first_failure(&items);
+        VersionConflict {
+            error: "CONFLICT".to_string(),
+            message,
+            key,
+            items,
+        }
+    }
+
+    /// The status and JSON body it is sent to clients as.
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse::json(StatusCode::CONFLICT, self)
+    }
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_policy() {
+        let policy = KeyPolicy {
+            max_len: 16,
+            allowed: None,
+        };
+        assert!(policy.validate("channel/1").is_ok());
+        assert!(policy.validate("caf\u{e9}").is_ok());
+        for key in ["", "a_very_long_key_name", "a\0b", "a\nb", "cafe\u{301}"] {
+            assert_eq!(policy.validate(key).unwrap_err().key.as_deref(), Some(key));
+        }
+
+        let policy = KeyPolicy {
+            max_len: 16,
+            allowed: Some("a-z0-9_/-".parse::<Charset>().unwrap()),
+        };
+        assert!(policy.validate("monitors/ab-01").is_ok());
+        let err = policy.validate("Monitors").unwrap_err();
+        assert_eq!(err.message, "Key contains disallowed character 'M'");
+        assert!("z-a".parse::<Charset>().is_err());
+        assert!("".parse::<Charset>().is_err());
+    }
+
+    #[test]
+    fn test_store_id_policy() {
+        let policy = StoreIdPolicy::default();
+        assert!(policy.validate("store_id").is_ok());
+        assert!(policy.validate("").is_err());
+        assert!(policy.validate("a\0b").is_err());
+        assert!(policy.validate(&"a".repeat(256)).is_err());
+
+        let pubkey = "03a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd";
+        let policy = StoreIdPolicy {
+            min_len: 66,
+            max_len: 66,
+            allowed: Some("0-9a-f".parse::<Charset>().unwrap()),
+            pubkey: true,
+        };
+        assert!(policy.validate(pubkey).is_ok());
+        let err = policy.validate(&pubkey.to_uppercase()).unwrap_err();
+        assert_eq!(err.store_id, Some(pubkey.to_uppercase()));
+        // right length and charset, but not a point on the curve
+        assert!(policy.validate(&format!("04{}", &pubkey[2..])).is_err());
+    }
+}